@@ -0,0 +1,203 @@
+use crate::bounds3::Bounds3;
+use crate::bvh::{BVHAccel, SplitMethod};
+use crate::grid::UniformGrid;
+use crate::intersection::IntersectData;
+use crate::kdtree::KdTreeAccel;
+use crate::packet::{keep_nearest, RayPacket};
+use crate::ray::Ray;
+use crate::wide_bvh::{BVH4, BVH8};
+
+// spelled like BVHAccel
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum AcceleratorType {
+    BVH,
+    BVH4,
+    BVH8,
+    UniformGrid,
+    KdTree,
+}
+
+// leaf callback of get_intersection_packet
+pub type PacketIntersect<'a, 'b> = dyn Fn(usize, &RayPacket, &[bool], &mut [Option<IntersectData<'b>>]) + 'a;
+
+// [comment]
+// Spatial index over primitives known only by their bounds and an index, the same
+// contract as BVHAccel::from_bounds. get_intersection calls `intersect` with the
+// original index of each candidate and returns the nearest hit.
+// [/comment]
+pub trait Accelerator: Sync {
+    fn get_bounds(&self) -> Bounds3;
+
+    fn get_intersection<'b>(
+        &self,
+        ray: &Ray,
+        intersect: &dyn Fn(usize, &Ray) -> Option<IntersectData<'b>>
+    ) -> Option<IntersectData<'b>>;
+
+    // [comment]
+    // True when `occluded` reports a hit closer than t_max for any candidate. The
+    // default walks the ray through get_intersection and keeps no hit data, every
+    // accelerator here overrides it to stop at the first blocker.
+    // [/comment]
+    fn occluded(&self, ray: &Ray, t_max: f32, occluded: &dyn Fn(usize, &Ray, f32) -> bool) -> bool {
+        let found = std::cell::Cell::new(false);
+        self.get_intersection(ray, &|prim, r| {
+            if !found.get() && occluded(prim, r, t_max) {
+                found.set(true);
+            }
+            return None;
+        });
+        return found.get();
+    }
+
+    // [comment]
+    // Packet version of get_intersection for the rays marked in `active`. Leaves call
+    // `intersect` with the packet and the rays that reached them, which must only
+    // replace hits[k] by nearer hits. The default traces every ray on its own.
+    // [/comment]
+    fn get_intersection_packet<'b>(
+        &self,
+        packet: &RayPacket,
+        active: &[bool],
+        intersect: &PacketIntersect<'_, 'b>,
+        hits: &mut [Option<IntersectData<'b>>]
+    ) {
+        for (k, ray) in packet.rays.iter().enumerate() {
+            if !active[k] {
+                continue;
+            }
+            let hit = self.get_intersection(ray, &|prim, r| {
+                let mut single = [None];
+                intersect(prim, &RayPacket::new(std::slice::from_ref(r)), &[true], &mut single);
+                return single[0].take();
+            });
+            if let Some(data) = hit {
+                keep_nearest(&mut hits[k], data);
+            }
+        }
+    }
+
+    // primitives moved but kept their indices
+    fn refit(&mut self, bounds: &[Bounds3]);
+
+    fn as_bvh(&self) -> Option<&BVHAccel> {
+        return None;
+    }
+}
+
+pub fn build_accelerator(
+    accelerator_type: AcceleratorType,
+    bounds: Vec<Bounds3>,
+    max_prims_in_node: u32,
+    split_method: SplitMethod,
+) -> Box<dyn Accelerator> {
+    return match accelerator_type {
+        AcceleratorType::BVH => Box::new(BVHAccel::from_bounds(bounds, max_prims_in_node, split_method)),
+        AcceleratorType::BVH4 => Box::new(BVH4::new(bounds, max_prims_in_node, split_method)),
+        AcceleratorType::BVH8 => Box::new(BVH8::new(bounds, max_prims_in_node, split_method)),
+        AcceleratorType::UniformGrid => Box::new(UniformGrid::new(bounds)),
+        AcceleratorType::KdTree => Box::new(KdTreeAccel::new(bounds, max_prims_in_node)),
+    };
+}
+
+impl Accelerator for BVHAccel {
+    fn get_bounds(&self) -> Bounds3 {
+        return BVHAccel::get_bounds(self);
+    }
+
+    fn get_intersection<'b>(
+        &self,
+        ray: &Ray,
+        intersect: &dyn Fn(usize, &Ray) -> Option<IntersectData<'b>>
+    ) -> Option<IntersectData<'b>> {
+        return BVHAccel::get_intersection(self, ray, intersect);
+    }
+
+    fn get_intersection_packet<'b>(
+        &self,
+        packet: &RayPacket,
+        active: &[bool],
+        intersect: &PacketIntersect<'_, 'b>,
+        hits: &mut [Option<IntersectData<'b>>]
+    ) {
+        BVHAccel::get_intersection_packet(self, packet, active, intersect, hits);
+    }
+
+    fn occluded(&self, ray: &Ray, t_max: f32, occluded: &dyn Fn(usize, &Ray, f32) -> bool) -> bool {
+        return BVHAccel::occluded(self, ray, t_max, occluded);
+    }
+
+    fn refit(&mut self, bounds: &[Bounds3]) {
+        BVHAccel::refit(self, bounds);
+    }
+
+    fn as_bvh(&self) -> Option<&BVHAccel> {
+        return Some(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accelerator::{build_accelerator, AcceleratorType};
+    use crate::bvh::SplitMethod;
+    use crate::material::Material;
+    use crate::object::ObjectTrait;
+    use crate::ray::Ray;
+    use crate::sphere::Sphere;
+
+    #[test]
+    fn test_accelerators_agree() {
+        let mat = Material::default();
+        // a loose cloud of small spheres plus one large sphere spanning many cells
+        let mut spheres = Vec::new();
+        for i in 0..200 {
+            let f = i as f32;
+            let c = glm::vec3((f * 7.3) % 20. - 10., (f * 3.1) % 20. - 10., -(f * 5.7) % 20. - 15.);
+            spheres.push(Sphere::new(&c, 0.3 + (i % 5) as f32 * 0.2, &mat));
+        }
+        spheres.push(Sphere::new(&glm::vec3(0., 0., -25.), 6., &mat));
+        let objects: Vec<&dyn ObjectTrait> = spheres.iter().map(|s| s as &dyn ObjectTrait).collect();
+        let bounds: Vec<_> = objects.iter().map(|o| o.get_bounds()).collect();
+
+        let types = [
+            AcceleratorType::BVH,
+            AcceleratorType::BVH4,
+            AcceleratorType::BVH8,
+            AcceleratorType::UniformGrid,
+            AcceleratorType::KdTree,
+        ];
+        let accels: Vec<_> = types
+            .iter()
+            .map(|&t| build_accelerator(t, bounds.clone(), 1, SplitMethod::SAH))
+            .collect();
+
+        let mut hits = 0;
+        for j in 0..40 {
+            for i in 0..40 {
+                let dir = glm::vec3(i as f32 / 20. - 1., j as f32 / 20. - 1., -1.).normalize();
+                let ray = Ray::new(&glm::vec3(0., 0., 0.), &dir);
+                let results: Vec<Option<f32>> = accels.iter()
+                    .map(|a| a.get_intersection(&ray, &|p, r| objects[p].get_intersection(r)).map(|d| d.distance))
+                    .collect();
+                if results[0].is_some() {
+                    hits += 1;
+                }
+                let blocked: Vec<bool> = accels.iter()
+                    .map(|a| a.occluded(&ray, 20., &|p, r, t| {
+                        objects[p].get_intersection(r).is_some_and(|d| d.distance < t)
+                    }))
+                    .collect();
+                assert!(blocked.iter().all(|&b| b == results[0].is_some_and(|d| d < 20.)), "{:?}", blocked);
+                for r in &results[1..] {
+                    match (results[0], r) {
+                        (None, None) => {}
+                        (Some(a), Some(b)) => assert!((a - b).abs() < 1e-4, "{} {}", a, b),
+                        _ => panic!("accelerators disagree on ray {} {}: {:?}", i, j, results),
+                    }
+                }
+            }
+        }
+        assert!(hits > 100, "{}", hits);
+    }
+}
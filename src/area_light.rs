@@ -1,28 +1,46 @@
-use crate::light::Light;
 use crate::global::get_random_f32;
+use crate::object::ObjectTrait;
 
-struct AreaLight {
-    pub base: Light,
-    pub length: f32,
-    pub normal: glm::Vec3,
-    pub u: glm::Vec3,
-    pub v: glm::Vec3,
+// [comment]
+// A shape with a finite area that light can be emitted from. sample maps (u, v)
+// in [0, 1) x [0, 1) to a point on the surface, uniformly over the area, and
+// returns it with the unit normal there. Light leaves on the side of the normal.
+// [/comment]
+pub trait Emitter: ObjectTrait {
+    fn sample(&self, u: f32, v: f32) -> (glm::Vec3, glm::Vec3);
 }
 
-impl AreaLight {
-    pub fn new(p: &glm::Vec3, i: &glm::Vec3) -> AreaLight {
+// [comment]
+// Light spread over the surface of a shape. It is traced as samples_per_axis^2
+// point lights on jittered strata of the surface, each with an equal share of
+// the intensity, which gives soft shadows.
+// [/comment]
+pub struct AreaLight<'a> {
+    pub shape: &'a dyn Emitter,
+    pub intensity: glm::Vec3,
+    pub samples_per_axis: u32,
+}
+
+impl<'a> AreaLight<'a> {
+    pub fn new(shape: &'a dyn Emitter, intensity: &glm::Vec3) -> AreaLight<'a> {
         AreaLight {
-            base: Light::new(p, i),
-            normal: glm::vec3(0., -1., 0.),
-            u: glm::vec3(1., 0., 0.),
-            v: glm::vec3(0., 0., 1.),
-            length: 100.0
+            shape,
+            intensity: *intensity,
+            samples_per_axis: 4,
         }
     }
 
-    pub fn sample_point(&self) -> glm::Vec3 {
-        let random_u = get_random_f32();
-        let random_v = get_random_f32();
-        return self.base.position + random_u * self.u + random_v * self.v;
+    // points and normals of one stratified set of samples
+    pub fn sample_points(&self) -> Vec<(glm::Vec3, glm::Vec3)> {
+        let n = self.samples_per_axis.max(1);
+        let mut samples = Vec::with_capacity((n * n) as usize);
+        for j in 0..n {
+            for i in 0..n {
+                let u = (i as f32 + get_random_f32()) / n as f32;
+                let v = (j as f32 + get_random_f32()) / n as f32;
+                samples.push(self.shape.sample(u.min(1. - f32::EPSILON), v.min(1. - f32::EPSILON)));
+            }
+        }
+        return samples;
     }
-}
\ No newline at end of file
+}
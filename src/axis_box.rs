@@ -0,0 +1,204 @@
+use crate::area_light::Emitter;
+use crate::bounds3::Bounds3;
+use crate::csg::{Solid, Span};
+use crate::global::*;
+use crate::intersection::IntersectData;
+use crate::material::Material;
+use crate::object::ObjectTrait;
+use crate::ray::Ray;
+
+// [comment]
+// Solid axis aligned box. Normals point out of the box on every face, a ray
+// starting inside hits the face it leaves through from the back. Texture
+// coordinates run from 0 to 1 over each face, along the same axes as Rectangle.
+// [/comment]
+pub struct AxisBox<'a> {
+    pub bounds: Bounds3,
+    pub m: &'a Material,
+}
+
+impl<'a> AxisBox<'a> {
+    pub fn new(corner0: &glm::Vec3, corner1: &glm::Vec3, m: &'a Material) -> AxisBox<'a> {
+        AxisBox {
+            bounds: Bounds3::new(corner0, corner1),
+            m,
+        }
+    }
+
+    // [comment]
+    // Entry and exit distances of the ray through the slabs with the axis each of
+    // them was found on. Axes the ray runs parallel to only have to contain the
+    // origin, which avoids the 0 * inf NaN of the usual slab test.
+    // [/comment]
+    fn slabs(&self, ray: &Ray) -> Option<(f32, usize, f32, usize)> {
+        let (mut t_near, mut near_axis) = (f32::NEG_INFINITY, 0);
+        let (mut t_far, mut far_axis) = (f32::INFINITY, 0);
+        for k in 0..3 {
+            if ray.direction[k] == 0. {
+                if ray.origin[k] < self.bounds.p_min[k] || ray.origin[k] > self.bounds.p_max[k] {
+                    return None;
+                }
+                continue;
+            }
+            let inv = 1. / ray.direction[k];
+            let mut t0 = (self.bounds.p_min[k] - ray.origin[k]) * inv;
+            let mut t1 = (self.bounds.p_max[k] - ray.origin[k]) * inv;
+            if t0 > t1 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            if t0 > t_near {
+                t_near = t0;
+                near_axis = k;
+            }
+            if t1 < t_far {
+                t_far = t1;
+                far_axis = k;
+            }
+        }
+        if t_near > t_far {
+            return None;
+        }
+        return Some((t_near, near_axis, t_far, far_axis));
+    }
+
+    // hit on the face perpendicular to axis where the ray enters (side -1) or
+    // leaves (side 1) the box
+    fn hit_data(&self, ray: &Ray, t: f32, axis: usize, side: f32) -> IntersectData<'_> {
+        let mut normal = glm::vec3(0., 0., 0.);
+        normal[axis] = side * ray.direction[axis].signum();
+        let mut coords = ray.origin + ray.direction * t;
+        coords[axis] = if normal[axis] > 0. { self.bounds.p_max[axis] } else { self.bounds.p_min[axis] };
+        // the face coordinate is snapped to the box, so it is exact
+        let mut p_error = ray_point_error(&ray.origin, &ray.direction, t);
+        p_error[axis] = 0.;
+        let st = self.face_st(&coords, axis);
+
+        return IntersectData {
+            coords,
+            normal,
+            shading_normal: normal,
+            p_error,
+            barycentric: glm::zero(),
+            front_face: glm::dot(&ray.direction, &normal) < 0.,
+            distance: t,
+            index: u32::MAX,
+            m: self.m,
+            eval_diffuse_color: self.m.eval_diffuse_color(&st),
+            uv: glm::zero(),
+            st,
+        };
+    }
+
+    fn face_st(&self, p: &glm::Vec3, axis: usize) -> glm::Vec2 {
+        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+        let d = self.bounds.diagonal();
+        return glm::vec2(
+            if d[a] > 0. { (p[a] - self.bounds.p_min[a]) / d[a] } else { 0. },
+            if d[b] > 0. { (p[b] - self.bounds.p_min[b]) / d[b] } else { 0. },
+        );
+    }
+}
+
+impl<'a> ObjectTrait for AxisBox<'a> {
+    fn get_intersection(&self, ray: &Ray) -> Option<IntersectData<'_>> {
+        let (t_near, near_axis, t_far, far_axis) = self.slabs(ray)?;
+        // the entry face unless the ray starts inside, then the exit face
+        if ray.in_range(t_near) {
+            return Some(self.hit_data(ray, t_near, near_axis, -1.));
+        } else if ray.in_range(t_far) {
+            return Some(self.hit_data(ray, t_far, far_axis, 1.));
+        }
+        return None;
+    }
+
+    fn get_bounds(&self) -> Bounds3 {
+        return self.bounds.clone();
+    }
+}
+
+impl<'a> Solid for AxisBox<'a> {
+    fn get_spans(&self, ray: &Ray) -> Vec<Span<'_>> {
+        return match self.slabs(ray) {
+            // a ray without direction has no span
+            Some((t_near, near_axis, t_far, far_axis)) if t_near.is_finite() && t_far.is_finite() => vec![Span {
+                enter: self.hit_data(ray, t_near, near_axis, -1.),
+                exit: self.hit_data(ray, t_far, far_axis, 1.),
+            }],
+            _ => Vec::new(),
+        };
+    }
+}
+
+impl<'a> Emitter for AxisBox<'a> {
+    // u first picks one of the six faces in proportion to its area
+    fn sample(&self, u: f32, v: f32) -> (glm::Vec3, glm::Vec3) {
+        let d = self.bounds.diagonal();
+        let face_areas = [d.y * d.z, d.z * d.x, d.x * d.y];
+        let total = 2. * (face_areas[0] + face_areas[1] + face_areas[2]);
+        let mut x = u * total;
+        let mut face = 5;
+        for f in 0..6 {
+            if x < face_areas[f / 2] || f == 5 {
+                face = f;
+                break;
+            }
+            x -= face_areas[f / 2];
+        }
+        let axis = face / 2;
+        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+        let mut p = self.bounds.p_min;
+        let mut normal = glm::vec3(0., 0., 0.);
+        normal[axis] = -1.;
+        if face % 2 == 1 {
+            p[axis] = self.bounds.p_max[axis];
+            normal[axis] = 1.;
+        }
+        // the rest of u within the face spans its first axis
+        let s = if face_areas[axis] > 0. { (x / face_areas[axis]).min(1.) } else { 0. };
+        p[a] += s * d[a];
+        p[b] += v * d[b];
+        return (p, normal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::area_light::Emitter;
+    use crate::axis_box::AxisBox;
+    use crate::global::offset_ray_origin;
+    use crate::material::Material;
+    use crate::object::ObjectTrait;
+    use crate::ray::Ray;
+
+    #[test]
+    fn test_axis_box() {
+        let mat = Material::default();
+        let cube = AxisBox::new(&glm::vec3(1., 1., 1.), &glm::vec3(-1., -1., -1.), &mat);
+
+        let hit = cube.get_intersection(&Ray::new(&glm::vec3(0.5, 0., 5.), &glm::vec3(0., 0., -1.))).unwrap();
+        assert_eq!(hit.coords, glm::vec3(0.5, 0., 1.));
+        assert_eq!(hit.normal, glm::vec3(0., 0., 1.));
+        assert!(hit.front_face);
+        assert!(glm::distance(&hit.st, &glm::vec2(0.75, 0.5)) < 1e-6);
+
+        // from inside the exit face is hit from the back
+        let inside = cube.get_intersection(&Ray::new(&glm::vec3(0., 0., 0.), &glm::vec3(-1., 0., 0.))).unwrap();
+        assert_eq!(inside.normal, glm::vec3(-1., 0., 0.));
+        assert!(!inside.front_face && (inside.distance - 1.).abs() < 1e-6);
+        // a shadow ray leaving a face does not find it again
+        let origin = offset_ray_origin(&hit.coords, &hit.p_error, &hit.normal, &glm::vec3(0., 0., 1.));
+        assert!(cube.get_intersection(&Ray::new(&origin, &glm::vec3(0., 0., 1.))).is_none());
+
+        // parallel to a slab outside of it, and pointing away
+        assert!(cube.get_intersection(&Ray::new(&glm::vec3(0., 2., 5.), &glm::vec3(0., 0., -1.))).is_none());
+        assert!(cube.get_intersection(&Ray::new(&glm::vec3(0., 0., 5.), &glm::vec3(0., 0., 1.))).is_none());
+
+        for &(u, v) in [(0., 0.), (0.3, 0.7), (0.999, 0.5)].iter() {
+            let (p, n) = cube.sample(u, v);
+            // on the face the normal points out of
+            let k = (0..3).find(|&k| n[k] != 0.).unwrap();
+            assert_eq!(p[k], n[k]);
+            assert!(p.iter().all(|c| c.abs() <= 1.));
+        }
+    }
+}
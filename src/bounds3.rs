@@ -35,6 +35,16 @@ impl Bounds3 {
         }
     }
 
+    // false for the empty default bounds and bounds of NaN or infinite points
+    pub fn is_finite(&self) -> bool {
+        return self.p_min.iter().chain(self.p_max.iter()).all(|v| v.is_finite());
+    }
+
+    // true for the default bounds and bounds with a NaN coordinate
+    pub fn is_empty(&self) -> bool {
+        return !(0..3).all(|k| self.p_min[k] <= self.p_max[k]);
+    }
+
     pub fn diagonal(&self) -> glm::Vec3 {
         return self.p_max - self.p_min;
     }
@@ -86,9 +96,21 @@ impl Bounds3 {
             t2.y * ray.direction_inv.y,
             t2.z * ray.direction_inv.z
         );
-        let t_min = glm::comp_min(&glm::min2(&t1, &t2));
-        let t_max = glm::comp_max(&glm::max2(&t1, &t2));
-        return if t_min <= t_max && t_max > 0.0 { true } else { false };
+        // enter once inside all slabs, leave as soon as one slab is left, both
+        // clipped to the ray interval
+        let t_min = glm::comp_max(&glm::min2(&t1, &t2)).max(ray.t_min);
+        let t_max = glm::comp_min(&glm::max2(&t1, &t2)).min(ray.t_max);
+        return if t_min <= t_max { true } else { false };
+    }
+
+    // parametric range [t_enter, t_exit] of the ray inside the box, clipped to
+    // [ray.t_min, ray.t_max]
+    pub fn ray_interval(&self, ray: &Ray) -> Option<(f32, f32)> {
+        let t1 = (self.p_min - ray.origin).component_mul(&ray.direction_inv);
+        let t2 = (self.p_max - ray.origin).component_mul(&ray.direction_inv);
+        let t_enter = glm::comp_max(&glm::min2(&t1, &t2)).max(ray.t_min);
+        let t_exit = glm::comp_min(&glm::max2(&t1, &t2)).min(ray.t_max);
+        return if t_enter <= t_exit { Some((t_enter, t_exit)) } else { None };
     }
 
     pub fn offset(&self, p: &glm::Vec3) -> glm::Vec3 {
@@ -125,3 +147,34 @@ impl Bounds3 {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::bounds3::Bounds3;
+    use crate::ray::Ray;
+
+    #[test]
+    fn test_intersect_ray() {
+        let b = Bounds3::new(&glm::vec3(1., 1., 1.), &glm::vec3(2., 2., 2.));
+        let hit = Ray::new(&glm::vec3(0., 0., 0.), &glm::vec3(1., 1., 1.).normalize());
+        assert!(b.intersect_ray(&hit));
+        // overlaps the box in x and y, but not at the same time as in z
+        let miss = Ray::new(&glm::vec3(0., 0., 0.), &glm::vec3(1., 1., 4.).normalize());
+        assert!(!b.intersect_ray(&miss));
+        let behind = Ray::new(&glm::vec3(3., 3., 3.), &glm::vec3(1., 1., 1.).normalize());
+        assert!(!b.intersect_ray(&behind));
+    }
+
+    #[test]
+    fn test_ray_interval_clipped() {
+        let b = Bounds3::new(&glm::vec3(1., -1., -1.), &glm::vec3(3., 1., 1.));
+        let dir = glm::vec3(1., 0., 0.);
+        let o = glm::vec3(0., 0., 0.);
+        assert_eq!(b.ray_interval(&Ray::new(&o, &dir)), Some((1., 3.)));
+        assert_eq!(b.ray_interval(&Ray::segment(&o, &dir, 2., 10.)), Some((2., 3.)));
+        assert_eq!(b.ray_interval(&Ray::segment(&o, &dir, 0., 1.5)), Some((1., 1.5)));
+        // the segment ends before the box
+        assert!(!b.intersect_ray(&Ray::segment(&o, &dir, 0., 0.5)));
+        assert!(!b.intersect_ray(&Ray::segment(&o, &dir, 3.5, 10.)));
+    }
+}
@@ -1,129 +1,812 @@
+use rayon::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use crate::intersection::IntersectData;
 use crate::object::ObjectTrait;
+use crate::packet::{hit_distance, RayPacket, PACKET_SIZE};
 use crate::bounds3::Bounds3;
 use crate::ray::Ray;
 
+// below this many primitives a subtree is built on the current thread, the
+// cost of spawning a rayon task outweighs the work
+const PARALLEL_BUILD_THRESHOLD: usize = 4096;
+// number of buckets used by the binned SAH split
+const SAH_BUCKETS: usize = 12;
+// relative cost of one traversal step against one primitive test
+const SAH_TRAVERSAL_COST: f32 = 0.125;
+// refit spawns rayon tasks for the top levels of large trees only
+const PARALLEL_REFIT_DEPTH: u32 = 6;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum SplitMethod {
     NAIVE,
     SAH,
 }
 
-pub struct BVHBuildNode<'a> {
+// [comment]
+// Work done by ray queries on the current thread, summed over every bvh the rays
+// went through. Counting is off until enable_traversal_stats is called, so plain
+// renders only pay for one relaxed load per count. Reset it before a render and
+// read it afterwards.
+// [/comment]
+#[derive(Copy, Clone, Default, Debug)]
+pub struct TraversalStats {
+    pub rays: u64,
+    pub nodes_visited: u64,
+    pub primitives_tested: u64,
+}
+
+thread_local! {
+    static TRAVERSAL_STATS: std::cell::Cell<TraversalStats> = std::cell::Cell::new(TraversalStats::default());
+}
+
+static TRAVERSAL_STATS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable_traversal_stats() {
+    TRAVERSAL_STATS_ENABLED.store(true, Ordering::Relaxed);
+}
+
+#[inline]
+fn traversal_stats_enabled() -> bool {
+    return TRAVERSAL_STATS_ENABLED.load(Ordering::Relaxed);
+}
+
+pub fn traversal_stats() -> TraversalStats {
+    return TRAVERSAL_STATS.with(|s| s.get());
+}
+
+pub fn reset_traversal_stats() {
+    TRAVERSAL_STATS.with(|s| s.set(TraversalStats::default()));
+}
+
+pub fn count_ray() {
+    if !traversal_stats_enabled() {
+        return;
+    }
+    TRAVERSAL_STATS.with(|s| {
+        let mut v = s.get();
+        v.rays += 1;
+        s.set(v);
+    });
+}
+
+pub fn count_node_visit() {
+    if !traversal_stats_enabled() {
+        return;
+    }
+    TRAVERSAL_STATS.with(|s| {
+        let mut v = s.get();
+        v.nodes_visited += 1;
+        s.set(v);
+    });
+}
+
+pub fn count_primitive_test() {
+    if !traversal_stats_enabled() {
+        return;
+    }
+    TRAVERSAL_STATS.with(|s| {
+        let mut v = s.get();
+        v.primitives_tested += 1;
+        s.set(v);
+    });
+}
+
+impl TraversalStats {
+    // work done since `earlier` was read on this thread
+    pub fn since(&self, earlier: &TraversalStats) -> TraversalStats {
+        TraversalStats {
+            rays: self.rays - earlier.rays,
+            nodes_visited: self.nodes_visited - earlier.nodes_visited,
+            primitives_tested: self.primitives_tested - earlier.primitives_tested,
+        }
+    }
+}
+
+impl std::fmt::Display for TraversalStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rays = self.rays.max(1) as f64;
+        writeln!(f, "rays traced          : {}", self.rays)?;
+        writeln!(f, "nodes visited        : {} ({:.2} per ray)", self.nodes_visited, self.nodes_visited as f64 / rays)?;
+        write!(f, "primitives tested    : {} ({:.2} per ray)", self.primitives_tested, self.primitives_tested as f64 / rays)
+    }
+}
+
+// [comment]
+// Per primitive data precomputed once before the build, so the recursive
+// build never calls ObjectTrait::get_bounds again.
+// [/comment]
+#[derive(Clone)]
+pub struct BVHPrimitiveInfo {
+    pub index: usize,
+    pub bounds: Bounds3,
+    pub centroid: glm::Vec3,
+}
+
+impl BVHPrimitiveInfo {
+    pub fn new(index: usize, bounds: Bounds3) -> Self {
+        let centroid = bounds.centroid();
+        BVHPrimitiveInfo {
+            index, bounds, centroid
+        }
+    }
+}
+
+// [comment]
+// Leaf nodes reference `n_primitives` entries of BVHAccel::primitives starting
+// at `first_prim_offset`, interior nodes have n_primitives == 0.
+// [/comment]
+pub struct BVHBuildNode {
     pub bounds: Bounds3,
-    pub left: Option<Box<BVHBuildNode<'a>>>,
-    pub right: Option<Box<BVHBuildNode<'a>>>,
-    pub object: Option<&'a dyn ObjectTrait>,
+    pub left: Option<Box<BVHBuildNode>>,
+    pub right: Option<Box<BVHBuildNode>>,
+    pub first_prim_offset: usize,
+    pub n_primitives: usize,
 }
 
-impl<'a> Default for BVHBuildNode<'a> {
+impl Default for BVHBuildNode {
     fn default() -> Self {
         BVHBuildNode {
             bounds: Bounds3::default(),
             left: None,
             right: None,
-            object: None,
+            first_prim_offset: 0,
+            n_primitives: 0,
         }
     }
 }
 
-pub struct BVHAccel<'a> {
-    pub root: Option<Box<BVHBuildNode<'a>>>,
+impl BVHBuildNode {
+    pub fn is_leaf(&self) -> bool {
+        return self.n_primitives > 0;
+    }
+}
+
+pub struct BVHAccel {
+    pub root: Option<Box<BVHBuildNode>>,
     pub max_prims_in_node: u32,
     pub split_method: SplitMethod,
-    pub primitives: Vec<&'a dyn ObjectTrait>,
+    // primitive indices in leaf order
+    pub primitives: Vec<usize>,
+    // sah cost right after the last full build, reference for refit quality
+    pub build_sah_cost: f32,
 }
 
-impl<'a> BVHAccel<'a> {
+impl BVHAccel {
     pub fn new(
-        mut p: Vec<&'a dyn ObjectTrait>, 
+        p: &[&dyn ObjectTrait],
+        max_prims_in_node: u32,
+        split_method: SplitMethod
+    ) -> Self {
+        let bounds = p.par_iter().map(|obj| obj.get_bounds()).collect();
+        return BVHAccel::from_bounds(bounds, max_prims_in_node, split_method);
+    }
+
+    // [comment]
+    // Build the hierarchy over primitives given only by their bounds. Intersection
+    // is resolved later through the primitive index, see get_intersection.
+    // [/comment]
+    pub fn from_bounds(
+        bounds: Vec<Bounds3>,
         max_prims_in_node: u32,
         split_method: SplitMethod
     ) -> Self {
-        let root = BVHAccel::recursive_build(&mut p[..]);
-        BVHAccel {
+        let max_prims_in_node = max_prims_in_node.max(1);
+        let mut infos: Vec<BVHPrimitiveInfo> = bounds
+            .into_par_iter()
+            .enumerate()
+            .map(|(i, b)| BVHPrimitiveInfo::new(i, b))
+            .collect();
+
+        let root = BVHAccel::recursive_build(
+            &mut infos[..], 0, max_prims_in_node as usize, &split_method
+        );
+        let primitives = infos.iter().map(|info| info.index).collect();
+        let mut bvh = BVHAccel {
             root,
             max_prims_in_node,
             split_method,
-            primitives: p,
+            primitives,
+            build_sah_cost: 0.,
+        };
+        bvh.build_sah_cost = bvh.sah_cost();
+        return bvh;
+    }
+
+    // [comment]
+    // Update node bounds bottom up for moved primitives, the tree topology and
+    // primitive order are kept. `bounds` is indexed like the build input.
+    // [/comment]
+    pub fn refit(&mut self, bounds: &[Bounds3]) {
+        let primitives = &self.primitives;
+        let parallel = primitives.len() > PARALLEL_BUILD_THRESHOLD;
+        if let Some(root) = self.root.as_mut() {
+            BVHAccel::recursive_refit(root, primitives, bounds, if parallel { PARALLEL_REFIT_DEPTH } else { 0 });
+        }
+    }
+
+    fn recursive_refit(node: &mut BVHBuildNode, primitives: &[usize], bounds: &[Bounds3], parallel_depth: u32) {
+        if node.is_leaf() {
+            let begin = node.first_prim_offset;
+            let mut b = Bounds3::default();
+            for &prim in &primitives[begin..begin + node.n_primitives] {
+                b = Bounds3::union(&b, &bounds[prim]);
+            }
+            node.bounds = b;
+            return;
+        }
+
+        let depth = parallel_depth.saturating_sub(1);
+        let refit_child = |child: &mut Option<Box<BVHBuildNode>>| {
+            if let Some(c) = child.as_mut() {
+                BVHAccel::recursive_refit(c, primitives, bounds, depth);
+            }
+        };
+        let (left, right) = (&mut node.left, &mut node.right);
+        if parallel_depth > 0 {
+            rayon::join(|| refit_child(left), || refit_child(right));
+        } else {
+            refit_child(left);
+            refit_child(right);
+        }
+
+        let mut b = Bounds3::default();
+        for c in [&node.left, &node.right].iter().copied().flatten() {
+            b = Bounds3::union(&b, &c.bounds);
         }
+        node.bounds = b;
     }
 
-    pub fn recursive_build(objects: &mut [&'a dyn ObjectTrait]) 
-        -> Option<Box<BVHBuildNode<'a>>>
+    // [comment]
+    // Expected cost of a random ray through the tree, each node weighted by its
+    // surface area relative to the root.
+    // [/comment]
+    pub fn sah_cost(&self) -> f32 {
+        let root = match &self.root {
+            Some(root) => root,
+            None => return 0.,
+        };
+        let root_area = root.bounds.surface_area();
+        if root_area <= 0. {
+            return root.n_primitives as f32;
+        }
+        return BVHAccel::recursive_sah_cost(root) / root_area;
+    }
+
+    fn recursive_sah_cost(node: &BVHBuildNode) -> f32 {
+        let area = node.bounds.surface_area();
+        if node.is_leaf() {
+            return area * node.n_primitives as f32;
+        }
+        let mut cost = area * SAH_TRAVERSAL_COST;
+        for c in [&node.left, &node.right].iter().copied().flatten() {
+            cost += BVHAccel::recursive_sah_cost(c);
+        }
+        return cost;
+    }
+
+    // [comment]
+    // Ratio of the current sah cost to the one after the last build. Refitting
+    // keeps it near 1.0 for rigid or mild motion and grows it when primitives
+    // drift apart from their original neighbours.
+    // [/comment]
+    pub fn refit_quality(&self) -> f32 {
+        if self.build_sah_cost <= 0. {
+            return 1.;
+        }
+        return self.sah_cost() / self.build_sah_cost;
+    }
+
+    // true when a full rebuild is expected to pay off against refitting
+    pub fn needs_rebuild(&self, max_cost_ratio: f32) -> bool {
+        return self.refit_quality() > max_cost_ratio;
+    }
+
+    pub fn recursive_build(
+        infos: &mut [BVHPrimitiveInfo],
+        offset: usize,
+        max_prims_in_node: usize,
+        split_method: &SplitMethod,
+    ) -> Option<Box<BVHBuildNode>>
     {
-        if objects.len() == 0 {
+        if infos.is_empty() {
             return None;
         }
         let mut node = Box::new(BVHBuildNode::default());
         let mut bounds = Bounds3::default();
-        for obj in objects.iter() {
-            bounds = Bounds3::union(&bounds, &obj.get_bounds());
+        let mut centroid_bounds = Bounds3::default();
+        for info in infos.iter() {
+            bounds = Bounds3::union(&bounds, &info.bounds);
+            centroid_bounds = centroid_bounds.union_p(&info.centroid);
         }
         node.bounds = bounds;
 
-        if objects.len() == 0 {
-            node.object = Some(objects[0]);
+        let dim = centroid_bounds.max_extent();
+        // all centroids on top of each other, no split can separate them
+        if infos.len() <= max_prims_in_node
+            || centroid_bounds.p_max[dim] <= centroid_bounds.p_min[dim] {
+            node.first_prim_offset = offset;
+            node.n_primitives = infos.len();
+            return Some(node);
+        }
+
+        let mid = match split_method {
+            SplitMethod::NAIVE => BVHAccel::split_equal_counts(infos, dim),
+            SplitMethod::SAH => BVHAccel::split_sah(infos, dim, &centroid_bounds, &node.bounds),
+        };
+
+        let (left, right) = infos.split_at_mut(mid);
+        let (left, right) = if left.len() + right.len() > PARALLEL_BUILD_THRESHOLD {
+            rayon::join(
+                || BVHAccel::recursive_build(left, offset, max_prims_in_node, split_method),
+                || BVHAccel::recursive_build(right, offset + mid, max_prims_in_node, split_method),
+            )
+        } else {
+            (
+                BVHAccel::recursive_build(left, offset, max_prims_in_node, split_method),
+                BVHAccel::recursive_build(right, offset + mid, max_prims_in_node, split_method),
+            )
+        };
+        node.left = left;
+        node.right = right;
+
+        return Some(node)
+    }
+
+    // partition around the median centroid, O(n) instead of a full sort
+    fn split_equal_counts(infos: &mut [BVHPrimitiveInfo], dim: usize) -> usize {
+        let mid = infos.len() / 2;
+        infos.select_nth_unstable_by(mid, |a, b| {
+            a.centroid[dim].total_cmp(&b.centroid[dim])
+        });
+        return mid;
+    }
+
+    // [comment]
+    // Binned surface area heuristic. Returns the split position, nodes small
+    // enough for a leaf never get here.
+    // [/comment]
+    fn split_sah(
+        infos: &mut [BVHPrimitiveInfo],
+        dim: usize,
+        centroid_bounds: &Bounds3,
+        node_bounds: &Bounds3,
+    ) -> usize {
+        if infos.len() <= 4 {
+            return BVHAccel::split_equal_counts(infos, dim);
         }
-        else {
-            let mut centroid_bounds = Bounds3::default();
-            for obj in objects.iter() {
-                centroid_bounds = centroid_bounds.union_p(&obj.get_bounds().centroid());
+
+        let c_min = centroid_bounds.p_min[dim];
+        let c_extent = centroid_bounds.p_max[dim] - c_min;
+        let bucket_of = |info: &BVHPrimitiveInfo| -> usize {
+            let b = (SAH_BUCKETS as f32 * (info.centroid[dim] - c_min) / c_extent) as usize;
+            return b.min(SAH_BUCKETS - 1);
+        };
+
+        let mut counts = [0usize; SAH_BUCKETS];
+        let mut bucket_bounds = vec![Bounds3::default(); SAH_BUCKETS];
+        for info in infos.iter() {
+            let b = bucket_of(info);
+            counts[b] += 1;
+            bucket_bounds[b] = Bounds3::union(&bucket_bounds[b], &info.bounds);
+        }
+
+        // cost of splitting after each bucket
+        let node_area = node_bounds.surface_area();
+        let mut min_cost = f32::INFINITY;
+        let mut min_bucket = 0;
+        for i in 0..SAH_BUCKETS - 1 {
+            let mut b0 = Bounds3::default();
+            let mut b1 = Bounds3::default();
+            let mut count0 = 0;
+            let mut count1 = 0;
+            for j in 0..=i {
+                b0 = Bounds3::union(&b0, &bucket_bounds[j]);
+                count0 += counts[j];
             }
-            let dim = centroid_bounds.max_extent();
-            objects.sort_by(
-                |&a, &b| {
-                    a.get_bounds().centroid()[dim]
-                        .partial_cmp(&b.get_bounds().centroid()[dim])
-                        .unwrap()
-                }
-            );
-            let mid = objects.len() / 2;
+            for j in i + 1..SAH_BUCKETS {
+                b1 = Bounds3::union(&b1, &bucket_bounds[j]);
+                count1 += counts[j];
+            }
+            if count0 == 0 || count1 == 0 {
+                continue;
+            }
+            let cost = SAH_TRAVERSAL_COST
+                + (count0 as f32 * b0.surface_area() + count1 as f32 * b1.surface_area()) / node_area;
+            if cost < min_cost {
+                min_cost = cost;
+                min_bucket = i;
+            }
+        }
 
-            node.left  = BVHAccel::recursive_build(&mut objects[0..mid+1]);
-            node.right = BVHAccel::recursive_build(&mut objects[mid..]);
+        if !min_cost.is_finite() {
+            return BVHAccel::split_equal_counts(infos, dim);
         }
 
-        return Some(node)
+        // partition in place, primitives of buckets <= min_bucket go first
+        let mut mid = 0;
+        for i in 0..infos.len() {
+            if bucket_of(&infos[i]) <= min_bucket {
+                infos.swap(i, mid);
+                mid += 1;
+            }
+        }
+        return mid;
+    }
+
+    pub fn get_bounds(&self) -> Bounds3 {
+        return match &self.root {
+            Some(node) => node.bounds.clone(),
+            None => Bounds3::default(),
+        };
+    }
+
+    // [comment]
+    // Find the nearest hit. `intersect` is called with the original index of
+    // every primitive whose leaf is reached by the ray. The ray passed on has its
+    // t_max shrunk to the nearest hit so far, so farther nodes are skipped and
+    // primitives may reject farther hits early.
+    // [/comment]
+    pub fn get_intersection<'b, F>(&self, ray: &Ray, intersect: F) -> Option<IntersectData<'b>>
+        where F: Fn(usize, &Ray) -> Option<IntersectData<'b>>
+    {
+        let mut ray = ray.clone();
+        return self._get_intersection(&self.root, &mut ray, &intersect);
+    }
+
+    // [comment]
+    // Any hit query, true as soon as `occluded` reports a primitive blocking the
+    // ray before t_max. Nodes entered beyond t_max are skipped.
+    // [/comment]
+    pub fn occluded<F>(&self, ray: &Ray, t_max: f32, occluded: F) -> bool
+        where F: Fn(usize, &Ray, f32) -> bool
+    {
+        let mut stack: Vec<&BVHBuildNode> = Vec::with_capacity(64);
+        if let Some(root) = &self.root {
+            stack.push(root);
+        }
+        while let Some(node) = stack.pop() {
+            count_node_visit();
+            match node.bounds.ray_interval(ray) {
+                Some((t_enter, _)) if t_enter <= t_max => {}
+                _ => continue,
+            }
+            if node.is_leaf() {
+                let begin = node.first_prim_offset;
+                for &prim in &self.primitives[begin..begin + node.n_primitives] {
+                    count_primitive_test();
+                    if occluded(prim, ray, t_max) {
+                        return true;
+                    }
+                }
+                continue;
+            }
+            for c in [&node.right, &node.left].iter().copied().flatten() {
+                stack.push(c);
+            }
+        }
+        return false;
     }
 
-    pub fn get_intersection(&self, ray: &Ray) -> Option<IntersectData> {
-        return self._get_intersection(&self.root, ray);
+    // [comment]
+    // Trace the active rays of a packet with one shared stack. A node is culled
+    // against the packet frustum first, then tested ray by ray, and only the rays
+    // that hit its bounds before their current nearest hit go on to its children.
+    // [/comment]
+    pub fn get_intersection_packet<'b, F>(
+        &self,
+        packet: &RayPacket,
+        active: &[bool],
+        intersect: F,
+        hits: &mut [Option<IntersectData<'b>>]
+    )
+        where F: Fn(usize, &RayPacket, &[bool], &mut [Option<IntersectData<'b>>])
+    {
+        let root = match &self.root {
+            Some(root) => root,
+            None => return,
+        };
+        // the masks are fixed size arrays, larger packets are traced in parts
+        for (c, rays) in packet.rays.chunks(PACKET_SIZE).enumerate() {
+            let part = RayPacket {
+                rays,
+                frustum: packet.frustum.clone(),
+            };
+            let range = c * PACKET_SIZE..c * PACKET_SIZE + rays.len();
+            self.traverse_packet(root, &part, &active[range.clone()], &intersect, &mut hits[range]);
+        }
     }
 
-    fn _get_intersection(&self, node: &Option<Box<BVHBuildNode<'a>>>, ray: &Ray
-    ) -> Option<IntersectData> {
+    fn traverse_packet<'b, F>(
+        &self,
+        root: &BVHBuildNode,
+        packet: &RayPacket,
+        active: &[bool],
+        intersect: &F,
+        hits: &mut [Option<IntersectData<'b>>]
+    )
+        where F: Fn(usize, &RayPacket, &[bool], &mut [Option<IntersectData<'b>>])
+    {
+        let n = packet.len();
+        let mut root_mask = [false; PACKET_SIZE];
+        root_mask[..n].copy_from_slice(active);
+        let mut stack: Vec<(&BVHBuildNode, [bool; PACKET_SIZE])> = Vec::with_capacity(64);
+        stack.push((root, root_mask));
+        while let Some((node, parent_mask)) = stack.pop() {
+            if let Some(frustum) = &packet.frustum {
+                if frustum.culls(&node.bounds) {
+                    continue;
+                }
+            }
+            count_node_visit();
+            let mut mask = [false; PACKET_SIZE];
+            let mut first_active: Option<usize> = None;
+            for k in 0..n {
+                if !parent_mask[k] {
+                    continue;
+                }
+                let ray = &packet.rays[k];
+                if let Some((t_enter, _)) = node.bounds.ray_interval(ray) {
+                    if t_enter <= hit_distance(&hits[k], ray) {
+                        mask[k] = true;
+                        if first_active.is_none() {
+                            first_active = Some(k);
+                        }
+                    }
+                }
+            }
+            let first_active = match first_active {
+                Some(k) => k,
+                None => continue,
+            };
+
+            if node.is_leaf() {
+                let begin = node.first_prim_offset;
+                for &prim in &self.primitives[begin..begin + node.n_primitives] {
+                    for _ in mask.iter().filter(|&&m| m) {
+                        count_primitive_test();
+                    }
+                    intersect(prim, packet, &mask[..n], hits);
+                }
+                continue;
+            }
+
+            // visit the child the first active ray enters first before the other one
+            let ray = &packet.rays[first_active];
+            let entry = |child: &Option<Box<BVHBuildNode>>| -> f32 {
+                return match child {
+                    Some(c) => c.bounds.ray_interval(ray).map_or(f32::INFINITY, |t| t.0),
+                    None => f32::INFINITY,
+                };
+            };
+            let (near, far) = if entry(&node.left) <= entry(&node.right) {
+                (&node.left, &node.right)
+            } else {
+                (&node.right, &node.left)
+            };
+            if let Some(far) = far {
+                stack.push((far, mask));
+            }
+            if let Some(near) = near {
+                stack.push((near, mask));
+            }
+        }
+    }
+
+    fn _get_intersection<'b, F>(&self, node: &Option<Box<BVHBuildNode>>, ray: &mut Ray, intersect: &F
+    ) -> Option<IntersectData<'b>>
+        where F: Fn(usize, &Ray) -> Option<IntersectData<'b>>
+    {
         // no node
         if node.is_none() {
             return None;
         }
         let node_data = &node.as_ref().unwrap();
         // check bound failed
+        count_node_visit();
         if !node_data.bounds.intersect_ray(ray) {
             return None;
         }
         // is leaf node
-        if node_data.object.is_some() {
-            return node_data.object.unwrap().get_intersection(ray);
-        }
-        // check left node
-        let left_data = self._get_intersection(&node_data.left, ray);
-        // check right node
-        let right_data = self._get_intersection(&node_data.right, ray);
-
-        return if left_data.is_none() {
-            right_data
+        if node_data.is_leaf() {
+            let mut nearest: Option<IntersectData<'b>> = None;
+            let begin = node_data.first_prim_offset;
+            for &prim in &self.primitives[begin..begin + node_data.n_primitives] {
+                count_primitive_test();
+                if let Some(data) = intersect(prim, ray) {
+                    if nearest.is_none() || data.distance < nearest.as_ref().unwrap().distance {
+                        ray.t_max = data.distance;
+                        nearest = Some(data);
+                    }
+                }
+            }
+            return nearest;
         }
-        else if right_data.is_none() {
-            left_data
+        // check the child the ray enters first
+        let left_entry = BVHAccel::entry_distance(&node_data.left, ray);
+        let right_entry = BVHAccel::entry_distance(&node_data.right, ray);
+        let (near, far, far_entry) = if left_entry <= right_entry {
+            (&node_data.left, &node_data.right, right_entry)
+        } else {
+            (&node_data.right, &node_data.left, left_entry)
+        };
+        let near_data = self._get_intersection(near, ray, intersect);
+        // the far child starts behind the nearest hit, nothing in it is closer
+        if near_data.as_ref().is_some_and(|data| data.distance < far_entry) {
+            return near_data;
         }
+        let far_data = self._get_intersection(far, ray, intersect);
+
         // get neatest intersection data
-        else if left_data.as_ref().unwrap().distance < right_data.as_ref().unwrap().distance {
-            left_data
+        return match (near_data, far_data) {
+            (Some(near_data), Some(far_data)) if far_data.distance < near_data.distance => Some(far_data),
+            (None, far_data) => far_data,
+            (near_data, _) => near_data,
+        };
+    }
+
+    // distance along the ray to the child's box, infinite when it is missed
+    fn entry_distance(node: &Option<Box<BVHBuildNode>>, ray: &Ray) -> f32 {
+        return node.as_ref()
+            .and_then(|node| node.bounds.ray_interval(ray))
+            .map_or(f32::INFINITY, |(t_enter, _)| t_enter);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bounds3::Bounds3;
+    use crate::bvh::{count_node_visit, enable_traversal_stats, reset_traversal_stats, traversal_stats};
+    use crate::bvh::{BVHAccel, BVHBuildNode, SplitMethod};
+    use crate::intersection::IntersectData;
+    use crate::material::Material;
+    use crate::ray::Ray;
+
+    fn grid_bounds(n: usize) -> Vec<Bounds3> {
+        let mut bounds = Vec::with_capacity(n * n * n);
+        for x in 0..n {
+            for y in 0..n {
+                for z in 0..n {
+                    let p = glm::vec3(x as f32, y as f32, z as f32) * 2.0;
+                    bounds.push(Bounds3::new(&p, &(p + glm::vec3(1., 1., 1.))));
+                }
+            }
         }
-        else {
-            right_data
+        return bounds;
+    }
+
+    fn collect_leaves(node: &Option<Box<BVHBuildNode>>, max_prims: usize, out: &mut Vec<(usize, usize)>) {
+        if let Some(n) = node {
+            if n.is_leaf() {
+                assert!(n.left.is_none() && n.right.is_none());
+                assert!(n.n_primitives <= max_prims, "{}", n.n_primitives);
+                out.push((n.first_prim_offset, n.n_primitives));
+            }
+            collect_leaves(&n.left, max_prims, out);
+            collect_leaves(&n.right, max_prims, out);
         }
     }
-}
 
+    #[test]
+    fn test_build_covers_all_primitives() {
+        // large enough to go through the parallel path
+        let bounds = grid_bounds(20);
+        for method in [SplitMethod::NAIVE, SplitMethod::SAH] {
+            let bvh = BVHAccel::from_bounds(bounds.clone(), 4, method);
+
+            let mut leaves = Vec::new();
+            collect_leaves(&bvh.root, 4, &mut leaves);
+            leaves.sort();
+            let mut next = 0;
+            for (offset, count) in leaves {
+                assert_eq!(offset, next);
+                next += count;
+            }
+            assert_eq!(next, bounds.len());
+
+            let mut order = bvh.primitives.clone();
+            order.sort();
+            assert!(order.iter().enumerate().all(|(i, &p)| i == p));
+
+            let b = bvh.get_bounds();
+            assert_eq!(b.p_min, glm::vec3(0., 0., 0.));
+            assert_eq!(b.p_max, glm::vec3(39., 39., 39.));
+        }
+    }
 
+    #[test]
+    fn test_traversal_stats() {
+        enable_traversal_stats();
+        reset_traversal_stats();
+        count_node_visit();
+        count_node_visit();
+        let stats = traversal_stats();
+        assert_eq!((stats.rays, stats.nodes_visited, stats.primitives_tested), (0, 2, 0));
+    }
+
+    #[test]
+    fn test_build_single_and_empty() {
+        let bvh = BVHAccel::from_bounds(grid_bounds(1), 1, SplitMethod::SAH);
+        assert!(bvh.root.as_ref().unwrap().is_leaf());
+        let bvh = BVHAccel::from_bounds(Vec::new(), 1, SplitMethod::NAIVE);
+        assert!(bvh.root.is_none());
+    }
+
+    #[test]
+    fn test_nearest_child_first() {
+        let bounds = grid_bounds(4);
+        let bvh = BVHAccel::from_bounds(bounds.clone(), 1, SplitMethod::SAH);
+        let m = Material::default();
+        let tests = std::cell::Cell::new(0);
+        // a ray along the row of boxes at y = z = 0, each box is hit at its entry
+        let ray = Ray::new(&glm::vec3(-5., 0.5, 0.5), &glm::vec3(1., 0., 0.));
+        let hit = bvh.get_intersection(&ray, |i, r| {
+            tests.set(tests.get() + 1);
+            return bounds[i].ray_interval(r).map(|(t_enter, _)| IntersectData {
+                coords: r.origin + r.direction * t_enter,
+                distance: t_enter,
+                index: i as u32,
+                normal: glm::vec3(-1., 0., 0.),
+                shading_normal: glm::vec3(-1., 0., 0.),
+                p_error: glm::zero(),
+                barycentric: glm::zero(),
+                front_face: true,
+                uv: glm::zero(),
+                st: glm::zero(),
+                m: &m,
+                eval_diffuse_color: glm::zero(),
+            });
+        }).unwrap();
+        assert_eq!(hit.distance, 5.);
+        assert_eq!(bounds[hit.index as usize].p_min, glm::vec3(0., 0., 0.));
+        // the boxes behind the first hit are skipped
+        assert!(tests.get() < 4, "{}", tests.get());
+    }
+
+    #[test]
+    fn test_occluded() {
+        let bounds = grid_bounds(4);
+        let bvh = BVHAccel::from_bounds(bounds.clone(), 2, SplitMethod::SAH);
+        // treat every box as a solid primitive
+        let hit_box = |prim: usize, r: &Ray, t_max: f32| -> bool {
+            return bounds[prim].ray_interval(r).is_some_and(|(t, _)| t < t_max);
+        };
+        // along the x axis through the row of boxes at y = z = 0
+        let ray = Ray::new(&glm::vec3(-3., 0.5, 0.5), &glm::vec3(1., 0., 0.));
+        assert!(bvh.occluded(&ray, 100., hit_box));
+        // the first box starts 3 units away
+        assert!(!bvh.occluded(&ray, 2.9, hit_box));
+        assert!(bvh.occluded(&ray, 3.1, hit_box));
+        // through the gap between rows
+        let ray = Ray::new(&glm::vec3(-3., 1.5, 0.5), &glm::vec3(1., 0., 0.));
+        assert!(!bvh.occluded(&ray, 100., hit_box));
+    }
+
+    #[test]
+    fn test_refit() {
+        let bounds = grid_bounds(6);
+        let mut bvh = BVHAccel::from_bounds(bounds.clone(), 2, SplitMethod::SAH);
+        assert!((bvh.refit_quality() - 1.).abs() < 1e-5);
+
+        // rigid motion keeps the tree as good as it was
+        let offset = glm::vec3(3., -2., 1.);
+        let moved: Vec<Bounds3> = bounds.iter()
+            .map(|b| Bounds3::new(&(b.p_min + offset), &(b.p_max + offset)))
+            .collect();
+        bvh.refit(&moved);
+        let b = bvh.get_bounds();
+        assert_eq!(b.p_min, glm::vec3(3., -2., 1.));
+        assert_eq!(b.p_max, glm::vec3(14., 9., 12.));
+        assert!((bvh.refit_quality() - 1.).abs() < 1e-3, "{}", bvh.refit_quality());
+        assert!(!bvh.needs_rebuild(1.5));
+
+        // scattering the primitives makes leaves span most of the grid
+        let shuffled: Vec<Bounds3> = (0..bounds.len())
+            .map(|i| bounds[(i * 37) % bounds.len()].clone())
+            .collect();
+        bvh.refit(&shuffled);
+        assert!(bvh.needs_rebuild(1.2), "{}", bvh.refit_quality());
+        let rebuilt = BVHAccel::from_bounds(shuffled, 2, SplitMethod::SAH);
+        assert!(rebuilt.sah_cost() < bvh.sah_cost());
+    }
+}
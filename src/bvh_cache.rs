@@ -0,0 +1,268 @@
+use std::io::{Read, Write};
+
+use crate::bounds3::Bounds3;
+use crate::bvh::{BVHAccel, BVHBuildNode, SplitMethod};
+
+// [comment]
+// Binary cache of a built BVHAccel, all values little endian:
+//
+//    magic "BVHC", version u32, content hash u64
+//
+//    max_prims_in_node u32, split method u8, build sah cost f32
+//
+//    primitive count u64, then the primitive order as u32
+//
+//    node count u64, then nodes in pre-order: child flags u8 (1 left, 2 right),
+//    bounds 6 x f32, first_prim_offset u32, n_primitives u32
+// [/comment]
+const MAGIC: &[u8; 4] = b"BVHC";
+const VERSION: u32 = 1;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    let mut h = hash;
+    for b in bytes {
+        h ^= *b as u64;
+        h = h.wrapping_mul(FNV_PRIME);
+    }
+    return h;
+}
+
+// hash of everything the tree depends on, a cache is only valid for the same value
+pub fn mesh_hash(vertices: &[glm::Vec3], indices: &[u32], max_prims_in_node: u32, split_method: &SplitMethod) -> u64 {
+    let mut h = FNV_OFFSET;
+    h = fnv1a(h, &(vertices.len() as u64).to_le_bytes());
+    for v in vertices {
+        for i in 0..3usize {
+            h = fnv1a(h, &v[i].to_bits().to_le_bytes());
+        }
+    }
+    h = fnv1a(h, &(indices.len() as u64).to_le_bytes());
+    for i in indices {
+        h = fnv1a(h, &i.to_le_bytes());
+    }
+    h = fnv1a(h, &max_prims_in_node.to_le_bytes());
+    h = fnv1a(h, &[split_method_id(split_method)]);
+    return h;
+}
+
+fn split_method_id(split_method: &SplitMethod) -> u8 {
+    return match split_method {
+        SplitMethod::NAIVE => 0,
+        SplitMethod::SAH => 1,
+    };
+}
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    return std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string());
+}
+
+fn read_u8(r: &mut dyn Read) -> std::io::Result<u8> {
+    let mut b = [0u8; 1];
+    r.read_exact(&mut b)?;
+    return Ok(b[0]);
+}
+
+fn read_u32(r: &mut dyn Read) -> std::io::Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    return Ok(u32::from_le_bytes(b));
+}
+
+fn read_u64(r: &mut dyn Read) -> std::io::Result<u64> {
+    let mut b = [0u8; 8];
+    r.read_exact(&mut b)?;
+    return Ok(u64::from_le_bytes(b));
+}
+
+fn read_f32(r: &mut dyn Read) -> std::io::Result<f32> {
+    return Ok(f32::from_bits(read_u32(r)?));
+}
+
+fn count_nodes(node: &Option<Box<BVHBuildNode>>) -> u64 {
+    return match node {
+        Some(n) => 1 + count_nodes(&n.left) + count_nodes(&n.right),
+        None => 0,
+    };
+}
+
+fn write_node(w: &mut dyn Write, node: &BVHBuildNode) -> std::io::Result<()> {
+    let flags = node.left.is_some() as u8 | ((node.right.is_some() as u8) << 1);
+    w.write_all(&[flags])?;
+    for p in [&node.bounds.p_min, &node.bounds.p_max].iter() {
+        for i in 0..3usize {
+            w.write_all(&p[i].to_le_bytes())?;
+        }
+    }
+    w.write_all(&(node.first_prim_offset as u32).to_le_bytes())?;
+    w.write_all(&(node.n_primitives as u32).to_le_bytes())?;
+    for c in [&node.left, &node.right].iter().copied().flatten() {
+        write_node(w, c)?;
+    }
+    return Ok(());
+}
+
+fn read_node(r: &mut dyn Read, remaining: &mut u64, num_primitives: usize) -> std::io::Result<Box<BVHBuildNode>> {
+    if *remaining == 0 {
+        return Err(invalid_data("bvh cache: node count mismatch"));
+    }
+    *remaining -= 1;
+
+    let flags = read_u8(r)?;
+    let mut node = Box::new(BVHBuildNode::default());
+    let mut b = [0f32; 6];
+    for v in b.iter_mut() {
+        *v = read_f32(r)?;
+    }
+    node.bounds = Bounds3 {
+        p_min: glm::vec3(b[0], b[1], b[2]),
+        p_max: glm::vec3(b[3], b[4], b[5]),
+    };
+    node.first_prim_offset = read_u32(r)? as usize;
+    node.n_primitives = read_u32(r)? as usize;
+    if node.first_prim_offset + node.n_primitives > num_primitives {
+        return Err(invalid_data("bvh cache: leaf out of range"));
+    }
+    if flags & 1 != 0 {
+        node.left = Some(read_node(r, remaining, num_primitives)?);
+    }
+    if flags & 2 != 0 {
+        node.right = Some(read_node(r, remaining, num_primitives)?);
+    }
+    return Ok(node);
+}
+
+pub fn write_bvh(w: &mut dyn Write, bvh: &BVHAccel, hash: u64) -> std::io::Result<()> {
+    w.write_all(MAGIC)?;
+    w.write_all(&VERSION.to_le_bytes())?;
+    w.write_all(&hash.to_le_bytes())?;
+    w.write_all(&bvh.max_prims_in_node.to_le_bytes())?;
+    w.write_all(&[split_method_id(&bvh.split_method)])?;
+    w.write_all(&bvh.build_sah_cost.to_le_bytes())?;
+
+    w.write_all(&(bvh.primitives.len() as u64).to_le_bytes())?;
+    for p in &bvh.primitives {
+        w.write_all(&(*p as u32).to_le_bytes())?;
+    }
+
+    w.write_all(&count_nodes(&bvh.root).to_le_bytes())?;
+    if let Some(root) = &bvh.root {
+        write_node(w, root)?;
+    }
+    return Ok(());
+}
+
+// [comment]
+// Returns Ok(None) when the cache was written for other content or by another
+// version, the caller is expected to rebuild and overwrite it. The primitive
+// count has to match the mesh before anything is allocated for it.
+// [/comment]
+pub fn read_bvh(r: &mut dyn Read, expected_hash: u64, expected_primitives: usize) -> std::io::Result<Option<BVHAccel>> {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC || read_u32(r)? != VERSION || read_u64(r)? != expected_hash {
+        return Ok(None);
+    }
+    let max_prims_in_node = read_u32(r)?;
+    let split_method = match read_u8(r)? {
+        0 => SplitMethod::NAIVE,
+        1 => SplitMethod::SAH,
+        _ => return Err(invalid_data("bvh cache: unknown split method")),
+    };
+    let build_sah_cost = read_f32(r)?;
+
+    let num_primitives = read_u64(r)?;
+    if num_primitives != expected_primitives as u64 {
+        return Err(invalid_data("bvh cache: primitive count mismatch"));
+    }
+    let num_primitives = num_primitives as usize;
+    let mut primitives = Vec::with_capacity(num_primitives);
+    for _ in 0..num_primitives {
+        let p = read_u32(r)? as usize;
+        if p >= num_primitives {
+            return Err(invalid_data("bvh cache: primitive index out of range"));
+        }
+        primitives.push(p);
+    }
+
+    let mut remaining = read_u64(r)?;
+    let root = if remaining > 0 {
+        Some(read_node(r, &mut remaining, num_primitives)?)
+    } else {
+        None
+    };
+    if remaining != 0 {
+        return Err(invalid_data("bvh cache: node count mismatch"));
+    }
+
+    return Ok(Some(BVHAccel {
+        root,
+        max_prims_in_node,
+        split_method,
+        primitives,
+        build_sah_cost,
+    }));
+}
+
+pub fn save_bvh(path: &std::path::Path, bvh: &BVHAccel, hash: u64) -> std::io::Result<()> {
+    let file = std::fs::File::create(path)?;
+    let mut w = std::io::BufWriter::new(file);
+    write_bvh(&mut w, bvh, hash)?;
+    return w.flush();
+}
+
+pub fn load_bvh(path: &std::path::Path, expected_hash: u64, expected_primitives: usize) -> std::io::Result<Option<BVHAccel>> {
+    let file = std::fs::File::open(path)?;
+    let mut r = std::io::BufReader::new(file);
+    return read_bvh(&mut r, expected_hash, expected_primitives);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bounds3::Bounds3;
+    use crate::bvh::{BVHAccel, BVHBuildNode, SplitMethod};
+    use crate::bvh_cache::{read_bvh, write_bvh};
+
+    fn same_tree(a: &Option<Box<BVHBuildNode>>, b: &Option<Box<BVHBuildNode>>) -> bool {
+        return match (a, b) {
+            (None, None) => true,
+            (Some(a), Some(b)) => {
+                a.bounds.p_min == b.bounds.p_min && a.bounds.p_max == b.bounds.p_max
+                    && a.first_prim_offset == b.first_prim_offset
+                    && a.n_primitives == b.n_primitives
+                    && same_tree(&a.left, &b.left) && same_tree(&a.right, &b.right)
+            }
+            _ => false,
+        };
+    }
+
+    #[test]
+    fn test_bvh_cache_roundtrip() {
+        let bounds: Vec<Bounds3> = (0..100)
+            .map(|i| {
+                let p = glm::vec3((i % 7) as f32, (i % 11) as f32 * 0.5, i as f32 * 0.1);
+                Bounds3::new(&p, &(p + glm::vec3(0.3, 0.3, 0.3)))
+            })
+            .collect();
+        let bvh = BVHAccel::from_bounds(bounds, 2, SplitMethod::SAH);
+
+        let mut buf = Vec::new();
+        write_bvh(&mut buf, &bvh, 42).unwrap();
+
+        let loaded = read_bvh(&mut &buf[..], 42, 100).unwrap().unwrap();
+        assert_eq!(loaded.primitives, bvh.primitives);
+        assert_eq!(loaded.max_prims_in_node, 2);
+        assert!(same_tree(&loaded.root, &bvh.root));
+
+        // other content
+        assert!(read_bvh(&mut &buf[..], 43, 100).unwrap().is_none());
+        // truncated file
+        assert!(read_bvh(&mut &buf[..buf.len() - 5], 42, 100).is_err());
+        // a count that does not match the mesh, or a corrupted huge one
+        assert!(read_bvh(&mut &buf[..], 42, 99).is_err());
+        buf[25..33].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(read_bvh(&mut &buf[..], 42, 100).is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use crate::bounds3::Bounds3;
+use crate::bvh::{BVHAccel, BVHBuildNode, SplitMethod};
+
+// [comment]
+// Static shape and quality of a built BVHAccel. Overlap is the surface area of
+// the region shared by two siblings relative to their parent, averaged over all
+// interior nodes, 0.0 means siblings never overlap.
+// [/comment]
+#[derive(Clone, Debug)]
+pub struct BVHStats {
+    pub split_method: SplitMethod,
+    pub primitive_count: usize,
+    pub node_count: usize,
+    pub interior_count: usize,
+    pub leaf_count: usize,
+    pub max_depth: usize,
+    pub avg_leaf_depth: f32,
+    pub min_leaf_size: usize,
+    pub max_leaf_size: usize,
+    pub avg_leaf_size: f32,
+    pub sah_cost: f32,
+    pub avg_sibling_overlap: f32,
+    pub memory_bytes: usize,
+}
+
+fn overlap_area(a: &Bounds3, b: &Bounds3) -> f32 {
+    let p_min = glm::max2(&a.p_min, &b.p_min);
+    let p_max = glm::min2(&a.p_max, &b.p_max);
+    if p_min.x > p_max.x || p_min.y > p_max.y || p_min.z > p_max.z {
+        return 0.;
+    }
+    return Bounds3 { p_min, p_max }.surface_area();
+}
+
+impl BVHStats {
+    pub fn new(bvh: &BVHAccel) -> BVHStats {
+        let mut stats = BVHStats {
+            split_method: bvh.split_method,
+            primitive_count: bvh.primitives.len(),
+            node_count: 0,
+            interior_count: 0,
+            leaf_count: 0,
+            max_depth: 0,
+            avg_leaf_depth: 0.,
+            min_leaf_size: 0,
+            max_leaf_size: 0,
+            avg_leaf_size: 0.,
+            sah_cost: bvh.sah_cost(),
+            avg_sibling_overlap: 0.,
+            memory_bytes: 0,
+        };
+
+        let mut leaf_depth_sum = 0usize;
+        let mut overlap_sum = 0f32;
+        let mut min_leaf_size = usize::MAX;
+        let mut stack: Vec<(&BVHBuildNode, usize)> = Vec::new();
+        if let Some(root) = &bvh.root {
+            stack.push((root, 0));
+        }
+        while let Some((node, depth)) = stack.pop() {
+            stats.node_count += 1;
+            stats.max_depth = stats.max_depth.max(depth);
+            if node.is_leaf() {
+                stats.leaf_count += 1;
+                leaf_depth_sum += depth;
+                min_leaf_size = min_leaf_size.min(node.n_primitives);
+                stats.max_leaf_size = stats.max_leaf_size.max(node.n_primitives);
+                continue;
+            }
+            stats.interior_count += 1;
+            if let (Some(l), Some(r)) = (&node.left, &node.right) {
+                let area = node.bounds.surface_area();
+                if area > 0. {
+                    overlap_sum += overlap_area(&l.bounds, &r.bounds) / area;
+                }
+            }
+            for c in [&node.left, &node.right].iter().copied().flatten() {
+                stack.push((c, depth + 1));
+            }
+        }
+
+        if stats.leaf_count > 0 {
+            stats.min_leaf_size = min_leaf_size;
+            stats.avg_leaf_size = stats.primitive_count as f32 / stats.leaf_count as f32;
+            stats.avg_leaf_depth = leaf_depth_sum as f32 / stats.leaf_count as f32;
+        }
+        if stats.interior_count > 0 {
+            stats.avg_sibling_overlap = overlap_sum / stats.interior_count as f32;
+        }
+        stats.memory_bytes = std::mem::size_of::<BVHAccel>()
+            + stats.node_count * std::mem::size_of::<BVHBuildNode>()
+            + bvh.primitives.capacity() * std::mem::size_of::<usize>();
+        return stats;
+    }
+}
+
+impl std::fmt::Display for BVHStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "split method         : {:?}", self.split_method)?;
+        writeln!(f, "primitives           : {}", self.primitive_count)?;
+        writeln!(f, "nodes                : {} ({} interior, {} leaves)", self.node_count, self.interior_count, self.leaf_count)?;
+        writeln!(f, "depth                : max {}, avg leaf {:.2}", self.max_depth, self.avg_leaf_depth)?;
+        writeln!(f, "leaf size            : min {}, max {}, avg {:.2}", self.min_leaf_size, self.max_leaf_size, self.avg_leaf_size)?;
+        writeln!(f, "sah cost             : {:.3}", self.sah_cost)?;
+        writeln!(f, "sibling overlap      : {:.2}%", self.avg_sibling_overlap * 100.)?;
+        write!(f, "memory               : {:.1} KiB", self.memory_bytes as f64 / 1024.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bounds3::Bounds3;
+    use crate::bvh::{BVHAccel, SplitMethod};
+    use crate::bvh_stats::BVHStats;
+
+    #[test]
+    fn test_bvh_stats() {
+        // 8 disjoint unit boxes along x, a perfect tree with 1 primitive per leaf
+        let bounds: Vec<Bounds3> = (0..8)
+            .map(|i| {
+                let p = glm::vec3(i as f32 * 2., 0., 0.);
+                Bounds3::new(&p, &(p + glm::vec3(1., 1., 1.)))
+            })
+            .collect();
+        let bvh = BVHAccel::from_bounds(bounds, 1, SplitMethod::NAIVE);
+        let stats = BVHStats::new(&bvh);
+
+        assert_eq!(stats.primitive_count, 8);
+        assert_eq!(stats.leaf_count, 8);
+        assert_eq!(stats.interior_count, 7);
+        assert_eq!(stats.node_count, 15);
+        assert_eq!(stats.max_depth, 3);
+        assert_eq!(stats.min_leaf_size, 1);
+        assert_eq!(stats.max_leaf_size, 1);
+        assert!((stats.avg_leaf_depth - 3.).abs() < 1e-5);
+        assert!(stats.avg_sibling_overlap.abs() < 1e-6);
+        assert!((stats.sah_cost - bvh.sah_cost()).abs() < 1e-6);
+        assert!(stats.memory_bytes > 0);
+        assert!(format!("{}", stats).contains("15 (7 interior, 8 leaves)"));
+    }
+}
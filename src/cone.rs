@@ -0,0 +1,114 @@
+use crate::bounds3::Bounds3;
+use crate::global::*;
+use crate::intersection::IntersectData;
+use crate::material::Material;
+use crate::object::ObjectTrait;
+use crate::ray::Ray;
+
+// [comment]
+// Open cone around the z axis with its base of the given radius at z = 0 and its
+// apex at z = height, swept by phi_max like Cylinder. u follows the sweep and v
+// runs from the base to the apex.
+// [/comment]
+pub struct Cone<'a> {
+    pub radius: f32,
+    pub height: f32,
+    // radians
+    pub phi_max: f32,
+    pub m: &'a Material,
+}
+
+impl<'a> Cone<'a> {
+    // \param phi_max: sweep in degrees, 360 for a full cone
+    pub fn new(radius: f32, height: f32, phi_max: f32, m: &'a Material) -> Cone<'a> {
+        Cone {
+            radius,
+            // a flat cone would divide by its height
+            height: if height.abs() < 1e-6 { 1e-6f32.copysign(height) } else { height },
+            phi_max: sweep_radians(phi_max),
+            m,
+        }
+    }
+}
+
+impl<'a> ObjectTrait for Cone<'a> {
+    fn get_intersection(&self, ray: &Ray) -> Option<IntersectData<'_>> {
+        // x^2 + y^2 = k (z - height)^2
+        let (o, d) = (&ray.origin, &ray.direction);
+        let k = (self.radius / self.height) * (self.radius / self.height);
+        let oz = o.z - self.height;
+        let a = d.x * d.x + d.y * d.y - k * d.z * d.z;
+        let b = 2. * (d.x * o.x + d.y * o.y - k * d.z * oz);
+        let c = o.x * o.x + o.y * o.y - k * oz * oz;
+        let (z_min, z_max) = (self.height.min(0.), self.height.max(0.));
+        let accept = |p: &glm::Vec3| p.z >= z_min && p.z <= z_max && sweep_angle(p) <= self.phi_max;
+        let (t, p) = nearest_quadric_hit(ray, a, b, c, &accept)?;
+        let r = self.radius * (1. - p.z / self.height);
+        let (coords, p_error) = reproject_to_radius(&p, r, self.radius.abs() * gamma(3), ray_point_error(o, d, t).z);
+
+        let mut normal = glm::vec3(coords.x, coords.y, -k * (coords.z - self.height));
+        // the gradient vanishes at the apex
+        normal = if glm::length2(&normal) > 0. { normal.normalize() } else { glm::vec3(0., 0., self.height.signum()) };
+        let st = glm::vec2(sweep_angle(&coords) / self.phi_max, coords.z / self.height);
+
+        return Some(IntersectData {
+            coords,
+            normal,
+            shading_normal: normal,
+            p_error,
+            barycentric: glm::zero(),
+            front_face: glm::dot(d, &normal) < 0.,
+            distance: t,
+            index: u32::MAX,
+            m: self.m,
+            eval_diffuse_color: self.m.eval_diffuse_color(&st),
+            uv: glm::zero(),
+            st,
+        });
+    }
+
+    fn get_bounds(&self) -> Bounds3 {
+        return Bounds3::new(
+            &glm::vec3(-self.radius, -self.radius, 0.),
+            &glm::vec3(self.radius, self.radius, self.height),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cone::Cone;
+    use crate::material::Material;
+    use crate::object::ObjectTrait;
+    use crate::ray::Ray;
+
+    #[test]
+    fn test_cone() {
+        let mat = Material::default();
+        let cone = Cone::new(1., 2., 360., &mat);
+
+        // halfway up the radius is 0.5, the normal leans up by the slope
+        let hit = cone.get_intersection(&Ray::new(&glm::vec3(5., 0., 1.), &glm::vec3(-1., 0., 0.))).unwrap();
+        assert!((hit.distance - 4.5).abs() < 1e-5);
+        assert!(glm::distance(&hit.normal, &glm::vec3(2., 0., 1.).normalize()) < 1e-5);
+        assert!(hit.front_face && (hit.st.y - 0.5).abs() < 1e-5);
+
+        // the second nappe above the apex is cut away, the open base lets rays in
+        assert!(cone.get_intersection(&Ray::new(&glm::vec3(5., 0., 3.), &glm::vec3(-1., 0., 0.))).is_none());
+        let inside = cone.get_intersection(&Ray::new(&glm::vec3(0., 0., -1.), &glm::vec3(0.2, 0., 1.))).unwrap();
+        assert!(!inside.front_face && inside.coords.z > 0. && inside.coords.z < 2.);
+
+        let quarter = Cone::new(1., 2., 90., &mat);
+        assert!(quarter.get_intersection(&Ray::new(&glm::vec3(-5., 0.1, 0.5), &glm::vec3(1., 0., 0.))).is_some());
+        assert!(quarter.get_intersection(&Ray::new(&glm::vec3(-5., -0.1, 0.5), &glm::vec3(1., 0., 0.))).is_none());
+
+        let bounds = cone.get_bounds();
+        assert_eq!(bounds.p_max, glm::vec3(1., 1., 2.));
+
+        // a flat cone and an empty sweep are clamped so texture coordinates stay finite
+        let flat = Cone::new(1., 0., 0., &mat);
+        assert!(flat.height > 0. && flat.phi_max > 0.);
+        let hit = flat.get_intersection(&Ray::new(&glm::vec3(0.5, 0., 1.), &glm::vec3(0., 0., -1.))).unwrap();
+        assert!(hit.st.x.is_finite() && hit.st.y.is_finite());
+    }
+}
@@ -0,0 +1,235 @@
+use crate::bounds3::Bounds3;
+use crate::intersection::IntersectData;
+use crate::object::ObjectTrait;
+use crate::ray::Ray;
+
+// part of a ray inside a solid, from where it enters to where it leaves
+pub struct Span<'a> {
+    pub enter: IntersectData<'a>,
+    pub exit: IntersectData<'a>,
+}
+
+// [comment]
+// A closed shape that can tell every part of a ray inside it, which is what CSG
+// needs to combine shapes. Spans cover the whole line of the ray, including
+// negative distances and ignoring t_min and t_max, so a ray starting inside has
+// a span that entered behind its origin. They are in order and do not overlap,
+// normals point out of the solid at both ends.
+// [/comment]
+pub trait Solid: ObjectTrait {
+    fn get_spans(&self, ray: &Ray) -> Vec<Span<'_>>;
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    // the first solid minus the second
+    Difference,
+}
+
+impl CsgOperation {
+    fn inside(&self, in_a: bool, in_b: bool) -> bool {
+        return match self {
+            CsgOperation::Union => in_a || in_b,
+            CsgOperation::Intersection => in_a && in_b,
+            CsgOperation::Difference => in_a && !in_b,
+        };
+    }
+}
+
+// [comment]
+// Boolean combination of two solids, itself a solid so trees can be built. The
+// surfaces keep the material of the solid they come from. Where the second solid
+// is cut out of the first its surface faces the other way, into the hole, so its
+// normals are flipped.
+// [/comment]
+pub struct Csg<'a> {
+    pub a: &'a dyn Solid,
+    pub b: &'a dyn Solid,
+    pub operation: CsgOperation,
+    pub bounding_box: Bounds3,
+}
+
+impl<'a> Csg<'a> {
+    pub fn new(a: &'a dyn Solid, b: &'a dyn Solid, operation: CsgOperation) -> Csg<'a> {
+        let (bounds_a, bounds_b) = (a.get_bounds(), b.get_bounds());
+        let bounding_box = match operation {
+            CsgOperation::Union => Bounds3::union(&bounds_a, &bounds_b),
+            CsgOperation::Intersection => bounds_a.intersect(&bounds_b),
+            CsgOperation::Difference => bounds_a,
+        };
+        Csg {
+            a,
+            b,
+            operation,
+            bounding_box,
+        }
+    }
+
+    pub fn union(a: &'a dyn Solid, b: &'a dyn Solid) -> Csg<'a> {
+        return Csg::new(a, b, CsgOperation::Union);
+    }
+
+    pub fn intersection(a: &'a dyn Solid, b: &'a dyn Solid) -> Csg<'a> {
+        return Csg::new(a, b, CsgOperation::Intersection);
+    }
+
+    pub fn difference(a: &'a dyn Solid, b: &'a dyn Solid) -> Csg<'a> {
+        return Csg::new(a, b, CsgOperation::Difference);
+    }
+}
+
+fn flip(inter: &mut IntersectData<'_>, direction: &glm::Vec3) {
+    inter.normal = -inter.normal;
+    inter.shading_normal = -inter.shading_normal;
+    inter.front_face = glm::dot(direction, &inter.normal) < 0.;
+}
+
+impl<'a> Solid for Csg<'a> {
+    // [comment]
+    // Walks the span ends of both solids in order, tracking whether the ray is
+    // inside each of them. Every end where the operation turns from outside to
+    // inside or back starts or ends a span of the result.
+    // [/comment]
+    fn get_spans(&self, ray: &Ray) -> Vec<Span<'_>> {
+        if self.bounding_box.ray_interval(&Ray::segment(&ray.origin, &ray.direction, f32::MIN, f32::MAX)).is_none() {
+            return Vec::new();
+        }
+        // (hit, from b, entering)
+        let mut events: Vec<(IntersectData<'_>, bool, bool)> = Vec::new();
+        for (solid, from_b) in [(self.a, false), (self.b, true)].iter() {
+            for span in solid.get_spans(ray) {
+                events.push((span.enter, *from_b, true));
+                events.push((span.exit, *from_b, false));
+            }
+        }
+        // at equal distances entries go first, so solids that touch merge into one span
+        events.sort_by(|x, y| x.0.distance.total_cmp(&y.0.distance).then(y.2.cmp(&x.2)));
+
+        let mut spans = Vec::new();
+        let (mut in_a, mut in_b) = (0i32, 0i32);
+        let mut enter = None;
+        for (mut inter, from_b, entering) in events {
+            let was_inside = self.operation.inside(in_a > 0, in_b > 0);
+            let count = if from_b { &mut in_b } else { &mut in_a };
+            *count += if entering { 1 } else { -1 };
+            let inside = self.operation.inside(in_a > 0, in_b > 0);
+            if was_inside == inside {
+                continue;
+            }
+            if from_b && self.operation == CsgOperation::Difference {
+                flip(&mut inter, &ray.direction);
+            }
+            if inside {
+                enter = Some(inter);
+            } else if let Some(enter) = enter.take() {
+                spans.push(Span { enter, exit: inter });
+            }
+        }
+        return spans;
+    }
+}
+
+impl<'a> ObjectTrait for Csg<'a> {
+    // the first span end inside the ray interval, an exit when the ray starts inside
+    fn get_intersection(&self, ray: &Ray) -> Option<IntersectData<'_>> {
+        if !self.bounding_box.intersect_ray(ray) {
+            return None;
+        }
+        for span in self.get_spans(ray) {
+            if ray.in_range(span.enter.distance) {
+                return Some(span.enter);
+            }
+            if ray.in_range(span.exit.distance) {
+                return Some(span.exit);
+            }
+        }
+        return None;
+    }
+
+    fn get_bounds(&self) -> Bounds3 {
+        return self.bounding_box.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::axis_box::AxisBox;
+    use crate::csg::{Csg, Solid};
+    use crate::material::Material;
+    use crate::object::ObjectTrait;
+    use crate::ray::Ray;
+    use crate::sphere::Sphere;
+    use crate::torus::Torus;
+
+    fn spans_of(solid: &dyn Solid, ray: &Ray) -> Vec<(f32, f32)> {
+        return solid.get_spans(ray).iter().map(|s| (s.enter.distance, s.exit.distance)).collect();
+    }
+
+    fn close(spans: &[(f32, f32)], expected: &[(f32, f32)]) -> bool {
+        return spans.len() == expected.len()
+            && spans.iter().zip(expected.iter()).all(|(s, e)| (s.0 - e.0).abs() < 1e-4 && (s.1 - e.1).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_csg_spans() {
+        let mat = Material::default();
+        let cube = AxisBox::new(&glm::vec3(-1., -1., -1.), &glm::vec3(1., 1., 1.), &mat);
+        let ball = Sphere::new(&glm::vec3(0., 0., 0.), 1.25, &mat);
+        let small = Sphere::new(&glm::vec3(0., 0., 0.), 0.5, &mat);
+        // along x through the centers, from x = -5
+        let ray = Ray::new(&glm::vec3(-5., 0., 0.), &glm::vec3(1., 0., 0.));
+
+        assert!(close(&spans_of(&cube, &ray), &[(4., 6.)]));
+        assert!(close(&spans_of(&Csg::union(&cube, &ball), &ray), &[(3.75, 6.25)]));
+        assert!(close(&spans_of(&Csg::intersection(&cube, &ball), &ray), &[(4., 6.)]));
+        // the ball pokes out of the faces of the cube, so the difference is only
+        // there along a diagonal
+        assert!(spans_of(&Csg::difference(&cube, &ball), &ray).is_empty());
+        let diagonal = Ray::new(&glm::vec3(-5., -5., -5.), &glm::vec3(1., 1., 1.).normalize());
+        let corner = 5. * 3f32.sqrt();
+        let spans = spans_of(&Csg::difference(&cube, &ball), &diagonal);
+        assert!(close(&spans, &[(corner - 3f32.sqrt(), corner - 1.25), (corner + 1.25, corner + 3f32.sqrt())]), "{:?}", spans);
+
+        // nested: a cube with a hole, and a small ball back inside the hole
+        let hollow = Csg::difference(&cube, &small);
+        let nested = Csg::union(&hollow, &small);
+        assert!(close(&spans_of(&hollow, &ray), &[(4., 4.5), (5.5, 6.)]));
+        assert!(close(&spans_of(&nested, &ray), &[(4., 6.)]));
+
+        // the spans of a torus pair up the roots of the quartic
+        let ring = Torus::new(2., 0.5, 360., &mat);
+        assert!(close(&spans_of(&ring, &Ray::new(&glm::vec3(-5., 0., 0.), &glm::vec3(1., 0., 0.))), &[(2.5, 3.5), (6.5, 7.5)]));
+    }
+
+    #[test]
+    fn test_csg_intersection() {
+        let mat = Material::default();
+        let cube = AxisBox::new(&glm::vec3(-1., -1., -1.), &glm::vec3(1., 1., 1.), &mat);
+        let hole = Sphere::new(&glm::vec3(0., 0., 1.), 0.75, &mat);
+        let dented = Csg::difference(&cube, &hole);
+
+        // into the dent in the top face the surface of the sphere faces up
+        let down = Ray::new(&glm::vec3(0., 0., 5.), &glm::vec3(0., 0., -1.));
+        let hit = dented.get_intersection(&down).unwrap();
+        assert!((hit.distance - 4.75).abs() < 1e-4);
+        assert!(glm::distance(&hit.normal, &glm::vec3(0., 0., 1.)) < 1e-5);
+        assert!(hit.front_face);
+        // beside the dent the top face of the cube
+        let hit = dented.get_intersection(&Ray::new(&glm::vec3(0.9, 0., 5.), &glm::vec3(0., 0., -1.))).unwrap();
+        assert!((hit.distance - 4.).abs() < 1e-4);
+
+        // from inside the solid part the next surface is left from the back
+        let inside = dented.get_intersection(&Ray::new(&glm::vec3(0., 0., -0.5), &glm::vec3(0., 0., 1.))).unwrap();
+        assert!((inside.coords.z - 0.25).abs() < 1e-4);
+        assert!(!inside.front_face);
+        // and a ray starting in the dent sees the bottom of it from the front
+        let hit = dented.get_intersection(&Ray::new(&glm::vec3(0., 0., 0.9), &glm::vec3(0., 0., -1.))).unwrap();
+        assert!((hit.coords.z - 0.25).abs() < 1e-4 && hit.front_face);
+
+        let bounds = dented.get_bounds();
+        assert_eq!(bounds.p_max, glm::vec3(1., 1., 1.));
+        assert!(dented.get_intersection(&Ray::new(&glm::vec3(5., 5., 5.), &glm::vec3(0., 0., 1.))).is_none());
+    }
+}
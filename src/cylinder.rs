@@ -0,0 +1,108 @@
+use crate::bounds3::Bounds3;
+use crate::global::*;
+use crate::intersection::IntersectData;
+use crate::material::Material;
+use crate::object::ObjectTrait;
+use crate::ray::Ray;
+
+// [comment]
+// Open cylinder around the z axis between z_min and z_max, swept from the +x axis
+// counterclockwise by phi_max. Like the other quadrics it lives in object space,
+// TransformedObject places it in the scene. u follows the sweep and v the height,
+// both from 0 to 1. Normals point away from the axis.
+// [/comment]
+pub struct Cylinder<'a> {
+    pub radius: f32,
+    pub z_min: f32,
+    pub z_max: f32,
+    // radians
+    pub phi_max: f32,
+    pub m: &'a Material,
+}
+
+impl<'a> Cylinder<'a> {
+    // \param phi_max: sweep in degrees, 360 for a full cylinder
+    pub fn new(radius: f32, z_min: f32, z_max: f32, phi_max: f32, m: &'a Material) -> Cylinder<'a> {
+        Cylinder {
+            radius,
+            z_min: z_min.min(z_max),
+            z_max: z_min.max(z_max),
+            phi_max: sweep_radians(phi_max),
+            m,
+        }
+    }
+}
+
+impl<'a> ObjectTrait for Cylinder<'a> {
+    fn get_intersection(&self, ray: &Ray) -> Option<IntersectData<'_>> {
+        let (o, d) = (&ray.origin, &ray.direction);
+        let a = d.x * d.x + d.y * d.y;
+        let b = 2. * (d.x * o.x + d.y * o.y);
+        let c = o.x * o.x + o.y * o.y - self.radius * self.radius;
+        let accept = |p: &glm::Vec3| p.z >= self.z_min && p.z <= self.z_max && sweep_angle(p) <= self.phi_max;
+        let (t, p) = nearest_quadric_hit(ray, a, b, c, &accept)?;
+
+        let (coords, p_error) = reproject_to_radius(&p, self.radius, 0., ray_point_error(o, d, t).z);
+        let normal = glm::vec3(coords.x, coords.y, 0.) / self.radius;
+        let v = if self.z_max > self.z_min { (coords.z - self.z_min) / (self.z_max - self.z_min) } else { 0. };
+        let st = glm::vec2(sweep_angle(&coords) / self.phi_max, v);
+
+        return Some(IntersectData {
+            coords,
+            normal,
+            shading_normal: normal,
+            p_error,
+            barycentric: glm::zero(),
+            front_face: glm::dot(d, &normal) < 0.,
+            distance: t,
+            index: u32::MAX,
+            m: self.m,
+            eval_diffuse_color: self.m.eval_diffuse_color(&st),
+            uv: glm::zero(),
+            st,
+        });
+    }
+
+    fn get_bounds(&self) -> Bounds3 {
+        return Bounds3::new(
+            &glm::vec3(-self.radius, -self.radius, self.z_min),
+            &glm::vec3(self.radius, self.radius, self.z_max),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cylinder::Cylinder;
+    use crate::material::Material;
+    use crate::object::ObjectTrait;
+    use crate::ray::Ray;
+
+    #[test]
+    fn test_cylinder() {
+        let mat = Material::default();
+        let pipe = Cylinder::new(1., -1., 2., 360., &mat);
+
+        let hit = pipe.get_intersection(&Ray::new(&glm::vec3(5., 0., 0.5), &glm::vec3(-1., 0., 0.))).unwrap();
+        assert!((hit.distance - 4.).abs() < 1e-5);
+        assert_eq!(hit.normal, glm::vec3(1., 0., 0.));
+        assert!(hit.front_face);
+        assert!(glm::distance(&hit.st, &glm::vec2(0., 0.5)) < 1e-5);
+
+        // the open ends let rays along the axis through, from inside the back is hit
+        assert!(pipe.get_intersection(&Ray::new(&glm::vec3(0., 0., 5.), &glm::vec3(0., 0., -1.))).is_none());
+        let inside = pipe.get_intersection(&Ray::new(&glm::vec3(0., 0., 0.), &glm::vec3(0., 1., 0.))).unwrap();
+        assert!(!inside.front_face && (inside.st.x - 0.25).abs() < 1e-5);
+        // above the top the ray misses
+        assert!(pipe.get_intersection(&Ray::new(&glm::vec3(5., 0., 3.), &glm::vec3(-1., 0., 0.))).is_none());
+
+        // a half pipe over y >= 0 is seen from inside through the missing half
+        let half = Cylinder::new(1., -1., 2., 180., &mat);
+        let hit = half.get_intersection(&Ray::new(&glm::vec3(0., -5., 0.), &glm::vec3(0., 1., 0.))).unwrap();
+        assert!((hit.distance - 6.).abs() < 1e-5 && !hit.front_face);
+
+        let bounds = pipe.get_bounds();
+        assert_eq!(bounds.p_min, glm::vec3(-1., -1., -1.));
+        assert_eq!(bounds.p_max, glm::vec3(1., 1., 2.));
+    }
+}
@@ -0,0 +1,217 @@
+use crate::area_light::Emitter;
+use crate::axis_box::AxisBox;
+use crate::cone::Cone;
+use crate::csg::{Csg, Solid};
+use crate::cylinder::Cylinder;
+use crate::disk::Disk;
+use crate::hyperboloid::Hyperboloid;
+use crate::material::{Material, MaterialType};
+use crate::object::ObjectTrait;
+use crate::paraboloid::Paraboloid;
+use crate::plane::Plane;
+use crate::rectangle::Rectangle;
+use crate::sdf::{Sdf, SdfObject};
+use crate::sphere::Sphere;
+use crate::torus::Torus;
+use crate::transform::Transform;
+use crate::transformed::TransformedObject;
+
+// [comment]
+// The room of --demo, built around the bunny seen by the default camera: a floor
+// plane, walls, a box, a disk, the quadrics, sphere traced blobs and csg objects
+// lit by a rectangular light in the ceiling. The objects borrow the materials, so
+// they are made from a DemoScene that outlives the render, like the shapes of a
+// pbrt scene.
+// [/comment]
+pub struct DemoScene {
+    pub white: Material,
+    pub red: Material,
+    pub green: Material,
+    pub mirror: Material,
+    pub gold: Material,
+    pub blue: Material,
+    pub light: Material,
+    pub light_intensity: glm::Vec3,
+}
+
+// the bunny rests on the floor
+const FLOOR: f32 = -4.01;
+const CEILING: f32 = 12.;
+
+impl DemoScene {
+    pub fn new() -> DemoScene {
+        DemoScene {
+            white: Material::new(None, Some(glm::vec3(0.8, 0.8, 0.8)), None),
+            red: Material::new(None, Some(glm::vec3(0.8, 0.2, 0.2)), None),
+            green: Material::new(None, Some(glm::vec3(0.2, 0.7, 0.3)), None),
+            mirror: Material::new(Some(MaterialType::REFLECTION), None, None),
+            gold: Material::new(None, Some(glm::vec3(0.9, 0.7, 0.2)), None),
+            blue: Material::new(None, Some(glm::vec3(0.2, 0.4, 0.9)), None),
+            light: Material::new(None, None, Some(glm::vec3(4., 4., 4.))),
+            light_intensity: glm::vec3(1., 1., 1.),
+        }
+    }
+
+    pub fn objects(&self) -> Vec<Box<dyn ObjectTrait + '_>> {
+        return vec![
+            Box::new(Plane::new(&glm::vec3(0., FLOOR, 0.), &glm::vec3(0., 1., 0.), &self.white)),
+            Box::new(Rectangle::new(&glm::vec3(-14., FLOOR, -18.), &glm::vec3(12., CEILING, -18.), &self.white)),
+            Box::new(Rectangle::new(&glm::vec3(-14., FLOOR, -18.), &glm::vec3(-14., CEILING, 0.), &self.red)),
+            Box::new(Rectangle::new(&glm::vec3(12., FLOOR, -18.), &glm::vec3(12., CEILING, 0.), &self.green).flip_normal()),
+            Box::new(AxisBox::new(&glm::vec3(5., FLOOR, -15.), &glm::vec3(9., 0., -11.), &self.white)),
+            Box::new(Disk::new(&glm::vec3(-8., 5., -17.9), &glm::vec3(0., 0., 1.), 3., &self.mirror)),
+            // a rounded cube melting into a ball, in front of the bunny's tail
+            Box::new(SdfObject::new(
+                Sdf::cuboid(&glm::vec3(4.2, FLOOR + 0.6, -7.), &glm::vec3(0.4, 0.4, 0.4)).round(0.2)
+                    .smooth_union(Sdf::sphere(&glm::vec3(4.2, FLOOR + 1.6, -7.), 0.5), 0.4),
+                &self.blue,
+            )),
+            // a round plate with a ring and a grid of posts, the middle one scooped out
+            Box::new(SdfObject::new(
+                Sdf::cuboid(&glm::vec3(0., 0.1, 0.), &glm::vec3(0.9, 0.1, 0.9))
+                    .union(Sdf::capsule(&glm::vec3(0., 0.2, 0.), &glm::vec3(0., 0.8, 0.), 0.1)
+                        .repeat(&glm::vec3(0.6, 0., 0.6), [1, 0, 1]))
+                    .union(Sdf::torus(&glm::vec3(0., 0.2, 0.), 0.7, 0.08))
+                    .intersection(Sdf::sphere(&glm::vec3(0., 0.2, 0.), 0.95))
+                    .subtract(Sdf::cuboid(&glm::vec3(0., 0.2, 0.9), &glm::vec3(0.15, 0.05, 0.2)))
+                    .smooth_subtract(Sdf::sphere(&glm::vec3(0., 0.8, 0.), 0.3), 0.1)
+                    .translate(&glm::vec3(-2.4, FLOOR, -5.5)),
+                &self.green,
+            )),
+        ];
+    }
+
+    // the shapes around the z axis in object space with their size, turn around the
+    // vertical in degrees and position, see shape_instances
+    pub fn shapes(&self) -> Vec<(Box<dyn ObjectTrait + '_>, f32, f32, glm::Vec3)> {
+        return vec![
+            (Box::new(Torus::new(1.2, 0.4, 360., &self.gold)), 1., 0., glm::vec3(-9., FLOOR + 0.4, -9.)),
+            (Box::new(Cone::new(1.5, 3., 360., &self.red)), 1., 0., glm::vec3(7., 0., -13.)),
+            // turned so the cut out quarter shows
+            (Box::new(Cylinder::new(0.7, 0., 2.5, 270., &self.white)), 1., -90., glm::vec3(7.5, FLOOR, -7.5)),
+            (Box::new(Paraboloid::new(0.8, 0., 1., 360., &self.green)), 1.5, 0., glm::vec3(-11., FLOOR, -12.)),
+            (Box::new(Hyperboloid::new(0.6, 0.5, -1.5, 1.5, 360., &self.gold)), 1., 0., glm::vec3(-12.5, FLOOR + 1.5, -16.)),
+        ];
+    }
+
+    // the shapes stand up, are scaled, turned and then moved in place
+    pub fn shape_instances<'b>(
+        &self,
+        shapes: &'b [(Box<dyn ObjectTrait + 'b>, f32, f32, glm::Vec3)],
+    ) -> Vec<TransformedObject<'b>> {
+        return shapes.iter()
+            .map(|(shape, size, turn, position)| {
+                TransformedObject::new(shape.as_ref(), Transform::rotate(-90., &glm::vec3(1., 0., 0.)))
+                    .scale(*size, *size, *size)
+                    .rotate(*turn, &glm::vec3(0., 1., 0.))
+                    .translate(position)
+            })
+            .collect();
+    }
+
+    // the solids the csg objects are made of, see csg_objects
+    pub fn solids(&self) -> Vec<Box<dyn Solid + '_>> {
+        let corner = glm::vec3(-3.7, FLOOR + 1.6, -4.7);
+        let die = glm::vec3(2.6, FLOOR + 0.6, -4.6);
+        return vec![
+            Box::new(AxisBox::new(&glm::vec3(-5.3, FLOOR, -6.3), &corner, &self.white)),
+            Box::new(Sphere::new(&corner, 1., &self.red)),
+            Box::new(AxisBox::new(&(die - glm::vec3(0.6, 0.6, 0.6)), &(die + glm::vec3(0.6, 0.6, 0.6)), &self.blue)),
+            Box::new(Sphere::new(&die, 0.8, &self.gold)),
+            Box::new(Sphere::new(&glm::vec3(0.8, FLOOR + 0.5, -4.4), 0.5, &self.white)),
+            Box::new(Sphere::new(&glm::vec3(0.8, FLOOR + 1.2, -4.4), 0.35, &self.white)),
+        ];
+    }
+
+    // a box with a ball bitten out of it, a die with rounded corners and a snowman
+    pub fn csg_objects<'b>(&self, solids: &'b [Box<dyn Solid + 'b>]) -> Vec<Csg<'b>> {
+        return vec![
+            Csg::difference(solids[0].as_ref(), solids[1].as_ref()),
+            Csg::intersection(solids[2].as_ref(), solids[3].as_ref()),
+            Csg::union(solids[4].as_ref(), solids[5].as_ref()),
+        ];
+    }
+
+    // the ceiling light, also add each one as an object so it is seen
+    pub fn emitters(&self) -> Vec<Box<dyn Emitter + '_>> {
+        return vec![
+            Box::new(Rectangle::new(&glm::vec3(-3., CEILING - 0.1, -13.), &glm::vec3(3., CEILING - 0.1, -7.), &self.light).flip_normal()),
+        ];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::area_light::AreaLight;
+    use crate::demo::DemoScene;
+    use crate::object::ObjectTrait;
+    use crate::ray::Ray;
+    use crate::scene::Scene;
+
+    #[test]
+    fn test_demo_room() {
+        let demo = DemoScene::new();
+        let objects = demo.objects();
+        let shapes = demo.shapes();
+        let instances = demo.shape_instances(&shapes);
+        let solids = demo.solids();
+        let csgs = demo.csg_objects(&solids);
+        let emitters = demo.emitters();
+        let mut scene = Scene::new(16, 16);
+        for csg in csgs.iter() {
+            scene.add_object(csg as &dyn ObjectTrait);
+        }
+        for object in objects.iter() {
+            scene.add_object(object.as_ref());
+        }
+        for instance in instances.iter() {
+            scene.add_object(instance as &dyn ObjectTrait);
+        }
+        for emitter in emitters.iter() {
+            scene.add_object(emitter.as_ref() as &dyn ObjectTrait);
+            scene.add_area_light(AreaLight::new(emitter.as_ref(), &demo.light_intensity));
+        }
+        scene.build_accelerator().unwrap();
+
+        // the floor under the light is lit, the light is seen from below
+        let down = Ray::new(&glm::vec3(0., 0., -10.), &glm::vec3(0., -1., 0.));
+        assert!((scene.get_intersect(&down).unwrap().distance - 4.01).abs() < 1e-4);
+        assert!(scene.cast_ray(&down, 0).x > 0.);
+        let up = scene.cast_ray(&Ray::new(&glm::vec3(0., 0., -10.), &glm::vec3(0., 1., 0.)), 0);
+        assert!(up.x >= 4.);
+        // the walls close the room on three sides
+        for direction in [glm::vec3(0., 0., -1.), glm::vec3(-1., 0., 0.), glm::vec3(1., 0., 0.)].iter() {
+            assert!(scene.get_intersect(&Ray::new(&glm::vec3(0., 8., -10.), direction)).is_some());
+        }
+        // the torus lies flat on the floor, a ray down its hole reaches the floor
+        let hole = scene.get_intersect(&Ray::new(&glm::vec3(-9., 5., -9.), &glm::vec3(0., -1., 0.))).unwrap();
+        assert!((hole.coords.y - -4.01).abs() < 1e-4);
+        let tube = scene.get_intersect(&Ray::new(&glm::vec3(-7.8, 5., -9.), &glm::vec3(0., -1., 0.))).unwrap();
+        assert!((tube.coords.y - (-4.01 + 0.8)).abs() < 1e-3, "{:?}", tube.coords);
+        // the blob is sphere traced between the meshes and quadrics
+        let blob = scene.get_intersect(&Ray::new(&glm::vec3(4.2, 5., -7.), &glm::vec3(0., -1., 0.))).unwrap();
+        assert!((blob.coords.y - (-4.01 + 2.1)).abs() < 1e-2, "{:?}", blob.coords);
+        // the ball takes a bite out of the top corner of the box, the rest of the top is flat
+        let bite = scene.get_intersect(&Ray::new(&glm::vec3(-3.8, 5., -4.8), &glm::vec3(0., -1., 0.))).unwrap();
+        assert!(bite.coords.y < -4.01 + 1.6 - 0.9 && std::ptr::eq(bite.m, &demo.red), "{:?}", bite.coords);
+        let top = scene.get_intersect(&Ray::new(&glm::vec3(-5.2, 5., -6.2), &glm::vec3(0., -1., 0.))).unwrap();
+        assert!((top.coords.y - (-4.01 + 1.6)).abs() < 1e-4 && std::ptr::eq(top.m, &demo.white));
+        // the die is flat on its faces and round at its corners, the snowman stacks up
+        let face = scene.get_intersect(&Ray::new(&glm::vec3(2.6, 5., -4.6), &glm::vec3(0., -1., 0.))).unwrap();
+        assert!((face.coords.y - (-4.01 + 1.2)).abs() < 1e-4 && std::ptr::eq(face.m, &demo.blue));
+        let corner = scene.get_intersect(&Ray::new(&glm::vec3(3.15, 5., -4.05), &glm::vec3(0., -1., 0.))).unwrap();
+        assert!(corner.coords.y < -4.01 + 1.1 && std::ptr::eq(corner.m, &demo.gold));
+        let head = scene.get_intersect(&Ray::new(&glm::vec3(0.8, 5., -4.4), &glm::vec3(0., -1., 0.))).unwrap();
+        assert!((head.coords.y - (-4.01 + 1.55)).abs() < 1e-4);
+        // the plate is flat between the posts and cut off round at its corners
+        let plate = scene.get_intersect(&Ray::new(&glm::vec3(-2.1, 5., -5.2), &glm::vec3(0., -1., 0.))).unwrap();
+        assert!((plate.coords.y - (-4.01 + 0.2)).abs() < 1e-2 && std::ptr::eq(plate.m, &demo.green), "{:?}", plate.coords);
+        let corner = scene.get_intersect(&Ray::new(&glm::vec3(-1.55, 5., -4.65), &glm::vec3(0., -1., 0.))).unwrap();
+        assert!((corner.coords.y - -4.01).abs() < 1e-4 && std::ptr::eq(corner.m, &demo.white));
+        let scoop = scene.get_intersect(&Ray::new(&glm::vec3(-2.4, 5., -5.5), &glm::vec3(0., -1., 0.))).unwrap();
+        assert!(scoop.coords.y > -4.01 + 0.3 && scoop.coords.y < -4.01 + 0.6, "{:?}", scoop.coords);
+        // the cone stands on the box with its apex up
+        let apex = scene.get_intersect(&Ray::new(&glm::vec3(7., 5., -13.), &glm::vec3(0., -1., 0.))).unwrap();
+        assert!(apex.coords.y > 2.9 && apex.coords.y <= 3.);
+    }
+}
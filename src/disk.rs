@@ -0,0 +1,123 @@
+use crate::area_light::Emitter;
+use crate::bounds3::Bounds3;
+use crate::global::*;
+use crate::intersection::IntersectData;
+use crate::material::Material;
+use crate::object::ObjectTrait;
+use crate::ray::Ray;
+
+// [comment]
+// Flat disk around center, facing along normal. Texture coordinates map the
+// square around the disk to [0, 1] x [0, 1] along s_axis and t_axis.
+// [/comment]
+pub struct Disk<'a> {
+    pub center: glm::Vec3,
+    pub normal: glm::Vec3,
+    pub radius: f32,
+    pub s_axis: glm::Vec3,
+    pub t_axis: glm::Vec3,
+    pub m: &'a Material,
+}
+
+impl<'a> Disk<'a> {
+    pub fn new(center: &glm::Vec3, normal: &glm::Vec3, radius: f32, m: &'a Material) -> Disk<'a> {
+        let normal = normal.normalize();
+        let (s_axis, t_axis) = coordinate_system(&normal);
+        Disk {
+            center: *center,
+            normal,
+            radius,
+            s_axis,
+            t_axis,
+            m,
+        }
+    }
+}
+
+impl<'a> ObjectTrait for Disk<'a> {
+    fn get_intersection(&self, ray: &Ray) -> Option<IntersectData<'_>> {
+        let denom = glm::dot(&ray.direction, &self.normal);
+        if denom == 0. {
+            return None;
+        }
+        let t = glm::dot(&(self.center - ray.origin), &self.normal) / denom;
+        if !ray.in_range(t) {
+            return None;
+        }
+        let coords = ray.origin + ray.direction * t;
+        let local = coords - self.center;
+        let (x, y) = (glm::dot(&local, &self.s_axis), glm::dot(&local, &self.t_axis));
+        if x * x + y * y > self.radius * self.radius {
+            return None;
+        }
+        let st = glm::vec2(x / self.radius * 0.5 + 0.5, y / self.radius * 0.5 + 0.5);
+
+        return Some(IntersectData {
+            coords,
+            normal: self.normal,
+            shading_normal: self.normal,
+            p_error: ray_point_error(&ray.origin, &ray.direction, t),
+            barycentric: glm::zero(),
+            front_face: denom < 0.,
+            distance: t,
+            index: u32::MAX,
+            m: self.m,
+            eval_diffuse_color: self.m.eval_diffuse_color(&st),
+            uv: glm::zero(),
+            st,
+        });
+    }
+
+    fn get_bounds(&self) -> Bounds3 {
+        // the disk reaches radius along every axis except the share of the normal
+        let extent = glm::vec3(
+            (1. - self.normal.x * self.normal.x).max(0.).sqrt(),
+            (1. - self.normal.y * self.normal.y).max(0.).sqrt(),
+            (1. - self.normal.z * self.normal.z).max(0.).sqrt(),
+        ) * self.radius;
+        return Bounds3::new(&(self.center - extent), &(self.center + extent));
+    }
+}
+
+impl<'a> Emitter for Disk<'a> {
+    fn sample(&self, u: f32, v: f32) -> (glm::Vec3, glm::Vec3) {
+        let r = self.radius * u.sqrt();
+        let phi = 2. * M_PI * v;
+        let p = self.center + (self.s_axis * phi.cos() + self.t_axis * phi.sin()) * r;
+        return (p, self.normal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::area_light::Emitter;
+    use crate::disk::Disk;
+    use crate::material::Material;
+    use crate::object::ObjectTrait;
+    use crate::ray::Ray;
+
+    #[test]
+    fn test_disk() {
+        let mat = Material::default();
+        let disk = Disk::new(&glm::vec3(0., 0., -4.), &glm::vec3(0., 0., 1.), 2., &mat);
+
+        let hit = disk.get_intersection(&Ray::new(&glm::vec3(1.5, 0., 0.), &glm::vec3(0., 0., -1.))).unwrap();
+        assert!((hit.distance - 4.).abs() < 1e-5);
+        assert!(hit.front_face);
+        assert!(hit.st.x >= 0. && hit.st.x <= 1. && hit.st.y >= 0. && hit.st.y <= 1.);
+        let center = disk.get_intersection(&Ray::new(&glm::vec3(0., 0., 0.), &glm::vec3(0., 0., -1.))).unwrap();
+        assert!(glm::distance(&center.st, &glm::vec2(0.5, 0.5)) < 1e-6);
+        // inside the bounding square but outside the disk
+        assert!(disk.get_intersection(&Ray::new(&glm::vec3(1.5, 1.5, 0.), &glm::vec3(0., 0., -1.))).is_none());
+
+        let bounds = disk.get_bounds();
+        assert_eq!(bounds.p_min, glm::vec3(-2., -2., -4.));
+        assert_eq!(bounds.p_max, glm::vec3(2., 2., -4.));
+
+        for &(u, v) in [(0., 0.), (0.99, 0.3), (0.5, 0.99)].iter() {
+            let (p, n) = disk.sample(u, v);
+            assert!(glm::distance(&p, &glm::vec3(0., 0., -4.)) <= 2. + 1e-5 && p.z == -4.);
+            assert_eq!(n, glm::vec3(0., 0., 1.));
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::fmt;
+
+// [comment]
+// Errors of loading, building and rendering a scene. Io is a file that could not
+// be read, Parse a file with content that could not be understood, InvalidScene
+// geometry or settings the renderer can not work with and Output an image that
+// could not be written.
+// [/comment]
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Parse(String),
+    InvalidScene(String),
+    Output(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// shorthand for the Parse errors of the file loaders
+pub fn invalid<S: Into<String>>(message: S) -> Error {
+    return Error::Parse(message.into());
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Parse(message) => write!(f, "parse error: {}", message),
+            Error::InvalidScene(message) => write!(f, "invalid scene: {}", message),
+            Error::Output(message) => write!(f, "output error: {}", message),
+        };
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        return match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        };
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        return Error::Io(err);
+    }
+}
+
+impl From<obj_rs::ObjError> for Error {
+    fn from(err: obj_rs::ObjError) -> Error {
+        return match err {
+            obj_rs::ObjError::Io(err) => Error::Io(err),
+            err => Error::Parse(err.to_string()),
+        };
+    }
+}
+
+impl From<gltf::Error> for Error {
+    fn from(err: gltf::Error) -> Error {
+        return match err {
+            gltf::Error::Io(err) => Error::Io(err),
+            err => Error::Parse(err.to_string()),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+
+    #[test]
+    fn test_error_conversions() {
+        let missing = std::io::Error::new(std::io::ErrorKind::NotFound, "no such file");
+        assert!(matches!(Error::from(missing), Error::Io(_)));
+
+        let obj: obj_rs::ObjError = "x".parse::<f32>().unwrap_err().into();
+        let err = Error::from(obj);
+        assert!(matches!(err, Error::Parse(_)));
+        assert!(err.to_string().starts_with("parse error: "));
+    }
+}
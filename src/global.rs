@@ -1,9 +1,11 @@
 
 use rand::Rng;
+use crate::ray::Ray;
 
 pub const M_PI:f32 = 3.14159265358979323846;
 
-pub const EPSILON: f32 = 0.00001;
+// bound on the relative error of a single rounded f32 operation
+pub const MACHINE_EPSILON: f32 = f32::EPSILON * 0.5;
 
 pub fn solve_quadratic(a:f32, b:f32, c:f32, x0:&mut f32, x1:&mut f32) -> bool{
     let discr = b*b-4.0*a*c; // 判别式
@@ -31,6 +33,142 @@ pub fn solve_quadratic(a:f32, b:f32, c:f32, x0:&mut f32, x1:&mut f32) -> bool{
     return true;
 }
 
+// [comment]
+// Nearest root of a*t^2 + b*t + c = 0 inside the ray interval whose point passes
+// `accept`, with that point. Quadrics cut by a height range or a sweep angle
+// reject the near root and fall back to the far one. When a is zero the ray runs
+// along the quadric and only the linear root is left.
+// [/comment]
+pub fn nearest_quadric_hit(ray: &Ray, a: f32, b: f32, c: f32, accept: &dyn Fn(&glm::Vec3) -> bool) -> Option<(f32, glm::Vec3)> {
+    let mut t0 = 0.0f32;
+    let mut t1 = 0.0f32;
+    if a == 0. {
+        if b == 0. {
+            return None;
+        }
+        t0 = -c / b;
+        t1 = t0;
+    } else if !solve_quadratic(a, b, c, &mut t0, &mut t1) {
+        return None;
+    }
+    for &t in [t0, t1].iter() {
+        if !ray.in_range(t) {
+            continue;
+        }
+        let p = ray.origin + ray.direction * t;
+        if accept(&p) {
+            return Some((t, p));
+        }
+    }
+    return None;
+}
+
+// angle of p around the z axis in [0, 2 pi), the parameter partial sweeps clip
+pub fn sweep_angle(p: &glm::Vec3) -> f32 {
+    let phi = p.y.atan2(p.x);
+    return if phi < 0. { phi + 2. * M_PI } else { phi };
+}
+
+// [comment]
+// Hit p of a shape around the z axis moved sideways onto its circle of radius r at
+// the height of p, with the error of the result. The ray parameter of a quadric
+// carries the error of solving the quadratic, the moved point only the error of
+// the scaling and r_error, the error of r. z keeps the error z_error of the ray point.
+// [/comment]
+pub fn reproject_to_radius(p: &glm::Vec3, r: f32, r_error: f32, z_error: f32) -> (glm::Vec3, glm::Vec3) {
+    let hit_radius = (p.x * p.x + p.y * p.y).sqrt();
+    // only the apex of a cone is hit on the axis
+    if hit_radius == 0. {
+        return (*p, glm::vec3(r_error, r_error, z_error));
+    }
+    let coords = glm::vec3(p.x * r / hit_radius, p.y * r / hit_radius, p.z);
+    let p_error = glm::vec3(coords.x.abs(), coords.y.abs(), 0.) * gamma(3) + glm::vec3(r_error, r_error, z_error);
+    return (coords, p_error);
+}
+
+// sweep of a shape in radians from degrees, above 0 so texture coordinates can divide by it
+pub fn sweep_radians(phi_max: f32) -> f32 {
+    return deg_2_rad(phi_max.clamp(1e-3, 360.));
+}
+
+// [comment]
+// Real roots of the monic cubic x^3 + a2*x^2 + a1*x + a0, by the trigonometric
+// method when there are three and Cardano's formula otherwise.
+// [/comment]
+pub fn solve_cubic(a2: f64, a1: f64, a0: f64) -> Vec<f64> {
+    let q = (a2 * a2 - 3. * a1) / 9.;
+    let r = (2. * a2 * a2 * a2 - 9. * a2 * a1 + 27. * a0) / 54.;
+    let shift = a2 / 3.;
+    if r * r < q * q * q {
+        let theta = (r / (q * q * q).sqrt()).acos();
+        let k = -2. * q.sqrt();
+        let tau = std::f64::consts::PI * 2.;
+        return vec![
+            k * (theta / 3.).cos() - shift,
+            k * ((theta + tau) / 3.).cos() - shift,
+            k * ((theta - tau) / 3.).cos() - shift,
+        ];
+    }
+    let a = -r.signum() * (r.abs() + (r * r - q * q * q).sqrt()).cbrt();
+    let b = if a != 0. { q / a } else { 0. };
+    return vec![a + b - shift];
+}
+
+// [comment]
+// Real roots of a*x^4 + b*x^3 + c*x^2 + d*x + e in ascending order (Ferrari).
+// The depressed quartic y^4 + p*y^2 + q*y + r is split into two quadratics with a
+// positive root m of its resolvent cubic, then every root is polished with Newton
+// steps on the original polynomial. Computed in f64, the coefficients of ray
+// tests lose too much in f32.
+// [/comment]
+pub fn solve_quartic(a: f64, b: f64, c: f64, d: f64, e: f64) -> Vec<f64> {
+    let (b, c, d, e) = (b / a, c / a, d / a, e / a);
+    let b2 = b * b;
+    let p = c - 3. * b2 / 8.;
+    let q = d - b * c / 2. + b2 * b / 8.;
+    let r = e - b * d / 4. + b2 * c / 16. - 3. * b2 * b2 / 256.;
+
+    let mut ys = Vec::with_capacity(4);
+    let mut push_quadratic = |qa: f64, qb: f64, qc: f64| {
+        let discr = qb * qb - 4. * qa * qc;
+        if discr >= 0. {
+            let root = discr.sqrt();
+            ys.push((-qb - root) / (2. * qa));
+            ys.push((-qb + root) / (2. * qa));
+        }
+    };
+    let m = solve_cubic(p, p * p / 4. - r, -q * q / 8.).into_iter().fold(0f64, f64::max);
+    if q.abs() < 1e-12 || m <= 0. {
+        // biquadratic, y^2 = z
+        let discr = p * p - 4. * r;
+        if discr >= 0. {
+            for z in [(-p - discr.sqrt()) / 2., (-p + discr.sqrt()) / 2.].iter() {
+                if *z >= 0. {
+                    ys.push(-z.sqrt());
+                    ys.push(z.sqrt());
+                }
+            }
+        }
+    } else {
+        let s = (2. * m).sqrt();
+        push_quadratic(1., -s, p / 2. + m + q / (2. * s));
+        push_quadratic(1., s, p / 2. + m - q / (2. * s));
+    }
+
+    let mut roots: Vec<f64> = ys.iter().map(|y| y - b / 4.).collect();
+    for x in roots.iter_mut() {
+        for _ in 0..2 {
+            let f = (((*x + b) * *x + c) * *x + d) * *x + e;
+            let df = ((4. * *x + 3. * b) * *x + 2. * c) * *x + d;
+            if df != 0. {
+                *x -= f / df;
+            }
+        }
+    }
+    roots.sort_by(|x, y| x.partial_cmp(y).unwrap());
+    return roots;
+}
+
 pub fn get_random_f32() -> f32 {
     let mut rng = rand::thread_rng();
     return rng.gen_range(0.0..1.0f32);
@@ -54,10 +192,82 @@ pub fn update_progress(progress: f32){
     println!("] {}%", (progress * 100.0) as i32);
 }
 
+// [comment]
+// Bound on the relative error accumulated by n rounded operations, (1 ± e)^n is
+// within 1 ± gamma(n).
+// [/comment]
+pub fn gamma(n: i32) -> f32 {
+    let ne = n as f32 * MACHINE_EPSILON;
+    return ne / (1.0 - ne);
+}
+
+pub fn next_float_up(v: f32) -> f32 {
+    if v.is_infinite() && v > 0. {
+        return v;
+    }
+    // -0.0 and 0.0 both step to the smallest positive float
+    let v = if v == -0. { 0. } else { v };
+    let bits = v.to_bits();
+    let bits = if v >= 0. { bits + 1 } else { bits - 1 };
+    return f32::from_bits(bits);
+}
+
+pub fn next_float_down(v: f32) -> f32 {
+    if v.is_infinite() && v < 0. {
+        return v;
+    }
+    let v = if v == 0. { -0. } else { v };
+    let bits = v.to_bits();
+    let bits = if v > 0. { bits - 1 } else { bits + 1 };
+    return f32::from_bits(bits);
+}
+
+// [comment]
+// Origin for a ray leaving the surface point p in direction w. p is only known up
+// to p_error per axis, so it is pushed along the normal n just far enough to be
+// outside that error box on the side w points to, then rounded away from the
+// surface. The offset grows with the magnitude of p, unlike a fixed epsilon.
+// Rounding away happens even for an exact p, so the origin always leaves the surface.
+// [/comment]
+pub fn offset_ray_origin(p: &glm::Vec3, p_error: &glm::Vec3, n: &glm::Vec3, w: &glm::Vec3) -> glm::Vec3 {
+    let d = glm::dot(&glm::abs(n), p_error);
+    let side = if glm::dot(w, n) < 0. { -n } else { *n };
+    let mut po = p + side * d;
+    for i in 0..3usize {
+        if side[i] > 0. {
+            po[i] = next_float_up(po[i]);
+        } else if side[i] < 0. {
+            po[i] = next_float_down(po[i]);
+        }
+    }
+    return po;
+}
+
+// [comment]
+// Conservative rounding error bound per axis of the point origin + t * direction,
+// for a t found by intersecting a ray with a plane.
+// [/comment]
+pub fn ray_point_error(origin: &glm::Vec3, direction: &glm::Vec3, t: f32) -> glm::Vec3 {
+    return (glm::abs(origin) + glm::abs(&(direction * t))) * gamma(7);
+}
+
 pub fn deg_2_rad(deg: f32) -> f32 {
     return deg * M_PI / 180.0;
 }
 
+// [comment]
+// Two unit vectors that make a right handed orthonormal basis with the unit vector
+// n, without branching on the smallest component (Duff et al. 2017).
+// [/comment]
+pub fn coordinate_system(n: &glm::Vec3) -> (glm::Vec3, glm::Vec3) {
+    let sign = 1f32.copysign(n.z);
+    let a = -1. / (sign + n.z);
+    let b = n.x * n.y * a;
+    let s = glm::vec3(1. + sign * n.x * n.x * a, sign * b, -sign * n.x);
+    let t = glm::vec3(b, sign + n.y * n.y * a, -n.y);
+    return (s, t);
+}
+
 pub fn reflect(I: &glm::Vec3, N: &glm::Vec3) -> glm::Vec3{
    return I - 2.0 * glm::dot(I, N) * N;
 }
@@ -129,42 +339,143 @@ pub fn fresnel(I: &glm::Vec3, N: &glm::Vec3, ior: f32) -> f32{
 }
 
 
-pub fn load_mesh(path: String) 
-    -> obj_rs::ObjResult<(
-        Vec<glm::Vec3>, Vec<glm::Vec2>, 
-        Vec<u32>
-    )> 
-{
-    use obj_rs::*;
-
-    let _file = std::fs::File::open(path)?;
-    let read_buf = std::io::BufReader::new(_file);
-
-    let wf_obj: Obj<TexturedVertex> = load_obj(read_buf)?;
-
-    let mut vertices = Vec::with_capacity(wf_obj.vertices.len());
-    let mut sts = Vec::with_capacity(wf_obj.vertices.len());
+// [comment]
+// A triangle mesh read from a file. Texture coordinates are zero when the file
+// has none, normals and colors are per vertex and empty when the file has none.
+// [/comment]
+#[derive(Default, Clone)]
+pub struct LoadedMesh {
+    pub vertices: Vec<glm::Vec3>,
+    pub st_coordinates: Vec<glm::Vec2>,
+    pub normals: Vec<glm::Vec3>,
+    pub colors: Vec<glm::Vec3>,
+    pub indices: Vec<u32>,
+}
 
-    for wf_v in &wf_obj.vertices {
-        let pos = glm::vec3(wf_v.position[0], wf_v.position[1], wf_v.position[2]);
-        let uv = glm::vec2(wf_v.texture[0], wf_v.texture[1]);
-        vertices.push(pos);
-        sts.push(uv);
+// [comment]
+// Load an obj, ply or stl file as a single mesh, the format is told from the
+// contents. All objects of an obj file are merged and materials are ignored.
+// Normals are only kept when every vertex has one.
+// [/comment]
+pub fn load_mesh(path: String) -> crate::error::Result<LoadedMesh> {
+    let path = std::path::Path::new(&path);
+    let data = std::fs::read(path)?;
+    if data.starts_with(b"ply") {
+        return crate::ply_loader::parse_ply(&data);
     }
-
-    let mut indices = Vec::with_capacity(wf_obj.indices.len());
-    for i in 0..wf_obj.indices.len() {
-        indices.push(wf_obj.indices[i] as u32);
+    let binary_stl = data.len() >= 84
+        && data.len() == 84 + 50 * u32::from_le_bytes([data[80], data[81], data[82], data[83]]) as usize;
+    if binary_stl || data.starts_with(b"solid") {
+        return crate::stl_loader::parse_stl(&data);
     }
 
-    return Ok((vertices, sts, indices))
+    let scene = crate::obj_loader::load_obj(path)?;
+    let mut mesh = LoadedMesh::default();
+    let mut all_normals = true;
+    for obj in scene.meshes {
+        let offset = mesh.vertices.len() as u32;
+        mesh.indices.extend(obj.indices.iter().map(|i| i + offset));
+        all_normals &= !obj.normals.is_empty();
+        mesh.vertices.extend(obj.vertices);
+        mesh.st_coordinates.extend(obj.st_coordinates);
+        mesh.normals.extend(obj.normals);
+    }
+    if !all_normals {
+        mesh.normals.clear();
+    }
+    return Ok(mesh);
 }
+
 #[cfg(test)]
 mod tests {
-    use crate::global::update_progress;
+    use crate::global::*;
 
     #[test]
     fn test_update_progress() {
         update_progress(0.5);
     }
+
+    #[test]
+    fn test_next_float() {
+        assert!(next_float_up(1.) > 1.);
+        assert_eq!(next_float_down(next_float_up(1.)), 1.);
+        assert!(next_float_up(0.) > 0. && next_float_up(-0.) > 0.);
+        assert!(next_float_down(0.) < 0.);
+        assert!(next_float_up(-2.) > -2.);
+        assert_eq!(next_float_up(f32::INFINITY), f32::INFINITY);
+    }
+
+    #[test]
+    fn test_solve_quartic() {
+        // (x - 1)(x + 2)(x - 3)(x - 0.5)
+        let roots = solve_quartic(1., -2.5, -4., 8.5, -3.);
+        assert_eq!(roots.len(), 4);
+        for (r, expected) in roots.iter().zip([-2., 0.5, 1., 3.].iter()) {
+            assert!((r - expected).abs() < 1e-9, "{:?}", roots);
+        }
+        // x^4 + 1 has no real roots, (x^2 - 4)^2 = x^4 - 8x^2 + 16 has double roots
+        assert!(solve_quartic(2., 0., 0., 0., 2.).is_empty());
+        let roots = solve_quartic(1., 0., -8., 0., 16.);
+        assert!(roots.iter().all(|r| (r.abs() - 2.).abs() < 1e-6), "{:?}", roots);
+
+        let mut cubic = solve_cubic(-6., 11., -6.);
+        cubic.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert!(cubic.iter().zip([1., 2., 3.].iter()).all(|(r, e)| (r - e).abs() < 1e-9));
+    }
+
+    #[test]
+    fn test_offset_ray_origin() {
+        let n = glm::vec3(0., 0., 1.);
+        for &scale in [1f32, 1e3, 1e5].iter() {
+            let p = glm::vec3(scale, -scale, scale);
+            let err = glm::abs(&p) * gamma(7);
+            let up = offset_ray_origin(&p, &err, &n, &glm::vec3(0.3, 0., 1.));
+            let down = offset_ray_origin(&p, &err, &n, &glm::vec3(0.3, 0., -1.));
+            // outside the error box on the requested side, unmoved in the plane
+            assert!(up.z > p.z + err.z && down.z < p.z - err.z);
+            assert_eq!((up.x, up.y), (p.x, p.y));
+            // and still close relative to the magnitude of p
+            assert!((up.z - p.z) / scale < 1e-5);
+        }
+        // an exact point still moves off the surface by at least one ulp
+        let p = glm::vec3(0.5, 2., 3.);
+        let up = offset_ray_origin(&p, &glm::zero(), &n, &glm::vec3(0., 0., 1.));
+        let down = offset_ray_origin(&p, &glm::zero(), &n, &glm::vec3(0., 0., -1.));
+        assert_eq!(up, glm::vec3(0.5, 2., next_float_up(3.)));
+        assert_eq!(down, glm::vec3(0.5, 2., next_float_down(3.)));
+    }
+
+    #[test]
+    fn test_load_mesh() {
+        let dir = std::env::temp_dir();
+        // a quad with normals and texture coordinates, fan triangulated
+        let path = dir.join("game101_5_test_quad.obj");
+        std::fs::write(&path, "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\n\
+            vn 0 0 1\nf 1/1/1 2/2/1 3/3/1 4/4/1\n").unwrap();
+        let mesh = load_mesh(path.to_str().unwrap().to_string()).unwrap();
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.indices, vec![0, 1, 2, 0, 2, 3]);
+        assert_eq!(mesh.st_coordinates[2], glm::vec2(1., 1.));
+        assert_eq!(mesh.normals, vec![glm::vec3(0., 0., 1.); 4]);
+
+        // without normals, a shared position with different uvs is split
+        let path = dir.join("game101_5_test_seam.obj");
+        std::fs::write(&path, "v 0 0 0\nv 1 0 0\nv 0 1 0\nv 1 1 0\nvt 0 0\nvt 1 0\nvt 0 1\nvt 0.5 0.5\n\
+            f 1/1 2/2 3/3\nf 2/4 4/2 3/3\n").unwrap();
+        let mesh = load_mesh(path.to_str().unwrap().to_string()).unwrap();
+        assert_eq!(mesh.vertices.len(), 5);
+        assert_eq!(mesh.indices.len(), 6);
+        assert!(mesh.normals.is_empty());
+
+        // ply and stl are recognized by their contents whatever the extension
+        let path = dir.join("game101_5_test_ply.mesh");
+        std::fs::write(&path, "ply\nformat ascii 1.0\nelement vertex 3\nproperty float x\nproperty float y\n\
+            property float z\nelement face 1\nproperty list uchar int vertex_indices\nend_header\n\
+            0 0 0\n1 0 0\n0 1 0\n3 0 1 2\n").unwrap();
+        assert_eq!(load_mesh(path.to_str().unwrap().to_string()).unwrap().indices, vec![0, 1, 2]);
+        let path = dir.join("game101_5_test_stl.mesh");
+        std::fs::write(&path, "solid t\nfacet normal 0 0 1\nouter loop\nvertex 0 0 0\nvertex 1 0 0\n\
+            vertex 0 1 0\nendloop\nendfacet\nendsolid t\n").unwrap();
+        assert_eq!(load_mesh(path.to_str().unwrap().to_string()).unwrap().vertices.len(), 3);
+    }
 }
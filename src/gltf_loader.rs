@@ -0,0 +1,454 @@
+use crate::error::{invalid, Result};
+use crate::global::LoadedMesh;
+use crate::instance::Instance;
+use crate::light::Light;
+use crate::material::{Material, MaterialType};
+use crate::mesh_validation::repair_mesh;
+use crate::texture::Texture;
+use crate::transform::Transform;
+use crate::triangle::MeshTriangle;
+use std::path::Path;
+use std::sync::Arc;
+
+// directional lights become point lights this far against their direction
+const DIRECTIONAL_LIGHT_DISTANCE: f32 = 1e5;
+
+// [comment]
+// One primitive of a gltf mesh in its own object space. Normals are per vertex
+// and empty when the file has none, the primitive is flat shaded then.
+// [/comment]
+pub struct GltfMesh {
+    pub name: String,
+    pub vertices: Vec<glm::Vec3>,
+    pub st_coordinates: Vec<glm::Vec2>,
+    pub normals: Vec<glm::Vec3>,
+    pub indices: Vec<u32>,
+    // index into GltfScene::materials
+    pub material: usize,
+}
+
+// a node placing a mesh in the world
+pub struct GltfInstance {
+    pub mesh: usize,
+    pub object_to_world: glm::Mat4,
+}
+
+pub struct GltfCamera {
+    pub camera_to_world: glm::Mat4,
+    // vertical field of view in degrees
+    pub fov: f32,
+}
+
+// [comment]
+// A gltf scene flattened into meshes and their placements. materials[0] is the
+// default material, gltf material i is materials[i + 1].
+// [/comment]
+pub struct GltfScene {
+    pub meshes: Vec<GltfMesh>,
+    pub materials: Vec<Material>,
+    pub instances: Vec<GltfInstance>,
+    pub cameras: Vec<GltfCamera>,
+    pub lights: Vec<Light>,
+}
+
+impl GltfScene {
+    // [comment]
+    // Unbuilt meshes, set their accelerator type and call build before tracing rays.
+    // Meshes are repaired first, see repair_mesh, and the ones without normals get
+    // smooth ones generated.
+    // [/comment]
+    pub fn mesh_triangles(&self, crease_angle: f32, weld_tolerance: f32) -> Result<Vec<MeshTriangle<'_>>> {
+        return self.meshes.iter().map(|mesh| {
+            let mut loaded = LoadedMesh {
+                vertices: mesh.vertices.clone(),
+                st_coordinates: mesh.st_coordinates.clone(),
+                normals: mesh.normals.clone(),
+                colors: Vec::new(),
+                indices: mesh.indices.clone(),
+            };
+            let report = repair_mesh(&mut loaded, weld_tolerance);
+            if !report.is_clean() {
+                println!("repaired {}: {}", mesh.name, report);
+            }
+            let mut triangles = MeshTriangle::from_loaded(loaded, &self.materials[mesh.material], crease_angle)?;
+            triangles.mesh_data.checker_pattern = false;
+            return Ok(triangles);
+        }).collect();
+    }
+
+    pub fn mesh_instances<'a>(&self, meshes: &'a [MeshTriangle<'a>]) -> Vec<Instance<'a>> {
+        return self.instances.iter()
+            .map(|instance| Instance::new(&meshes[instance.mesh], Transform::new(&instance.object_to_world)))
+            .collect();
+    }
+
+    // [comment]
+    // Scale light intensities to sum up to 1 keeping their ratios. The renderer has
+    // no light falloff and expects intensities around 1.
+    // [/comment]
+    pub fn normalize_lights(&mut self) {
+        let total: f32 = self.lights.iter().map(|l| glm::comp_max(&l.intensity)).sum();
+        if total > 0. {
+            for light in self.lights.iter_mut() {
+                light.intensity /= total;
+            }
+        }
+    }
+}
+
+// None for pixel formats without a conversion, an error when the pixels run short
+fn texture_from_image(image: &gltf::image::Data) -> Result<Option<Texture>> {
+    use gltf::image::Format;
+    let (channels, bytes) = match image.format {
+        Format::R8 => (1, 1),
+        Format::R8G8 => (2, 1),
+        Format::R8G8B8 => (3, 1),
+        Format::R8G8B8A8 => (4, 1),
+        Format::R16 => (1, 2),
+        Format::R16G16 => (2, 2),
+        Format::R16G16B16 => (3, 2),
+        Format::R16G16B16A16 => (4, 2),
+        _ => return Ok(None),
+    };
+    let expected = image.width as usize * image.height as usize * channels * bytes;
+    if image.pixels.len() < expected {
+        return Err(invalid(format!(
+            "image has {} bytes but {}x{} pixels need {}", image.pixels.len(), image.width, image.height, expected
+        )));
+    }
+    let value = |offset: usize| -> f32 {
+        if bytes == 1 {
+            return image.pixels[offset] as f32 / 255.;
+        }
+        return u16::from_le_bytes([image.pixels[offset], image.pixels[offset + 1]]) as f32 / 65535.;
+    };
+    let texels = (0..(image.width * image.height) as usize).map(|i| {
+        let offset = i * channels * bytes;
+        // gray images repeat their single channel
+        let c = |k: usize| value(offset + k.min(if channels < 3 { 0 } else { 2 }) * bytes);
+        return glm::vec3(c(0), c(1), c(2));
+    }).collect();
+    return Ok(Some(Texture::new(image.width, image.height, texels)?));
+}
+
+// [comment]
+// Approximate a metallic-roughness material with the phong model of the renderer.
+// Transmissive materials become glass and smooth metals mirrors, everything else
+// is diffuse with a highlight that narrows and strengthens as roughness goes to 0.
+// Only the base color texture is used.
+// [/comment]
+pub fn material_from_gltf(material: &gltf::Material, textures: &[Option<Arc<Texture>>]) -> Material {
+    let pbr = material.pbr_metallic_roughness();
+    let base = pbr.base_color_factor();
+    let metallic = pbr.metallic_factor();
+    let roughness = pbr.roughness_factor();
+    let transmission = material.transmission().map(|t| t.transmission_factor()).unwrap_or(0.);
+    let m_type = if transmission > 0.5 {
+        MaterialType::REFLECTION_AND_REFRACTION
+    } else if metallic > 0.5 && roughness < 0.1 {
+        MaterialType::REFLECTION
+    } else {
+        MaterialType::DIFFUSE_AND_GLOSSY
+    };
+    let mut m = Material::new(
+        Some(m_type),
+        Some(glm::vec3(base[0], base[1], base[2])),
+        Some(glm::make_vec3(&material.emissive_factor())),
+    );
+    m.ior = material.ior().unwrap_or(1.5);
+    m.Kd = 1. - metallic;
+    m.Ks = (1. - roughness) * (0.04 + 0.96 * metallic);
+    // blinn phong exponent of the ggx lobe with alpha = roughness^2
+    let alpha = (roughness * roughness).max(1e-3);
+    m.specular_exponent = (2. / (alpha * alpha) - 2.).clamp(1., 1e4);
+    m.diffuse_texture = pbr.base_color_texture()
+        .and_then(|info| textures[info.texture().source().index()].clone());
+    return m;
+}
+
+// [comment]
+// Ok(None) for primitives without a surface. Indices and the per vertex attributes
+// are checked against the positions, a primitive that does not add up is an error.
+// [/comment]
+fn read_primitive(primitive: &gltf::Primitive, buffers: &[gltf::buffer::Data], name: String, material: usize
+) -> Result<Option<GltfMesh>> {
+    use gltf::mesh::Mode;
+    let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+    let vertices: Vec<glm::Vec3> = match reader.read_positions() {
+        Some(positions) => positions.map(|p| glm::make_vec3(&p)).collect(),
+        None => return Ok(None),
+    };
+    let normals: Vec<glm::Vec3> = match reader.read_normals() {
+        Some(normals) => normals.map(|n| glm::make_vec3(&n)).collect(),
+        None => Vec::new(),
+    };
+    // gltf puts the origin of texture space at the top left
+    let st_coordinates: Vec<glm::Vec2> = match reader.read_tex_coords(0) {
+        Some(sts) => sts.into_f32().map(|t| glm::vec2(t[0], 1. - t[1])).collect(),
+        None => vec![glm::vec2(0., 0.); vertices.len()],
+    };
+    let elements: Vec<u32> = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect(),
+        None => (0..vertices.len() as u32).collect(),
+    };
+    if elements.iter().any(|&i| i as usize >= vertices.len()) {
+        return Err(invalid(format!("gltf primitive {}: index out of range", name)));
+    }
+    if (!normals.is_empty() && normals.len() != vertices.len()) || st_coordinates.len() != vertices.len() {
+        return Err(invalid(format!("gltf primitive {}: attribute counts differ from POSITION", name)));
+    }
+    let n = elements.len();
+    let indices = match primitive.mode() {
+        Mode::Triangles => elements[..n - n % 3].to_vec(),
+        Mode::TriangleStrip => (0..n.saturating_sub(2)).flat_map(|i| {
+            // every other triangle is flipped to keep the winding
+            if i % 2 == 0 {
+                vec![elements[i], elements[i + 1], elements[i + 2]]
+            } else {
+                vec![elements[i + 1], elements[i], elements[i + 2]]
+            }
+        }).collect(),
+        Mode::TriangleFan => (1..n.saturating_sub(1))
+            .flat_map(|i| vec![elements[0], elements[i], elements[i + 1]])
+            .collect(),
+        // points and lines have no surface
+        _ => return Ok(None),
+    };
+    if indices.is_empty() {
+        return Ok(None);
+    }
+    return Ok(Some(GltfMesh {
+        name,
+        vertices,
+        st_coordinates,
+        normals,
+        indices,
+        material,
+    }));
+}
+
+struct Importer {
+    // meshes of every primitive of every gltf mesh
+    mesh_primitives: Vec<Vec<usize>>,
+    scene: GltfScene,
+}
+
+impl Importer {
+    fn visit(&mut self, node: &gltf::Node, parent_to_world: &glm::Mat4) {
+        let node_to_world = parent_to_world * glm::Mat4::from(node.transform().matrix());
+        if let Some(mesh) = node.mesh() {
+            for &m in &self.mesh_primitives[mesh.index()] {
+                self.scene.instances.push(GltfInstance {
+                    mesh: m,
+                    object_to_world: node_to_world,
+                });
+            }
+        }
+        if let Some(camera) = node.camera() {
+            // orthographic cameras are not supported
+            if let gltf::camera::Projection::Perspective(p) = camera.projection() {
+                self.scene.cameras.push(GltfCamera {
+                    camera_to_world: node_to_world,
+                    fov: p.yfov().to_degrees(),
+                });
+            }
+        }
+        if let Some(light) = node.light() {
+            use gltf::khr_lights_punctual::Kind;
+            let position = glm::vec4_to_vec3(&(node_to_world * glm::vec4(0., 0., 0., 1.)));
+            let position = match light.kind() {
+                Kind::Directional => {
+                    let direction = glm::vec4_to_vec3(&(node_to_world * glm::vec4(0., 0., -1., 0.))).normalize();
+                    position - direction * DIRECTIONAL_LIGHT_DISTANCE
+                }
+                // spot lights shine in all directions, there are no cones in the renderer
+                Kind::Point | Kind::Spot { .. } => position,
+            };
+            let intensity = glm::make_vec3(&light.color()) * light.intensity();
+            self.scene.lights.push(Light::new(&position, &intensity));
+        }
+        for child in node.children() {
+            self.visit(&child, &node_to_world);
+        }
+    }
+}
+
+// [comment]
+// Import the default scene of a .gltf or .glb file. Nodes become instances of the
+// meshes they reference, so meshes used by several nodes are stored once. Light
+// intensities are kept as they are, see normalize_lights.
+// [/comment]
+pub fn load_gltf(path: &Path) -> Result<GltfScene> {
+    let (document, buffers, images) = gltf::import(path)?;
+
+    let textures: Vec<Option<Arc<Texture>>> = images.iter()
+        .map(|image| Ok(texture_from_image(image)?.map(Arc::new)))
+        .collect::<Result<_>>()?;
+    let mut materials = vec![Material::default()];
+    for material in document.materials() {
+        materials.push(material_from_gltf(&material, &textures));
+    }
+
+    let mut meshes = Vec::new();
+    let mut mesh_primitives = Vec::new();
+    for mesh in document.meshes() {
+        let mut primitives = Vec::new();
+        for (k, primitive) in mesh.primitives().enumerate() {
+            let name = format!("{}/{}", mesh.name().unwrap_or("mesh"), k);
+            let material = primitive.material().index().map(|i| i + 1).unwrap_or(0);
+            if let Some(m) = read_primitive(&primitive, &buffers, name, material)? {
+                primitives.push(meshes.len());
+                meshes.push(m);
+            }
+        }
+        mesh_primitives.push(primitives);
+    }
+
+    let mut importer = Importer {
+        mesh_primitives,
+        scene: GltfScene {
+            meshes,
+            materials,
+            instances: Vec::new(),
+            cameras: Vec::new(),
+            lights: Vec::new(),
+        },
+    };
+    if let Some(scene) = document.default_scene().or_else(|| document.scenes().next()) {
+        for node in scene.nodes() {
+            importer.visit(&node, &glm::identity());
+        }
+    }
+    return Ok(importer.scene);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gltf_loader::load_gltf;
+    use crate::material::MaterialType;
+    use crate::object::ObjectTrait;
+    use crate::ray::Ray;
+
+    #[test]
+    fn test_load_gltf() {
+        let dir = std::env::temp_dir().join("game101_5_test_gltf");
+        std::fs::create_dir_all(&dir).unwrap();
+        // one triangle in the xy plane and its indices
+        let mut bin = Vec::new();
+        for v in [-1f32, -1., 0., 1., -1., 0., 0., 1., 0.].iter() {
+            bin.extend_from_slice(&v.to_le_bytes());
+        }
+        for i in [0u16, 1, 2, 0].iter() {
+            bin.extend_from_slice(&i.to_le_bytes());
+        }
+        std::fs::write(dir.join("scene.bin"), &bin).unwrap();
+        std::fs::write(dir.join("scene.gltf"), r#"{
+            "asset": {"version": "2.0"},
+            "extensionsUsed": ["KHR_lights_punctual"],
+            "extensions": {"KHR_lights_punctual": {"lights": [
+                {"type": "point", "color": [1, 0.5, 0.5], "intensity": 30},
+                {"type": "directional", "intensity": 10}
+            ]}},
+            "buffers": [{"uri": "scene.bin", "byteLength": 44}],
+            "bufferViews": [
+                {"buffer": 0, "byteOffset": 0, "byteLength": 36},
+                {"buffer": 0, "byteOffset": 36, "byteLength": 6}
+            ],
+            "accessors": [
+                {"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                 "min": [-1, -1, 0], "max": [1, 1, 0]},
+                {"bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR"}
+            ],
+            "materials": [
+                {"pbrMetallicRoughness": {"baseColorFactor": [1, 0, 0, 1], "metallicFactor": 0, "roughnessFactor": 0.5}},
+                {"pbrMetallicRoughness": {"metallicFactor": 1, "roughnessFactor": 0}}
+            ],
+            "meshes": [{"name": "tri", "primitives": [{"attributes": {"POSITION": 0}, "indices": 1, "material": 0}]}],
+            "cameras": [{"type": "perspective", "perspective": {"yfov": 0.5, "znear": 0.1}}],
+            "nodes": [
+                {"mesh": 0, "translation": [0, 0, -5], "children": [1]},
+                {"camera": 0, "translation": [0, 0, 5]},
+                {"extensions": {"KHR_lights_punctual": {"light": 0}}, "translation": [0, 10, 0]},
+                {"extensions": {"KHR_lights_punctual": {"light": 1}}, "rotation": [-0.7071068, 0, 0, 0.7071068]},
+                {"mesh": 0, "translation": [10, 0, -5], "scale": [2, 2, 2]}
+            ],
+            "scenes": [{"nodes": [0, 2, 3, 4]}],
+            "scene": 0
+        }"#).unwrap();
+
+        let mut scene = load_gltf(&dir.join("scene.gltf")).unwrap();
+        assert_eq!(scene.meshes.len(), 1);
+        assert_eq!(scene.meshes[0].indices, vec![0, 1, 2]);
+        assert_eq!(scene.meshes[0].material, 1);
+        assert_eq!(scene.materials[1].m_color, glm::vec3(1., 0., 0.));
+        assert!(matches!(scene.materials[1].m_type, MaterialType::DIFFUSE_AND_GLOSSY));
+        assert!(matches!(scene.materials[2].m_type, MaterialType::REFLECTION));
+
+        // the camera is a child of the mesh node and ends up at the origin
+        assert_eq!(scene.cameras.len(), 1);
+        let eye = scene.cameras[0].camera_to_world * glm::vec4(0., 0., 0., 1.);
+        assert!(glm::length(&eye.xyz()) < 1e-6);
+        assert!((scene.cameras[0].fov - 0.5f32.to_degrees()).abs() < 1e-4);
+
+        // the point light keeps its position, the directional one points down from far above
+        assert_eq!(scene.lights.len(), 2);
+        assert_eq!(scene.lights[0].position, glm::vec3(0., 10., 0.));
+        assert_eq!(scene.lights[0].intensity, glm::vec3(30., 15., 15.));
+        assert!(scene.lights[1].position.y > 1e4);
+        assert_eq!(scene.lights[1].intensity, glm::vec3(10., 10., 10.));
+        // normalized they sum up to 1 keeping their ratio
+        scene.normalize_lights();
+        assert!((scene.lights[0].intensity.x - 0.75).abs() < 1e-6);
+        assert!((scene.lights[1].intensity.x - 0.25).abs() < 1e-6);
+
+        // two instances share the mesh
+        let mut meshes = scene.mesh_triangles(180., 0.).unwrap();
+        for mesh in meshes.iter_mut() {
+            mesh.build().unwrap();
+        }
+        let instances = scene.mesh_instances(&meshes);
+        assert_eq!(instances.len(), 2);
+        let hit = |x: f32| instances.iter()
+            .filter_map(|i| i.get_intersection(&Ray::new(&glm::vec3(x, -0.5, 0.), &glm::vec3(0., 0., -1.))))
+            .next();
+        assert!((hit(0.).unwrap().distance - 5.).abs() < 1e-5);
+        assert!(hit(11.).is_some());
+        assert!(hit(5.).is_none());
+        // the file has no normals, generated ones face the winding
+        assert!((hit(0.).unwrap().shading_normal - glm::vec3(0., 0., 1.)).norm() < 1e-6);
+    }
+
+    #[test]
+    fn test_load_gltf_bad_indices() {
+        let dir = std::env::temp_dir().join("game101_5_test_gltf_bad");
+        std::fs::create_dir_all(&dir).unwrap();
+        // index 7 into three vertices
+        let mut bin = Vec::new();
+        for v in [-1f32, -1., 0., 1., -1., 0., 0., 1., 0.].iter() {
+            bin.extend_from_slice(&v.to_le_bytes());
+        }
+        for i in [0u16, 1, 7, 0].iter() {
+            bin.extend_from_slice(&i.to_le_bytes());
+        }
+        std::fs::write(dir.join("scene.bin"), &bin).unwrap();
+        std::fs::write(dir.join("scene.gltf"), r#"{
+            "asset": {"version": "2.0"},
+            "buffers": [{"uri": "scene.bin", "byteLength": 44}],
+            "bufferViews": [
+                {"buffer": 0, "byteOffset": 0, "byteLength": 36},
+                {"buffer": 0, "byteOffset": 36, "byteLength": 6}
+            ],
+            "accessors": [
+                {"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                 "min": [-1, -1, 0], "max": [1, 1, 0]},
+                {"bufferView": 1, "componentType": 5123, "count": 3, "type": "SCALAR"}
+            ],
+            "meshes": [{"primitives": [{"attributes": {"POSITION": 0}, "indices": 1}]}],
+            "nodes": [{"mesh": 0}],
+            "scenes": [{"nodes": [0]}]
+        }"#).unwrap();
+
+        let err = load_gltf(&dir.join("scene.gltf")).err().unwrap();
+        assert!(err.to_string().contains("index out of range"), "{}", err);
+    }
+}
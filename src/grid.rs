@@ -0,0 +1,245 @@
+use crate::accelerator::Accelerator;
+use crate::bounds3::Bounds3;
+use crate::bvh::{count_node_visit, count_primitive_test};
+use crate::intersection::IntersectData;
+use crate::ray::Ray;
+
+// upper limit of voxels along one axis
+const MAX_RESOLUTION: usize = 128;
+
+// [comment]
+// Uniform grid over the world bounds, about 3 * cbrt(n) voxels along the widest
+// axis and cubic voxels otherwise. Every voxel lists the primitives whose bounds
+// overlap it, cells are stored back to back in `cell_prims`.
+// [/comment]
+pub struct UniformGrid {
+    pub bounds: Bounds3,
+    pub resolution: [usize; 3],
+    pub voxel_width: glm::Vec3,
+    // voxel v owns cell_prims[cell_start[v]..cell_start[v + 1]]
+    pub cell_start: Vec<u32>,
+    pub cell_prims: Vec<u32>,
+}
+
+impl UniformGrid {
+    pub fn new(prim_bounds: Vec<Bounds3>) -> UniformGrid {
+        let mut bounds = Bounds3::default();
+        for b in &prim_bounds {
+            bounds = Bounds3::union(&bounds, b);
+        }
+        let mut grid = UniformGrid {
+            bounds,
+            resolution: [1; 3],
+            voxel_width: glm::zero(),
+            cell_start: vec![0, 0],
+            cell_prims: Vec::new(),
+        };
+        if prim_bounds.is_empty() {
+            return grid;
+        }
+
+        let delta = grid.bounds.diagonal();
+        let max_width = delta[grid.bounds.max_extent()];
+        let voxels_per_unit = if max_width > 0. {
+            3.0 * (prim_bounds.len() as f32).cbrt() / max_width
+        } else {
+            0.
+        };
+        for axis in 0..3usize {
+            let n = (delta[axis] * voxels_per_unit).round() as usize;
+            grid.resolution[axis] = n.clamp(1, MAX_RESOLUTION);
+            grid.voxel_width[axis] = delta[axis] / grid.resolution[axis] as f32;
+        }
+
+        // count, prefix sum, then fill
+        let num_voxels = grid.resolution[0] * grid.resolution[1] * grid.resolution[2];
+        let mut counts = vec![0u32; num_voxels + 1];
+        for b in &prim_bounds {
+            let (lo, hi) = (grid.pos_to_voxel(&b.p_min), grid.pos_to_voxel(&b.p_max));
+            for z in lo[2]..=hi[2] {
+                for y in lo[1]..=hi[1] {
+                    for x in lo[0]..=hi[0] {
+                        counts[grid.offset(&[x, y, z]) + 1] += 1;
+                    }
+                }
+            }
+        }
+        for v in 0..num_voxels {
+            counts[v + 1] += counts[v];
+        }
+        let mut cursor = counts.clone();
+        let mut cell_prims = vec![0u32; counts[num_voxels] as usize];
+        for (i, b) in prim_bounds.iter().enumerate() {
+            let (lo, hi) = (grid.pos_to_voxel(&b.p_min), grid.pos_to_voxel(&b.p_max));
+            for z in lo[2]..=hi[2] {
+                for y in lo[1]..=hi[1] {
+                    for x in lo[0]..=hi[0] {
+                        let v = grid.offset(&[x, y, z]);
+                        cell_prims[cursor[v] as usize] = i as u32;
+                        cursor[v] += 1;
+                    }
+                }
+            }
+        }
+        grid.cell_start = counts;
+        grid.cell_prims = cell_prims;
+        return grid;
+    }
+
+    fn pos_to_voxel_axis(&self, p: &glm::Vec3, axis: usize) -> usize {
+        if self.voxel_width[axis] <= 0. {
+            return 0;
+        }
+        let v = ((p[axis] - self.bounds.p_min[axis]) / self.voxel_width[axis]) as i64;
+        return v.clamp(0, self.resolution[axis] as i64 - 1) as usize;
+    }
+
+    fn pos_to_voxel(&self, p: &glm::Vec3) -> [usize; 3] {
+        return [
+            self.pos_to_voxel_axis(p, 0),
+            self.pos_to_voxel_axis(p, 1),
+            self.pos_to_voxel_axis(p, 2),
+        ];
+    }
+
+    fn offset(&self, v: &[usize; 3]) -> usize {
+        return (v[2] * self.resolution[1] + v[1]) * self.resolution[0] + v[0];
+    }
+
+    // [comment]
+    // 3D DDA walk through the voxels pierced by the ray up to t_max. `visit` gets
+    // the primitives of each voxel and the distance the ray leaves it at, and
+    // returns true to end the walk.
+    // [/comment]
+    fn walk<F>(&self, ray: &Ray, t_max: f32, mut visit: F) where F: FnMut(&[u32], f32) -> bool {
+        if self.cell_prims.is_empty() {
+            return;
+        }
+        let (t_enter, t_exit) = match self.bounds.ray_interval(ray) {
+            Some(interval) => interval,
+            None => return,
+        };
+        if t_enter > t_max {
+            return;
+        }
+        let t_exit = t_exit.min(t_max);
+        let p = ray.origin + ray.direction * t_enter;
+
+        let mut pos = [0i64; 3];
+        let mut step = [0i64; 3];
+        let mut out = [0i64; 3];
+        let mut next_crossing = [f32::INFINITY; 3];
+        let mut delta_t = [f32::INFINITY; 3];
+        for axis in 0..3usize {
+            pos[axis] = self.pos_to_voxel_axis(&p, axis) as i64;
+            let d = ray.direction[axis];
+            if d > 0. {
+                let boundary = self.bounds.p_min[axis] + (pos[axis] + 1) as f32 * self.voxel_width[axis];
+                next_crossing[axis] = t_enter + (boundary - p[axis]) / d;
+                delta_t[axis] = self.voxel_width[axis] / d;
+                step[axis] = 1;
+                out[axis] = self.resolution[axis] as i64;
+            } else if d < 0. {
+                let boundary = self.bounds.p_min[axis] + pos[axis] as f32 * self.voxel_width[axis];
+                next_crossing[axis] = t_enter + (boundary - p[axis]) / d;
+                delta_t[axis] = -self.voxel_width[axis] / d;
+                step[axis] = -1;
+                out[axis] = -1;
+            }
+        }
+
+        loop {
+            count_node_visit();
+            let v = self.offset(&[pos[0] as usize, pos[1] as usize, pos[2] as usize]);
+            let cell = &self.cell_prims[self.cell_start[v] as usize..self.cell_start[v + 1] as usize];
+            let axis = if next_crossing[0] < next_crossing[1] {
+                if next_crossing[0] < next_crossing[2] { 0 } else { 2 }
+            } else {
+                if next_crossing[1] < next_crossing[2] { 1 } else { 2 }
+            };
+            if visit(cell, next_crossing[axis]) {
+                break;
+            }
+            if next_crossing[axis] > t_exit {
+                break;
+            }
+            pos[axis] += step[axis];
+            if pos[axis] == out[axis] {
+                break;
+            }
+            next_crossing[axis] += delta_t[axis];
+        }
+    }
+}
+
+impl Accelerator for UniformGrid {
+    fn get_bounds(&self) -> Bounds3 {
+        return self.bounds.clone();
+    }
+
+    // [comment]
+    // A primitive may span several voxels, so a hit only ends the walk once it
+    // lies before the exit of the current voxel.
+    // [/comment]
+    fn get_intersection<'b>(
+        &self,
+        ray: &Ray,
+        intersect: &dyn Fn(usize, &Ray) -> Option<IntersectData<'b>>
+    ) -> Option<IntersectData<'b>> {
+        // t_max shrinks to the nearest hit so far
+        let mut shortened = ray.clone();
+        let mut nearest: Option<IntersectData<'b>> = None;
+        self.walk(ray, f32::INFINITY, |cell, cell_exit| {
+            for &prim in cell {
+                count_primitive_test();
+                if let Some(data) = intersect(prim as usize, &shortened) {
+                    if nearest.is_none() || data.distance < nearest.as_ref().unwrap().distance {
+                        shortened.t_max = data.distance;
+                        nearest = Some(data);
+                    }
+                }
+            }
+            return nearest.as_ref().is_some_and(|data| data.distance <= cell_exit);
+        });
+        return nearest;
+    }
+
+    // the walk stops at t_max and at the first primitive that blocks the ray
+    fn occluded(&self, ray: &Ray, t_max: f32, occluded: &dyn Fn(usize, &Ray, f32) -> bool) -> bool {
+        let mut hit = false;
+        self.walk(ray, t_max, |cell, _| {
+            hit = cell.iter().any(|&prim| {
+                count_primitive_test();
+                return occluded(prim as usize, ray, t_max);
+            });
+            return hit;
+        });
+        return hit;
+    }
+
+    fn refit(&mut self, bounds: &[Bounds3]) {
+        *self = UniformGrid::new(bounds.to_vec());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bounds3::Bounds3;
+    use crate::grid::UniformGrid;
+
+    #[test]
+    fn test_grid_cells() {
+        // a flat row of boxes, the grid must not split the empty y and z axes
+        let bounds: Vec<Bounds3> = (0..27)
+            .map(|i| {
+                let p = glm::vec3(i as f32, 0., 0.);
+                Bounds3::new(&p, &(p + glm::vec3(0.5, 0., 0.)))
+            })
+            .collect();
+        let grid = UniformGrid::new(bounds);
+        assert_eq!(grid.resolution, [9, 1, 1]);
+        // 3 boxes per voxel, none straddles a voxel border
+        assert_eq!(grid.cell_prims.len(), 27);
+        assert!(grid.cell_start.windows(2).all(|w| w[1] - w[0] == 3));
+    }
+}
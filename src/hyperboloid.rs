@@ -0,0 +1,111 @@
+use crate::bounds3::Bounds3;
+use crate::global::*;
+use crate::intersection::IntersectData;
+use crate::material::Material;
+use crate::object::ObjectTrait;
+use crate::ray::Ray;
+
+// [comment]
+// Hyperboloid of one sheet x^2 + y^2 - (slope * z)^2 = waist^2 around the z axis,
+// cut to [z_min, z_max] and swept by phi_max. Its radius is waist at z = 0 and
+// grows towards sqrt(waist^2 + (slope * z)^2), a slope of 0 gives a cylinder and
+// a waist of 0 a double cone. u follows the sweep and v the height.
+// [/comment]
+pub struct Hyperboloid<'a> {
+    pub waist: f32,
+    pub slope: f32,
+    pub z_min: f32,
+    pub z_max: f32,
+    // radians
+    pub phi_max: f32,
+    pub m: &'a Material,
+}
+
+impl<'a> Hyperboloid<'a> {
+    // \param phi_max: sweep in degrees, 360 for a full hyperboloid
+    pub fn new(waist: f32, slope: f32, z_min: f32, z_max: f32, phi_max: f32, m: &'a Material) -> Hyperboloid<'a> {
+        Hyperboloid {
+            waist,
+            slope,
+            z_min: z_min.min(z_max),
+            z_max: z_min.max(z_max),
+            phi_max: sweep_radians(phi_max),
+            m,
+        }
+    }
+
+    pub fn radius_at(&self, z: f32) -> f32 {
+        return (self.waist * self.waist + self.slope * self.slope * z * z).sqrt();
+    }
+}
+
+impl<'a> ObjectTrait for Hyperboloid<'a> {
+    fn get_intersection(&self, ray: &Ray) -> Option<IntersectData<'_>> {
+        let (o, d) = (&ray.origin, &ray.direction);
+        let s2 = self.slope * self.slope;
+        let a = d.x * d.x + d.y * d.y - s2 * d.z * d.z;
+        let b = 2. * (d.x * o.x + d.y * o.y - s2 * d.z * o.z);
+        let c = o.x * o.x + o.y * o.y - s2 * o.z * o.z - self.waist * self.waist;
+        let accept = |p: &glm::Vec3| p.z >= self.z_min && p.z <= self.z_max && sweep_angle(p) <= self.phi_max;
+        let (t, p) = nearest_quadric_hit(ray, a, b, c, &accept)?;
+        let r = self.radius_at(p.z);
+        let (coords, p_error) = reproject_to_radius(&p, r, r * gamma(4), ray_point_error(o, d, t).z);
+
+        let mut normal = glm::vec3(coords.x, coords.y, -s2 * coords.z);
+        normal = if glm::length2(&normal) > 0. { normal.normalize() } else { glm::vec3(0., 0., 1.) };
+        let v = if self.z_max > self.z_min { (coords.z - self.z_min) / (self.z_max - self.z_min) } else { 0. };
+        let st = glm::vec2(sweep_angle(&coords) / self.phi_max, v);
+
+        return Some(IntersectData {
+            coords,
+            normal,
+            shading_normal: normal,
+            p_error,
+            barycentric: glm::zero(),
+            front_face: glm::dot(d, &normal) < 0.,
+            distance: t,
+            index: u32::MAX,
+            m: self.m,
+            eval_diffuse_color: self.m.eval_diffuse_color(&st),
+            uv: glm::zero(),
+            st,
+        });
+    }
+
+    fn get_bounds(&self) -> Bounds3 {
+        let r = self.radius_at(self.z_min).max(self.radius_at(self.z_max));
+        return Bounds3::new(&glm::vec3(-r, -r, self.z_min), &glm::vec3(r, r, self.z_max));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hyperboloid::Hyperboloid;
+    use crate::material::Material;
+    use crate::object::ObjectTrait;
+    use crate::ray::Ray;
+
+    #[test]
+    fn test_hyperboloid() {
+        let mat = Material::default();
+        let tower = Hyperboloid::new(1., 1., -2., 2., 360., &mat);
+
+        // the waist at z = 0 and the flared top at z = 2
+        let hit = tower.get_intersection(&Ray::new(&glm::vec3(5., 0., 0.), &glm::vec3(-1., 0., 0.))).unwrap();
+        assert!((hit.distance - 4.).abs() < 1e-5);
+        assert_eq!(hit.normal, glm::vec3(1., 0., 0.));
+        assert!((hit.st.y - 0.5).abs() < 1e-5);
+        let top = tower.get_intersection(&Ray::new(&glm::vec3(0., 5., 1.9), &glm::vec3(0., -1., 0.))).unwrap();
+        assert!((top.coords.y - tower.radius_at(1.9)).abs() < 1e-4);
+        // flaring out the normal leans down at the top
+        assert!(top.normal.z < 0. && top.front_face);
+
+        // down the axis nothing is hit, out of the waist the inside is
+        assert!(tower.get_intersection(&Ray::new(&glm::vec3(0., 0., 5.), &glm::vec3(0., 0., -1.))).is_none());
+        let inside = tower.get_intersection(&Ray::new(&glm::vec3(0., 0., 0.), &glm::vec3(1., 0., 0.))).unwrap();
+        assert!(!inside.front_face);
+
+        let bounds = tower.get_bounds();
+        assert!((bounds.p_max.x - 5f32.sqrt()).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,59 @@
+use crate::transformed::TransformedObject;
+
+// [comment]
+// A placement of a shared mesh. The mesh and its bvh are only borrowed by the
+// TransformedObject, an instance adds nothing but the transform, so the scene bvh
+// over many instances acts as the top level of a two level acceleration structure.
+// [/comment]
+pub type Instance<'a> = TransformedObject<'a>;
+
+#[cfg(test)]
+mod tests {
+    use crate::bvh::{BVHAccel, SplitMethod};
+    use crate::instance::Instance;
+    use crate::material::Material;
+    use crate::object::ObjectTrait;
+    use crate::ray::Ray;
+    use crate::transform::Transform;
+    use crate::triangle::MeshTriangle;
+
+    fn unit_quad(mat: &Material) -> MeshTriangle<'_> {
+        MeshTriangle::new(
+            vec![
+                glm::vec3(-1., -1., 0.), glm::vec3(1., -1., 0.),
+                glm::vec3(1., 1., 0.), glm::vec3(-1., 1., 0.),
+            ],
+            vec![glm::vec2(0., 0.), glm::vec2(1., 0.), glm::vec2(1., 1.), glm::vec2(0., 1.)],
+            vec![0, 1, 2, 0, 2, 3],
+            mat
+        ).unwrap()
+    }
+
+    #[test]
+    fn test_instances_share_mesh() {
+        let mat = Material::default();
+        let quad = unit_quad(&mat);
+
+        let instances: Vec<Instance> = (0..4)
+            .map(|i| {
+                let m = glm::translation(&glm::vec3(i as f32 * 4., 0., -5.))
+                    * glm::scaling(&glm::vec3(1., 1., 1. + i as f32));
+                Instance::new(&quad, Transform::new(&m))
+            })
+            .collect();
+        let objects: Vec<&dyn ObjectTrait> = instances.iter().map(|i| i as &dyn ObjectTrait).collect();
+        let top = BVHAccel::new(&objects, 1, SplitMethod::SAH);
+
+        let b = instances[2].get_bounds();
+        assert!(glm::distance(&b.p_min, &glm::vec3(7., -1., -5.)) < 1e-5);
+
+        for i in 0..4 {
+            let ray = Ray::new(&glm::vec3(i as f32 * 4. + 0.5, 0.5, 0.), &glm::vec3(0., 0., -1.));
+            let inter = top.get_intersection(&ray, |p, r| objects[p].get_intersection(r)).unwrap();
+            assert!((inter.distance - 5.).abs() < 1e-4, "{}", inter.distance);
+        }
+
+        let miss = Ray::new(&glm::vec3(2., 0., 0.), &glm::vec3(0., 0., -1.));
+        assert!(top.get_intersection(&miss, |p, r| objects[p].get_intersection(r)).is_none());
+    }
+}
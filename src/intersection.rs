@@ -1,11 +1,23 @@
 use crate::material::Material;
 
 pub struct IntersectData<'a> {
+    // world space hit position
     pub coords: glm::Vec3,
     pub distance: f32,
     pub index: u32,
+    // unit geometric normal, for triangles it follows the winding of the vertices
     pub normal: glm::Vec3,
+    // unit normal used for lighting, interpolated on smooth meshes
+    pub shading_normal: glm::Vec3,
+    // conservative bound on the absolute rounding error of coords per axis
+    pub p_error: glm::Vec3,
+    // weights of the three triangle vertices, zero for other shapes
+    pub barycentric: glm::Vec3,
+    // true when the ray arrives on the side the normal points to
+    pub front_face: bool,
+    // triangle: weights of v1 and v2, the same as barycentric.yz
     pub uv: glm::Vec2,
+    // texture coordinates interpolated at the hit
     pub st: glm::Vec2,
     pub m: &'a Material,
     pub eval_diffuse_color: glm::Vec3,
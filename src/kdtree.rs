@@ -0,0 +1,339 @@
+use crate::accelerator::Accelerator;
+use crate::bounds3::Bounds3;
+use crate::bvh::{count_node_visit, count_primitive_test};
+use crate::intersection::IntersectData;
+use crate::ray::Ray;
+
+// sah constants, a primitive test is 80 times as expensive as a traversal step
+const ISECT_COST: f32 = 80.0;
+const TRAVERSAL_COST: f32 = 1.0;
+// preference for splits that cut off empty space
+const EMPTY_BONUS: f32 = 0.5;
+
+pub enum KdNode {
+    // prims[first..first + count] of KdTreeAccel::prims
+    Leaf { first: u32, count: u32 },
+    // below child directly follows its parent, the above child is stored by index
+    Interior { axis: u8, split: f32, above_child: u32 },
+}
+
+#[derive(Copy, Clone)]
+struct BoundEdge {
+    t: f32,
+    prim: u32,
+    start: bool,
+}
+
+// [comment]
+// kd-tree with splits chosen by the surface area heuristic over all primitive
+// bound edges, nodes are kept in depth first order in a flat vector.
+// [/comment]
+pub struct KdTreeAccel {
+    pub bounds: Bounds3,
+    pub nodes: Vec<KdNode>,
+    pub prims: Vec<u32>,
+    pub max_prims: u32,
+    pub max_depth: u32,
+}
+
+impl KdTreeAccel {
+    pub fn new(prim_bounds: Vec<Bounds3>, max_prims: u32) -> KdTreeAccel {
+        let mut bounds = Bounds3::default();
+        for b in &prim_bounds {
+            bounds = Bounds3::union(&bounds, b);
+        }
+        let n = prim_bounds.len().max(1) as f32;
+        let mut kd = KdTreeAccel {
+            bounds: bounds.clone(),
+            nodes: Vec::new(),
+            prims: Vec::new(),
+            max_prims: max_prims.max(1),
+            max_depth: (8.0 + 1.3 * n.log2()).round() as u32,
+        };
+        let prims: Vec<u32> = (0..prim_bounds.len() as u32).collect();
+        kd.build_node(&bounds, &prim_bounds, prims, kd.max_depth, 0);
+        return kd;
+    }
+
+    fn make_leaf(&mut self, prims: &[u32]) {
+        self.nodes.push(KdNode::Leaf {
+            first: self.prims.len() as u32,
+            count: prims.len() as u32,
+        });
+        self.prims.extend_from_slice(prims);
+    }
+
+    fn build_node(&mut self, node_bounds: &Bounds3, all_bounds: &[Bounds3],
+                  prims: Vec<u32>, depth: u32, mut bad_refines: u32) {
+        if prims.len() <= self.max_prims as usize || depth == 0 {
+            self.make_leaf(&prims);
+            return;
+        }
+
+        let d = node_bounds.diagonal();
+        let total_sa = node_bounds.surface_area();
+        let inv_total_sa = if total_sa > 0. { 1.0 / total_sa } else { 0. };
+        let old_cost = ISECT_COST * prims.len() as f32;
+
+        let mut best_axis: Option<usize> = None;
+        let mut best_offset = 0usize;
+        let mut best_cost = f32::INFINITY;
+        let mut best_edges: Vec<BoundEdge> = Vec::new();
+        let mut axis = node_bounds.max_extent();
+        for _retry in 0..3 {
+            let mut edges = Vec::with_capacity(prims.len() * 2);
+            for &p in &prims {
+                let b = &all_bounds[p as usize];
+                edges.push(BoundEdge { t: b.p_min[axis], prim: p, start: true });
+                edges.push(BoundEdge { t: b.p_max[axis], prim: p, start: false });
+            }
+            // starts before ends at the same position
+            edges.sort_by(|a, b| {
+                a.t.total_cmp(&b.t).then(b.start.cmp(&a.start))
+            });
+
+            let (o0, o1) = ((axis + 1) % 3, (axis + 2) % 3);
+            let mut n_below = 0usize;
+            let mut n_above = prims.len();
+            for (i, e) in edges.iter().enumerate() {
+                if !e.start {
+                    n_above -= 1;
+                }
+                if e.t > node_bounds.p_min[axis] && e.t < node_bounds.p_max[axis] {
+                    let below_sa = 2.0 * (d[o0] * d[o1] + (e.t - node_bounds.p_min[axis]) * (d[o0] + d[o1]));
+                    let above_sa = 2.0 * (d[o0] * d[o1] + (node_bounds.p_max[axis] - e.t) * (d[o0] + d[o1]));
+                    let p_below = below_sa * inv_total_sa;
+                    let p_above = above_sa * inv_total_sa;
+                    let eb = if n_above == 0 || n_below == 0 { EMPTY_BONUS } else { 0. };
+                    let cost = TRAVERSAL_COST
+                        + ISECT_COST * (1.0 - eb) * (p_below * n_below as f32 + p_above * n_above as f32);
+                    if cost < best_cost {
+                        best_cost = cost;
+                        best_axis = Some(axis);
+                        best_offset = i;
+                    }
+                }
+                if e.start {
+                    n_below += 1;
+                }
+            }
+
+            if best_axis == Some(axis) {
+                best_edges = edges;
+                break;
+            }
+            axis = (axis + 1) % 3;
+        }
+
+        if best_cost > old_cost {
+            bad_refines += 1;
+        }
+        let axis = match best_axis {
+            Some(a) if !((best_cost > 4.0 * old_cost && prims.len() < 16) || bad_refines == 3) => a,
+            _ => {
+                self.make_leaf(&prims);
+                return;
+            }
+        };
+
+        let mut prims0 = Vec::new();
+        let mut prims1 = Vec::new();
+        for e in &best_edges[..best_offset] {
+            if e.start {
+                prims0.push(e.prim);
+            }
+        }
+        for e in &best_edges[best_offset + 1..] {
+            if !e.start {
+                prims1.push(e.prim);
+            }
+        }
+        let split = best_edges[best_offset].t;
+        let mut bounds0 = node_bounds.clone();
+        let mut bounds1 = node_bounds.clone();
+        bounds0.p_max[axis] = split;
+        bounds1.p_min[axis] = split;
+
+        let node_index = self.nodes.len();
+        self.nodes.push(KdNode::Interior { axis: axis as u8, split, above_child: 0 });
+        self.build_node(&bounds0, all_bounds, prims0, depth - 1, bad_refines);
+        let above = self.nodes.len() as u32;
+        if let KdNode::Interior { above_child, .. } = &mut self.nodes[node_index] {
+            *above_child = above;
+        }
+        self.build_node(&bounds1, all_bounds, prims1, depth - 1, bad_refines);
+    }
+}
+
+impl Accelerator for KdTreeAccel {
+    fn get_bounds(&self) -> Bounds3 {
+        return self.bounds.clone();
+    }
+
+    // [comment]
+    // Front to back traversal with an explicit stack of (node, t_min, t_max), stops
+    // once the nearest hit lies before the next pending interval.
+    // [/comment]
+    fn get_intersection<'b>(
+        &self,
+        ray: &Ray,
+        intersect: &dyn Fn(usize, &Ray) -> Option<IntersectData<'b>>
+    ) -> Option<IntersectData<'b>> {
+        if self.prims.is_empty() {
+            return None;
+        }
+        let (mut t_min, mut t_max) = self.bounds.ray_interval(ray)?;
+
+        let mut stack: Vec<(usize, f32, f32)> = Vec::with_capacity(64);
+        // t_max shrinks to the nearest hit so far
+        let mut ray = ray.clone();
+        let mut nearest: Option<IntersectData<'b>> = None;
+        let mut node = 0usize;
+        loop {
+            if let Some(data) = &nearest {
+                if data.distance < t_min {
+                    break;
+                }
+            }
+            count_node_visit();
+            match &self.nodes[node] {
+                KdNode::Interior { axis, split, above_child } => {
+                    let axis = *axis as usize;
+                    let t_plane = (split - ray.origin[axis]) * ray.direction_inv[axis];
+                    let below_first = ray.origin[axis] < *split
+                        || (ray.origin[axis] == *split && ray.direction[axis] <= 0.);
+                    let (first, second) = if below_first {
+                        (node + 1, *above_child as usize)
+                    } else {
+                        (*above_child as usize, node + 1)
+                    };
+                    if t_plane > t_max || t_plane <= 0. {
+                        node = first;
+                    } else if t_plane < t_min {
+                        node = second;
+                    } else {
+                        stack.push((second, t_plane, t_max));
+                        node = first;
+                        t_max = t_plane;
+                    }
+                    continue;
+                }
+                KdNode::Leaf { first, count } => {
+                    for &prim in &self.prims[*first as usize..(*first + *count) as usize] {
+                        count_primitive_test();
+                        if let Some(data) = intersect(prim as usize, &ray) {
+                            if nearest.is_none() || data.distance < nearest.as_ref().unwrap().distance {
+                                ray.t_max = data.distance;
+                                nearest = Some(data);
+                            }
+                        }
+                    }
+                }
+            }
+            match stack.pop() {
+                Some((n, t0, t1)) => {
+                    node = n;
+                    t_min = t0;
+                    t_max = t1;
+                }
+                None => break,
+            }
+        }
+        return nearest;
+    }
+
+    // same walk as get_intersection limited to t_max, in no particular order, and
+    // it ends at the first primitive that blocks the ray
+    fn occluded(&self, ray: &Ray, t_max: f32, occluded: &dyn Fn(usize, &Ray, f32) -> bool) -> bool {
+        if self.prims.is_empty() {
+            return false;
+        }
+        let (mut t_min, mut t_far) = match self.bounds.ray_interval(ray) {
+            Some(interval) => interval,
+            None => return false,
+        };
+        if t_min > t_max {
+            return false;
+        }
+        t_far = t_far.min(t_max);
+
+        let mut stack: Vec<(usize, f32, f32)> = Vec::with_capacity(64);
+        let mut node = 0usize;
+        loop {
+            count_node_visit();
+            match &self.nodes[node] {
+                KdNode::Interior { axis, split, above_child } => {
+                    let axis = *axis as usize;
+                    let t_plane = (split - ray.origin[axis]) * ray.direction_inv[axis];
+                    let below_first = ray.origin[axis] < *split
+                        || (ray.origin[axis] == *split && ray.direction[axis] <= 0.);
+                    let (first, second) = if below_first {
+                        (node + 1, *above_child as usize)
+                    } else {
+                        (*above_child as usize, node + 1)
+                    };
+                    if t_plane > t_far || t_plane <= 0. {
+                        node = first;
+                    } else if t_plane < t_min {
+                        node = second;
+                    } else {
+                        stack.push((second, t_plane, t_far));
+                        node = first;
+                        t_far = t_plane;
+                    }
+                    continue;
+                }
+                KdNode::Leaf { first, count } => {
+                    for &prim in &self.prims[*first as usize..(*first + *count) as usize] {
+                        count_primitive_test();
+                        if occluded(prim as usize, ray, t_max) {
+                            return true;
+                        }
+                    }
+                }
+            }
+            match stack.pop() {
+                Some((n, t0, t1)) => {
+                    node = n;
+                    t_min = t0;
+                    t_far = t1;
+                }
+                None => return false,
+            }
+        }
+    }
+
+    fn refit(&mut self, bounds: &[Bounds3]) {
+        *self = KdTreeAccel::new(bounds.to_vec(), self.max_prims);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bounds3::Bounds3;
+    use crate::kdtree::{KdNode, KdTreeAccel};
+
+    #[test]
+    fn test_kdtree_splits_clusters() {
+        // two clusters far apart, the root must separate them with an empty gap
+        let mut bounds = Vec::new();
+        for i in 0..20 {
+            let x = if i < 10 { i as f32 * 0.1 } else { 100. + i as f32 * 0.1 };
+            let p = glm::vec3(x, 0., 0.);
+            bounds.push(Bounds3::new(&p, &(p + glm::vec3(0.05, 1., 1.))));
+        }
+        let kd = KdTreeAccel::new(bounds, 1);
+        match &kd.nodes[0] {
+            KdNode::Interior { axis, split, .. } => {
+                assert_eq!(*axis, 0);
+                assert!(*split >= 0.95 && *split <= 101., "{}", split);
+            }
+            _ => panic!("root is a leaf"),
+        }
+        // every primitive is referenced by at least one leaf
+        let mut seen = kd.prims.clone();
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), 20);
+    }
+}
@@ -1,7 +1,23 @@
+// functions end in an explicit return throughout the crate
+#![allow(clippy::needless_return)]
+
 mod global;
+mod error;
 mod object;
 mod intersection;
 mod sphere;
+mod plane;
+mod disk;
+mod rectangle;
+mod axis_box;
+mod cylinder;
+mod cone;
+mod paraboloid;
+mod hyperboloid;
+mod torus;
+mod sdf;
+mod csg;
+mod demo;
 mod triangle;
 mod scene;
 mod light;
@@ -11,10 +27,28 @@ mod area_light;
 mod bounds3;
 mod ray;
 mod bvh;
+mod bvh_cache;
+mod bvh_stats;
+mod transform;
+mod instance;
+mod transformed;
+mod accelerator;
+mod grid;
+mod kdtree;
+mod wide_bvh;
+mod packet;
+mod obj_loader;
+mod texture;
+mod gltf_loader;
+mod ply_loader;
+mod stl_loader;
+mod mesh_validation;
+mod pbrt_loader;
 
 extern crate nalgebra_glm as glm;
 extern crate image;
 extern crate obj as obj_rs;
+extern crate rayon;
 
 use object::ObjectTrait;
 use render::RenderTrait;
@@ -23,34 +57,377 @@ const SCALE     :i32 = 10;
 const WIDTH     :i32 = 128i32   * SCALE;
 const HEIGHT    :i32 = 96i32    * SCALE;
 
+const USAGE: &str = "usage: game101_5 [--bvh-stats] [--split naive|sah] [--heatmap boxes|prims|total]\n                 [--accel bvh|bvh4|bvh8|grid|kdtree] [--crease-angle <degrees>]\n                 [--weld-tolerance <distance>] [--scene <file.gltf|file.glb|file.pbrt|file.obj|file.ply|file.stl>]\n                 [--normalize-lights] [--demo]";
+
+struct Options {
+    // print bvh statistics and per ray traversal counts
+    bvh_stats: bool,
+    split_method: bvh::SplitMethod,
+    // render traversal cost instead of shading
+    heatmap: Option<render::HeatmapMetric>,
+    accelerator_type: accelerator::AcceleratorType,
+    // faces meeting at a larger angle are not smoothed into each other
+    crease_angle: f32,
+    // vertices of mesh files and imported scenes closer than this are welded on load
+    weld_tolerance: f32,
+    // render this file instead of the bunny
+    scene: Option<std::path::PathBuf>,
+    // scale the lights of an imported scene to sum up to 1
+    normalize_lights: bool,
+    // put the scene in a room lit by an area light
+    demo: bool,
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        bvh_stats: false,
+        split_method: bvh::SplitMethod::SAH,
+        heatmap: None,
+        accelerator_type: accelerator::AcceleratorType::BVH,
+        crease_angle: 180.,
+        weld_tolerance: 0.,
+        scene: None,
+        normalize_lights: false,
+        demo: false,
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bvh-stats" => options.bvh_stats = true,
+            "--normalize-lights" => options.normalize_lights = true,
+            "--demo" => options.demo = true,
+            "--split" => {
+                options.split_method = match args.next().as_deref() {
+                    Some("naive") => bvh::SplitMethod::NAIVE,
+                    Some("sah") => bvh::SplitMethod::SAH,
+                    _ => {
+                        println!("{}", USAGE);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--heatmap" => {
+                options.heatmap = match args.next().as_deref() {
+                    Some("boxes") => Some(render::HeatmapMetric::BoxTests),
+                    Some("prims") => Some(render::HeatmapMetric::PrimitiveTests),
+                    Some("total") => Some(render::HeatmapMetric::Total),
+                    _ => {
+                        println!("{}", USAGE);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--accel" => {
+                options.accelerator_type = match args.next().as_deref() {
+                    Some("bvh") => accelerator::AcceleratorType::BVH,
+                    Some("bvh4") => accelerator::AcceleratorType::BVH4,
+                    Some("bvh8") => accelerator::AcceleratorType::BVH8,
+                    Some("grid") => accelerator::AcceleratorType::UniformGrid,
+                    Some("kdtree") => accelerator::AcceleratorType::KdTree,
+                    _ => {
+                        println!("{}", USAGE);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--crease-angle" => {
+                options.crease_angle = match args.next().and_then(|a| a.parse::<f32>().ok()) {
+                    Some(angle) if angle >= 0. => angle,
+                    _ => {
+                        println!("{}", USAGE);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--weld-tolerance" => {
+                options.weld_tolerance = match args.next().and_then(|a| a.parse::<f32>().ok()) {
+                    Some(distance) if distance >= 0. => distance,
+                    _ => {
+                        println!("{}", USAGE);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            "--scene" => {
+                options.scene = match args.next() {
+                    Some(path) => Some(std::path::PathBuf::from(path)),
+                    None => {
+                        println!("{}", USAGE);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            _ => {
+                println!("{}", USAGE);
+                std::process::exit(1);
+            }
+        }
+    }
+    return options;
+}
+
+// print what failed and why, then quit with a non zero status
+fn or_exit<T>(result: error::Result<T>, context: &str) -> T {
+    return match result {
+        Ok(value) => value,
+        Err(err) => {
+            println!("{}: {}", context, err);
+            std::process::exit(1);
+        }
+    };
+}
+
 fn main() {
+    let options = parse_args();
     let mut scene = scene::Scene::new(WIDTH, HEIGHT);
+    scene.split_method = options.split_method;
+    scene.accelerator_type = options.accelerator_type;
 
 
-    let bunny_mat = material::Material::default();
-    let bunny_data = global::load_mesh("../res/models/bunny.obj".to_string()).unwrap();
-    let mut bunny_obj = 
-        triangle::MeshTriangle::new(
-            bunny_data.0, 
-            bunny_data.1, 
-            bunny_data.2, 
-            &bunny_mat
-        );
+    let is_gltf = options.scene.as_ref().is_some_and(|path| {
+        matches!(path.extension().and_then(|e| e.to_str()), Some("gltf") | Some("glb"))
+    });
+    let imported = options.scene.as_ref().filter(|_| is_gltf).map(|path| {
+        let mut imported = or_exit(gltf_loader::load_gltf(path), &format!("failed to load {}", path.display()));
+        if options.normalize_lights {
+            imported.normalize_lights();
+        }
+        imported
+    });
+    let mut imported_meshes = match &imported {
+        Some(imported) => or_exit(imported.mesh_triangles(options.crease_angle, options.weld_tolerance), "failed to build mesh"),
+        None => Vec::new(),
+    };
+    for mesh in imported_meshes.iter_mut() {
+        mesh.split_method = options.split_method;
+        mesh.accelerator_type = options.accelerator_type;
+        or_exit(mesh.build(), "failed to build mesh");
+    }
+    let imported_instances = match &imported {
+        Some(imported) => imported.mesh_instances(&imported_meshes),
+        None => Vec::new(),
+    };
+    for instance in imported_instances.iter() {
+        scene.add_object(instance as &dyn ObjectTrait);
+    }
 
-    scene.add_object(&bunny_obj as &dyn ObjectTrait);
+    let is_pbrt = options.scene.as_ref().is_some_and(|path| {
+        path.extension().and_then(|e| e.to_str()) == Some("pbrt")
+    });
+    let pbrt = options.scene.as_ref().filter(|_| is_pbrt).map(|path| {
+        let mut pbrt = or_exit(pbrt_loader::load_pbrt(path), &format!("failed to load {}", path.display()));
+        if options.normalize_lights {
+            pbrt.normalize_lights();
+        }
+        pbrt
+    });
+    let mut pbrt_meshes = match &pbrt {
+        Some(pbrt) => or_exit(pbrt.mesh_triangles(options.crease_angle, options.weld_tolerance), "failed to build mesh"),
+        None => Vec::new(),
+    };
+    for mesh in pbrt_meshes.iter_mut() {
+        mesh.split_method = options.split_method;
+        mesh.accelerator_type = options.accelerator_type;
+        or_exit(mesh.build(), "failed to build mesh");
+    }
+    let pbrt_shapes = match &pbrt {
+        Some(pbrt) => pbrt.shape_objects(),
+        None => Vec::new(),
+    };
+    let pbrt_instances = match &pbrt {
+        Some(pbrt) => pbrt.shape_instances(&pbrt_shapes),
+        None => Vec::new(),
+    };
+    for mesh in pbrt_meshes.iter() {
+        scene.add_object(mesh as &dyn ObjectTrait);
+    }
+    for instance in pbrt_instances.iter() {
+        scene.add_object(instance as &dyn ObjectTrait);
+    }
+
+    let is_obj = options.scene.as_ref().is_some_and(|path| {
+        path.extension().and_then(|e| e.to_str()) == Some("obj")
+    });
+    let obj_scene = options.scene.as_ref().filter(|_| is_obj).map(|path| {
+        or_exit(obj_loader::load_obj(path), &format!("failed to load {}", path.display()))
+    });
+    let mut obj_meshes = match &obj_scene {
+        Some(obj_scene) => or_exit(obj_scene.mesh_triangles(options.crease_angle), "failed to build mesh"),
+        None => Vec::new(),
+    };
+    for mesh in obj_meshes.iter_mut() {
+        mesh.split_method = options.split_method;
+        mesh.accelerator_type = options.accelerator_type;
+        or_exit(mesh.build(), "failed to build mesh");
+    }
+    for mesh in obj_meshes.iter() {
+        scene.add_object(mesh as &dyn ObjectTrait);
+    }
 
+    // any other scene file is a single mesh shown in place of the bunny
+    let mesh_mat = material::Material::default();
+    let mesh_obj = match &options.scene {
+        Some(_) if is_gltf || is_pbrt || is_obj => None,
+        Some(path) => {
+            let mut data = or_exit(
+                global::load_mesh(path.to_string_lossy().to_string()),
+                &format!("failed to load {}", path.display())
+            );
+            let report = mesh_validation::repair_mesh(&mut data, options.weld_tolerance);
+            if !report.is_clean() {
+                println!("repaired {}: {}", path.display(), report);
+            }
+            let mut mesh_obj = or_exit(
+                triangle::MeshTriangle::from_loaded(data, &mesh_mat, options.crease_angle),
+                "failed to build mesh"
+            );
+            mesh_obj.mesh_data.checker_pattern = false;
+            mesh_obj.split_method = options.split_method;
+            mesh_obj.accelerator_type = options.accelerator_type;
+            or_exit(mesh_obj.build(), "failed to build mesh");
+            Some(mesh_obj)
+        }
+        None => {
+            let bunny_path = "../res/models/bunny.obj".to_string();
+            let mut bunny_data = or_exit(global::load_mesh(bunny_path.clone()), &format!("failed to load {}", bunny_path));
+            let report = mesh_validation::repair_mesh(&mut bunny_data, options.weld_tolerance);
+            if !report.is_clean() {
+                println!("repaired {}: {}", bunny_path, report);
+            }
+            let mut bunny_obj = or_exit(
+                triangle::MeshTriangle::from_loaded(bunny_data, &mesh_mat, options.crease_angle),
+                "failed to build mesh"
+            );
+            bunny_obj.split_method = options.split_method;
+            bunny_obj.accelerator_type = options.accelerator_type;
+            or_exit(bunny_obj.build_cached(std::path::Path::new("bunny.bvh")), "failed to build mesh");
+            Some(bunny_obj)
+        }
+    };
+    if let Some(mesh_obj) = &mesh_obj {
+        scene.add_object(mesh_obj as &dyn ObjectTrait);
+    }
 
-    let l1 = light::Light {
-        position: glm::vec3(-20., 70., 20.),
-        intensity: glm::vec3(0.5, 0.5, 0.5),
+    let demo = if options.demo { Some(demo::DemoScene::new()) } else { None };
+    let demo_objects = match &demo {
+        Some(demo) => demo.objects(),
+        None => Vec::new(),
+    };
+    let demo_emitters = match &demo {
+        Some(demo) => demo.emitters(),
+        None => Vec::new(),
+    };
+    let demo_shapes = match &demo {
+        Some(demo) => demo.shapes(),
+        None => Vec::new(),
+    };
+    let demo_instances = match &demo {
+        Some(demo) => demo.shape_instances(&demo_shapes),
+        None => Vec::new(),
     };
-    let l2 = light::Light {
-        position: glm::vec3(30., 50., -12.),
-        intensity: glm::vec3(0.5, 0.5, 0.5),
+    let demo_solids = match &demo {
+        Some(demo) => demo.solids(),
+        None => Vec::new(),
+    };
+    let demo_csgs = match &demo {
+        Some(demo) => demo.csg_objects(&demo_solids),
+        None => Vec::new(),
+    };
+    for object in demo_objects.iter() {
+        scene.add_object(object.as_ref());
+    }
+    for csg in demo_csgs.iter() {
+        scene.add_object(csg as &dyn ObjectTrait);
+    }
+    for instance in demo_instances.iter() {
+        scene.add_object(instance as &dyn ObjectTrait);
+    }
+    if let Some(demo) = &demo {
+        for emitter in demo_emitters.iter() {
+            scene.add_object(emitter.as_ref() as &dyn ObjectTrait);
+            scene.add_area_light(area_light::AreaLight::new(emitter.as_ref(), &demo.light_intensity));
+        }
+    }
+
+    match (&imported, &pbrt) {
+        (Some(imported), _) => {
+            for light in imported.lights.iter() {
+                scene.add_light(light::Light::new(&light.position, &light.intensity));
+            }
+            if let Some(camera) = imported.cameras.first() {
+                scene.camera_to_world = camera.camera_to_world;
+                scene.fov = camera.fov;
+            }
+        }
+        (_, Some(pbrt)) => {
+            for warning in pbrt.warnings.iter() {
+                println!("warning: {}", warning);
+            }
+            for light in pbrt.lights.iter() {
+                scene.add_light(light::Light::new(&light.position, &light.intensity));
+            }
+            scene.camera_to_world = pbrt.camera_to_world;
+            scene.fov = pbrt.fov;
+            scene.width = pbrt.width.unwrap_or(scene.width);
+            scene.height = pbrt.height.unwrap_or(scene.height);
+            if let Some(background_color) = pbrt.background_color {
+                scene.background_color = background_color;
+            }
+        }
+        // the demo room is lit by its area light only
+        (None, None) if demo.is_some() => {}
+        (None, None) => {
+            let l1 = light::Light {
+                position: glm::vec3(-20., 70., 20.),
+                intensity: glm::vec3(0.5, 0.5, 0.5),
+            };
+            let l2 = light::Light {
+                position: glm::vec3(30., 50., -12.),
+                intensity: glm::vec3(0.5, 0.5, 0.5),
+            };
+            scene.add_light(l1);
+            scene.add_light(l2);
+        }
+    }
+
+    or_exit(scene.build_accelerator(), "failed to build scene");
+
+    if options.bvh_stats {
+        if let Some(bvh) = mesh_obj.as_ref().and_then(|m| m.accelerator.as_ref()?.as_bvh()) {
+            println!("== mesh bvh\n{}", bvh_stats::BVHStats::new(bvh));
+        }
+        if let Some(imported) = &imported {
+            for (gltf_mesh, mesh) in imported.meshes.iter().zip(imported_meshes.iter()) {
+                if let Some(bvh) = mesh.accelerator.as_ref().and_then(|a| a.as_bvh()) {
+                    println!("== {} bvh\n{}", gltf_mesh.name, bvh_stats::BVHStats::new(bvh));
+                }
+            }
+        }
+        if let Some(obj_scene) = &obj_scene {
+            for (obj, mesh) in obj_scene.meshes.iter().zip(obj_meshes.iter()) {
+                if let Some(bvh) = mesh.accelerator.as_ref().and_then(|a| a.as_bvh()) {
+                    println!("== {} bvh\n{}", obj_scene.describe_mesh(obj), bvh_stats::BVHStats::new(bvh));
+                }
+            }
+        }
+        if let Some(bvh) = scene.accelerator.as_ref().and_then(|a| a.as_bvh()) {
+            println!("== scene bvh\n{}", bvh_stats::BVHStats::new(bvh));
+        }
+    }
+    let render_start = std::time::Instant::now();
+
+    if options.bvh_stats {
+        bvh::enable_traversal_stats();
+    }
+    bvh::reset_traversal_stats();
+    let rendered = match options.heatmap {
+        Some(metric) => render::HeatmapRenderer::new(metric).render(&scene),
+        None => render::Renderer{}.render(&scene),
     };
-    scene.add_light(l1);
-    scene.add_light(l2);
+    or_exit(rendered, "failed to render");
 
-    let r = render::Renderer{};
-    r.render(&scene);
+    if options.bvh_stats {
+        println!("== traversal\n{}", bvh::traversal_stats());
+        println!("render time          : {:.3}s", render_start.elapsed().as_secs_f32());
+    }
 }
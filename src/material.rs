@@ -1,3 +1,6 @@
+use crate::texture::Texture;
+use std::sync::Arc;
+
 
 #[derive(Copy, Clone)]
 pub enum MaterialType{
@@ -14,7 +17,9 @@ pub struct Material {
     pub ior: f32,
     pub Kd: f32,
     pub Ks: f32,
-    pub specular_exponent: f32
+    pub specular_exponent: f32,
+    // multiplies m_color where set, shared between materials of an imported scene
+    pub diffuse_texture: Option<Arc<Texture>>,
 }
 
 impl Default for Material {
@@ -22,11 +27,13 @@ impl Default for Material {
         Material {
             m_type: MaterialType::INVALID,
             m_color: glm::vec3(1.0, 1.0, 1.0),
-            m_emission: glm::vec3(1.0, 1.0, 1.0),
+            // emission is shaded, so only emitters set it, as with Material::new
+            m_emission: glm::vec3(0.0, 0.0, 0.0),
             ior: 1.0,
             Kd: 1.0,
             Ks: 1.0,
             specular_exponent: 150.0,
+            diffuse_texture: None,
         }
     }
 }
@@ -41,6 +48,7 @@ impl Material {
             Kd: 1.0,
             Ks: 1.0,
             specular_exponent: 150.0,
+            diffuse_texture: None,
         }
     }
 
@@ -50,6 +58,14 @@ impl Material {
 
     pub fn get_emission(&self) -> glm::Vec3 { return self.m_emission.clone(); }
 
+    // diffuse color at texture coordinates st, the texture times m_color when set
+    pub fn eval_diffuse_color(&self, st: &glm::Vec2) -> glm::Vec3 {
+        return match &self.diffuse_texture {
+            Some(texture) => texture.sample(st).component_mul(&self.m_color),
+            None => self.get_color(),
+        };
+    }
+
     pub fn get_color_at(u: f32, v: f32) -> glm::Vec3{
         return glm::zero();
     }
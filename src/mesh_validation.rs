@@ -0,0 +1,237 @@
+use crate::global::LoadedMesh;
+use std::collections::HashMap;
+use std::fmt;
+
+// [comment]
+// What repair_mesh fixed in a mesh. vertices and triangles are the counts of the
+// repaired mesh.
+// [/comment]
+#[derive(Default, Debug, PartialEq)]
+pub struct MeshReport {
+    pub vertices: usize,
+    pub triangles: usize,
+    // indices after the last multiple of three
+    pub trailing_indices: usize,
+    pub non_finite_vertices: usize,
+    // triangles with an index past the vertices or a vertex that is not finite
+    pub invalid_triangles: usize,
+    pub welded_vertices: usize,
+    pub degenerate_triangles: usize,
+    pub unused_vertices: usize,
+}
+
+impl MeshReport {
+    pub fn is_clean(&self) -> bool {
+        return self.trailing_indices == 0
+            && self.non_finite_vertices == 0
+            && self.invalid_triangles == 0
+            && self.welded_vertices == 0
+            && self.degenerate_triangles == 0
+            && self.unused_vertices == 0;
+    }
+}
+
+impl fmt::Display for MeshReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} vertices, {} triangles", self.vertices, self.triangles)?;
+        let problems = [
+            (self.trailing_indices, "trailing indices"),
+            (self.non_finite_vertices, "vertices that are not finite"),
+            (self.invalid_triangles, "invalid triangles"),
+            (self.welded_vertices, "welded vertices"),
+            (self.degenerate_triangles, "degenerate triangles"),
+            (self.unused_vertices, "unused vertices"),
+        ];
+        for (count, what) in problems.iter().filter(|p| p.0 > 0) {
+            write!(f, ", {} {}", count, what)?;
+        }
+        return Ok(());
+    }
+}
+
+fn close(a: &[f32], b: &[f32], tolerance: f32) -> bool {
+    return a.iter().zip(b.iter()).all(|(x, y)| (x - y).abs() <= tolerance);
+}
+
+// [comment]
+// Index of the vertex every vertex is welded to. Vertices are welded when their
+// positions are within tolerance and their texture coordinates, normals and colors
+// agree as well, so seams of the texture or of hard edges stay open. Positions are
+// hashed to cells of the tolerance size and the neighbouring cells are searched.
+// [/comment]
+fn weld_map(mesh: &LoadedMesh, finite: &[bool], tolerance: f32) -> Vec<u32> {
+    let n = mesh.vertices.len();
+    let mut map: Vec<u32> = (0..n as u32).collect();
+    let cell_size = tolerance.max(f32::MIN_POSITIVE);
+    let cell = |p: &glm::Vec3| -> [i64; 3] {
+        if tolerance == 0. {
+            return [p.x.to_bits() as i64, p.y.to_bits() as i64, p.z.to_bits() as i64];
+        }
+        return [(p.x / cell_size).floor() as i64, (p.y / cell_size).floor() as i64, (p.z / cell_size).floor() as i64];
+    };
+    let same = |a: usize, b: usize| {
+        let (pa, pb) = (&mesh.vertices[a], &mesh.vertices[b]);
+        return glm::distance(pa, pb) <= tolerance
+            && mesh.st_coordinates.get(a).is_none_or(|st| close(st.as_slice(), mesh.st_coordinates[b].as_slice(), tolerance))
+            && mesh.normals.get(a).is_none_or(|nm| close(nm.as_slice(), mesh.normals[b].as_slice(), tolerance))
+            && mesh.colors.get(a).is_none_or(|c| close(c.as_slice(), mesh.colors[b].as_slice(), tolerance));
+    };
+
+    let mut cells: HashMap<[i64; 3], Vec<u32>> = HashMap::new();
+    let reach = if tolerance == 0. { 0 } else { 1 };
+    for v in 0..n {
+        if !finite[v] {
+            continue;
+        }
+        let c = cell(&mesh.vertices[v]);
+        let mut found = None;
+        'search: for dx in -reach..=reach {
+            for dy in -reach..=reach {
+                for dz in -reach..=reach {
+                    if let Some(candidates) = cells.get(&[c[0] + dx, c[1] + dy, c[2] + dz]) {
+                        if let Some(&w) = candidates.iter().find(|&&w| same(v, w as usize)) {
+                            found = Some(w);
+                            break 'search;
+                        }
+                    }
+                }
+            }
+        }
+        match found {
+            Some(w) => map[v] = w,
+            None => cells.entry(c).or_default().push(v as u32),
+        }
+    }
+    return map;
+}
+
+// twice the area is below what float precision can tell from zero
+fn is_degenerate(a: &glm::Vec3, b: &glm::Vec3, c: &glm::Vec3) -> bool {
+    let (e1, e2) = (b - a, c - a);
+    let longest2 = e1.norm_squared().max(e2.norm_squared()).max((c - b).norm_squared());
+    return glm::cross(&e1, &e2).norm() <= f32::EPSILON * longest2;
+}
+
+// per vertex values of the used vertices, empty attributes stay empty
+fn keep_used<T>(values: &mut Vec<T>, used: &[bool]) {
+    if values.len() == used.len() {
+        let mut k = 0;
+        values.retain(|_| {
+            k += 1;
+            return used[k - 1];
+        });
+    }
+}
+
+// [comment]
+// Repair a mesh in place: trailing indices are cut, triangles with out of range
+// indices or vertices that are not finite are dropped, vertices within
+// weld_tolerance are welded (0 welds exact duplicates only), triangles without
+// area are dropped and vertices no triangle uses are removed.
+// [/comment]
+pub fn repair_mesh(mesh: &mut LoadedMesh, weld_tolerance: f32) -> MeshReport {
+    let mut report = MeshReport::default();
+    let n = mesh.vertices.len();
+
+    report.trailing_indices = mesh.indices.len() % 3;
+    mesh.indices.truncate(mesh.indices.len() - report.trailing_indices);
+
+    let finite: Vec<bool> = mesh.vertices.iter().map(|v| v.iter().all(|c| c.is_finite())).collect();
+    report.non_finite_vertices = finite.iter().filter(|&&f| !f).count();
+
+    let map = weld_map(mesh, &finite, weld_tolerance);
+    let mut indices = Vec::with_capacity(mesh.indices.len());
+    for tri in mesh.indices.chunks_exact(3) {
+        if tri.iter().any(|&i| i as usize >= n || !finite[i as usize]) {
+            report.invalid_triangles += 1;
+            continue;
+        }
+        let welded = [map[tri[0] as usize], map[tri[1] as usize], map[tri[2] as usize]];
+        let [a, b, c] = welded.map(|i| mesh.vertices[i as usize]);
+        if welded[0] == welded[1] || welded[1] == welded[2] || welded[0] == welded[2] || is_degenerate(&a, &b, &c) {
+            report.degenerate_triangles += 1;
+            continue;
+        }
+        indices.extend_from_slice(&welded);
+    }
+
+    // compact the vertices the remaining triangles use, in their old order
+    let mut used = vec![false; n];
+    for &i in &indices {
+        used[i as usize] = true;
+    }
+    let mut new_index = vec![u32::MAX; n];
+    let mut kept = 0;
+    for v in 0..n {
+        if used[v] {
+            new_index[v] = kept;
+            kept += 1;
+        } else if finite[v] && map[v] != v as u32 {
+            report.welded_vertices += 1;
+        } else if finite[v] {
+            report.unused_vertices += 1;
+        }
+    }
+    keep_used(&mut mesh.vertices, &used);
+    keep_used(&mut mesh.st_coordinates, &used);
+    keep_used(&mut mesh.normals, &used);
+    keep_used(&mut mesh.colors, &used);
+    mesh.indices = indices.iter().map(|&i| new_index[i as usize]).collect();
+
+    report.vertices = mesh.vertices.len();
+    report.triangles = mesh.indices.len() / 3;
+    return report;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::global::LoadedMesh;
+    use crate::mesh_validation::{repair_mesh, MeshReport};
+
+    #[test]
+    fn test_repair_mesh() {
+        let mut mesh = LoadedMesh {
+            vertices: vec![
+                glm::vec3(0., 0., 0.), glm::vec3(1., 0., 0.), glm::vec3(1., 1., 0.),
+                // a near duplicate of vertex 0, a NaN and an unused vertex
+                glm::vec3(0., 0., 1e-5), glm::vec3(f32::NAN, 0., 0.), glm::vec3(5., 5., 5.),
+                glm::vec3(0., 1., 0.), glm::vec3(2., 0., 0.),
+            ],
+            st_coordinates: vec![glm::vec2(0., 0.); 8],
+            normals: Vec::new(),
+            colors: Vec::new(),
+            indices: vec![
+                0, 1, 2,
+                3, 2, 6,
+                // welded to a line, collinear, out of range and NaN
+                0, 3, 1,
+                0, 1, 7,
+                0, 1, 9,
+                4, 1, 2,
+                // trailing
+                0, 1,
+            ],
+        };
+
+        let expected = MeshReport {
+            vertices: 4,
+            triangles: 2,
+            trailing_indices: 2,
+            non_finite_vertices: 1,
+            invalid_triangles: 2,
+            welded_vertices: 1,
+            degenerate_triangles: 2,
+            unused_vertices: 2,
+        };
+        assert_eq!(repair_mesh(&mut mesh, 1e-4), expected);
+        assert_eq!(mesh.indices, vec![0, 1, 2, 0, 2, 3]);
+        assert_eq!(mesh.vertices[3], glm::vec3(0., 1., 0.));
+        assert_eq!(mesh.st_coordinates.len(), 4);
+        assert!(!expected.is_clean());
+
+        // a repaired mesh is clean, and without tolerance only exact duplicates weld
+        let clean = repair_mesh(&mut mesh, 0.);
+        assert!(clean.is_clean(), "{}", clean);
+        assert_eq!(clean.to_string(), "4 vertices, 2 triangles");
+    }
+}
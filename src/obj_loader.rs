@@ -0,0 +1,445 @@
+use crate::error::Result;
+use crate::material::{Material, MaterialType};
+use crate::triangle::MeshTriangle;
+use obj_rs::raw::material::MtlColor;
+use obj_rs::{LoadError, LoadErrorKind, ObjResult};
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::Path;
+
+// [comment]
+// One object or group of an obj file. Vertices are made for every distinct
+// position/texture/normal combination the faces use. Normals are per vertex and
+// left empty unless every vertex has one, material_ids holds an index into
+// ObjScene::materials for every triangle.
+// [/comment]
+pub struct ObjMesh {
+    pub name: String,
+    pub vertices: Vec<glm::Vec3>,
+    pub st_coordinates: Vec<glm::Vec2>,
+    pub normals: Vec<glm::Vec3>,
+    pub indices: Vec<u32>,
+    pub material_ids: Vec<u32>,
+}
+
+// materials[0] is the default material of faces without usemtl
+pub struct ObjScene {
+    pub meshes: Vec<ObjMesh>,
+    pub materials: Vec<Material>,
+    pub material_names: Vec<String>,
+}
+
+impl ObjScene {
+    // [comment]
+    // One unbuilt mesh per object or group, its triangles keep their mtl materials.
+    // Meshes without normals get smooth ones generated, set their accelerator type
+    // and call build before tracing rays.
+    // [/comment]
+    pub fn mesh_triangles(&self, crease_angle: f32) -> Result<Vec<MeshTriangle<'_>>> {
+        return self.meshes.iter().map(|mesh| {
+            let mut triangles = MeshTriangle::from_obj(mesh, &self.materials)?;
+            if mesh.normals.is_empty() {
+                triangles.generate_normals(crease_angle)?;
+            } else {
+                triangles.set_vertex_normals(&mesh.normals, crease_angle)?;
+            }
+            return Ok(triangles);
+        }).collect();
+    }
+
+    // mesh name followed by the names of the materials its triangles use
+    pub fn describe_mesh(&self, mesh: &ObjMesh) -> String {
+        let mut ids = mesh.material_ids.clone();
+        ids.sort_unstable();
+        ids.dedup();
+        let names: Vec<&str> = ids.iter().map(|&id| self.material_names[id as usize].as_str()).collect();
+        return format!("{} ({})", mesh.name, names.join(", "));
+    }
+}
+
+// corner of a face, (position, texture, normal) indices into the file lists
+type Corner = (usize, Option<usize>, Option<usize>);
+
+struct MeshBuilder {
+    mesh: ObjMesh,
+    vertex_map: HashMap<Corner, u32>,
+    all_normals: bool,
+}
+
+impl MeshBuilder {
+    fn new(name: String) -> MeshBuilder {
+        MeshBuilder {
+            mesh: ObjMesh {
+                name,
+                vertices: Vec::new(),
+                st_coordinates: Vec::new(),
+                normals: Vec::new(),
+                indices: Vec::new(),
+                material_ids: Vec::new(),
+            },
+            vertex_map: HashMap::new(),
+            all_normals: true,
+        }
+    }
+
+    fn vertex(&mut self, corner: Corner, positions: &[glm::Vec3], sts: &[glm::Vec2], normals: &[glm::Vec3]) -> u32 {
+        if let Some(&index) = self.vertex_map.get(&corner) {
+            return index;
+        }
+        let (p, t, n) = corner;
+        self.mesh.vertices.push(positions[p]);
+        self.mesh.st_coordinates.push(match t {
+            Some(t) => sts[t],
+            None => glm::vec2(0., 0.),
+        });
+        match n {
+            Some(n) => self.mesh.normals.push(normals[n]),
+            None => self.all_normals = false,
+        }
+        let index = (self.mesh.vertices.len() - 1) as u32;
+        self.vertex_map.insert(corner, index);
+        return index;
+    }
+
+    fn finish(mut self) -> ObjMesh {
+        if !self.all_normals {
+            self.mesh.normals.clear();
+        }
+        return self.mesh;
+    }
+}
+
+fn load_error(kind: LoadErrorKind, message: &'static str) -> obj_rs::ObjError {
+    return LoadError::new(kind, message).into();
+}
+
+fn parse_floats(args: &[&str], n: usize) -> ObjResult<Vec<f32>> {
+    if args.len() < n {
+        return Err(load_error(LoadErrorKind::WrongNumberOfArguments, "Expected more coordinates"));
+    }
+    let mut values = Vec::with_capacity(n);
+    for arg in &args[..n] {
+        values.push(arg.parse::<f32>()?);
+    }
+    return Ok(values);
+}
+
+// obj indices start at 1, negative ones count back from the last element read
+fn resolve_index(arg: &str, len: usize) -> ObjResult<usize> {
+    let i = arg.parse::<i64>()?;
+    let index = if i < 0 { len as i64 + i } else { i - 1 };
+    if index < 0 || index >= len as i64 {
+        return Err(load_error(LoadErrorKind::IndexOutOfRange, "Face index out of range"));
+    }
+    return Ok(index as usize);
+}
+
+fn parse_corner(arg: &str, n_positions: usize, n_sts: usize, n_normals: usize) -> ObjResult<Corner> {
+    let mut parts = arg.split('/');
+    let p = resolve_index(parts.next().unwrap_or(""), n_positions)?;
+    let t = match parts.next() {
+        Some(t) if !t.is_empty() => Some(resolve_index(t, n_sts)?),
+        _ => None,
+    };
+    let n = match parts.next() {
+        Some(n) if !n.is_empty() => Some(resolve_index(n, n_normals)?),
+        _ => None,
+    };
+    return Ok((p, t, n));
+}
+
+fn color(c: &Option<MtlColor>) -> Option<glm::Vec3> {
+    return match c {
+        Some(MtlColor::Rgb(r, g, b)) => Some(glm::vec3(*r, *g, *b)),
+        _ => None,
+    };
+}
+
+// [comment]
+// Map an mtl material onto the phong material of the renderer. Kd becomes the
+// color, the largest Ks component the specular weight and the illumination model
+// picks mirrors (3) and glass (4, 6, 7).
+// [/comment]
+pub fn material_from_mtl(mtl: &obj_rs::raw::material::Material) -> Material {
+    let m_type = match mtl.illumination_model {
+        Some(3) => MaterialType::REFLECTION,
+        Some(4) | Some(6) | Some(7) => MaterialType::REFLECTION_AND_REFRACTION,
+        _ => MaterialType::DIFFUSE_AND_GLOSSY,
+    };
+    let mut material = Material::new(Some(m_type), color(&mtl.diffuse), color(&mtl.emissive));
+    if let Some(ks) = color(&mtl.specular) {
+        material.Ks = glm::comp_max(&ks);
+    }
+    if let Some(ns) = mtl.specular_exponent {
+        material.specular_exponent = ns;
+    }
+    if let Some(ni) = mtl.optical_density {
+        material.ior = ni;
+    }
+    return material;
+}
+
+// [comment]
+// Split a planar polygon into triangles by ear clipping, so concave faces are
+// covered exactly. The polygon is projected along the largest axis of its Newell
+// normal. Returns indices into the polygon, falls back to a fan when no ear is
+// left, which only happens for degenerate or self intersecting polygons.
+// [/comment]
+pub fn triangulate(polygon: &[glm::Vec3]) -> Vec<[usize; 3]> {
+    let n = polygon.len();
+    if n < 3 {
+        return Vec::new();
+    }
+    if n == 3 {
+        return vec![[0, 1, 2]];
+    }
+    let mut normal = glm::Vec3::zeros();
+    for i in 0..n {
+        let (a, b) = (&polygon[i], &polygon[(i + 1) % n]);
+        normal += glm::vec3((a.y - b.y) * (a.z + b.z), (a.z - b.z) * (a.x + b.x), (a.x - b.x) * (a.y + b.y));
+    }
+    let axis = glm::abs(&normal).imax();
+    let (ax, ay) = ((axis + 1) % 3, (axis + 2) % 3);
+    // counter clockwise in 2d whichever way the normal points
+    let flip = normal[axis] < 0.;
+    let points: Vec<glm::Vec2> = polygon.iter().map(|p| {
+        if flip { glm::vec2(p[ay], p[ax]) } else { glm::vec2(p[ax], p[ay]) }
+    }).collect();
+    let cross = |o: &glm::Vec2, a: &glm::Vec2, b: &glm::Vec2| (a.x - o.x) * (b.y - o.y) - (a.y - o.y) * (b.x - o.x);
+
+    let mut remaining: Vec<usize> = (0..n).collect();
+    let mut triangles = Vec::with_capacity(n - 2);
+    while remaining.len() > 3 {
+        let m = remaining.len();
+        // starting at the second corner gives a fan for convex polygons
+        let ear = (0..m).map(|k| (k + 1) % m).find(|&k| {
+            let (i0, i1, i2) = (remaining[(k + m - 1) % m], remaining[k], remaining[(k + 1) % m]);
+            let (a, b, c) = (&points[i0], &points[i1], &points[i2]);
+            if cross(a, b, c) <= 0. {
+                return false;
+            }
+            // no other corner inside or on the candidate triangle
+            return remaining.iter().all(|&j| {
+                if j == i0 || j == i1 || j == i2 {
+                    return true;
+                }
+                let p = &points[j];
+                return cross(a, b, p) < 0. || cross(b, c, p) < 0. || cross(c, a, p) < 0.;
+            });
+        });
+        match ear {
+            Some(k) => {
+                triangles.push([remaining[(k + m - 1) % m], remaining[k], remaining[(k + 1) % m]]);
+                remaining.remove(k);
+            }
+            None => break,
+        }
+    }
+    for k in 1..remaining.len() - 1 {
+        triangles.push([remaining[0], remaining[k], remaining[k + 1]]);
+    }
+    return triangles;
+}
+
+// [comment]
+// Load an obj file with its mtl libraries, which are looked up next to it. Every
+// o and g statement starts a new mesh named after the current object and group,
+// meshes without faces are dropped. usemtl names missing from the libraries use
+// the default material.
+// [/comment]
+pub fn load_obj(path: &Path) -> Result<ObjScene> {
+    let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    let dir = path.parent().unwrap_or(Path::new(""));
+
+    let mut positions: Vec<glm::Vec3> = Vec::new();
+    let mut sts: Vec<glm::Vec2> = Vec::new();
+    let mut normals: Vec<glm::Vec3> = Vec::new();
+    let mut materials = vec![Material::default()];
+    let mut material_names = vec!["default".to_string()];
+    let mut material_index: HashMap<String, u32> = HashMap::new();
+    let mut meshes = Vec::new();
+
+    let mut object = String::new();
+    let mut current = MeshBuilder::new(String::new());
+    let mut current_material = 0u32;
+
+    for line in reader.lines() {
+        let line = line?;
+        let mut args: Vec<&str> = line.split_whitespace().collect();
+        if args.is_empty() || args[0].starts_with('#') {
+            continue;
+        }
+        let statement = args.remove(0);
+        match statement {
+            "v" => {
+                let v = parse_floats(&args, 3)?;
+                positions.push(glm::vec3(v[0], v[1], v[2]));
+            }
+            "vt" => {
+                let v = parse_floats(&args, 1)?;
+                let t = if args.len() > 1 { parse_floats(&args[1..], 1)?[0] } else { 0. };
+                sts.push(glm::vec2(v[0], t));
+            }
+            "vn" => {
+                let v = parse_floats(&args, 3)?;
+                normals.push(glm::vec3(v[0], v[1], v[2]));
+            }
+            "f" => {
+                if args.len() < 3 {
+                    return Err(load_error(LoadErrorKind::WrongNumberOfArguments, "A face needs at least 3 vertices").into());
+                }
+                let mut corners = Vec::with_capacity(args.len());
+                for arg in &args {
+                    corners.push(parse_corner(arg, positions.len(), sts.len(), normals.len())?);
+                }
+                let polygon: Vec<glm::Vec3> = corners.iter().map(|c| positions[c.0]).collect();
+                for tri in triangulate(&polygon) {
+                    for &k in tri.iter() {
+                        let index = current.vertex(corners[k], &positions, &sts, &normals);
+                        current.mesh.indices.push(index);
+                    }
+                    current.mesh.material_ids.push(current_material);
+                }
+            }
+            "o" | "g" => {
+                let name = args.join(" ");
+                let name = if statement == "o" {
+                    object = name;
+                    object.clone()
+                } else if object.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", object, name)
+                };
+                let previous = std::mem::replace(&mut current, MeshBuilder::new(name));
+                if !previous.mesh.indices.is_empty() {
+                    meshes.push(previous.finish());
+                }
+            }
+            "usemtl" => {
+                current_material = *material_index.get(&args.join(" ")).unwrap_or(&0);
+            }
+            "mtllib" => {
+                for lib in &args {
+                    let file = std::fs::File::open(dir.join(lib))?;
+                    let mtl = obj_rs::raw::parse_mtl(std::io::BufReader::new(file))?;
+                    // sorted so material ids do not depend on hash map order
+                    let mut names: Vec<&String> = mtl.materials.keys().collect();
+                    names.sort();
+                    for name in names {
+                        material_index.insert(name.clone(), materials.len() as u32);
+                        materials.push(material_from_mtl(&mtl.materials[name]));
+                        material_names.push(name.clone());
+                    }
+                }
+            }
+            // smoothing groups, lines, points and free form geometry are ignored
+            _ => {}
+        }
+    }
+    if !current.mesh.indices.is_empty() {
+        meshes.push(current.finish());
+    }
+
+    return Ok(ObjScene {
+        meshes,
+        materials,
+        material_names,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::material::MaterialType;
+    use crate::obj_loader::{load_obj, triangulate};
+    use crate::object::ObjectTrait;
+    use crate::ray::Ray;
+
+    fn area(polygon: &[glm::Vec3], triangles: &[[usize; 3]]) -> f32 {
+        return triangles.iter().map(|t| {
+            glm::length(&glm::cross(&(polygon[t[1]] - polygon[t[0]]), &(polygon[t[2]] - polygon[t[0]]))) / 2.
+        }).sum();
+    }
+
+    #[test]
+    fn test_triangulate_concave() {
+        // an L shape, a fan from its first corner would leave the polygon
+        let polygon = vec![
+            glm::vec3(0., 0., 0.), glm::vec3(2., 0., 0.), glm::vec3(2., 1., 0.),
+            glm::vec3(1., 1., 0.), glm::vec3(1., 2., 0.), glm::vec3(0., 2., 0.),
+        ];
+        let triangles = triangulate(&polygon);
+        assert_eq!(triangles.len(), 4);
+        assert!((area(&polygon, &triangles) - 3.).abs() < 1e-5);
+
+        // the same shape wound the other way in the xz plane keeps its winding
+        let mut polygon: Vec<glm::Vec3> = polygon.iter().map(|p| glm::vec3(p.x, 0., p.y)).collect();
+        polygon.reverse();
+        let triangles = triangulate(&polygon);
+        assert!((area(&polygon, &triangles) - 3.).abs() < 1e-5);
+        for t in &triangles {
+            let n = glm::cross(&(polygon[t[1]] - polygon[t[0]]), &(polygon[t[2]] - polygon[t[0]]));
+            assert!(n.y > 0.);
+        }
+    }
+
+    #[test]
+    fn test_load_obj_groups_and_materials() {
+        let dir = std::env::temp_dir().join("game101_5_test_obj");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("scene.mtl"), "newmtl red\nKd 1 0 0\nKs 0.5 0.25 0\nNs 20\n\
+            newmtl glass\nKd 1 1 1\nNi 1.5\nillum 7\n").unwrap();
+        std::fs::write(dir.join("scene.obj"), "mtllib scene.mtl\n\
+            v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 0 0 1\n\
+            o box\ng top\nusemtl red\nf 1 2 3 4\nusemtl glass\nf 1 2 5\n\
+            g side\nusemtl missing\nf -5 -3 -1\n").unwrap();
+
+        let scene = load_obj(&dir.join("scene.obj")).unwrap();
+        assert_eq!(scene.material_names, vec!["default", "glass", "red"]);
+        let red = &scene.materials[2];
+        assert_eq!(red.m_color, glm::vec3(1., 0., 0.));
+        assert_eq!((red.Ks, red.specular_exponent), (0.5, 20.));
+        assert!(matches!(scene.materials[1].m_type, MaterialType::REFLECTION_AND_REFRACTION));
+        assert_eq!(scene.materials[1].ior, 1.5);
+
+        assert_eq!(scene.meshes.len(), 2);
+        assert_eq!(scene.describe_mesh(&scene.meshes[0]), "box/top (glass, red)");
+        let top = &scene.meshes[0];
+        assert_eq!(top.name, "box/top");
+        assert_eq!(top.vertices.len(), 5);
+        assert_eq!(top.indices.len(), 9);
+        assert_eq!(top.material_ids, vec![2, 2, 1]);
+        assert!(top.normals.is_empty());
+        let side = &scene.meshes[1];
+        assert_eq!(side.name, "box/side");
+        assert_eq!(side.vertices, vec![glm::vec3(0., 0., 0.), glm::vec3(1., 1., 0.), glm::vec3(0., 0., 1.)]);
+        assert_eq!(side.material_ids, vec![0]);
+    }
+
+    #[test]
+    fn test_obj_mesh_triangles() {
+        let dir = std::env::temp_dir().join("game101_5_test_obj_meshes");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("two.mtl"), "newmtl red\nKd 1 0 0\nnewmtl blue\nKd 0 0 1\n").unwrap();
+        // two quads at z = -1 side by side, one group each
+        std::fs::write(dir.join("two.obj"), "mtllib two.mtl\n\
+            v -2 -1 -1\nv 0 -1 -1\nv 0 1 -1\nv -2 1 -1\nv 2 -1 -1\nv 2 1 -1\n\
+            g left\nusemtl red\nf 1 2 3 4\n\
+            g right\nusemtl blue\nf 2 5 6 3\n").unwrap();
+
+        let scene = load_obj(&dir.join("two.obj")).unwrap();
+        let mut meshes = scene.mesh_triangles(180.).unwrap();
+        assert_eq!(meshes.len(), 2);
+        for mesh in meshes.iter_mut() {
+            mesh.build().unwrap();
+        }
+        let hit = |mesh: usize, x: f32| {
+            return meshes[mesh].get_intersection(&Ray::new(&glm::vec3(x, 0.5, 0.), &glm::vec3(0., 0., -1.)));
+        };
+        assert_eq!(hit(0, -1.).unwrap().m.m_color, glm::vec3(1., 0., 0.));
+        assert_eq!(hit(1, 1.).unwrap().m.m_color, glm::vec3(0., 0., 1.));
+        assert!(hit(0, 1.).is_none());
+        // generated normals face the quads' winding
+        assert_eq!(hit(1, 1.).unwrap().shading_normal, glm::vec3(0., 0., 1.));
+    }
+}
@@ -1,9 +1,42 @@
 use crate::ray::Ray;
 use crate::intersection::IntersectData;
 use crate::bounds3::Bounds3;
+use crate::packet::{keep_nearest, RayPacket};
 
-pub trait ObjectTrait {
+pub trait ObjectTrait: Sync {
     fn get_intersection(&self, ray: &Ray) -> Option<IntersectData>;
 
     fn get_bounds(&self) -> Bounds3;
+
+    // [comment]
+    // Shadow ray query, true when anything blocks the ray before t_max. Only the
+    // answer is needed, so implementations may stop at the first hit found.
+    // [/comment]
+    fn occluded(&self, ray: &Ray, t_max: f32) -> bool {
+        return match self.get_intersection(ray) {
+            Some(data) => data.distance < t_max,
+            None => false,
+        };
+    }
+
+    // [comment]
+    // Intersect the active rays of a packet, hits[k] is only replaced by a nearer
+    // hit. Objects with their own acceleration structure override this to trace the
+    // packet together, the default traces the rays one at a time.
+    // [/comment]
+    fn get_intersection_packet<'s>(
+        &'s self,
+        packet: &RayPacket,
+        active: &[bool],
+        hits: &mut [Option<IntersectData<'s>>]
+    ) {
+        for (k, ray) in packet.rays.iter().enumerate() {
+            if !active[k] {
+                continue;
+            }
+            if let Some(data) = self.get_intersection(ray) {
+                keep_nearest(&mut hits[k], data);
+            }
+        }
+    }
 }
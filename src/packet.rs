@@ -0,0 +1,185 @@
+use crate::bounds3::Bounds3;
+use crate::intersection::IntersectData;
+use crate::ray::Ray;
+
+// primary rays are traced in PACKET_WIDTH x PACKET_WIDTH pixel tiles
+pub const PACKET_WIDTH: usize = 4;
+// rays per packet, traversal keeps its masks in arrays of this size
+pub const PACKET_SIZE: usize = PACKET_WIDTH * PACKET_WIDTH;
+
+// [comment]
+// Pyramid with its apex at the common ray origin bounded by four planes through
+// the corner rays of a packet. Every ray of the packet lies inside, so a box fully
+// outside one of the planes can be skipped without testing the rays one by one.
+// [/comment]
+#[derive(Clone)]
+pub struct Frustum {
+    pub origin: glm::Vec3,
+    pub normals: [glm::Vec3; 4],
+}
+
+impl Frustum {
+    // corners are the directions of the corner rays in order around the packet
+    pub fn new(origin: &glm::Vec3, corners: &[glm::Vec3; 4]) -> Frustum {
+        let center = corners[0] + corners[1] + corners[2] + corners[3];
+        let mut normals = [glm::Vec3::zeros(); 4];
+        for k in 0..4usize {
+            let n = glm::cross(&corners[k], &corners[(k + 1) % 4]);
+            // point inwards whichever way the corners wind
+            normals[k] = if glm::dot(&n, &center) < 0. { -n } else { n };
+        }
+        Frustum {
+            origin: *origin,
+            normals,
+        }
+    }
+
+    // [comment]
+    // Conservative, true only when the box lies completely outside a plane. The
+    // tested corner is the one furthest along the plane normal.
+    // [/comment]
+    pub fn culls(&self, bounds: &Bounds3) -> bool {
+        for n in self.normals.iter() {
+            let corner = glm::vec3(
+                if n.x >= 0. { bounds.p_max.x } else { bounds.p_min.x },
+                if n.y >= 0. { bounds.p_max.y } else { bounds.p_min.y },
+                if n.z >= 0. { bounds.p_max.z } else { bounds.p_min.z },
+            );
+            if glm::dot(n, &(corner - self.origin)) < 0. {
+                return true;
+            }
+        }
+        return false;
+    }
+}
+
+// [comment]
+// A group of rays traced together. The frustum is only set for coherent rays
+// sharing one origin, without it every node is tested ray by ray.
+// [/comment]
+pub struct RayPacket<'r> {
+    pub rays: &'r [Ray],
+    pub frustum: Option<Frustum>,
+}
+
+impl<'r> RayPacket<'r> {
+    pub fn new(rays: &'r [Ray]) -> RayPacket<'r> {
+        RayPacket {
+            rays,
+            frustum: None,
+        }
+    }
+
+    pub fn with_frustum(rays: &'r [Ray], frustum: Frustum) -> RayPacket<'r> {
+        RayPacket {
+            rays,
+            frustum: Some(frustum),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        return self.rays.len();
+    }
+}
+
+pub fn keep_nearest<'b>(hit: &mut Option<IntersectData<'b>>, data: IntersectData<'b>) {
+    if hit.is_none() || data.distance < hit.as_ref().unwrap().distance {
+        *hit = Some(data);
+    }
+}
+
+// distance to the current nearest hit, rays are not traced past it
+pub fn hit_distance(hit: &Option<IntersectData>, ray: &Ray) -> f32 {
+    return match hit {
+        Some(data) => data.distance,
+        None => ray.t_max,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accelerator::AcceleratorType;
+    use crate::bounds3::Bounds3;
+    use crate::material::Material;
+    use crate::object::ObjectTrait;
+    use crate::packet::{Frustum, RayPacket};
+    use crate::ray::Ray;
+    use crate::triangle::MeshTriangle;
+
+    #[test]
+    fn test_frustum_culls() {
+        let corners = [
+            glm::vec3(-1., -1., -1.),
+            glm::vec3(1., -1., -1.),
+            glm::vec3(1., 1., -1.),
+            glm::vec3(-1., 1., -1.),
+        ];
+        let frustum = Frustum::new(&glm::vec3(0., 0., 0.), &corners);
+        let unit = |x: f32, y: f32, z: f32| {
+            let p = glm::vec3(x, y, z);
+            Bounds3::new(&p, &(p + glm::vec3(1., 1., 1.)))
+        };
+        assert!(!frustum.culls(&unit(-0.5, -0.5, -5.)));
+        // straddles a side plane
+        assert!(!frustum.culls(&unit(4.5, 0., -5.)));
+        assert!(frustum.culls(&unit(7., 0., -5.)));
+        assert!(frustum.culls(&unit(0., -8., -5.)));
+        // behind the origin
+        assert!(frustum.culls(&unit(-0.5, -0.5, 3.)));
+    }
+
+    #[test]
+    fn test_packet_matches_single_rays() {
+        let mat = Material::default();
+        // a bumpy grid of triangles at z = -5
+        let n = 16usize;
+        let mut vertices = Vec::new();
+        for j in 0..=n {
+            for i in 0..=n {
+                let z = -5. + ((i * 7 + j * 3) % 5) as f32 * 0.1;
+                vertices.push(glm::vec3(i as f32 / 2. - 4., j as f32 / 2. - 4., z));
+            }
+        }
+        let mut indices = Vec::new();
+        for j in 0..n {
+            for i in 0..n {
+                let v = (j * (n + 1) + i) as u32;
+                indices.extend_from_slice(&[v, v + 1, v + n as u32 + 2, v, v + n as u32 + 2, v + n as u32 + 1]);
+            }
+        }
+        let st = vec![glm::vec2(0., 0.); vertices.len()];
+
+        for &accelerator_type in [AcceleratorType::BVH, AcceleratorType::UniformGrid].iter() {
+            let mut mesh = MeshTriangle::new_unbuilt(vertices.clone(), st.clone(), indices.clone(), &mat);
+            mesh.accelerator_type = accelerator_type;
+            mesh.build().unwrap();
+
+            // an 8x8 tile of rays, some of them miss the grid
+            let mut rays = Vec::new();
+            for j in 0..8 {
+                for i in 0..8 {
+                    let dir = glm::vec3(i as f32 * 0.25 - 0.5, j as f32 * 0.25 - 0.5, -1.).normalize();
+                    rays.push(Ray::new(&glm::vec3(0., 0., 0.), &dir));
+                }
+            }
+            let corners = [rays[0].direction, rays[7].direction, rays[63].direction, rays[56].direction];
+            let packet = RayPacket::with_frustum(&rays, Frustum::new(&glm::vec3(0., 0., 0.), &corners));
+            let mut hits: Vec<_> = rays.iter().map(|_| None).collect();
+            mesh.get_intersection_packet(&packet, &vec![true; rays.len()], &mut hits);
+
+            let mut n_hits = 0;
+            for (ray, hit) in rays.iter().zip(hits.iter()) {
+                let single = mesh.get_intersection(ray);
+                match (single, hit) {
+                    (None, None) => {}
+                    (Some(a), Some(b)) => {
+                        assert!((a.distance - b.distance).abs() < 1e-5);
+                        n_hits += 1;
+                    }
+                    _ => panic!("packet and single ray traversal disagree"),
+                }
+            }
+            assert!(n_hits > 20 && n_hits < 64, "{}", n_hits);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use crate::bounds3::Bounds3;
+use crate::global::*;
+use crate::intersection::IntersectData;
+use crate::material::Material;
+use crate::object::ObjectTrait;
+use crate::ray::Ray;
+
+// [comment]
+// Paraboloid z = z_max * (x^2 + y^2) / radius^2 opening up the z axis, so it has
+// the given radius at z_max, cut to [z_min, z_max] and swept by phi_max. u
+// follows the sweep and v the height, both from 0 to 1.
+// [/comment]
+pub struct Paraboloid<'a> {
+    pub radius: f32,
+    pub z_min: f32,
+    pub z_max: f32,
+    // radians
+    pub phi_max: f32,
+    pub m: &'a Material,
+}
+
+impl<'a> Paraboloid<'a> {
+    // \param phi_max: sweep in degrees, 360 for a full paraboloid
+    pub fn new(radius: f32, z_min: f32, z_max: f32, phi_max: f32, m: &'a Material) -> Paraboloid<'a> {
+        let z_max = z_max.max(0.);
+        Paraboloid {
+            radius,
+            z_min: z_min.max(0.).min(z_max),
+            z_max,
+            phi_max: sweep_radians(phi_max),
+            m,
+        }
+    }
+}
+
+impl<'a> ObjectTrait for Paraboloid<'a> {
+    fn get_intersection(&self, ray: &Ray) -> Option<IntersectData<'_>> {
+        // k (x^2 + y^2) - z = 0
+        let (o, d) = (&ray.origin, &ray.direction);
+        let k = self.z_max / (self.radius * self.radius);
+        let a = k * (d.x * d.x + d.y * d.y);
+        let b = 2. * k * (d.x * o.x + d.y * o.y) - d.z;
+        let c = k * (o.x * o.x + o.y * o.y) - o.z;
+        let accept = |p: &glm::Vec3| p.z >= self.z_min && p.z <= self.z_max && sweep_angle(p) <= self.phi_max;
+        let (t, p) = nearest_quadric_hit(ray, a, b, c, &accept)?;
+        let r = if self.z_max > 0. { self.radius * (p.z.max(0.) / self.z_max).sqrt() } else { 0. };
+        let (coords, p_error) = reproject_to_radius(&p, r, r * gamma(3), ray_point_error(o, d, t).z);
+
+        let normal = glm::vec3(2. * k * coords.x, 2. * k * coords.y, -1.).normalize();
+        let v = if self.z_max > self.z_min { (coords.z - self.z_min) / (self.z_max - self.z_min) } else { 0. };
+        let st = glm::vec2(sweep_angle(&coords) / self.phi_max, v);
+
+        return Some(IntersectData {
+            coords,
+            normal,
+            shading_normal: normal,
+            p_error,
+            barycentric: glm::zero(),
+            front_face: glm::dot(d, &normal) < 0.,
+            distance: t,
+            index: u32::MAX,
+            m: self.m,
+            eval_diffuse_color: self.m.eval_diffuse_color(&st),
+            uv: glm::zero(),
+            st,
+        });
+    }
+
+    fn get_bounds(&self) -> Bounds3 {
+        return Bounds3::new(
+            &glm::vec3(-self.radius, -self.radius, self.z_min),
+            &glm::vec3(self.radius, self.radius, self.z_max),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::material::Material;
+    use crate::object::ObjectTrait;
+    use crate::paraboloid::Paraboloid;
+    use crate::ray::Ray;
+
+    #[test]
+    fn test_paraboloid() {
+        let mat = Material::default();
+        let dish = Paraboloid::new(2., 0., 4., 360., &mat);
+
+        // z = x^2, from below the outside is hit
+        let hit = dish.get_intersection(&Ray::new(&glm::vec3(1., 0., -5.), &glm::vec3(0., 0., 1.))).unwrap();
+        assert!((hit.distance - 6.).abs() < 1e-5);
+        assert!(glm::distance(&hit.normal, &glm::vec3(2., 0., -1.).normalize()) < 1e-5);
+        assert!(hit.front_face && (hit.st.y - 0.25).abs() < 1e-5);
+
+        // from above the inside of the bowl
+        let inside = dish.get_intersection(&Ray::new(&glm::vec3(1., 0., 10.), &glm::vec3(0., 0., -1.))).unwrap();
+        assert!((inside.coords.z - 1.).abs() < 1e-5 && !inside.front_face);
+
+        // a cut bottom lets the ray through the vertex region
+        let cut = Paraboloid::new(2., 1., 4., 360., &mat);
+        let hit = cut.get_intersection(&Ray::new(&glm::vec3(0.5, 0., -5.), &glm::vec3(0., 0., 1.)));
+        assert!(hit.is_none());
+        assert_eq!(cut.get_bounds().p_min, glm::vec3(-2., -2., 1.));
+    }
+}
@@ -0,0 +1,896 @@
+use crate::cone::Cone;
+use crate::cylinder::Cylinder;
+use crate::disk::Disk;
+use crate::error::{invalid, Result};
+use crate::global::{deg_2_rad, LoadedMesh};
+use crate::light::Light;
+use crate::material::{Material, MaterialType};
+use crate::mesh_validation::repair_mesh;
+use crate::obj_loader::triangulate;
+use crate::object::ObjectTrait;
+use crate::paraboloid::Paraboloid;
+use crate::sphere::Sphere;
+use crate::transform::Transform;
+use crate::transformed::TransformedObject;
+use crate::triangle::MeshTriangle;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+// distant lights become point lights this far against their direction
+const DISTANT_LIGHT_DISTANCE: f32 = 1e5;
+// deepest nesting of Include and Import, also stops files including themselves
+const MAX_INCLUDE_DEPTH: usize = 32;
+
+// a triangle mesh in world space
+pub struct PbrtMesh {
+    pub mesh: LoadedMesh,
+    // index into PbrtScene::materials
+    pub material: usize,
+}
+
+pub enum PbrtShapeType {
+    Sphere { radius: f32 },
+    // facing +z at z = height
+    Disk { height: f32, radius: f32 },
+    // around the z axis, phi_max in degrees
+    Cylinder { radius: f32, z_min: f32, z_max: f32, phi_max: f32 },
+    Cone { radius: f32, height: f32, phi_max: f32 },
+    Paraboloid { radius: f32, z_min: f32, z_max: f32, phi_max: f32 },
+}
+
+// an analytic shape in its own object space
+pub struct PbrtShape {
+    pub shape: PbrtShapeType,
+    pub object_to_world: glm::Mat4,
+    pub material: usize,
+}
+
+// [comment]
+// The supported part of a pbrt scene. materials[0] is the default material,
+// everything pbrt would do that the importer could not map is listed in warnings.
+// [/comment]
+pub struct PbrtScene {
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub camera_to_world: glm::Mat4,
+    // vertical field of view in degrees, pbrt gives it for the shorter image side
+    pub fov: f32,
+    pub background_color: Option<glm::Vec3>,
+    pub materials: Vec<Material>,
+    pub meshes: Vec<PbrtMesh>,
+    pub shapes: Vec<PbrtShape>,
+    pub lights: Vec<Light>,
+    pub warnings: Vec<String>,
+}
+
+impl PbrtScene {
+    // [comment]
+    // Unbuilt meshes, set their accelerator type and call build before tracing rays.
+    // Meshes are repaired first, see repair_mesh.
+    // [/comment]
+    pub fn mesh_triangles(&self, crease_angle: f32, weld_tolerance: f32) -> Result<Vec<MeshTriangle<'_>>> {
+        return self.meshes.iter().enumerate().map(|(k, m)| {
+            let mut mesh = m.mesh.clone();
+            let report = repair_mesh(&mut mesh, weld_tolerance);
+            if !report.is_clean() {
+                println!("repaired pbrt mesh {}: {}", k, report);
+            }
+            let mut triangles = MeshTriangle::from_loaded(mesh, &self.materials[m.material], crease_angle)?;
+            triangles.mesh_data.checker_pattern = false;
+            return Ok(triangles);
+        }).collect();
+    }
+
+    // the shapes in object space, shape_instances places them in the scene
+    pub fn shape_objects(&self) -> Vec<Box<dyn ObjectTrait + '_>> {
+        return self.shapes.iter().map(|s| {
+            let m = &self.materials[s.material];
+            return match s.shape {
+                PbrtShapeType::Sphere { radius } => Box::new(Sphere::new(&glm::zero(), radius, m)) as Box<dyn ObjectTrait>,
+                PbrtShapeType::Disk { height, radius } => Box::new(Disk::new(&glm::vec3(0., 0., height), &glm::vec3(0., 0., 1.), radius, m)),
+                PbrtShapeType::Cylinder { radius, z_min, z_max, phi_max } => Box::new(Cylinder::new(radius, z_min, z_max, phi_max, m)),
+                PbrtShapeType::Cone { radius, height, phi_max } => Box::new(Cone::new(radius, height, phi_max, m)),
+                PbrtShapeType::Paraboloid { radius, z_min, z_max, phi_max } => Box::new(Paraboloid::new(radius, z_min, z_max, phi_max, m)),
+            };
+        }).collect();
+    }
+
+    pub fn shape_instances<'b>(&self, objects: &'b [Box<dyn ObjectTrait + 'b>]) -> Vec<TransformedObject<'b>> {
+        return self.shapes.iter().zip(objects.iter())
+            .map(|(s, object)| TransformedObject::new(object.as_ref(), Transform::new(&s.object_to_world)))
+            .collect();
+    }
+
+    // [comment]
+    // Scale light intensities to sum up to 1 keeping their ratios, as gltf scenes
+    // are. The renderer has no falloff, so physically bright pbrt lights wash out.
+    // [/comment]
+    pub fn normalize_lights(&mut self) {
+        let total: f32 = self.lights.iter().map(|l| glm::comp_max(&l.intensity)).sum();
+        if total > 0. {
+            for light in self.lights.iter_mut() {
+                light.intensity /= total;
+            }
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    // directive names, and bare words such as true and false
+    Word(String),
+    Str(String),
+    Num(f32),
+    Open,
+    Close,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '#' {
+            for c in chars.by_ref() {
+                if c == '\n' {
+                    break;
+                }
+            }
+        } else if c == '[' || c == ']' {
+            chars.next();
+            tokens.push(if c == '[' { Token::Open } else { Token::Close });
+        } else if c == '"' {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => s.push(c),
+                    None => return Err(invalid("unterminated string in pbrt file")),
+                }
+            }
+            tokens.push(Token::Str(s));
+        } else {
+            let mut s = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '[' || c == ']' || c == '"' || c == '#' {
+                    break;
+                }
+                s.push(c);
+                chars.next();
+            }
+            tokens.push(match s.parse::<f32>() {
+                Ok(x) => Token::Num(x),
+                Err(_) => Token::Word(s),
+            });
+        }
+    }
+    return Ok(tokens);
+}
+
+enum Value {
+    Nums(Vec<f32>),
+    Strs(Vec<String>),
+}
+
+// "type name" value pairs following a directive
+struct Params {
+    list: Vec<(String, String, Value)>,
+}
+
+impl Params {
+    fn get(&self, name: &str) -> Option<(&str, &Value)> {
+        return self.list.iter().find(|p| p.1 == name).map(|p| (p.0.as_str(), &p.2));
+    }
+
+    fn floats(&self, name: &str) -> Option<&[f32]> {
+        return match self.get(name) {
+            Some((_, Value::Nums(v))) => Some(v),
+            _ => None,
+        };
+    }
+
+    fn float(&self, name: &str, default: f32) -> f32 {
+        return self.floats(name).and_then(|v| v.first().copied()).unwrap_or(default);
+    }
+
+    fn int(&self, name: &str) -> Option<i32> {
+        return self.floats(name).and_then(|v| v.first()).map(|&x| x as i32);
+    }
+
+    fn string(&self, name: &str) -> Option<&str> {
+        return match self.get(name) {
+            Some((_, Value::Strs(v))) => v.first().map(|s| s.as_str()),
+            _ => None,
+        };
+    }
+
+    fn point(&self, name: &str, default: glm::Vec3) -> glm::Vec3 {
+        return match self.floats(name) {
+            Some(v) if v.len() >= 3 => glm::vec3(v[0], v[1], v[2]),
+            _ => default,
+        };
+    }
+
+    // [comment]
+    // rgb or color values, a single float is a gray. Spectra, blackbodies and
+    // textures fall back to the default with a warning.
+    // [/comment]
+    fn rgb(&self, name: &str, default: glm::Vec3, warnings: &mut Vec<String>) -> glm::Vec3 {
+        return match self.get(name) {
+            None => default,
+            Some(("rgb", Value::Nums(v))) | Some(("color", Value::Nums(v))) if v.len() >= 3 => glm::vec3(v[0], v[1], v[2]),
+            Some(("float", Value::Nums(v))) if v.len() == 1 => glm::vec3(v[0], v[0], v[0]),
+            Some((ty, _)) => {
+                warnings.push(format!("\"{} {}\" is not supported, using the default", ty, name));
+                default
+            }
+        };
+    }
+}
+
+#[derive(Clone)]
+struct GraphicsState {
+    transform: glm::Mat4,
+    material: usize,
+}
+
+struct Parser {
+    dir: PathBuf,
+    state: GraphicsState,
+    stack: Vec<GraphicsState>,
+    named_coordinate_systems: HashMap<String, glm::Mat4>,
+    named_materials: HashMap<String, usize>,
+    // files being parsed that include the current one
+    include_depth: usize,
+    scene: PbrtScene,
+}
+
+fn look_at(eye: &glm::Vec3, look: &glm::Vec3, up: &glm::Vec3) -> glm::Mat4 {
+    // pbrt cameras look down +z of a left handed space
+    let dir = (look - eye).normalize();
+    let right = glm::cross(&up.normalize(), &dir).normalize();
+    let new_up = glm::cross(&dir, &right);
+    let camera_to_world = glm::mat4(
+        right.x, new_up.x, dir.x, eye.x,
+        right.y, new_up.y, dir.y, eye.y,
+        right.z, new_up.z, dir.z, eye.z,
+        0., 0., 0., 1.,
+    );
+    return glm::inverse(&camera_to_world);
+}
+
+impl Parser {
+    fn warn(&mut self, message: String) {
+        self.scene.warnings.push(message);
+    }
+
+    // arguments of the directive at tokens[pos], up to the next directive
+    fn arguments(tokens: &[Token], mut pos: usize) -> (Vec<&Token>, usize) {
+        let mut args = Vec::new();
+        while pos < tokens.len() {
+            match &tokens[pos] {
+                Token::Word(w) if w != "true" && w != "false" => break,
+                _ => {}
+            }
+            args.push(&tokens[pos]);
+            pos += 1;
+        }
+        return (args, pos);
+    }
+
+    fn numbers(args: &[&Token]) -> Vec<f32> {
+        return args.iter().filter_map(|t| match t {
+            Token::Num(x) => Some(*x),
+            _ => None,
+        }).collect();
+    }
+
+    // the type string of directives like Shape "sphere", then the parameter list
+    fn typed_params(args: &[&Token]) -> Result<(String, Params)> {
+        let ty = match args.first() {
+            Some(Token::Str(s)) => s.clone(),
+            _ => return Err(invalid("expected a type string")),
+        };
+        return Ok((ty, Parser::params(&args[1..])?));
+    }
+
+    fn params(args: &[&Token]) -> Result<Params> {
+        let mut list = Vec::new();
+        let mut k = 0;
+        while k < args.len() {
+            let decl = match args[k] {
+                Token::Str(s) => s,
+                _ => return Err(invalid("expected a parameter declaration")),
+            };
+            let words: Vec<&str> = decl.split_whitespace().collect();
+            if words.len() != 2 {
+                return Err(invalid(format!("bad parameter declaration \"{}\"", decl)));
+            }
+            k += 1;
+            let mut values = Vec::new();
+            if args.get(k) == Some(&&Token::Open) {
+                k += 1;
+                while k < args.len() && *args[k] != Token::Close {
+                    values.push(args[k]);
+                    k += 1;
+                }
+                k += 1;
+            } else if k < args.len() {
+                values.push(args[k]);
+                k += 1;
+            }
+            let value = if values.iter().all(|t| matches!(t, Token::Num(_))) {
+                Value::Nums(Parser::numbers(&values))
+            } else {
+                Value::Strs(values.iter().map(|t| match t {
+                    Token::Str(s) | Token::Word(s) => s.clone(),
+                    Token::Num(x) => x.to_string(),
+                    _ => String::new(),
+                }).collect())
+            };
+            list.push((words[0].to_string(), words[1].to_string(), value));
+        }
+        return Ok(Params { list });
+    }
+
+    fn apply(&mut self, m: glm::Mat4) {
+        self.state.transform *= m;
+    }
+
+    fn parse_file(&mut self, path: &Path) -> Result<()> {
+        let text = std::fs::read_to_string(path)?;
+        return self.parse(&tokenize(&text)?);
+    }
+
+    fn parse(&mut self, tokens: &[Token]) -> Result<()> {
+        let mut pos = 0;
+        while pos < tokens.len() {
+            let directive = match &tokens[pos] {
+                Token::Word(w) => w.clone(),
+                t => return Err(invalid(format!("expected a directive, found {:?}", t))),
+            };
+            let (args, next) = Parser::arguments(tokens, pos + 1);
+            pos = next;
+            let nums = Parser::numbers(&args);
+            let need = |n: usize| -> Result<()> {
+                if nums.len() < n {
+                    return Err(invalid(format!("{} expects {} numbers", directive, n)));
+                }
+                return Ok(());
+            };
+            match directive.as_str() {
+                "Identity" => self.state.transform = glm::identity(),
+                "Translate" => {
+                    need(3)?;
+                    self.apply(glm::translation(&glm::vec3(nums[0], nums[1], nums[2])));
+                }
+                "Scale" => {
+                    need(3)?;
+                    self.apply(glm::scaling(&glm::vec3(nums[0], nums[1], nums[2])));
+                }
+                "Rotate" => {
+                    need(4)?;
+                    self.apply(glm::rotation(deg_2_rad(nums[0]), &glm::vec3(nums[1], nums[2], nums[3])));
+                }
+                "LookAt" => {
+                    need(9)?;
+                    let v = |k: usize| glm::vec3(nums[k], nums[k + 1], nums[k + 2]);
+                    self.apply(look_at(&v(0), &v(3), &v(6)));
+                }
+                "Transform" | "ConcatTransform" => {
+                    need(16)?;
+                    // the numbers are the matrix in column order
+                    let m = glm::Mat4::from_column_slice(&nums[..16]);
+                    if directive == "Transform" {
+                        self.state.transform = m;
+                    } else {
+                        self.apply(m);
+                    }
+                }
+                "CoordinateSystem" | "CoordSysTransform" => {
+                    let name = match args.first() {
+                        Some(Token::Str(s)) => s.clone(),
+                        _ => return Err(invalid(format!("{} expects a name", directive))),
+                    };
+                    if directive == "CoordinateSystem" {
+                        self.named_coordinate_systems.insert(name, self.state.transform);
+                    } else {
+                        match self.named_coordinate_systems.get(&name) {
+                            Some(m) => self.state.transform = *m,
+                            None => self.warn(format!("unknown coordinate system \"{}\"", name)),
+                        }
+                    }
+                }
+                "AttributeBegin" | "TransformBegin" => self.stack.push(self.state.clone()),
+                "AttributeEnd" | "TransformEnd" => {
+                    let state = self.stack.pop().ok_or_else(|| invalid(format!("unmatched {}", directive)))?;
+                    if directive == "TransformEnd" {
+                        self.state.transform = state.transform;
+                    } else {
+                        self.state = state;
+                    }
+                }
+                "WorldBegin" => {
+                    self.state.transform = glm::identity();
+                    self.named_coordinate_systems.insert("world".to_string(), glm::identity());
+                }
+                "WorldEnd" => {}
+                "Camera" => {
+                    let (ty, params) = Parser::typed_params(&args)?;
+                    // camera space looks down +z in pbrt and -z here
+                    let camera_to_world = glm::inverse(&self.state.transform);
+                    self.named_coordinate_systems.insert("camera".to_string(), camera_to_world);
+                    self.scene.camera_to_world = camera_to_world * glm::scaling(&glm::vec3(1., 1., -1.));
+                    if ty != "perspective" {
+                        self.warn(format!("camera \"{}\" is rendered as perspective", ty));
+                    }
+                    self.scene.fov = params.float("fov", 90.);
+                }
+                "Film" => {
+                    let (_, params) = Parser::typed_params(&args)?;
+                    self.scene.width = params.int("xresolution").or(self.scene.width);
+                    self.scene.height = params.int("yresolution").or(self.scene.height);
+                }
+                // one sample through the pixel center is all the renderer takes
+                "Sampler" | "PixelFilter" => {}
+                "Include" | "Import" => {
+                    let file = match args.first() {
+                        Some(Token::Str(s)) => s.clone(),
+                        _ => return Err(invalid(format!("{} expects a file name", directive))),
+                    };
+                    if self.include_depth >= MAX_INCLUDE_DEPTH {
+                        return Err(invalid(format!("{} nested deeper than {} files", directive, MAX_INCLUDE_DEPTH)));
+                    }
+                    let path = self.dir.join(file);
+                    self.include_depth += 1;
+                    let parsed = self.parse_file(&path);
+                    self.include_depth -= 1;
+                    parsed?;
+                }
+                "Material" => {
+                    let (ty, params) = Parser::typed_params(&args)?;
+                    self.state.material = self.material(&ty, &params);
+                }
+                "MakeNamedMaterial" => {
+                    let (name, params) = Parser::typed_params(&args)?;
+                    let ty = params.string("type").unwrap_or("").to_string();
+                    let material = self.material(&ty, &params);
+                    self.named_materials.insert(name, material);
+                }
+                "NamedMaterial" => {
+                    let name = match args.first() {
+                        Some(Token::Str(s)) => s.clone(),
+                        _ => return Err(invalid("NamedMaterial expects a name")),
+                    };
+                    match self.named_materials.get(&name) {
+                        Some(&m) => self.state.material = m,
+                        None => self.warn(format!("unknown material \"{}\"", name)),
+                    }
+                }
+                "Shape" => {
+                    let (ty, params) = Parser::typed_params(&args)?;
+                    self.shape(&ty, &params)?;
+                }
+                "LightSource" => {
+                    let (ty, params) = Parser::typed_params(&args)?;
+                    self.light(&ty, &params);
+                }
+                // only affects which side area lights emit from
+                "ReverseOrientation" => {}
+                _ => self.warn(format!("directive {} is not supported", directive)),
+            }
+        }
+        return Ok(());
+    }
+
+    // [comment]
+    // Map a pbrt material onto the phong model. Diffuse materials have no highlight,
+    // coated and plastic ones get a highlight from their roughness, smooth metals
+    // are mirrors and dielectrics glass.
+    // [/comment]
+    fn material(&mut self, ty: &str, params: &Params) -> usize {
+        let warnings = &mut self.scene.warnings;
+        let color = |warnings: &mut Vec<String>| {
+            if params.get("reflectance").is_some() {
+                return params.rgb("reflectance", glm::vec3(0.5, 0.5, 0.5), warnings);
+            }
+            return params.rgb("Kd", glm::vec3(0.5, 0.5, 0.5), warnings);
+        };
+        let exponent = |roughness: f32| {
+            let r = roughness.max(1e-3);
+            return (2. / (r * r) - 2.).clamp(1., 1e4);
+        };
+        let mut m = match ty {
+            "matte" | "diffuse" => {
+                let mut m = Material::new(Some(MaterialType::DIFFUSE_AND_GLOSSY), Some(color(warnings)), None);
+                m.Ks = 0.;
+                m
+            }
+            "plastic" | "coateddiffuse" | "substrate" | "uber" => {
+                let mut m = Material::new(Some(MaterialType::DIFFUSE_AND_GLOSSY), Some(color(warnings)), None);
+                m.Ks = glm::comp_max(&params.rgb("Ks", glm::vec3(0.25, 0.25, 0.25), warnings));
+                m.specular_exponent = exponent(params.float("roughness", 0.1));
+                m
+            }
+            "mirror" => Material::new(Some(MaterialType::REFLECTION), None, None),
+            "metal" | "conductor" => {
+                let roughness = params.float("roughness", if ty == "metal" { 0.01 } else { 0. });
+                if roughness < 0.05 {
+                    Material::new(Some(MaterialType::REFLECTION), None, None)
+                } else {
+                    let mut m = Material::new(Some(MaterialType::DIFFUSE_AND_GLOSSY), Some(glm::vec3(0.8, 0.8, 0.8)), None);
+                    m.Kd = 0.2;
+                    m.specular_exponent = exponent(roughness);
+                    m
+                }
+            }
+            "glass" | "dielectric" | "thindielectric" => {
+                let mut m = Material::new(Some(MaterialType::REFLECTION_AND_REFRACTION), None, None);
+                m.ior = params.floats("eta").or(params.floats("index")).and_then(|v| v.first().copied()).unwrap_or(1.5);
+                m
+            }
+            "" | "none" | "interface" => {
+                warnings.push(format!("material \"{}\" is rendered as the default material", ty));
+                return 0;
+            }
+            _ => {
+                warnings.push(format!("material \"{}\" is rendered as diffuse", ty));
+                Material::new(Some(MaterialType::DIFFUSE_AND_GLOSSY), Some(color(warnings)), None)
+            }
+        };
+        if m.specular_exponent <= 0. {
+            m.specular_exponent = 1.;
+        }
+        self.scene.materials.push(m);
+        return self.scene.materials.len() - 1;
+    }
+
+    fn add_mesh(&mut self, mut mesh: LoadedMesh) {
+        let m = self.state.transform;
+        let normal_matrix = glm::transpose(&glm::inverse(&m));
+        for v in mesh.vertices.iter_mut() {
+            *v = glm::vec4_to_vec3(&(m * glm::vec4(v.x, v.y, v.z, 1.)));
+        }
+        for n in mesh.normals.iter_mut() {
+            *n = glm::vec4_to_vec3(&(normal_matrix * glm::vec4(n.x, n.y, n.z, 0.))).normalize();
+        }
+        self.scene.meshes.push(PbrtMesh {
+            mesh,
+            material: self.state.material,
+        });
+    }
+
+    fn add_shape(&mut self, shape: PbrtShapeType) {
+        self.scene.shapes.push(PbrtShape {
+            shape,
+            object_to_world: self.state.transform,
+            material: self.state.material,
+        });
+    }
+
+    fn shape(&mut self, ty: &str, params: &Params) -> Result<()> {
+        match ty {
+            "sphere" => self.add_shape(PbrtShapeType::Sphere { radius: params.float("radius", 1.) }),
+            "disk" => {
+                if params.float("innerradius", 0.) > 0. {
+                    self.warn("disk innerradius is not supported, the disk is filled".to_string());
+                }
+                self.add_shape(PbrtShapeType::Disk { height: params.float("height", 0.), radius: params.float("radius", 1.) });
+            }
+            "cylinder" => self.add_shape(PbrtShapeType::Cylinder {
+                radius: params.float("radius", 1.),
+                z_min: params.float("zmin", -1.),
+                z_max: params.float("zmax", 1.),
+                phi_max: params.float("phimax", 360.),
+            }),
+            "cone" => self.add_shape(PbrtShapeType::Cone {
+                radius: params.float("radius", 1.),
+                height: params.float("height", 1.),
+                phi_max: params.float("phimax", 360.),
+            }),
+            "paraboloid" => self.add_shape(PbrtShapeType::Paraboloid {
+                radius: params.float("radius", 1.),
+                z_min: params.float("zmin", 0.),
+                z_max: params.float("zmax", 1.),
+                phi_max: params.float("phimax", 360.),
+            }),
+            "trianglemesh" => {
+                let p = params.floats("P").ok_or_else(|| invalid("trianglemesh without P"))?;
+                let vertices: Vec<glm::Vec3> = p.chunks_exact(3).map(|c| glm::vec3(c[0], c[1], c[2])).collect();
+                let indices: Vec<u32> = match params.floats("indices") {
+                    Some(indices) => indices.iter().map(|&i| i as u32).collect(),
+                    None if vertices.len() == 3 => vec![0, 1, 2],
+                    None => return Err(invalid("trianglemesh without indices")),
+                };
+                if !indices.len().is_multiple_of(3) || indices.iter().any(|&i| i as usize >= vertices.len()) {
+                    return Err(invalid("bad trianglemesh indices"));
+                }
+                let st_coordinates = match params.floats("uv").or(params.floats("st")) {
+                    Some(uv) if uv.len() == vertices.len() * 2 => uv.chunks_exact(2).map(|c| glm::vec2(c[0], c[1])).collect(),
+                    _ => vec![glm::vec2(0., 0.); vertices.len()],
+                };
+                let normals = match params.floats("N") {
+                    Some(n) if n.len() == vertices.len() * 3 => n.chunks_exact(3).map(|c| glm::vec3(c[0], c[1], c[2])).collect(),
+                    _ => Vec::new(),
+                };
+                self.add_mesh(LoadedMesh {
+                    vertices,
+                    st_coordinates,
+                    normals,
+                    colors: Vec::new(),
+                    indices,
+                });
+            }
+            "plymesh" => {
+                let file = params.string("filename").ok_or_else(|| invalid("plymesh without filename"))?;
+                let mesh = crate::ply_loader::parse_ply(&std::fs::read(self.dir.join(file))?)?;
+                self.add_mesh(mesh);
+            }
+            "bilinearmesh" => {
+                // quads given as four corners in the order 0 1 3 2
+                let p = params.floats("P").ok_or_else(|| invalid("bilinearmesh without P"))?;
+                let vertices: Vec<glm::Vec3> = p.chunks_exact(3).map(|c| glm::vec3(c[0], c[1], c[2])).collect();
+                let mut indices = Vec::new();
+                let quads: Vec<u32> = match params.floats("indices") {
+                    Some(indices) => indices.iter().map(|&i| i as u32).collect(),
+                    None => (0..vertices.len() as u32).collect(),
+                };
+                if !quads.len().is_multiple_of(4) || quads.iter().any(|&i| i as usize >= vertices.len()) {
+                    return Err(invalid("bad bilinearmesh indices"));
+                }
+                for q in quads.chunks_exact(4) {
+                    let polygon = [q[0], q[1], q[3], q[2]];
+                    let positions: Vec<glm::Vec3> = polygon.iter().map(|&i| vertices[i as usize]).collect();
+                    for tri in triangulate(&positions) {
+                        indices.extend(tri.iter().map(|&k| polygon[k]));
+                    }
+                }
+                let n = vertices.len();
+                self.add_mesh(LoadedMesh {
+                    vertices,
+                    st_coordinates: vec![glm::vec2(0., 0.); n],
+                    normals: Vec::new(),
+                    colors: Vec::new(),
+                    indices,
+                });
+            }
+            _ => self.warn(format!("shape \"{}\" is not supported", ty)),
+        }
+        return Ok(());
+    }
+
+    fn light(&mut self, ty: &str, params: &Params) {
+        let m = self.state.transform;
+        let warnings = &mut self.scene.warnings;
+        // pbrt-v3 has a spectrum scale, pbrt-v4 a float one
+        let scale = match params.floats("scale") {
+            Some(v) if v.len() >= 3 => glm::vec3(v[0], v[1], v[2]),
+            Some(v) if v.len() == 1 => glm::vec3(v[0], v[0], v[0]),
+            _ => glm::vec3(1., 1., 1.),
+        };
+        let point = |p: glm::Vec3| glm::vec4_to_vec3(&(m * glm::vec4(p.x, p.y, p.z, 1.)));
+        let from = params.point("from", glm::vec3(0., 0., 0.));
+        match ty {
+            "point" | "spot" => {
+                if ty == "spot" {
+                    warnings.push("spot light is rendered as a point light".to_string());
+                }
+                let intensity = params.rgb("I", glm::vec3(1., 1., 1.), warnings).component_mul(&scale);
+                self.scene.lights.push(Light::new(&point(from), &intensity));
+            }
+            "distant" => {
+                let to = params.point("to", glm::vec3(0., 0., 1.));
+                let direction = (point(to) - point(from)).normalize();
+                let intensity = params.rgb("L", glm::vec3(1., 1., 1.), warnings).component_mul(&scale);
+                let position = -direction * DISTANT_LIGHT_DISTANCE;
+                self.scene.lights.push(Light::new(&position, &intensity));
+            }
+            "infinite" => {
+                if params.get("filename").is_some() || params.get("mapname").is_some() {
+                    warnings.push("environment maps are not supported, using a constant background".to_string());
+                }
+                let l = params.rgb("L", glm::vec3(1., 1., 1.), warnings).component_mul(&scale);
+                self.scene.background_color = Some(l);
+            }
+            _ => warnings.push(format!("light \"{}\" is not supported", ty)),
+        }
+    }
+}
+
+// [comment]
+// Import the supported subset of a pbrt-v3 or pbrt-v4 scene: the camera and film
+// resolution, triangle and ply shapes baked into world space, spheres placed by
+// their transform, materials and point, spot, distant and infinite lights.
+// Anything else is skipped with a warning. Light intensities are kept as given,
+// see PbrtScene::normalize_lights.
+// [/comment]
+pub fn load_pbrt(path: &Path) -> Result<PbrtScene> {
+    let mut parser = Parser {
+        dir: path.parent().unwrap_or(Path::new("")).to_path_buf(),
+        state: GraphicsState {
+            transform: glm::identity(),
+            material: 0,
+        },
+        stack: Vec::new(),
+        named_coordinate_systems: HashMap::new(),
+        named_materials: HashMap::new(),
+        include_depth: 0,
+        scene: PbrtScene {
+            width: None,
+            height: None,
+            // the default pbrt camera at the origin looking down +z
+            camera_to_world: glm::scaling(&glm::vec3(1., 1., -1.)),
+            fov: 90.,
+            background_color: None,
+            materials: vec![Material::default()],
+            meshes: Vec::new(),
+            shapes: Vec::new(),
+            lights: Vec::new(),
+            warnings: Vec::new(),
+        },
+    };
+    parser.parse_file(path)?;
+
+    let mut scene = parser.scene;
+    // pbrt gives the field of view of the shorter side
+    if let (Some(width), Some(height)) = (scene.width, scene.height) {
+        if height > width {
+            let half = deg_2_rad(scene.fov * 0.5).tan() * height as f32 / width as f32;
+            scene.fov = half.atan().to_degrees() * 2.;
+        }
+    }
+    return Ok(scene);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::material::MaterialType;
+    use crate::object::ObjectTrait;
+    use crate::pbrt_loader::{load_pbrt, PbrtShapeType};
+    use crate::ray::Ray;
+
+    #[test]
+    fn test_load_pbrt() {
+        let dir = std::env::temp_dir().join("game101_5_test_pbrt");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("geometry.pbrt"), r#"
+            AttributeBegin
+              Material "matte" "rgb Kd" [0.8 0.1 0.1]
+              Translate 0 0 5
+              Shape "trianglemesh" "point3 P" [-1 -1 0 1 -1 0 1 1 0 -1 1 0] "integer indices" [0 1 2 0 2 3]
+            AttributeEnd
+        "#).unwrap();
+        std::fs::write(dir.join("scene.pbrt"), r#"
+            # a camera at z = -5 looking at the origin
+            LookAt 0 0 -5  0 0 0  0 1 0
+            Camera "perspective" "float fov" [45]
+            Film "rgb" "integer xresolution" [200] "integer yresolution" [400] "string filename" "out.exr"
+            Sampler "halton" "integer pixelsamples" 16
+            Integrator "volpath"
+            WorldBegin
+            LightSource "point" "rgb I" [3 3 3] "point3 from" [0 10 0]
+            LightSource "distant" "point3 from" [0 0 0] "point3 to" [0 -1 0] "rgb L" [1 1 1]
+            LightSource "infinite" "rgb L" [0.1 0.2 0.3]
+            MakeNamedMaterial "glass" "string type" "dielectric" "float eta" 1.33
+            AttributeBegin
+              NamedMaterial "glass"
+              Translate 1 2 3
+              Scale 2 1 1
+              Shape "sphere" "float radius" 0.5
+            AttributeEnd
+            Include "geometry.pbrt"
+            Shape "curve" "point3 P" [0 0 0 1 1 1 2 2 2 3 3 3]
+        "#).unwrap();
+
+        let mut scene = load_pbrt(&dir.join("scene.pbrt")).unwrap();
+        assert_eq!((scene.width, scene.height), (Some(200), Some(400)));
+        // 45 degrees over the width is wider over the taller height
+        let expected = ((22.5f32).to_radians().tan() * 2.).atan().to_degrees() * 2.;
+        assert!((scene.fov - expected).abs() < 1e-3, "{}", scene.fov);
+        // the camera sits at z = -5 and its -z looks at the origin
+        let eye = scene.camera_to_world * glm::vec4(0., 0., 0., 1.);
+        let forward = scene.camera_to_world * glm::vec4(0., 0., -1., 0.);
+        assert!(glm::distance(&eye.xyz(), &glm::vec3(0., 0., -5.)) < 1e-5);
+        assert!(glm::distance(&forward.xyz(), &glm::vec3(0., 0., 1.)) < 1e-5);
+
+        assert_eq!(scene.shapes.len(), 1);
+        let sphere = &scene.shapes[0];
+        assert!(matches!(sphere.shape, PbrtShapeType::Sphere { radius } if radius == 0.5));
+        assert!(matches!(scene.materials[sphere.material].m_type, MaterialType::REFLECTION_AND_REFRACTION));
+        assert_eq!(scene.materials[sphere.material].ior, 1.33);
+        // placed by the full transform, the scale makes it an ellipsoid
+        {
+            let objects = scene.shape_objects();
+            let instances = scene.shape_instances(&objects);
+            let hit = instances[0].get_intersection(&Ray::new(&glm::vec3(1., 2., -10.), &glm::vec3(0., 0., 1.))).unwrap();
+            assert!((hit.coords.z - 2.5).abs() < 1e-4, "{:?}", hit.coords);
+            let hit = instances[0].get_intersection(&Ray::new(&glm::vec3(-10., 2., 3.), &glm::vec3(1., 0., 0.))).unwrap();
+            assert!((hit.coords.x - 0.).abs() < 1e-4, "{:?}", hit.coords);
+        }
+
+        assert_eq!(scene.meshes.len(), 1);
+        let mesh = &scene.meshes[0];
+        assert_eq!(mesh.mesh.vertices[2], glm::vec3(1., 1., 5.));
+        assert_eq!(scene.materials[mesh.material].m_color, glm::vec3(0.8, 0.1, 0.1));
+
+        assert_eq!(scene.lights.len(), 2);
+        assert_eq!(scene.lights[0].position, glm::vec3(0., 10., 0.));
+        assert_eq!(scene.lights[0].intensity, glm::vec3(3., 3., 3.));
+        assert!(scene.lights[1].position.y > 1e4);
+        assert_eq!(scene.background_color, Some(glm::vec3(0.1, 0.2, 0.3)));
+
+        assert_eq!(scene.warnings.len(), 2, "{:?}", scene.warnings);
+        assert!(scene.warnings[0].contains("Integrator"));
+        assert!(scene.warnings[1].contains("curve"));
+
+        scene.normalize_lights();
+        assert!((scene.lights[0].intensity.x - 0.75).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_pbrt_shapes() {
+        let dir = std::env::temp_dir().join("game101_5_test_pbrt_shapes");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("scene.pbrt"), r#"
+            WorldBegin
+            AttributeBegin
+              Translate 0 0 -5
+              Shape "disk" "float height" 1 "float radius" 2 "float innerradius" 0.5
+            AttributeEnd
+            AttributeBegin
+              Translate 10 0 0
+              Shape "cylinder" "float radius" 0.5 "float zmin" 0 "float zmax" 2 "float phimax" 180
+              Shape "cone" "float radius" 2 "float height" 0
+              Shape "paraboloid"
+            AttributeEnd
+        "#).unwrap();
+
+        let scene = load_pbrt(&dir.join("scene.pbrt")).unwrap();
+        assert_eq!(scene.warnings.len(), 1, "{:?}", scene.warnings);
+        assert!(scene.warnings[0].contains("innerradius"));
+        let objects = scene.shape_objects();
+        let instances = scene.shape_instances(&objects);
+        let hit = |x: f32| instances[0].get_intersection(&Ray::new(&glm::vec3(x, 0., 0.), &glm::vec3(0., 0., -1.)));
+        // the disk sits at z = -4 and is filled
+        assert!((hit(0.).unwrap().distance - 4.).abs() < 1e-5);
+        assert!(hit(1.9).is_some());
+        assert!(hit(2.1).is_none());
+
+        assert!(matches!(scene.shapes[1].shape, PbrtShapeType::Cylinder { z_min, phi_max, .. } if z_min == 0. && phi_max == 180.));
+        assert!(matches!(scene.shapes[2].shape, PbrtShapeType::Cone { radius, .. } if radius == 2.));
+        assert!(matches!(scene.shapes[3].shape, PbrtShapeType::Paraboloid { radius, z_max, .. } if radius == 1. && z_max == 1.));
+        // only the half of the cylinder with y >= 0 is there
+        let side = |y: f32| instances[1].get_intersection(&Ray::new(&glm::vec3(0., y, 1.), &glm::vec3(1., 0., 0.)));
+        assert!((side(0.3).unwrap().coords.x - (10. - 0.4)).abs() < 1e-4);
+        assert!(side(-0.3).is_none_or(|hit| hit.coords.x > 10.));
+        // the flat cone has a height to divide by
+        let flat = instances[2].get_intersection(&Ray::new(&glm::vec3(11., 0., 1.), &glm::vec3(0., 0., -1.)));
+        assert!(flat.is_none_or(|hit| hit.st.y.is_finite()));
+    }
+
+    #[test]
+    fn test_bad_pbrt() {
+        let dir = std::env::temp_dir().join("game101_5_test_bad_pbrt");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("loop.pbrt"), r#"Include "loop.pbrt""#).unwrap();
+        let err = load_pbrt(&dir.join("loop.pbrt")).err().unwrap();
+        assert!(matches!(err, Error::Parse(_)) && err.to_string().contains("nested"), "{}", err);
+
+        std::fs::write(dir.join("quads.pbrt"), r#"
+            Shape "bilinearmesh" "point3 P" [0 0 0 1 0 0 0 1 0 1 1 0] "integer indices" [0 1 2 4]
+        "#).unwrap();
+        assert!(load_pbrt(&dir.join("quads.pbrt")).is_err());
+
+        // the second triangle has no area and is dropped when the mesh is built
+        std::fs::write(dir.join("degenerate.pbrt"), r#"
+            Shape "trianglemesh" "point3 P" [0 0 0 1 0 0 0 1 0 2 0 0] "integer indices" [0 1 2 0 1 3]
+        "#).unwrap();
+        let scene = load_pbrt(&dir.join("degenerate.pbrt")).unwrap();
+        assert_eq!(scene.meshes[0].mesh.indices.len(), 6);
+        let meshes = scene.mesh_triangles(180., 0.).unwrap();
+        assert_eq!(meshes[0].mesh_data.num_triangles, 1);
+    }
+}
@@ -0,0 +1,112 @@
+use crate::bounds3::Bounds3;
+use crate::global::*;
+use crate::intersection::IntersectData;
+use crate::material::Material;
+use crate::object::ObjectTrait;
+use crate::ray::Ray;
+
+// [comment]
+// Infinite plane through point. Texture coordinates are the distances along
+// s_axis and t_axis from point, so a texture repeats every unit. The plane has no
+// area and can not be an emitter, see Rectangle and Disk for that.
+// [/comment]
+pub struct Plane<'a> {
+    pub point: glm::Vec3,
+    pub normal: glm::Vec3,
+    pub s_axis: glm::Vec3,
+    pub t_axis: glm::Vec3,
+    pub m: &'a Material,
+}
+
+impl<'a> Plane<'a> {
+    pub fn new(point: &glm::Vec3, normal: &glm::Vec3, m: &'a Material) -> Plane<'a> {
+        let normal = normal.normalize();
+        let (s_axis, t_axis) = coordinate_system(&normal);
+        Plane {
+            point: *point,
+            normal,
+            s_axis,
+            t_axis,
+            m,
+        }
+    }
+}
+
+impl<'a> ObjectTrait for Plane<'a> {
+    fn get_intersection(&self, ray: &Ray) -> Option<IntersectData<'_>> {
+        let denom = glm::dot(&ray.direction, &self.normal);
+        if denom == 0. {
+            return None;
+        }
+        let t = glm::dot(&(self.point - ray.origin), &self.normal) / denom;
+        if !ray.in_range(t) {
+            return None;
+        }
+        let coords = ray.origin + ray.direction * t;
+        let local = coords - self.point;
+        let st = glm::vec2(glm::dot(&local, &self.s_axis), glm::dot(&local, &self.t_axis));
+
+        return Some(IntersectData {
+            coords,
+            normal: self.normal,
+            shading_normal: self.normal,
+            p_error: ray_point_error(&ray.origin, &ray.direction, t),
+            barycentric: glm::zero(),
+            front_face: denom < 0.,
+            distance: t,
+            index: u32::MAX,
+            m: self.m,
+            eval_diffuse_color: self.m.eval_diffuse_color(&st),
+            uv: glm::zero(),
+            st,
+        });
+    }
+
+    // [comment]
+    // Infinite except along an axis the plane is perpendicular to. The scene keeps
+    // objects with infinite bounds out of its accelerator and tests them directly.
+    // [/comment]
+    fn get_bounds(&self) -> Bounds3 {
+        let mut bounds = Bounds3 {
+            p_min: glm::vec3(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+            p_max: glm::vec3(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+        };
+        for k in 0..3 {
+            if self.normal[k].abs() == 1. {
+                bounds.p_min[k] = self.point[k];
+                bounds.p_max[k] = self.point[k];
+            }
+        }
+        return bounds;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::material::Material;
+    use crate::object::ObjectTrait;
+    use crate::plane::Plane;
+    use crate::ray::Ray;
+
+    #[test]
+    fn test_plane_intersection() {
+        let mat = Material::default();
+        let plane = Plane::new(&glm::vec3(0., -1., 0.), &glm::vec3(0., 2., 0.), &mat);
+
+        let hit = plane.get_intersection(&Ray::new(&glm::vec3(3., 4., -2.), &glm::vec3(0., -1., 0.))).unwrap();
+        assert!((hit.distance - 5.).abs() < 1e-5);
+        assert_eq!(hit.normal, glm::vec3(0., 1., 0.));
+        assert!(hit.front_face);
+        // texture coordinates move with the hit along the plane
+        let other = plane.get_intersection(&Ray::new(&glm::vec3(4., 4., -2.), &glm::vec3(0., -1., 0.))).unwrap();
+        assert!((glm::distance(&hit.st, &other.st) - 1.).abs() < 1e-5);
+
+        // parallel and behind the origin
+        assert!(plane.get_intersection(&Ray::new(&glm::vec3(0., 0., 0.), &glm::vec3(1., 0., 0.))).is_none());
+        assert!(plane.get_intersection(&Ray::new(&glm::vec3(0., 0., 0.), &glm::vec3(0., 1., 0.))).is_none());
+
+        let bounds = plane.get_bounds();
+        assert_eq!((bounds.p_min.y, bounds.p_max.y), (-1., -1.));
+        assert!(bounds.p_max.x.is_infinite());
+    }
+}
@@ -0,0 +1,289 @@
+use crate::error::{invalid, Result};
+use crate::global::LoadedMesh;
+use crate::obj_loader::triangulate;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Scalar {
+    I8, U8, I16, U16, I32, U32, F32, F64,
+}
+
+impl Scalar {
+    fn parse(name: &str) -> Result<Scalar> {
+        return Ok(match name {
+            "char" | "int8" => Scalar::I8,
+            "uchar" | "uint8" => Scalar::U8,
+            "short" | "int16" => Scalar::I16,
+            "ushort" | "uint16" => Scalar::U16,
+            "int" | "int32" => Scalar::I32,
+            "uint" | "uint32" => Scalar::U32,
+            "float" | "float32" => Scalar::F32,
+            "double" | "float64" => Scalar::F64,
+            _ => return Err(invalid("unknown ply property type")),
+        });
+    }
+
+    fn size(&self) -> usize {
+        return match self {
+            Scalar::I8 | Scalar::U8 => 1,
+            Scalar::I16 | Scalar::U16 => 2,
+            Scalar::I32 | Scalar::U32 | Scalar::F32 => 4,
+            Scalar::F64 => 8,
+        };
+    }
+}
+
+enum PropertyType {
+    Scalar(Scalar),
+    // count type, item type
+    List(Scalar, Scalar),
+}
+
+struct Property {
+    name: String,
+    ty: PropertyType,
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+// reads values of the body one by one, whatever the encoding
+struct Body<'d> {
+    format: Format,
+    data: &'d [u8],
+    pos: usize,
+}
+
+impl<'d> Body<'d> {
+    fn next_token(&mut self) -> Result<&'d str> {
+        while self.pos < self.data.len() && self.data[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+        let start = self.pos;
+        while self.pos < self.data.len() && !self.data[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(invalid("ply body ends early"));
+        }
+        return std::str::from_utf8(&self.data[start..self.pos]).map_err(|_| invalid("ply body is not text"));
+    }
+
+    fn read(&mut self, ty: Scalar) -> Result<f64> {
+        if self.format == Format::Ascii {
+            return self.next_token()?.parse::<f64>().map_err(|_| invalid("bad number in ply body"));
+        }
+        let size = ty.size();
+        if self.pos + size > self.data.len() {
+            return Err(invalid("ply body ends early"));
+        }
+        let mut bytes = [0u8; 8];
+        bytes[..size].copy_from_slice(&self.data[self.pos..self.pos + size]);
+        if self.format == Format::BinaryBigEndian {
+            bytes[..size].reverse();
+        }
+        self.pos += size;
+        let b2 = [bytes[0], bytes[1]];
+        let b4 = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        return Ok(match ty {
+            Scalar::I8 => bytes[0] as i8 as f64,
+            Scalar::U8 => bytes[0] as f64,
+            Scalar::I16 => i16::from_le_bytes(b2) as f64,
+            Scalar::U16 => u16::from_le_bytes(b2) as f64,
+            Scalar::I32 => i32::from_le_bytes(b4) as f64,
+            Scalar::U32 => u32::from_le_bytes(b4) as f64,
+            Scalar::F32 => f32::from_le_bytes(b4) as f64,
+            Scalar::F64 => f64::from_le_bytes(bytes),
+        });
+    }
+}
+
+fn parse_header(data: &[u8]) -> Result<(Format, Vec<Element>, usize)> {
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    let mut pos = 0;
+    let mut first = true;
+    loop {
+        let end = match data[pos..].iter().position(|&b| b == b'\n') {
+            Some(end) => pos + end,
+            None => return Err(invalid("ply header has no end_header")),
+        };
+        let line = std::str::from_utf8(&data[pos..end]).map_err(|_| invalid("ply header is not text"))?;
+        pos = end + 1;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if first {
+            if words != ["ply"] {
+                return Err(invalid("not a ply file"));
+            }
+            first = false;
+            continue;
+        }
+        match words.as_slice() {
+            ["format", "ascii", _] => format = Some(Format::Ascii),
+            ["format", "binary_little_endian", _] => format = Some(Format::BinaryLittleEndian),
+            ["format", "binary_big_endian", _] => format = Some(Format::BinaryBigEndian),
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: count.parse().map_err(|_| invalid("bad ply element count"))?,
+                properties: Vec::new(),
+            }),
+            ["property", "list", count_ty, item_ty, name] => {
+                let element = elements.last_mut().ok_or_else(|| invalid("ply property outside an element"))?;
+                element.properties.push(Property {
+                    name: name.to_string(),
+                    ty: PropertyType::List(Scalar::parse(count_ty)?, Scalar::parse(item_ty)?),
+                });
+            }
+            ["property", ty, name] => {
+                let element = elements.last_mut().ok_or_else(|| invalid("ply property outside an element"))?;
+                element.properties.push(Property {
+                    name: name.to_string(),
+                    ty: PropertyType::Scalar(Scalar::parse(ty)?),
+                });
+            }
+            ["end_header"] => break,
+            // comment and obj_info lines
+            _ => {}
+        }
+    }
+    let format = format.ok_or_else(|| invalid("ply header has no format"))?;
+    return Ok((format, elements, pos));
+}
+
+// [comment]
+// Parse an ascii or binary ply file. Vertex positions, normals (nx, ny, nz),
+// colors (red, green, blue, integer or float) and texture coordinates (s/t, u/v or
+// texture_u/texture_v) are read, faces may be any polygon and are triangulated.
+// Other elements and properties are skipped.
+// [/comment]
+pub fn parse_ply(data: &[u8]) -> Result<LoadedMesh> {
+    let (format, elements, body_start) = parse_header(data)?;
+    let mut body = Body { format, data, pos: body_start };
+    let mut mesh = LoadedMesh::default();
+
+    for element in &elements {
+        let prop = |names: &[&str]| element.properties.iter().position(|p| names.contains(&p.name.as_str()));
+        let (x, y, z) = (prop(&["x"]), prop(&["y"]), prop(&["z"]));
+        let normal = (prop(&["nx"]), prop(&["ny"]), prop(&["nz"]));
+        let color = (prop(&["red", "r"]), prop(&["green", "g"]), prop(&["blue", "b"]));
+        let st = (prop(&["s", "u", "texture_u"]), prop(&["t", "v", "texture_v"]));
+        let face_indices = prop(&["vertex_indices", "vertex_index"]);
+
+        let mut values = vec![0f64; element.properties.len()];
+        let mut list: Vec<f64> = Vec::new();
+        for _ in 0..element.count {
+            for (k, property) in element.properties.iter().enumerate() {
+                match property.ty {
+                    PropertyType::Scalar(ty) => values[k] = body.read(ty)?,
+                    PropertyType::List(count_ty, item_ty) => {
+                        let count = body.read(count_ty)? as usize;
+                        let items = (0..count).map(|_| body.read(item_ty)).collect::<Result<Vec<f64>>>()?;
+                        if Some(k) == face_indices {
+                            list = items;
+                        }
+                    }
+                }
+            }
+            match element.name.as_str() {
+                "vertex" => {
+                    let get = |i: Option<usize>| i.map(|i| values[i] as f32).unwrap_or(0.);
+                    mesh.vertices.push(glm::vec3(get(x), get(y), get(z)));
+                    if let (Some(_), Some(_), Some(_)) = normal {
+                        mesh.normals.push(glm::vec3(get(normal.0), get(normal.1), get(normal.2)));
+                    }
+                    if let (Some(r), Some(_), Some(_)) = color {
+                        // integer colors are 0 to 255, float ones 0 to 1
+                        let scale = match element.properties[r].ty {
+                            PropertyType::Scalar(Scalar::F32) | PropertyType::Scalar(Scalar::F64) => 1.,
+                            _ => 1. / 255.,
+                        };
+                        mesh.colors.push(glm::vec3(get(color.0), get(color.1), get(color.2)) * scale);
+                    }
+                    mesh.st_coordinates.push(glm::vec2(get(st.0), get(st.1)));
+                }
+                "face" if face_indices.is_some() => {
+                    let n = mesh.vertices.len() as f64;
+                    // the list is read as floats, negative or fractional values are no index
+                    if list.iter().any(|&i| i < 0. || i.fract() != 0. || i >= n) {
+                        return Err(invalid("ply face index out of range"));
+                    }
+                    let polygon: Vec<usize> = list.iter().map(|&i| i as usize).collect();
+                    let positions: Vec<glm::Vec3> = polygon.iter().map(|&i| mesh.vertices[i]).collect();
+                    for tri in triangulate(&positions) {
+                        mesh.indices.extend(tri.iter().map(|&k| polygon[k] as u32));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    return Ok(mesh);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ply_loader::parse_ply;
+
+    #[test]
+    fn test_ascii_ply() {
+        let ply = b"ply\nformat ascii 1.0\ncomment a colored quad\nelement vertex 4\n\
+            property float x\nproperty float y\nproperty float z\n\
+            property float nx\nproperty float ny\nproperty float nz\n\
+            property uchar red\nproperty uchar green\nproperty uchar blue\n\
+            element face 1\nproperty list uchar int vertex_indices\nend_header\n\
+            0 0 0 0 0 1 255 0 0\n1 0 0 0 0 1 0 255 0\n1 1 0 0 0 1 0 0 255\n0 1 0 0 0 1 255 255 255\n\
+            4 0 1 2 3\n";
+        let mesh = parse_ply(ply).unwrap();
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.indices, vec![0, 1, 2, 0, 2, 3]);
+        assert_eq!(mesh.normals, vec![glm::vec3(0., 0., 1.); 4]);
+        assert_eq!(mesh.colors[1], glm::vec3(0., 1., 0.));
+        assert_eq!(mesh.st_coordinates.len(), 4);
+
+        // negative and fractional indices would saturate or truncate to valid ones
+        let header = "ply\nformat ascii 1.0\nelement vertex 3\n\
+            property float x\nproperty float y\nproperty float z\n\
+            element face 1\nproperty list uchar float vertex_indices\nend_header\n\
+            0 0 0\n1 0 0\n0 1 0\n";
+        for face in ["3 0 1 -1\n", "3 0 1 1.5\n", "3 0 1 3\n"].iter() {
+            assert!(parse_ply(format!("{}{}", header, face).as_bytes()).is_err(), "{}", face);
+        }
+        assert!(parse_ply(format!("{}3 0 1 2\n", header).as_bytes()).is_ok());
+    }
+
+    #[test]
+    fn test_binary_ply() {
+        // big endian with an extra vertex property and element that are skipped
+        let mut ply = b"ply\nformat binary_big_endian 1.0\nelement vertex 3\n\
+            property double x\nproperty double y\nproperty double z\nproperty short confidence\n\
+            element face 1\nproperty list uchar uint vertex_index\n\
+            element edge 1\nproperty int vertex1\nproperty int vertex2\nend_header\n".to_vec();
+        for v in [[0f64, 0., 0.], [2., 0., 0.], [0., 2., 0.]].iter() {
+            for c in v.iter() {
+                ply.extend_from_slice(&c.to_be_bytes());
+            }
+            ply.extend_from_slice(&7i16.to_be_bytes());
+        }
+        ply.push(3);
+        for i in [0u32, 1, 2].iter() {
+            ply.extend_from_slice(&i.to_be_bytes());
+        }
+        ply.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        let mesh = parse_ply(&ply).unwrap();
+        assert_eq!(mesh.vertices[1], glm::vec3(2., 0., 0.));
+        assert_eq!(mesh.indices, vec![0, 1, 2]);
+        assert!(mesh.normals.is_empty() && mesh.colors.is_empty());
+
+        // a truncated body is an error
+        assert!(parse_ply(&ply[..ply.len() - 12]).is_err());
+    }
+}
@@ -1,4 +1,5 @@
 
+#[derive(Clone)]
 pub struct Ray {
     pub origin          : glm::Vec3,
     pub direction       : glm::Vec3,
@@ -24,4 +25,16 @@ impl Ray {
             t_max: f32::MAX,
         }
     }
+
+    // ray restricted to the parametric interval [t_min, t_max]
+    pub fn segment(origin: &glm::Vec3, direction: &glm::Vec3, t_min: f32, t_max: f32) -> Self {
+        let mut ray = Ray::new(origin, direction);
+        ray.t_min = t_min;
+        ray.t_max = t_max;
+        return ray;
+    }
+
+    pub fn in_range(&self, t: f32) -> bool {
+        return t >= self.t_min && t <= self.t_max;
+    }
 }
@@ -0,0 +1,154 @@
+use crate::area_light::Emitter;
+use crate::bounds3::Bounds3;
+use crate::global::*;
+use crate::intersection::IntersectData;
+use crate::material::Material;
+use crate::object::ObjectTrait;
+use crate::ray::Ray;
+
+// [comment]
+// Axis aligned rectangle between two corners that agree on the coordinate of the
+// axis it is perpendicular to. The normal points along +axis, or -axis after
+// flip_normal. Texture coordinates run from 0 to 1 along the next two axes in
+// cyclic order, e.g. x and y for a rectangle perpendicular to z.
+// [/comment]
+pub struct Rectangle<'a> {
+    pub axis: usize,
+    pub p_min: glm::Vec3,
+    pub p_max: glm::Vec3,
+    // 1 or -1
+    pub normal_sign: f32,
+    pub m: &'a Material,
+}
+
+impl<'a> Rectangle<'a> {
+    // the axis is the one the corners are closest on, the rectangle lies halfway
+    pub fn new(corner0: &glm::Vec3, corner1: &glm::Vec3, m: &'a Material) -> Rectangle<'a> {
+        let bounds = Bounds3::new(corner0, corner1);
+        let extent = bounds.diagonal();
+        let axis = if extent.x <= extent.y && extent.x <= extent.z {
+            0
+        } else if extent.y <= extent.z {
+            1
+        } else {
+            2
+        };
+        let (mut p_min, mut p_max) = (bounds.p_min, bounds.p_max);
+        let k = (p_min[axis] + p_max[axis]) * 0.5;
+        p_min[axis] = k;
+        p_max[axis] = k;
+        Rectangle {
+            axis,
+            p_min,
+            p_max,
+            normal_sign: 1.,
+            m,
+        }
+    }
+
+    pub fn flip_normal(mut self) -> Rectangle<'a> {
+        self.normal_sign = -self.normal_sign;
+        return self;
+    }
+
+    pub fn normal(&self) -> glm::Vec3 {
+        let mut n = glm::vec3(0., 0., 0.);
+        n[self.axis] = self.normal_sign;
+        return n;
+    }
+
+    // the two in plane axes
+    fn plane_axes(&self) -> (usize, usize) {
+        return ((self.axis + 1) % 3, (self.axis + 2) % 3);
+    }
+}
+
+impl<'a> ObjectTrait for Rectangle<'a> {
+    fn get_intersection(&self, ray: &Ray) -> Option<IntersectData<'_>> {
+        let axis = self.axis;
+        if ray.direction[axis] == 0. {
+            return None;
+        }
+        let t = (self.p_min[axis] - ray.origin[axis]) / ray.direction[axis];
+        if !ray.in_range(t) {
+            return None;
+        }
+        let mut coords = ray.origin + ray.direction * t;
+        coords[axis] = self.p_min[axis];
+        let (a, b) = self.plane_axes();
+        if coords[a] < self.p_min[a] || coords[a] > self.p_max[a] || coords[b] < self.p_min[b] || coords[b] > self.p_max[b] {
+            return None;
+        }
+        let st = glm::vec2(
+            (coords[a] - self.p_min[a]) / (self.p_max[a] - self.p_min[a]),
+            (coords[b] - self.p_min[b]) / (self.p_max[b] - self.p_min[b]),
+        );
+        // the coordinate along the axis is snapped to the plane, so it is exact
+        let mut p_error = ray_point_error(&ray.origin, &ray.direction, t);
+        p_error[axis] = 0.;
+        let normal = self.normal();
+
+        return Some(IntersectData {
+            coords,
+            normal,
+            shading_normal: normal,
+            p_error,
+            barycentric: glm::zero(),
+            front_face: glm::dot(&ray.direction, &normal) < 0.,
+            distance: t,
+            index: u32::MAX,
+            m: self.m,
+            eval_diffuse_color: self.m.eval_diffuse_color(&st),
+            uv: glm::zero(),
+            st,
+        });
+    }
+
+    fn get_bounds(&self) -> Bounds3 {
+        return Bounds3::new(&self.p_min, &self.p_max);
+    }
+}
+
+impl<'a> Emitter for Rectangle<'a> {
+    fn sample(&self, u: f32, v: f32) -> (glm::Vec3, glm::Vec3) {
+        let (a, b) = self.plane_axes();
+        let mut p = self.p_min;
+        p[a] += u * (self.p_max[a] - self.p_min[a]);
+        p[b] += v * (self.p_max[b] - self.p_min[b]);
+        return (p, self.normal());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::area_light::Emitter;
+    use crate::global::offset_ray_origin;
+    use crate::material::Material;
+    use crate::object::ObjectTrait;
+    use crate::ray::Ray;
+    use crate::rectangle::Rectangle;
+
+    #[test]
+    fn test_rectangle() {
+        let mat = Material::default();
+        // a ceiling light facing down
+        let rect = Rectangle::new(&glm::vec3(-1., 5., -2.), &glm::vec3(1., 5., 2.), &mat).flip_normal();
+        assert_eq!(rect.axis, 1);
+
+        let hit = rect.get_intersection(&Ray::new(&glm::vec3(0.5, 0., 1.), &glm::vec3(0., 1., 0.))).unwrap();
+        assert_eq!(hit.coords, glm::vec3(0.5, 5., 1.));
+        assert_eq!(hit.normal, glm::vec3(0., -1., 0.));
+        assert!(hit.front_face);
+        let origin = offset_ray_origin(&hit.coords, &hit.p_error, &hit.normal, &glm::vec3(0.3, -1., 0.));
+        assert!(rect.get_intersection(&Ray::new(&origin, &glm::vec3(0.3, -1., 0.))).is_none());
+        // s runs along z and t along x for a rectangle perpendicular to y
+        assert!(glm::distance(&hit.st, &glm::vec2(0.75, 0.75)) < 1e-6);
+        assert!(rect.get_intersection(&Ray::new(&glm::vec3(1.5, 0., 0.), &glm::vec3(0., 1., 0.))).is_none());
+
+        let bounds = rect.get_bounds();
+        assert_eq!((bounds.p_min.y, bounds.p_max.y), (5., 5.));
+        let (p, n) = rect.sample(0.5, 0.25);
+        assert_eq!(p, glm::vec3(-0.5, 5., 0.));
+        assert_eq!(n, glm::vec3(0., -1., 0.));
+    }
+}
@@ -1,16 +1,64 @@
 use crate::scene::Scene;
+use crate::bvh;
+use crate::error::{Error, Result};
 use crate::global::*;
 use crate::ray::*;
+use crate::packet::{Frustum, RayPacket, PACKET_WIDTH};
 
 
 pub trait RenderTrait {
-    fn render(&self, scene: &Scene);
+    fn render(&self, scene: &Scene) -> Result<()>;
 }
 
 pub struct Renderer;
 
+// generate primary ray direction through the center of pixel (i, j)
+pub fn primary_ray(scene: &Scene, i: usize, j: usize) -> Ray {
+    let scale = deg_2_rad(scene.fov * 0.5).tan();
+    let image_aspect_radio = scene.width as f32 / scene.height as f32;
+    let eye_pos = glm::vec4_to_vec3(&(scene.camera_to_world * glm::vec4(0., 0., 0., 1.)));
 
-pub fn output_to_file(path: &String, frame_buffer: &Vec<glm::Vec3>, width: i32, height: i32) {
+    // TODO: Find the x and y positions of the current pixel to get the direction
+    // vector that passes through it.
+    // Also, don't forget to multiply both of them with the variable *scale*, and
+    // x (horizontal) variable with the *imageAspectRatio*
+    let x = (2.0 / scene.width  as f32 * (i as f32 + 0.5) - 1.0f32) * scale * image_aspect_radio;
+    let y = (2.0 / scene.height as f32 * (j as f32 + 0.5) - 1.0f32) * scale * -1.0f32;
+
+    let dir = glm::vec4_to_vec3(&(scene.camera_to_world * glm::vec4(x, y, -1.0, 0.))).normalize();
+    return Ray::new(&eye_pos, &dir);
+}
+
+// [comment]
+// Primary rays of the pixels [i0, i1) x [j0, j1) in row order, with the frustum
+// through the corner pixels.
+// [/comment]
+pub fn primary_packet(scene: &Scene, i0: usize, j0: usize, i1: usize, j1: usize) -> (Vec<Ray>, Frustum) {
+    let mut rays = Vec::with_capacity((i1 - i0) * (j1 - j0));
+    for j in j0..j1 {
+        for i in i0..i1 {
+            rays.push(primary_ray(scene, i, j));
+        }
+    }
+    let corners = [
+        primary_ray(scene, i0, j0).direction,
+        primary_ray(scene, i1 - 1, j0).direction,
+        primary_ray(scene, i1 - 1, j1 - 1).direction,
+        primary_ray(scene, i0, j1 - 1).direction,
+    ];
+    let frustum = Frustum::new(&rays[0].origin, &corners);
+    return (rays, frustum);
+}
+
+// image size the scene can be rendered at
+fn check_size(scene: &Scene) -> Result<()> {
+    if scene.width <= 0 || scene.height <= 0 {
+        return Err(Error::InvalidScene(format!("image size {}x{} is empty", scene.width, scene.height)));
+    }
+    return Ok(());
+}
+
+pub fn output_to_file(path: &String, frame_buffer: &[glm::Vec3], width: i32, height: i32) -> Result<()> {
     let mut u8_d = Vec::<u8>::new();
     u8_d.resize((width * height * 3) as usize, 0);
     for i in 0..(width * height) as usize {
@@ -18,7 +66,8 @@ pub fn output_to_file(path: &String, frame_buffer: &Vec<glm::Vec3>, width: i32,
             u8_d[i * 3 + j] = (frame_buffer[i][j].clamp(0., 1.) * 255.0) as i32 as u8;
         }
     }
-    image::save_buffer(&path, &u8_d, width as u32, height as u32, image::ColorType::Rgb8);
+    image::save_buffer(&path, &u8_d, width as u32, height as u32, image::ColorType::Rgb8)
+        .map_err(|err| Error::Output(format!("failed to write {}: {}", path, err)))?;
     // // write to file
     // let mut fp = File::create(path).unwrap();
     // let head_str = format!("P6\n{} {}\n255\n", width, height);
@@ -30,6 +79,7 @@ pub fn output_to_file(path: &String, frame_buffer: &Vec<glm::Vec3>, width: i32,
     //     }
     //     fp.write(&color3);
     // }
+    return Ok(());
 }
 
 impl RenderTrait for Renderer {
@@ -37,39 +87,192 @@ impl RenderTrait for Renderer {
     // The main render function. This where we iterate over all pixels in the image, generate
     // primary rays and cast these rays into the scene. The content of the framebuffer is
     // saved to a file.
+    // Primary rays are traced as packets of PACKET_WIDTH x PACKET_WIDTH pixels, the
+    // hits are then shaded one by one.
     // [/comment]
-    fn render(&self, scene: &Scene) {
+    fn render(&self, scene: &Scene) -> Result<()> {
+        check_size(scene)?;
         let mut frame_buffer = Vec::<glm::Vec3>::new();
         frame_buffer.resize((scene.width * scene.height) as usize, glm::zero());
 
-        let scale = deg_2_rad(scene.fov * 0.5).tan();
-        let image_aspect_radio = scene.width as f32 / scene.height as f32;
-        let eye_pos = glm::vec3(0., 0., 0.);
-        let mut m = 0;
+        let (width, height) = (scene.width as usize, scene.height as usize);
+        for j0 in (0..height).step_by(PACKET_WIDTH) {
+            for i0 in (0..width).step_by(PACKET_WIDTH) {
+                let (i1, j1) = ((i0 + PACKET_WIDTH).min(width), (j0 + PACKET_WIDTH).min(height));
+                let (rays, frustum) = primary_packet(scene, i0, j0, i1, j1);
+                let hits = scene.get_intersect_packet(&RayPacket::with_frustum(&rays, frustum));
+                for (k, (ray, hit)) in rays.iter().zip(hits).enumerate() {
+                    let (i, j) = (i0 + k % (i1 - i0), j0 + k / (i1 - i0));
+                    frame_buffer[j * width + i] = scene.shade(ray, hit, 0);
+                }
+            }
+        }
 
+       return output_to_file(&"binary.png".to_string(), &frame_buffer, scene.width, scene.height);
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum HeatmapMetric {
+    // Bounds3::intersect_ray tests
+    BoxTests,
+    // primitive get_intersection calls
+    PrimitiveTests,
+    // both of the above
+    Total,
+}
+
+// [comment]
+// Debug renderer that colors every pixel by the traversal work of its primary ray.
+// Counts are mapped from 0 to max_count (the largest count in the frame when None)
+// through color_ramp, a legend with the scale is drawn on the right.
+// [/comment]
+pub struct HeatmapRenderer {
+    pub metric: HeatmapMetric,
+    pub max_count: Option<u64>,
+    pub path: String,
+}
+
+impl HeatmapRenderer {
+    pub fn new(metric: HeatmapMetric) -> HeatmapRenderer {
+        HeatmapRenderer {
+            metric,
+            max_count: None,
+            path: "heatmap.png".to_string(),
+        }
+    }
+
+    pub fn render_frame(&self, scene: &Scene) -> Vec<glm::Vec3> {
+        bvh::enable_traversal_stats();
+        let mut counts = Vec::with_capacity((scene.width * scene.height) as usize);
         for j in 0..scene.height as usize {
             for i in 0..scene.width as usize {
-                // generate primary ray direction
-                // TODO: Find the x and y positions of the current pixel to get the direction
-                // vector that passes through it.
-                // Also, don't forget to multiply both of them with the variable *scale*, and
-                // x (horizontal) variable with the *imageAspectRatio*
-                let x = (2.0 / scene.width  as f32 * (i as f32 + 0.5) - 1.0f32) * scale * image_aspect_radio;
-                let y = (2.0 / scene.height as f32 * (j as f32 + 0.5) - 1.0f32) * scale * -1.0f32;
-
-                let dir = glm::vec3(x, y, -1.0).normalize();
-                frame_buffer[m] = scene.cast_ray(&Ray::new(&eye_pos, &dir), 0);
-                m = m + 1;
+                let cost = scene.cast_ray_cost(&primary_ray(scene, i, j));
+                counts.push(match self.metric {
+                    HeatmapMetric::BoxTests => cost.nodes_visited,
+                    HeatmapMetric::PrimitiveTests => cost.primitives_tested,
+                    HeatmapMetric::Total => cost.nodes_visited + cost.primitives_tested,
+                });
             }
         }
 
-       output_to_file(&"binary.png".to_string(), &frame_buffer, scene.width, scene.height);
+        let max_count = match self.max_count {
+            Some(c) => c,
+            None => counts.iter().cloned().max().unwrap_or(0),
+        }.max(1);
+        let mut frame_buffer: Vec<glm::Vec3> = counts.iter()
+            .map(|&c| color_ramp(c as f32 / max_count as f32))
+            .collect();
+        draw_legend(&mut frame_buffer, scene.width as usize, scene.height as usize, max_count);
+        return frame_buffer;
+    }
+}
+
+impl RenderTrait for HeatmapRenderer {
+    fn render(&self, scene: &Scene) -> Result<()> {
+        check_size(scene)?;
+        let frame_buffer = self.render_frame(scene);
+        return output_to_file(&self.path, &frame_buffer, scene.width, scene.height);
+    }
+}
+
+// [comment]
+// Blue -> cyan -> green -> yellow -> red, t is clamped to [0, 1]
+// [/comment]
+pub fn color_ramp(t: f32) -> glm::Vec3 {
+    const STOPS: [[f32; 3]; 5] = [
+        [0.0, 0.0, 0.5],
+        [0.0, 0.8, 1.0],
+        [0.1, 0.9, 0.1],
+        [1.0, 0.9, 0.0],
+        [0.9, 0.0, 0.0],
+    ];
+    let t = t.clamp(0., 1.) * (STOPS.len() - 1) as f32;
+    let i = (t as usize).min(STOPS.len() - 2);
+    let a = glm::make_vec3(&STOPS[i]);
+    let b = glm::make_vec3(&STOPS[i + 1]);
+    return glm::lerp(&a, &b, t - i as f32);
+}
+
+// 3x5 bitmap digits, one row per entry, highest bit is the left column
+const DIGITS: [[u8; 5]; 10] = [
+    [7, 5, 5, 5, 7], [2, 6, 2, 2, 7], [7, 1, 7, 4, 7], [7, 1, 7, 1, 7], [5, 5, 7, 1, 1],
+    [7, 4, 7, 1, 7], [7, 4, 7, 5, 7], [7, 1, 1, 1, 1], [7, 5, 7, 5, 7], [7, 5, 7, 1, 7],
+];
+const DIGIT_SCALE: usize = 2;
+
+// rect is x, y, w, h, clipped to the frame
+fn fill_rect(fb: &mut [glm::Vec3], width: usize, height: usize,
+             (x, y, w, h): (usize, usize, usize, usize), color: &glm::Vec3) {
+    for j in y..(y + h).min(height) {
+        for i in x..(x + w).min(width) {
+            fb[j * width + i] = *color;
+        }
+    }
+}
+
+// right aligned at x_right, returns the left edge of the text
+fn draw_number(fb: &mut [glm::Vec3], width: usize, height: usize,
+               x_right: usize, y: usize, n: u64) -> usize {
+    let text = n.to_string();
+    let advance = 4 * DIGIT_SCALE;
+    let x0 = x_right.saturating_sub(text.len() * advance);
+    let white = glm::vec3(1., 1., 1.);
+    for (k, ch) in text.bytes().enumerate() {
+        let glyph = &DIGITS[(ch - b'0') as usize];
+        for (row, bits) in glyph.iter().enumerate() {
+            for col in 0..3usize {
+                if bits & (4 >> col) != 0 {
+                    fill_rect(fb, width, height,
+                              (x0 + k * advance + col * DIGIT_SCALE, y + row * DIGIT_SCALE, DIGIT_SCALE, DIGIT_SCALE),
+                              &white);
+                }
+            }
+        }
+    }
+    return x0;
+}
+
+// [comment]
+// Vertical color bar on the right edge, max_count at the top and 0 at the bottom,
+// with the quarter marks labeled.
+// [/comment]
+pub fn draw_legend(fb: &mut [glm::Vec3], width: usize, height: usize, max_count: u64) {
+    let margin = 8usize;
+    let bar_w = 12usize;
+    let bar_h = height * 3 / 5;
+    let label_w = (max_count.to_string().len() * 4 + 1) * DIGIT_SCALE;
+    if width < bar_w + label_w + 3 * margin || bar_h < 5 * 5 * DIGIT_SCALE {
+        return;
+    }
+    let bar_x = width - margin - bar_w;
+    let bar_y = (height - bar_h) / 2;
+
+    // dark backdrop so the labels stay readable over any color
+    let panel_x = bar_x - label_w - margin;
+    fill_rect(fb, width, height, (panel_x - margin / 2, bar_y - margin, width - panel_x, bar_h + 2 * margin),
+              &glm::vec3(0.1, 0.1, 0.1));
+
+    for j in 0..bar_h {
+        let t = 1.0 - j as f32 / (bar_h - 1) as f32;
+        fill_rect(fb, width, height, (bar_x, bar_y + j, bar_w, 1), &color_ramp(t));
+    }
+    for q in 0..=4u64 {
+        let y = bar_y + (bar_h - 1) * (4 - q as usize) / 4;
+        fill_rect(fb, width, height, (bar_x - DIGIT_SCALE * 2, y, DIGIT_SCALE * 2, 1), &glm::vec3(1., 1., 1.));
+        let label_y = (y.saturating_sub(5 * DIGIT_SCALE / 2)).min(height - 5 * DIGIT_SCALE);
+        draw_number(fb, width, height, bar_x - margin, label_y, max_count * q / 4);
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::output_to_file;
+    use super::{color_ramp, output_to_file, HeatmapMetric, HeatmapRenderer, RenderTrait};
+    use crate::error::Error;
+    use crate::material::Material;
+    use crate::object::ObjectTrait;
+    use crate::scene::Scene;
+    use crate::sphere::Sphere;
 
     #[test]
     fn write_to_file() {
@@ -78,9 +281,40 @@ mod tests {
         frame_buffer.push(glm::vec3(0., 1., 0.));
         frame_buffer.push(glm::vec3(0., 0., 1.));
         frame_buffer.push(glm::vec3(1., 1., 0.));
-        output_to_file(&"test.png".to_string(), &frame_buffer, 2, 2);
+        output_to_file(&"test.png".to_string(), &frame_buffer, 2, 2).unwrap();
 
         // let d = std::fs::read(&"test.png".to_string()).unwrap();
         // println!("data:{:?}", d);
     }
+
+    #[test]
+    fn test_heatmap() {
+        assert!(glm::distance(&color_ramp(-1.), &glm::vec3(0., 0., 0.5)) < 1e-6);
+        assert!(glm::distance(&color_ramp(2.), &glm::vec3(0.9, 0., 0.)) < 1e-6);
+
+        let mat = Material::default();
+        let s = Sphere::new(&glm::vec3(0., 0., -5.), 1., &mat);
+        let mut scene = Scene::new(64, 48);
+        scene.add_object(&s as &dyn ObjectTrait);
+        scene.build_accelerator().unwrap();
+
+        let mut r = HeatmapRenderer::new(HeatmapMetric::PrimitiveTests);
+        r.max_count = Some(1);
+        let fb = r.render_frame(&scene);
+        // rays through the sphere bounds test the sphere, rays in the corner do not
+        let center = 24 * 64 + 32;
+        assert!(glm::distance(&fb[center], &color_ramp(1.)) < 1e-6);
+        assert!(glm::distance(&fb[0], &color_ramp(0.)) < 1e-6);
+    }
+
+    #[test]
+    fn test_render_errors() {
+        let scene = Scene::new(0, 48);
+        let result = HeatmapRenderer::new(HeatmapMetric::Total).render(&scene);
+        assert!(matches!(result, Err(Error::InvalidScene(_))));
+
+        let path = std::env::temp_dir().join("game101_5_missing_dir").join("out.png");
+        let result = output_to_file(&path.to_string_lossy().to_string(), &[glm::zero(); 4], 2, 2);
+        assert!(matches!(result, Err(Error::Output(_))));
+    }
 }
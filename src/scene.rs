@@ -2,21 +2,37 @@ use crate::global::*;
 use crate::intersection::IntersectData;
 use crate::object::ObjectTrait;
 use crate::light::Light;
+use crate::area_light::AreaLight;
 use crate::ray::Ray;
-use crate::bvh::{BVHAccel, SplitMethod};
+use crate::accelerator::{build_accelerator, Accelerator, AcceleratorType};
+use crate::bvh::{count_ray, traversal_stats, SplitMethod, TraversalStats};
 use crate::material::*;
+use crate::packet::{keep_nearest, RayPacket};
+use crate::bounds3::Bounds3;
+use crate::error::{Error, Result};
 use std::boxed::Box;
+use rayon::prelude::*;
 
 pub struct Scene<'a> {
     pub width: i32,
     pub height: i32,
+    // vertical field of view in degrees
     pub fov: f32,
+    // the camera looks down -z of its own space
+    pub camera_to_world: glm::Mat4,
     pub background_color: glm::Vec3,
     pub max_depth: i32,
-    pub bvh: Option<Box<BVHAccel<'a>>>,
+    pub accelerator: Option<Box<dyn Accelerator>>,
+    pub accelerator_type: AcceleratorType,
+    pub split_method: SplitMethod,
 
     objects: Vec<&'a dyn ObjectTrait>,
     lights: Vec<Light>,
+    area_lights: Vec<AreaLight<'a>>,
+    // objects in the accelerator by its primitive index, and objects with infinite
+    // bounds such as planes that are tested on every ray
+    bounded: Vec<usize>,
+    unbounded: Vec<usize>,
 }
 
 
@@ -26,11 +42,17 @@ impl<'a> Scene<'a> {
             width,
             height,
             fov: 90.0,
+            camera_to_world: glm::identity(),
             background_color: glm::vec3(0.235294, 0.67451, 0.843137),
             max_depth: 5,
             objects: Vec::new(),
             lights: Vec::new(),
-            bvh: None,
+            area_lights: Vec::new(),
+            bounded: Vec::new(),
+            unbounded: Vec::new(),
+            accelerator: None,
+            accelerator_type: AcceleratorType::BVH,
+            split_method: SplitMethod::SAH,
         }
     }
 
@@ -42,6 +64,10 @@ impl<'a> Scene<'a> {
         return &self.lights;
     }
 
+    pub fn get_area_lights(&self) -> &Vec<AreaLight<'a>> {
+        return &self.area_lights;
+    }
+
     pub fn add_object(&mut self, object: &'a dyn ObjectTrait) {
         self.objects.push(object);
     }
@@ -50,8 +76,94 @@ impl<'a> Scene<'a> {
         self.lights.push(light);
     }
 
+    // [comment]
+    // Only lights the scene, the shape has to be added with add_object as well to
+    // be seen and to cast shadows.
+    // [/comment]
+    pub fn add_area_light(&mut self, light: AreaLight<'a>) {
+        self.area_lights.push(light);
+    }
+
+    // [comment]
+    // Until build_accelerator is called every object is tested.
+    // [/comment]
     pub fn get_intersect(&self, ray: &Ray) -> Option<IntersectData> {
-        return self.bvh.as_ref().unwrap().get_intersection(ray);
+        count_ray();
+        let intersect = |i: usize, r: &Ray| self.objects[i].get_intersection(r);
+        return match &self.accelerator {
+            Some(accelerator) => {
+                let mut nearest = accelerator.get_intersection(ray, &|i, r| intersect(self.bounded[i], r));
+                for &i in &self.unbounded {
+                    if let Some(data) = intersect(i, ray) {
+                        keep_nearest(&mut nearest, data);
+                    }
+                }
+                nearest
+            }
+            None => {
+                let mut nearest = None;
+                for i in 0..self.objects.len() {
+                    if let Some(data) = intersect(i, ray) {
+                        keep_nearest(&mut nearest, data);
+                    }
+                }
+                nearest
+            }
+        };
+    }
+
+    // [comment]
+    // Whether anything lies on the ray before t_max, used for shadow rays.
+    // [/comment]
+    pub fn occluded(&self, ray: &Ray, t_max: f32) -> bool {
+        count_ray();
+        let occluded = |i: usize, r: &Ray, t: f32| self.objects[i].occluded(r, t);
+        return match &self.accelerator {
+            Some(accelerator) => {
+                self.unbounded.iter().any(|&i| occluded(i, ray, t_max))
+                    || accelerator.occluded(ray, t_max, &|i, r, t| occluded(self.bounded[i], r, t))
+            }
+            None => (0..self.objects.len()).any(|i| occluded(i, ray, t_max)),
+        };
+    }
+
+    // [comment]
+    // Nearest hit of every ray in the packet, traced together through the scene and
+    // mesh accelerators.
+    // [/comment]
+    pub fn get_intersect_packet(&self, packet: &RayPacket) -> Vec<Option<IntersectData<'_>>> {
+        for _ in packet.rays {
+            count_ray();
+        }
+        let mut hits: Vec<Option<IntersectData>> = packet.rays.iter().map(|_| None).collect();
+        let active = vec![true; packet.len()];
+        let intersect = |i: usize, p: &RayPacket, mask: &[bool], h: &mut [Option<IntersectData<'a>>]| {
+            self.objects[i].get_intersection_packet(p, mask, h)
+        };
+        match &self.accelerator {
+            Some(accelerator) => {
+                accelerator.get_intersection_packet(packet, &active, &|i, p, mask, h| intersect(self.bounded[i], p, mask, h), &mut hits);
+                for &i in &self.unbounded {
+                    intersect(i, packet, &active, &mut hits);
+                }
+            }
+            None => {
+                for i in 0..self.objects.len() {
+                    intersect(i, packet, &active, &mut hits);
+                }
+            }
+        }
+        return hits;
+    }
+
+    // [comment]
+    // Debug integrator used by the heatmap renderer. Nothing is shaded, the result is
+    // the number of bounding box tests and primitive intersection calls the ray needed.
+    // [/comment]
+    pub fn cast_ray_cost(&self, ray: &Ray) -> TraversalStats {
+        let before = traversal_stats();
+        self.get_intersect(ray);
+        return traversal_stats().since(&before);
     }
 
     pub fn cast_ray(&self, ray: &Ray, depth: i32
@@ -59,12 +171,19 @@ impl<'a> Scene<'a> {
         if depth > self.max_depth {
             return glm::zero();
         }
+        return self.shade(ray, self.get_intersect(ray), depth);
+    }
 
-        let inter_opt = self.get_intersect(ray);
+    // [comment]
+    // Color seen along `ray` given its nearest hit, secondary rays are traced one
+    // by one with cast_ray.
+    // [/comment]
+    pub fn shade(&self, ray: &Ray, inter_opt: Option<IntersectData>, depth: i32
+    ) -> glm::Vec3 {
         let mut hit_color = self.background_color.clone();
         if let Some(inter) = inter_opt {
             let mut hit_point = inter.coords;
-            let mut n = inter.normal;
+            let mut n = inter.shading_normal;
             let mut st = inter.st;
             match inter.m.get_type() {
                 MaterialType::REFLECTION_AND_REFRACTION => {
@@ -73,17 +192,9 @@ impl<'a> Scene<'a> {
                     let refraction_direction 
                         = glm::normalize(&refract(&ray.direction, &n, inter.m.ior));
                     let reflection_ray_orig 
-                        = if glm::dot(&reflection_direction, &n) < 0. {
-                            hit_point - n * EPSILON
-                        } else {
-                            hit_point + n * EPSILON
-                        };
+                        = offset_ray_origin(&hit_point, &inter.p_error, &inter.normal, &reflection_direction);
                     let refraction_ray_orig 
-                        = if glm::dot(&refraction_direction, &n) < 0. {
-                            hit_point - n * EPSILON 
-                        } else {
-                            hit_point + n *EPSILON 
-                        };
+                        = offset_ray_origin(&hit_point, &inter.p_error, &inter.normal, &refraction_direction);
 
                     let reflection_color 
                         = self.cast_ray(
@@ -104,11 +215,7 @@ impl<'a> Scene<'a> {
                     let reflection_direction 
                         = glm::normalize(&reflect(&ray.direction, &n));
                     let reflection_ray_orig 
-                        = if glm::dot(&reflection_direction, &n) < 0. {
-                            hit_point + n * EPSILON 
-                        } else {
-                            hit_point - n * EPSILON
-                        };
+                        = offset_ray_origin(&hit_point, &inter.p_error, &inter.normal, &reflection_direction);
                     let reflection_color 
                         = self.cast_ray(
                             &Ray::new(&reflection_ray_orig, &reflection_direction), 
@@ -123,12 +230,9 @@ impl<'a> Scene<'a> {
                     // [/comment]
                     let mut light_amt = glm::vec3(0., 0., 0.);
                     let mut specular_color = glm::vec3(0., 0., 0.);
+                    // shadow rays leave on the side the surface is seen from
                     let shadow_point_orig 
-                        = if glm::dot(&ray.direction, &n) < 0. {
-                            hit_point + n * EPSILON
-                        } else {
-                            hit_point - n * EPSILON
-                        };
+                        = offset_ray_origin(&hit_point, &inter.p_error, &inter.normal, &-ray.direction);
                     // [comment]
                     // Loop over all lights in the scene and sum their contribution up
                     // We also apply the lambert cosine law
@@ -138,9 +242,11 @@ impl<'a> Scene<'a> {
                         let light_distance2 = glm::dot(&light_dir, &light_dir);
                         let light_dir = light_dir.normalize();
                         let LdotN = glm::dot(&light_dir, &n).max(0.0f32);
-                        let in_shadow = self.get_intersect(
+                        // occluders behind the light cast no shadow
+                        let in_shadow = self.occluded(
                             &Ray::new(&shadow_point_orig, &light_dir),
-                        ).is_some() as i32 as f32;
+                            light_distance2.sqrt(),
+                        ) as i32 as f32;
                         let _light_amt = (1.0 - in_shadow) * light.intensity * LdotN;
                         light_amt += _light_amt;
                         let reflection_direction = reflect(&-light_dir, &n);
@@ -148,6 +254,34 @@ impl<'a> Scene<'a> {
                             .max(0.)
                             .powf(inter.m.specular_exponent) * light.intensity;
                     }
+                    // [comment]
+                    // Area lights are summed as point lights on their samples, each
+                    // weighted by the cosine at the emitter so the back is dark.
+                    // [/comment]
+                    for area_light in self.get_area_lights() {
+                        let samples = area_light.sample_points();
+                        let sample_intensity = area_light.intensity / samples.len() as f32;
+                        for (light_point, light_normal) in samples {
+                            let light_dir = light_point - hit_point;
+                            let light_distance = light_dir.norm();
+                            let light_dir = light_dir / light_distance;
+                            let emitted = glm::dot(&light_normal, &-light_dir).max(0.);
+                            let l_dot_n = glm::dot(&light_dir, &n).max(0.0f32);
+                            if emitted == 0. || l_dot_n == 0. {
+                                continue;
+                            }
+                            // stop short of the emitter so it does not shadow itself
+                            if self.occluded(&Ray::new(&shadow_point_orig, &light_dir), light_distance * 0.9999) {
+                                continue;
+                            }
+                            let intensity = sample_intensity * emitted;
+                            light_amt += intensity * l_dot_n;
+                            let reflection_direction = reflect(&-light_dir, &n);
+                            specular_color += (-glm::dot(&reflection_direction, &ray.direction))
+                                .max(0.)
+                                .powf(inter.m.specular_exponent) * intensity;
+                        }
+                    }
 
                     hit_color = 
                         inter.eval_diffuse_color * inter.m.Kd + specular_color * inter.m.Ks;
@@ -158,15 +292,130 @@ impl<'a> Scene<'a> {
                     );
                 }
             }
+            // emitters are seen at their own brightness from the front
+            if inter.front_face {
+                hit_color += inter.m.get_emission();
+            }
         }
 
         return hit_color;
     }
 
-    // private
-    fn build_bvh(&mut self) {
-        let mut bvh = BVHAccel::new(self.objects.clone(), 1, SplitMethod::NAIVE);
-        self.bvh = Some(Box::new(bvh))
+    // [comment]
+    // Objects with infinite bounds, e.g. planes, are kept out of the accelerator
+    // and tested on every ray. Fails when an object has empty or NaN bounds, e.g.
+    // a mesh without vertices, since no accelerator can sort it.
+    // [/comment]
+    pub fn build_accelerator(&mut self) -> Result<()> {
+        let all_bounds: Vec<Bounds3> = self.objects.par_iter().map(|obj| obj.get_bounds()).collect();
+        if let Some(i) = all_bounds.iter().position(|b| b.is_empty()) {
+            return Err(Error::InvalidScene(format!("object {} has empty bounds", i)));
+        }
+        self.bounded.clear();
+        self.unbounded.clear();
+        let mut bounds = Vec::with_capacity(all_bounds.len());
+        for (i, b) in all_bounds.into_iter().enumerate() {
+            if b.is_finite() {
+                self.bounded.push(i);
+                bounds.push(b);
+            } else {
+                self.unbounded.push(i);
+            }
+        }
+        self.accelerator = Some(build_accelerator(self.accelerator_type, bounds, 1, self.split_method));
+        return Ok(());
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::area_light::AreaLight;
+    use crate::material::Material;
+    use crate::object::ObjectTrait;
+    use crate::plane::Plane;
+    use crate::ray::Ray;
+    use crate::rectangle::Rectangle;
+    use crate::packet::RayPacket;
+    use crate::scene::Scene;
+    use crate::sdf::{Sdf, SdfObject};
+    use crate::sphere::Sphere;
+    use crate::triangle::MeshTriangle;
+
+    #[test]
+    fn test_unbounded_objects_and_area_light() {
+        let floor_mat = Material::default();
+        let light_mat = Material::new(None, None, Some(glm::vec3(2., 2., 2.)));
+        let floor = Plane::new(&glm::vec3(0., -1., 0.), &glm::vec3(0., 1., 0.), &floor_mat);
+        let light = Rectangle::new(&glm::vec3(-1., 3., -6.), &glm::vec3(1., 3., -4.), &light_mat).flip_normal();
+        // hides the whole light from the floor at x = 3.5, z = -5
+        let blocker = Sphere::new(&glm::vec3(2., 1., -5.), 1., &floor_mat);
+
+        let mut scene = Scene::new(16, 16);
+        scene.add_object(&floor as &dyn ObjectTrait);
+        scene.add_object(&light as &dyn ObjectTrait);
+        scene.add_object(&blocker as &dyn ObjectTrait);
+        scene.add_area_light(AreaLight::new(&light, &glm::vec3(1., 1., 1.)));
+        scene.build_accelerator().unwrap();
+
+        // the plane is found next to the accelerator, by single rays and shadow rays
+        let down = Ray::new(&glm::vec3(0., 0., -5.), &glm::vec3(0., -1., 0.));
+        assert!((scene.get_intersect(&down).unwrap().distance - 1.).abs() < 1e-5);
+        assert!(scene.occluded(&down, 2.));
+        assert!(!scene.occluded(&down, 0.5));
+
+        let lit = scene.cast_ray(&down, 0);
+        assert!(lit.x > 0.);
+        let far = scene.cast_ray(&Ray::new(&glm::vec3(40., 0., -5.), &glm::vec3(0., -1., 0.)), 0);
+        assert!(far.x < lit.x);
+        let shadowed = scene.cast_ray(&Ray::new(&glm::vec3(3.5, 0., -5.), &glm::vec3(0., -1., 0.)), 0);
+        let mirrored = scene.cast_ray(&Ray::new(&glm::vec3(-3.5, 0., -5.), &glm::vec3(0., -1., 0.)), 0);
+        // the floor has the default material, which adds no emission in the shadow
+        assert_eq!(shadowed.x, 0.);
+        assert!(mirrored.x > 0.);
+
+        // the emitter is seen at its emission from below but not from above
+        let up = scene.cast_ray(&Ray::new(&glm::vec3(0., 0., -5.), &glm::vec3(0., 1., 0.)), 0);
+        assert!(up.x >= 2.);
+        let above = scene.cast_ray(&Ray::new(&glm::vec3(0., 5., -5.), &glm::vec3(0., -1., 0.)), 0);
+        assert_eq!(above.x, 0.);
     }
 
+    #[test]
+    fn test_sdf_with_meshes() {
+        let mat = Material::default();
+        // a floor quad with its own BVH and a blobby SDF resting on it
+        let floor = MeshTriangle::new(
+            vec![glm::vec3(-5., -1., -10.), glm::vec3(5., -1., -10.), glm::vec3(5., -1., 0.), glm::vec3(-5., -1., 0.)],
+            vec![glm::vec2(0., 0.); 4],
+            vec![0, 2, 1, 0, 3, 2],
+            &mat,
+        ).unwrap();
+        let blob = SdfObject::new(
+            Sdf::sphere(&glm::vec3(-0.6, 0., -5.), 1.).smooth_union(Sdf::sphere(&glm::vec3(0.6, 0., -5.), 1.), 0.5),
+            &mat,
+        );
+        let mut scene = Scene::new(16, 16);
+        scene.add_object(&floor as &dyn ObjectTrait);
+        scene.add_object(&blob as &dyn ObjectTrait);
+        scene.build_accelerator().unwrap();
+
+        let rays = [
+            Ray::new(&glm::vec3(0., 0., 0.), &glm::vec3(0., 0., -1.)),
+            Ray::new(&glm::vec3(0., 0., 0.), &glm::vec3(0., -1., -3.).normalize()),
+            Ray::new(&glm::vec3(3., 0., 0.), &glm::vec3(0., -1., -1.).normalize()),
+        ];
+        let blob_hit = scene.get_intersect(&rays[0]).unwrap();
+        assert!(blob_hit.distance > 3.9 && blob_hit.distance < 4.2, "{}", blob_hit.distance);
+        assert!(glm::distance(&blob_hit.normal, &glm::vec3(0., 0., 1.)) < 1e-2);
+        // under the blob the floor is in its shadow, beside it the floor is hit
+        let floor_hit = scene.get_intersect(&rays[2]).unwrap();
+        assert!((floor_hit.coords.y + 1.).abs() < 1e-5);
+        assert!(scene.occluded(&Ray::new(&glm::vec3(0., -0.99, -5.), &glm::vec3(0., 1., 0.)), 10.));
+
+        let packet_hits = scene.get_intersect_packet(&RayPacket::new(&rays));
+        for (ray, hit) in rays.iter().zip(packet_hits.iter()) {
+            assert_eq!(hit.as_ref().map(|h| h.distance), scene.get_intersect(ray).map(|h| h.distance));
+        }
+    }
 }
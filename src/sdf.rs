@@ -0,0 +1,358 @@
+use crate::bounds3::Bounds3;
+use crate::intersection::IntersectData;
+use crate::material::Material;
+use crate::object::ObjectTrait;
+use crate::ray::Ray;
+
+// [comment]
+// Signed distance field built from primitives and operations on them, negative
+// inside. Trees are built with the constructors and the chained operations, e.g.
+// Sdf::sphere(&c, 1.).smooth_union(Sdf::cuboid(&c, &h), 0.2).
+// The smooth operations and the repetition only give a lower bound of the true
+// distance, which is all sphere tracing needs.
+// [/comment]
+pub enum Sdf {
+    Sphere { center: glm::Vec3, radius: f32 },
+    Cuboid { center: glm::Vec3, half_size: glm::Vec3 },
+    // around the z axis through center, like the Torus shape
+    Torus { center: glm::Vec3, major_radius: f32, minor_radius: f32 },
+    Capsule { a: glm::Vec3, b: glm::Vec3, radius: f32 },
+    Translate(Box<Sdf>, glm::Vec3),
+    // grows the surface by radius and rounds its edges
+    Round(Box<Sdf>, f32),
+    Union(Box<Sdf>, Box<Sdf>),
+    Intersection(Box<Sdf>, Box<Sdf>),
+    // the first minus the second
+    Subtraction(Box<Sdf>, Box<Sdf>),
+    SmoothUnion(Box<Sdf>, Box<Sdf>, f32),
+    SmoothSubtraction(Box<Sdf>, Box<Sdf>, f32),
+    // copies every spacing[k] along axis k, count[k] times on each side of the
+    // original, axes with a spacing of 0 are not repeated. The child has to fit
+    // in its cell for the distance to stay a bound.
+    Repeat(Box<Sdf>, glm::Vec3, [u32; 3]),
+}
+
+impl Sdf {
+    pub fn sphere(center: &glm::Vec3, radius: f32) -> Sdf {
+        return Sdf::Sphere { center: *center, radius };
+    }
+
+    pub fn cuboid(center: &glm::Vec3, half_size: &glm::Vec3) -> Sdf {
+        return Sdf::Cuboid { center: *center, half_size: *half_size };
+    }
+
+    pub fn torus(center: &glm::Vec3, major_radius: f32, minor_radius: f32) -> Sdf {
+        return Sdf::Torus { center: *center, major_radius, minor_radius };
+    }
+
+    pub fn capsule(a: &glm::Vec3, b: &glm::Vec3, radius: f32) -> Sdf {
+        return Sdf::Capsule { a: *a, b: *b, radius };
+    }
+
+    pub fn translate(self, delta: &glm::Vec3) -> Sdf {
+        return Sdf::Translate(Box::new(self), *delta);
+    }
+
+    pub fn round(self, radius: f32) -> Sdf {
+        return Sdf::Round(Box::new(self), radius);
+    }
+
+    pub fn union(self, other: Sdf) -> Sdf {
+        return Sdf::Union(Box::new(self), Box::new(other));
+    }
+
+    pub fn intersection(self, other: Sdf) -> Sdf {
+        return Sdf::Intersection(Box::new(self), Box::new(other));
+    }
+
+    pub fn subtract(self, other: Sdf) -> Sdf {
+        return Sdf::Subtraction(Box::new(self), Box::new(other));
+    }
+
+    // \param k: size of the blend between the two
+    pub fn smooth_union(self, other: Sdf, k: f32) -> Sdf {
+        return Sdf::SmoothUnion(Box::new(self), Box::new(other), k);
+    }
+
+    pub fn smooth_subtract(self, other: Sdf, k: f32) -> Sdf {
+        return Sdf::SmoothSubtraction(Box::new(self), Box::new(other), k);
+    }
+
+    pub fn repeat(self, spacing: &glm::Vec3, count: [u32; 3]) -> Sdf {
+        return Sdf::Repeat(Box::new(self), *spacing, count);
+    }
+
+    pub fn distance(&self, p: &glm::Vec3) -> f32 {
+        return match self {
+            Sdf::Sphere { center, radius } => glm::distance(p, center) - radius,
+            Sdf::Cuboid { center, half_size } => {
+                let q = glm::abs(&(p - center)) - half_size;
+                glm::length(&glm::max(&q, 0.)) + glm::comp_max(&q).min(0.)
+            }
+            Sdf::Torus { center, major_radius, minor_radius } => {
+                let q = p - center;
+                let ring = (q.x * q.x + q.y * q.y).sqrt() - major_radius;
+                (ring * ring + q.z * q.z).sqrt() - minor_radius
+            }
+            Sdf::Capsule { a, b, radius } => {
+                let (pa, ba) = (p - a, b - a);
+                let len2 = glm::dot(&ba, &ba);
+                let h = if len2 > 0. { (glm::dot(&pa, &ba) / len2).clamp(0., 1.) } else { 0. };
+                glm::length(&(pa - ba * h)) - radius
+            }
+            Sdf::Translate(child, delta) => child.distance(&(p - delta)),
+            Sdf::Round(child, radius) => child.distance(p) - radius,
+            Sdf::Union(a, b) => a.distance(p).min(b.distance(p)),
+            Sdf::Intersection(a, b) => a.distance(p).max(b.distance(p)),
+            Sdf::Subtraction(a, b) => a.distance(p).max(-b.distance(p)),
+            Sdf::SmoothUnion(a, b, k) => smooth_min(a.distance(p), b.distance(p), *k),
+            Sdf::SmoothSubtraction(a, b, k) => -smooth_min(-a.distance(p), b.distance(p), *k),
+            Sdf::Repeat(child, spacing, count) => {
+                let mut q = *p;
+                for k in 0..3 {
+                    if spacing[k] > 0. {
+                        let n = count[k] as f32;
+                        q[k] -= spacing[k] * (p[k] / spacing[k]).round().clamp(-n, n);
+                    }
+                }
+                child.distance(&q)
+            }
+        };
+    }
+
+    // [comment]
+    // Box the surface lies in. Smooth unions bulge by at most k / 4 between their
+    // children, subtractions and intersections never reach past their first child.
+    // [/comment]
+    pub fn bounds(&self) -> Bounds3 {
+        return match self {
+            Sdf::Sphere { center, radius } => {
+                let r = glm::vec3(*radius, *radius, *radius);
+                Bounds3::new(&(center - r), &(center + r))
+            }
+            Sdf::Cuboid { center, half_size } => Bounds3::new(&(center - half_size), &(center + half_size)),
+            Sdf::Torus { center, major_radius, minor_radius } => {
+                let outer = major_radius + minor_radius;
+                let r = glm::vec3(outer, outer, *minor_radius);
+                Bounds3::new(&(center - r), &(center + r))
+            }
+            Sdf::Capsule { a, b, radius } => {
+                let r = glm::vec3(*radius, *radius, *radius);
+                Bounds3::union(&Bounds3::new(&(a - r), &(a + r)), &Bounds3::new(&(b - r), &(b + r)))
+            }
+            Sdf::Translate(child, delta) => {
+                let b = child.bounds();
+                Bounds3::new(&(b.p_min + delta), &(b.p_max + delta))
+            }
+            Sdf::Round(child, radius) => expand(&child.bounds(), *radius),
+            Sdf::Union(a, b) => Bounds3::union(&a.bounds(), &b.bounds()),
+            Sdf::Intersection(a, b) => a.bounds().intersect(&b.bounds()),
+            Sdf::Subtraction(a, _) | Sdf::SmoothSubtraction(a, _, _) => a.bounds(),
+            Sdf::SmoothUnion(a, b, k) => expand(&Bounds3::union(&a.bounds(), &b.bounds()), k * 0.25),
+            Sdf::Repeat(child, spacing, count) => {
+                let b = child.bounds();
+                let reach = glm::vec3(
+                    spacing.x.max(0.) * count[0] as f32,
+                    spacing.y.max(0.) * count[1] as f32,
+                    spacing.z.max(0.) * count[2] as f32,
+                );
+                Bounds3::new(&(b.p_min - reach), &(b.p_max + reach))
+            }
+        };
+    }
+
+    // [comment]
+    // Normalized gradient by central differences of size h, the surface normal on
+    // the surface.
+    // [/comment]
+    pub fn normal(&self, p: &glm::Vec3, h: f32) -> glm::Vec3 {
+        let mut n = glm::vec3(0., 0., 0.);
+        for k in 0..3 {
+            let mut dp = glm::vec3(0., 0., 0.);
+            dp[k] = h;
+            n[k] = self.distance(&(p + dp)) - self.distance(&(p - dp));
+        }
+        return if glm::length2(&n) > 0. { n.normalize() } else { glm::vec3(0., 0., 1.) };
+    }
+}
+
+// polynomial smooth minimum, lies below min(a, b) by up to k / 4
+fn smooth_min(a: f32, b: f32, k: f32) -> f32 {
+    if k <= 0. {
+        return a.min(b);
+    }
+    let h = (0.5 + 0.5 * (b - a) / k).clamp(0., 1.);
+    return b + (a - b) * h - k * h * (1. - h);
+}
+
+fn expand(b: &Bounds3, r: f32) -> Bounds3 {
+    let r = glm::vec3(r, r, r);
+    return Bounds3::new(&(b.p_min - r), &(b.p_max + r));
+}
+
+// [comment]
+// Object rendered by sphere tracing its field inside the bounds of the field. A
+// ray stops when the distance is below epsilon and gives up after max_steps. Hits
+// have no texture coordinates, so textures show their st = (0, 0) color.
+// [/comment]
+pub struct SdfObject<'a> {
+    pub sdf: Sdf,
+    pub bounding_box: Bounds3,
+    pub epsilon: f32,
+    pub max_steps: u32,
+    pub m: &'a Material,
+}
+
+impl<'a> SdfObject<'a> {
+    pub fn new(sdf: Sdf, m: &'a Material) -> SdfObject<'a> {
+        let epsilon = 1e-4;
+        SdfObject {
+            bounding_box: expand(&sdf.bounds(), epsilon),
+            sdf,
+            epsilon,
+            max_steps: 256,
+            m,
+        }
+    }
+}
+
+impl<'a> ObjectTrait for SdfObject<'a> {
+    // [comment]
+    // Steps are taken on |distance| so rays that start inside, e.g. refraction
+    // rays, march to where they leave the surface. The step is divided by the
+    // length of the direction, rays of TransformedObject are not normalized.
+    // [/comment]
+    fn get_intersection(&self, ray: &Ray) -> Option<IntersectData<'_>> {
+        let (t_enter, t_exit) = self.bounding_box.ray_interval(ray)?;
+        let inv_len = 1. / glm::length(&ray.direction);
+        let mut t = t_enter;
+        let mut found = false;
+        for _ in 0..self.max_steps {
+            let d = self.sdf.distance(&(ray.origin + ray.direction * t)).abs();
+            if d < self.epsilon {
+                found = true;
+                break;
+            }
+            t += d * inv_len;
+            if t > t_exit {
+                break;
+            }
+        }
+        if !found {
+            return None;
+        }
+
+        let coords = ray.origin + ray.direction * t;
+        let normal = self.sdf.normal(&coords, self.epsilon);
+        let st = glm::vec2(0., 0.);
+        // the hit is only known to within epsilon, shadow rays have to start further
+        // off the surface than that to not find it again
+        let e = self.epsilon * 4.;
+        return Some(IntersectData {
+            coords,
+            normal,
+            shading_normal: normal,
+            p_error: glm::vec3(e, e, e),
+            barycentric: glm::zero(),
+            front_face: glm::dot(&ray.direction, &normal) < 0.,
+            distance: t,
+            index: u32::MAX,
+            m: self.m,
+            eval_diffuse_color: self.m.eval_diffuse_color(&st),
+            uv: glm::zero(),
+            st,
+        });
+    }
+
+    fn get_bounds(&self) -> Bounds3 {
+        return self.bounding_box.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::material::Material;
+    use crate::object::ObjectTrait;
+    use crate::ray::Ray;
+    use crate::sdf::{Sdf, SdfObject};
+
+    #[test]
+    fn test_sdf_distances() {
+        let origin = glm::vec3(0., 0., 0.);
+        let ball = Sdf::sphere(&origin, 1.);
+        assert_eq!(ball.distance(&glm::vec3(3., 0., 0.)), 2.);
+        let cube = Sdf::cuboid(&origin, &glm::vec3(1., 1., 1.));
+        assert_eq!(cube.distance(&glm::vec3(0., 0.5, 0.)), -0.5);
+        assert!((cube.distance(&glm::vec3(2., 2., 0.)) - 2f32.sqrt()).abs() < 1e-6);
+        assert!((Sdf::torus(&origin, 2., 0.5).distance(&glm::vec3(0., 2., 1.5)) - 1.).abs() < 1e-6);
+        let capsule = Sdf::capsule(&origin, &glm::vec3(0., 0., 2.), 0.5);
+        assert_eq!(capsule.distance(&glm::vec3(1., 0., 1.)), 0.5);
+
+        // a hole through the cube, and a blend that bulges between two balls
+        let holed = Sdf::cuboid(&origin, &glm::vec3(1., 1., 1.)).subtract(Sdf::sphere(&origin, 0.5));
+        assert_eq!(holed.distance(&origin), 0.5);
+        let two = |k| Sdf::sphere(&glm::vec3(-1.2, 0., 0.), 1.).smooth_union(Sdf::sphere(&glm::vec3(1.2, 0., 0.), 1.), k);
+        let gap = glm::vec3(0., 0., 0.);
+        assert!(two(0.).distance(&gap) > 0. && two(1.).distance(&gap) < 0.);
+        assert!(two(1.).bounds().p_max.x > 2.4);
+
+        // copies repeat the distance up to the count and not past it
+        let row = Sdf::sphere(&origin, 0.5).repeat(&glm::vec3(2., 0., 0.), [2, 0, 0]);
+        assert!((row.distance(&glm::vec3(4., 0., 0.)) + 0.5).abs() < 1e-6);
+        assert!((row.distance(&glm::vec3(6., 0., 0.)) - 1.5).abs() < 1e-6);
+        assert_eq!(row.bounds().p_max, glm::vec3(4.5, 0.5, 0.5));
+
+        let normal = ball.translate(&glm::vec3(0., 0., 2.)).normal(&glm::vec3(0., 1., 2.), 1e-3);
+        assert!(glm::distance(&normal, &glm::vec3(0., 1., 0.)) < 1e-3);
+    }
+
+    #[test]
+    fn test_sdf_operations() {
+        let left = || Sdf::sphere(&glm::vec3(-0.5, 0., 0.), 1.);
+        let right = || Sdf::sphere(&glm::vec3(0.5, 0., 0.), 1.);
+        let p = glm::vec3(-1.25, 0., 0.);
+
+        // p is inside the left ball only
+        let union = left().union(right());
+        assert_eq!(union.distance(&p), -0.25);
+        assert_eq!(union.bounds().p_min, glm::vec3(-1.5, -1., -1.));
+        assert_eq!(union.bounds().p_max, glm::vec3(1.5, 1., 1.));
+        let lens = left().intersection(right());
+        assert_eq!(lens.distance(&p), 0.75);
+        assert_eq!(lens.distance(&glm::vec3(0., 0., 0.)), -0.5);
+        assert_eq!(lens.bounds().p_min, glm::vec3(-0.5, -1., -1.));
+        assert_eq!(lens.bounds().p_max, glm::vec3(0.5, 1., 1.));
+
+        // without a blend the smooth subtraction is the plain one, with a blend it
+        // cuts deeper and keeps the bounds of the first
+        let bite = |k| left().smooth_subtract(right(), k);
+        let q = glm::vec3(-1., 0., 0.);
+        assert_eq!(bite(0.).distance(&q), left().subtract(right()).distance(&q));
+        assert!(bite(0.5).distance(&q) > bite(0.).distance(&q));
+        assert_eq!(bite(0.5).bounds().p_max, left().bounds().p_max);
+
+        // the smooth union bulges by k / 4 between its children, the bounds grow by as much
+        let blob = left().smooth_union(right(), 1.);
+        let top = glm::vec3(0., 0.866_025_4, 0.);
+        assert!((blob.distance(&top) + 0.25).abs() < 1e-3, "{}", blob.distance(&top));
+        assert_eq!(blob.bounds().p_max, glm::vec3(1.75, 1.25, 1.25));
+    }
+
+    #[test]
+    fn test_sdf_object() {
+        let mat = Material::default();
+        let rounded = SdfObject::new(Sdf::cuboid(&glm::vec3(0., 0., -5.), &glm::vec3(1., 1., 1.)).round(0.25), &mat);
+
+        let hit = rounded.get_intersection(&Ray::new(&glm::vec3(0., 0., 0.), &glm::vec3(0., 0., -1.))).unwrap();
+        assert!((hit.distance - 3.75).abs() < 1e-3, "{}", hit.distance);
+        assert!(glm::distance(&hit.normal, &glm::vec3(0., 0., 1.)) < 1e-3);
+        assert!(hit.front_face);
+        // past the rounded corner, and from inside to the far side
+        assert!(rounded.get_intersection(&Ray::new(&glm::vec3(1.2, 1.2, 0.), &glm::vec3(0., 0., -1.))).is_none());
+        let inside = rounded.get_intersection(&Ray::new(&glm::vec3(0., 0., -5.), &glm::vec3(1., 0., 0.))).unwrap();
+        assert!((inside.distance - 1.25).abs() < 1e-3 && !inside.front_face);
+        // a shadow ray leaving the surface does not find it again
+        let origin = crate::global::offset_ray_origin(&hit.coords, &hit.p_error, &hit.normal, &glm::vec3(0., 0., 1.));
+        assert!(rounded.get_intersection(&Ray::new(&origin, &glm::vec3(0., 0., 1.))).is_none());
+    }
+}
@@ -3,6 +3,8 @@ use crate::global::*;
 use crate::object::ObjectTrait;
 use crate::material::{Material};
 use crate::intersection::IntersectData;
+use crate::csg::{Solid, Span};
+use crate::ray::Ray;
 
 pub struct Sphere<'a> {
     pub center          : glm::Vec3,
@@ -20,10 +22,9 @@ impl<'a> Sphere<'a> {
             m,
         }
     }
-}
 
-impl<'a> ObjectTrait for Sphere<'a> {
-    fn get_intersection(&self, ray: &crate::ray::Ray) -> Option<IntersectData> {
+    // both roots along the whole line of the ray, ignoring its interval
+    fn roots(&self, ray: &Ray) -> Option<(f32, f32)> {
         let L = ray.origin - self.center;
         let a = glm::dot(&ray.direction, &ray.direction);
         let b = 2.0 * glm::dot(&ray.direction, &L);
@@ -33,22 +34,42 @@ impl<'a> ObjectTrait for Sphere<'a> {
         if !solve_quadratic(a, b, c, &mut t0, &mut t1) {
             return None;
         }
-        if t0 < 0.0 { t0 = t1; }
-        if t0 < 0.0 { return None;}
-
-        let coords = ray.origin + t0 * ray.direction;
+        return Some((t0, t1));
+    }
 
+    fn hit_data(&self, ray: &Ray, t: f32) -> IntersectData<'_> {
+        // reproject onto the surface, the error of the refined point only depends on
+        // its distance to the center
+        let local = ray.origin + t * ray.direction - self.center;
+        let local = local * (self.radius / glm::length(&local));
+        let coords = self.center + local;
+        let p_error = glm::abs(&local) * gamma(5) + glm::abs(&coords) * gamma(1);
+        let normal = local / self.radius;
 
-        return Some(IntersectData {
+        return IntersectData {
             coords: coords.clone(),
-            normal: (coords - self.center).normalize(),
-            distance: t0,
+            normal,
+            shading_normal: normal,
+            p_error,
+            barycentric: glm::zero(),
+            front_face: glm::dot(&ray.direction, &normal) < 0.,
+            distance: t,
             index: u32::MAX,
             m: self.m,
             eval_diffuse_color: self.m.get_color(),
             uv: glm::zero(),
             st: glm::zero(),
-        });
+        };
+    }
+}
+
+impl<'a> ObjectTrait for Sphere<'a> {
+    fn get_intersection(&self, ray: &Ray) -> Option<IntersectData<'_>> {
+        let (mut t0, t1) = self.roots(ray)?;
+        // nearest root inside the ray interval
+        if !ray.in_range(t0) { t0 = t1; }
+        if !ray.in_range(t0) { return None; }
+        return Some(self.hit_data(ray, t0));
     }
 
     fn get_bounds(&self) -> Bounds3 {
@@ -61,6 +82,15 @@ impl<'a> ObjectTrait for Sphere<'a> {
 
 }
 
+impl<'a> Solid for Sphere<'a> {
+    fn get_spans(&self, ray: &Ray) -> Vec<Span<'_>> {
+        return match self.roots(ray) {
+            Some((t0, t1)) => vec![Span { enter: self.hit_data(ray, t0), exit: self.hit_data(ray, t1) }],
+            None => Vec::new(),
+        };
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::material;
@@ -88,6 +118,21 @@ mod tests {
         );
         let inter = s.get_intersection(&ray).unwrap();
         assert!(f32::abs(inter.distance - (3.0_f32.sqrt() - 1.0)) < 0.001, format!("{}", inter.distance));
+
+        // skipping the near side finds the far side, a short segment finds nothing
+        let far = 3.0_f32.sqrt() + 1.0;
+        let ray = Ray::segment(&glm::vec3(0., 0., 0.), &glm::vec3(1., 1., 1.).normalize(), 1., f32::MAX);
+        let inter = s.get_intersection(&ray).unwrap();
+        assert!(f32::abs(inter.distance - far) < 0.001, "{}", inter.distance);
+        let ray = Ray::segment(&glm::vec3(0., 0., 0.), &glm::vec3(1., 1., 1.).normalize(), 0., 0.5);
+        assert!(s.get_intersection(&ray).is_none());
+
+        // leaving the sphere from its center hits the back of the surface
+        let ray = Ray::new(&glm::vec3(1., 1., 1.), &glm::vec3(0., 0., 1.));
+        let inter = s.get_intersection(&ray).unwrap();
+        assert!(glm::distance(&inter.coords, &glm::vec3(1., 1., 2.)) < 1e-5);
+        assert!(glm::distance(&inter.normal, &glm::vec3(0., 0., 1.)) < 1e-5);
+        assert!(!inter.front_face);
     }
 }
 
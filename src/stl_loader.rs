@@ -0,0 +1,115 @@
+use crate::error::{invalid, Result};
+use crate::global::LoadedMesh;
+use std::collections::HashMap;
+
+// [comment]
+// stl stores every triangle with its own three corners. Corners at the same
+// position are merged into one vertex so the mesh is connected, the facet normals
+// are dropped and smooth or faceted normals are generated later.
+// [/comment]
+fn weld(triangles: Vec<[glm::Vec3; 3]>) -> LoadedMesh {
+    let mut mesh = LoadedMesh::default();
+    let mut vertex_map: HashMap<[u32; 3], u32> = HashMap::new();
+    for triangle in triangles {
+        for p in triangle.iter() {
+            let key = [p.x.to_bits(), p.y.to_bits(), p.z.to_bits()];
+            let next = mesh.vertices.len() as u32;
+            let index = *vertex_map.entry(key).or_insert(next);
+            if index == next {
+                mesh.vertices.push(*p);
+                mesh.st_coordinates.push(glm::vec2(0., 0.));
+            }
+            mesh.indices.push(index);
+        }
+    }
+    return mesh;
+}
+
+fn parse_binary(data: &[u8]) -> Result<LoadedMesh> {
+    let count = u32::from_le_bytes([data[80], data[81], data[82], data[83]]) as usize;
+    let float = |offset: usize| {
+        f32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+    };
+    let triangles = (0..count).map(|t| {
+        // 12 bytes of facet normal before the corners, 2 attribute bytes after them
+        let base = 84 + t * 50 + 12;
+        let corner = |k: usize| glm::vec3(float(base + k * 12), float(base + k * 12 + 4), float(base + k * 12 + 8));
+        return [corner(0), corner(1), corner(2)];
+    }).collect();
+    return Ok(weld(triangles));
+}
+
+fn parse_ascii(text: &str) -> Result<LoadedMesh> {
+    let mut triangles = Vec::new();
+    let mut corners = Vec::with_capacity(3);
+    for line in text.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["vertex", x, y, z] => {
+                let parse = |s: &str| s.parse::<f32>().map_err(|_| invalid("bad stl vertex"));
+                corners.push(glm::vec3(parse(x)?, parse(y)?, parse(z)?));
+            }
+            ["endloop"] => {
+                if corners.len() != 3 {
+                    return Err(invalid("stl facet without three vertices"));
+                }
+                triangles.push([corners[0], corners[1], corners[2]]);
+                corners.clear();
+            }
+            _ => {}
+        }
+    }
+    return Ok(weld(triangles));
+}
+
+// [comment]
+// Parse a binary or ascii stl file. Binary files may also start with "solid", so
+// a file is taken as binary when its size matches the triangle count of the
+// binary header.
+// [/comment]
+pub fn parse_stl(data: &[u8]) -> Result<LoadedMesh> {
+    if data.len() >= 84 {
+        let count = u32::from_le_bytes([data[80], data[81], data[82], data[83]]) as usize;
+        if data.len() == 84 + count * 50 {
+            return parse_binary(data);
+        }
+    }
+    if data.starts_with(b"solid") {
+        let text = std::str::from_utf8(data).map_err(|_| invalid("ascii stl is not text"))?;
+        return parse_ascii(text);
+    }
+    return Err(invalid("not an stl file"));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stl_loader::parse_stl;
+
+    #[test]
+    fn test_ascii_and_binary_stl() {
+        // two triangles of a square sharing an edge
+        let ascii = b"solid square\n\
+            facet normal 0 0 1\n outer loop\n  vertex 0 0 0\n  vertex 1 0 0\n  vertex 1 1 0\n endloop\nendfacet\n\
+            facet normal 0 0 1\n outer loop\n  vertex 0 0 0\n  vertex 1 1 0\n  vertex 0 1 0\n endloop\nendfacet\n\
+            endsolid square\n";
+        let mesh = parse_stl(ascii).unwrap();
+        assert_eq!(mesh.vertices.len(), 4);
+        assert_eq!(mesh.indices, vec![0, 1, 2, 0, 2, 3]);
+
+        // the same square in binary, with a header that starts like an ascii file
+        let mut binary = b"solid but binary".to_vec();
+        binary.resize(80, 0);
+        binary.extend_from_slice(&2u32.to_le_bytes());
+        for tri in [[0f32, 0., 0., 1., 0., 0., 1., 1., 0.], [0., 0., 0., 1., 1., 0., 0., 1., 0.]].iter() {
+            for v in [0f32, 0., 1.].iter().chain(tri.iter()) {
+                binary.extend_from_slice(&v.to_le_bytes());
+            }
+            binary.extend_from_slice(&[0, 0]);
+        }
+        let mesh = parse_stl(&binary).unwrap();
+        assert_eq!(mesh.vertices[3], glm::vec3(0., 1., 0.));
+        assert_eq!(mesh.indices, vec![0, 1, 2, 0, 2, 3]);
+
+        assert!(parse_stl(b"not a mesh").is_err());
+    }
+}
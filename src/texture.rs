@@ -0,0 +1,71 @@
+use crate::error::{invalid, Result};
+
+// [comment]
+// RGB image sampled with bilinear filtering. Texture coordinates repeat outside
+// [0, 1] and (0, 0) is the bottom left corner like in obj files, texels are stored
+// in rows from the top.
+// [/comment]
+pub struct Texture {
+    pub width: u32,
+    pub height: u32,
+    pub texels: Vec<glm::Vec3>,
+}
+
+impl Texture {
+    pub fn new(width: u32, height: u32, texels: Vec<glm::Vec3>) -> Result<Texture> {
+        if width == 0 || height == 0 {
+            return Err(invalid(format!("texture is {}x{}", width, height)));
+        }
+        if texels.len() != width as usize * height as usize {
+            return Err(invalid(format!(
+                "{} texels for a {}x{} texture", texels.len(), width, height
+            )));
+        }
+        return Ok(Texture {
+            width,
+            height,
+            texels,
+        });
+    }
+
+    fn texel(&self, x: i64, y: i64) -> glm::Vec3 {
+        let x = x.rem_euclid(self.width as i64) as usize;
+        let y = y.rem_euclid(self.height as i64) as usize;
+        return self.texels[y * self.width as usize + x];
+    }
+
+    pub fn sample(&self, st: &glm::Vec2) -> glm::Vec3 {
+        // texel centers sit at half integer positions
+        let x = st.x * self.width as f32 - 0.5;
+        let y = (1. - st.y) * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = glm::lerp(&self.texel(x0, y0), &self.texel(x0 + 1, y0), fx);
+        let bottom = glm::lerp(&self.texel(x0, y0 + 1), &self.texel(x0 + 1, y0 + 1), fx);
+        return glm::lerp(&top, &bottom, fy);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::texture::Texture;
+
+    #[test]
+    fn test_sample() {
+        // black and white on the top row, red and green below
+        let texture = Texture::new(2, 2, vec![
+            glm::vec3(0., 0., 0.), glm::vec3(1., 1., 1.),
+            glm::vec3(1., 0., 0.), glm::vec3(0., 1., 0.),
+        ]).unwrap();
+        assert_eq!(texture.sample(&glm::vec2(0.25, 0.75)), glm::vec3(0., 0., 0.));
+        assert_eq!(texture.sample(&glm::vec2(0.75, 0.25)), glm::vec3(0., 1., 0.));
+        // halfway between the two texels of the top row
+        assert_eq!(texture.sample(&glm::vec2(0.5, 0.75)), glm::vec3(0.5, 0.5, 0.5));
+        // coordinates repeat
+        assert_eq!(texture.sample(&glm::vec2(1.25, -0.75)), glm::vec3(1., 0., 0.));
+
+        assert!(Texture::new(2, 2, vec![glm::vec3(0., 0., 0.); 3]).is_err());
+        assert!(Texture::new(0, 2, Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,236 @@
+use crate::bounds3::Bounds3;
+use crate::csg::{Solid, Span};
+use crate::global::*;
+use crate::intersection::IntersectData;
+use crate::material::Material;
+use crate::object::ObjectTrait;
+use crate::ray::Ray;
+
+// [comment]
+// Torus around the z axis: a tube of radius minor_radius whose center runs on
+// the circle of radius major_radius in the xy plane, swept by phi_max around z.
+// u follows the sweep and v the angle around the tube, starting on its outside.
+// [/comment]
+pub struct Torus<'a> {
+    pub major_radius: f32,
+    pub minor_radius: f32,
+    // radians
+    pub phi_max: f32,
+    pub m: &'a Material,
+}
+
+impl<'a> Torus<'a> {
+    // \param phi_max: sweep in degrees, 360 for a full torus
+    pub fn new(major_radius: f32, minor_radius: f32, phi_max: f32, m: &'a Material) -> Torus<'a> {
+        Torus {
+            major_radius,
+            minor_radius,
+            phi_max: sweep_radians(phi_max),
+            m,
+        }
+    }
+
+    // center of the tube nearest to p
+    fn tube_center(&self, p: &glm::Vec3) -> glm::Vec3 {
+        let r = (p.x * p.x + p.y * p.y).sqrt();
+        if r == 0. {
+            return glm::vec3(self.major_radius, 0., 0.);
+        }
+        return glm::vec3(p.x, p.y, 0.) * (self.major_radius / r);
+    }
+
+    // [comment]
+    // (|p|^2 + R^2 - r^2)^2 = 4 R^2 (x^2 + y^2) along the ray is a quartic in t.
+    // The ray is first moved to where it enters the bounds, which keeps the
+    // coefficients small for distant rays. Roots are in ascending order and only
+    // clipped to the ray interval through the bounds.
+    // [/comment]
+    fn roots(&self, ray: &Ray) -> Vec<f32> {
+        let t_shift = match self.get_bounds().ray_interval(ray) {
+            Some((t_enter, _)) => t_enter as f64,
+            None => return Vec::new(),
+        };
+        let o = ray.origin.cast::<f64>() + ray.direction.cast::<f64>() * t_shift;
+        let d = ray.direction.cast::<f64>();
+        let (big, small) = (self.major_radius as f64, self.minor_radius as f64);
+
+        let dd = d.dot(&d);
+        let od = o.dot(&d);
+        let k = o.dot(&o) - big * big - small * small;
+        let roots = solve_quartic(
+            dd * dd,
+            4. * dd * od,
+            2. * dd * k + 4. * od * od + 4. * big * big * d.z * d.z,
+            4. * od * k + 8. * big * big * o.z * d.z,
+            k * k - 4. * big * big * (small * small - o.z * o.z),
+        );
+        return roots.iter().map(|root| (root + t_shift) as f32).collect();
+    }
+
+    fn hit_data(&self, ray: &Ray, t: f32) -> IntersectData<'_> {
+        // reproject onto the tube
+        let p = ray.origin + ray.direction * t;
+        let center = self.tube_center(&p);
+        let normal = (p - center).normalize();
+        let coords = center + normal * self.minor_radius;
+        let p_error = glm::abs(&(coords - center)) * gamma(5) + glm::abs(&coords) * gamma(3);
+
+        let mut theta = normal.z.atan2(glm::dot(&normal, &(center / self.major_radius)));
+        if theta < 0. {
+            theta += 2. * M_PI;
+        }
+        let st = glm::vec2(sweep_angle(&coords) / self.phi_max, theta / (2. * M_PI));
+
+        return IntersectData {
+            coords,
+            normal,
+            shading_normal: normal,
+            p_error,
+            barycentric: glm::zero(),
+            front_face: glm::dot(&ray.direction, &normal) < 0.,
+            distance: t,
+            index: u32::MAX,
+            m: self.m,
+            eval_diffuse_color: self.m.eval_diffuse_color(&st),
+            uv: glm::zero(),
+            st,
+        };
+    }
+
+    // [comment]
+    // Intervals of the whole line of the ray that are inside the tube. A partial
+    // sweep is not closed, so it has none. The roots split the line into pieces
+    // that are alternately inside and outside the tube, but a double root where the
+    // ray grazes it or a root lost to rounding puts them out of step, so each piece
+    // is tested at its midpoint instead.
+    // [/comment]
+    pub fn inside_intervals(&self, ray: &Ray) -> Vec<(f32, f32)> {
+        if self.phi_max < deg_2_rad(360.) {
+            return Vec::new();
+        }
+        let line = Ray::segment(&ray.origin, &ray.direction, f32::MIN, f32::MAX);
+        return self.intervals_between(ray, &self.roots(&line));
+    }
+
+    fn intervals_between(&self, ray: &Ray, roots: &[f32]) -> Vec<(f32, f32)> {
+        let mut intervals: Vec<(f32, f32)> = Vec::new();
+        for pair in roots.windows(2) {
+            let mid = ray.origin + ray.direction * ((pair[0] + pair[1]) * 0.5);
+            if glm::distance(&mid, &self.tube_center(&mid)) >= self.minor_radius {
+                continue;
+            }
+            // a root inside the tube joins the pieces around it
+            match intervals.last_mut() {
+                Some(last) if last.1 == pair[0] => last.1 = pair[1],
+                _ => intervals.push((pair[0], pair[1])),
+            }
+        }
+        return intervals;
+    }
+}
+
+impl<'a> ObjectTrait for Torus<'a> {
+    fn get_intersection(&self, ray: &Ray) -> Option<IntersectData<'_>> {
+        for t in self.roots(ray) {
+            if ray.in_range(t) && sweep_angle(&(ray.origin + ray.direction * t)) <= self.phi_max {
+                return Some(self.hit_data(ray, t));
+            }
+        }
+        return None;
+    }
+
+    fn get_bounds(&self) -> Bounds3 {
+        let outer = self.major_radius + self.minor_radius;
+        return Bounds3::new(
+            &glm::vec3(-outer, -outer, -self.minor_radius),
+            &glm::vec3(outer, outer, self.minor_radius),
+        );
+    }
+}
+
+// [comment]
+// The intervals inside the tube are its spans. A partial sweep is not closed, so
+// it has none and drops out of any CSG.
+// [/comment]
+impl<'a> Solid for Torus<'a> {
+    fn get_spans(&self, ray: &Ray) -> Vec<Span<'_>> {
+        return self
+            .inside_intervals(ray)
+            .into_iter()
+            .map(|(enter, exit)| Span { enter: self.hit_data(ray, enter), exit: self.hit_data(ray, exit) })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::csg::Solid;
+    use crate::material::Material;
+    use crate::object::ObjectTrait;
+    use crate::ray::Ray;
+    use crate::torus::Torus;
+
+    #[test]
+    fn test_torus() {
+        let mat = Material::default();
+        let ring = Torus::new(2., 0.5, 360., &mat);
+
+        // along x the ray hits the outside of the tube, then the far side past the hole
+        let hit = ring.get_intersection(&Ray::new(&glm::vec3(10., 0., 0.), &glm::vec3(-1., 0., 0.))).unwrap();
+        assert!((hit.distance - 7.5).abs() < 1e-4, "{}", hit.distance);
+        assert!(glm::distance(&hit.normal, &glm::vec3(1., 0., 0.)) < 1e-5);
+        assert!(hit.front_face && hit.st.y.abs() < 1e-4);
+        let beyond = Ray::segment(&glm::vec3(10., 0., 0.), &glm::vec3(-1., 0., 0.), 9., f32::MAX);
+        let hit = ring.get_intersection(&beyond).unwrap();
+        assert!((hit.distance - 11.5).abs() < 1e-4, "{}", hit.distance);
+
+        // through the hole and from the top
+        assert!(ring.get_intersection(&Ray::new(&glm::vec3(0., 0., 5.), &glm::vec3(0., 0., -1.))).is_none());
+        let top = ring.get_intersection(&Ray::new(&glm::vec3(0., 2., 5.), &glm::vec3(0., 0., -1.))).unwrap();
+        assert!((top.distance - 4.5).abs() < 1e-4);
+        assert!((top.st.x - 0.25).abs() < 1e-5 && (top.st.y - 0.25).abs() < 1e-4);
+
+        // a far, grazing ray still finds the tube
+        let far = ring.get_intersection(&Ray::new(&glm::vec3(1e4, 2.4, 0.), &glm::vec3(-1., 0., 0.))).unwrap();
+        assert!((far.coords.x - 0.7).abs() < 1e-2, "{:?}", far.coords);
+
+        // half a torus over y >= 0
+        let half = Torus::new(2., 0.5, 180., &mat);
+        assert!(half.get_intersection(&Ray::new(&glm::vec3(0., -2., 5.), &glm::vec3(0., 0., -1.))).is_none());
+
+        let bounds = ring.get_bounds();
+        assert_eq!(bounds.p_max, glm::vec3(2.5, 2.5, 0.5));
+    }
+
+    #[test]
+    fn test_torus_inside_intervals() {
+        let mat = Material::default();
+        let ring = Torus::new(2., 0.5, 360., &mat);
+        let ray = Ray::new(&glm::vec3(10., 0., 0.), &glm::vec3(-1., 0., 0.));
+        let intervals = ring.inside_intervals(&ray);
+        assert_eq!(intervals.len(), 2);
+        assert!((intervals[0].0 - 7.5).abs() < 1e-4 && (intervals[0].1 - 8.5).abs() < 1e-4);
+        assert!((intervals[1].0 - 11.5).abs() < 1e-4 && (intervals[1].1 - 12.5).abs() < 1e-4);
+
+        // with the first root lost the hole is not taken for the inside of the tube
+        assert_eq!(ring.intervals_between(&ray, &[8.5, 11.5, 12.5]), vec![(11.5, 12.5)]);
+        // a spurious root inside the tube does not split its interval
+        assert_eq!(ring.intervals_between(&ray, &[7.5, 8., 8.5, 11.5, 12.5]), vec![(7.5, 8.5), (11.5, 12.5)]);
+
+        // a partial sweep is open
+        assert!(Torus::new(2., 0.5, 180., &mat).inside_intervals(&ray).is_empty());
+    }
+
+    #[test]
+    fn test_torus_spans() {
+        let mat = Material::default();
+        let ray = Ray::new(&glm::vec3(10., 0., 0.), &glm::vec3(-1., 0., 0.));
+        let ring = Torus::new(2., 0.5, 360., &mat);
+        let spans = ring.get_spans(&ray);
+        assert_eq!(spans.len(), 2);
+        assert!((spans[1].enter.distance - 11.5).abs() < 1e-4 && (spans[1].exit.distance - 12.5).abs() < 1e-4);
+        assert!(spans[1].enter.front_face && !spans[1].exit.front_face);
+        // an open half torus takes no part in CSG
+        assert!(Torus::new(2., 0.5, 180., &mat).get_spans(&ray).is_empty());
+    }
+}
@@ -0,0 +1,160 @@
+use crate::bounds3::Bounds3;
+use crate::global::{deg_2_rad, gamma};
+use crate::intersection::IntersectData;
+use crate::ray::Ray;
+
+// [comment]
+// Affine 4x4 transform with its inverse cached, m maps object space to world space.
+// [/comment]
+#[derive(Clone)]
+pub struct Transform {
+    pub m: glm::Mat4,
+    pub m_inv: glm::Mat4,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform {
+            m: glm::identity(),
+            m_inv: glm::identity(),
+        }
+    }
+}
+
+impl Transform {
+    pub fn new(m: &glm::Mat4) -> Transform {
+        Transform {
+            m: *m,
+            m_inv: glm::inverse(m),
+        }
+    }
+
+    pub fn translate(delta: &glm::Vec3) -> Transform {
+        return Transform::new(&glm::translation(delta));
+    }
+
+    pub fn scale(x: f32, y: f32, z: f32) -> Transform {
+        return Transform::new(&glm::scaling(&glm::vec3(x, y, z)));
+    }
+
+    // \param theta: angle in degrees around axis
+    pub fn rotate(theta: f32, axis: &glm::Vec3) -> Transform {
+        return Transform::new(&glm::rotation(deg_2_rad(theta), &axis.normalize()));
+    }
+
+    // self applied after t
+    pub fn compose(&self, t: &Transform) -> Transform {
+        Transform {
+            m: self.m * t.m,
+            m_inv: t.m_inv * self.m_inv,
+        }
+    }
+
+    pub fn inverse(&self) -> Transform {
+        Transform {
+            m: self.m_inv,
+            m_inv: self.m,
+        }
+    }
+
+    pub fn point(&self, p: &glm::Vec3) -> glm::Vec3 {
+        return (self.m * glm::vec4(p.x, p.y, p.z, 1.0)).xyz();
+    }
+
+    pub fn vector(&self, v: &glm::Vec3) -> glm::Vec3 {
+        return (self.m * glm::vec4(v.x, v.y, v.z, 0.0)).xyz();
+    }
+
+    // normals go through the inverse transpose, result is not normalized
+    pub fn normal(&self, n: &glm::Vec3) -> glm::Vec3 {
+        return (glm::transpose(&self.m_inv) * glm::vec4(n.x, n.y, n.z, 0.0)).xyz();
+    }
+
+    // bounds of the 8 transformed corners
+    pub fn bounds(&self, b: &Bounds3) -> Bounds3 {
+        let mut ret = Bounds3::default();
+        for i in 0..8usize {
+            let corner = glm::vec3(
+                if i & 1 == 0 { b.p_min.x } else { b.p_max.x },
+                if i & 2 == 0 { b.p_min.y } else { b.p_max.y },
+                if i & 4 == 0 { b.p_min.z } else { b.p_max.z },
+            );
+            ret = ret.union_p(&self.point(&corner));
+        }
+        return ret;
+    }
+
+    // [comment]
+    // The direction is not renormalized, so a hit distance found on the
+    // transformed ray is also the distance along the original ray.
+    // [/comment]
+    pub fn ray(&self, r: &Ray) -> Ray {
+        let mut ret = Ray::new(&self.point(&r.origin), &self.vector(&r.direction));
+        ret.t = r.t;
+        ret.t_min = r.t_min;
+        ret.t_max = r.t_max;
+        return ret;
+    }
+
+    // [comment]
+    // Error bound of point(p) when p itself is off by up to p_error per axis: the
+    // incoming error scaled by the matrix plus the rounding of the transform.
+    // [/comment]
+    pub fn point_error(&self, p: &glm::Vec3, p_error: &glm::Vec3) -> glm::Vec3 {
+        let m_abs = glm::abs(&self.m);
+        let m3 = glm::mat4_to_mat3(&m_abs);
+        let t = glm::vec3(m_abs[(0, 3)], m_abs[(1, 3)], m_abs[(2, 3)]);
+        return m3 * p_error * (gamma(3) + 1.) + (m3 * glm::abs(p) + t) * gamma(3);
+    }
+
+    // bring a hit found on a ray produced by inverse().ray() back to this space
+    pub fn intersect_data<'b>(&self, mut inter: IntersectData<'b>) -> IntersectData<'b> {
+        inter.p_error = self.point_error(&inter.coords, &inter.p_error);
+        inter.coords = self.point(&inter.coords);
+        let n = self.normal(&inter.normal);
+        if glm::length2(&n) > 0. {
+            inter.normal = n.normalize();
+        }
+        let n = self.normal(&inter.shading_normal);
+        if glm::length2(&n) > 0. {
+            inter.shading_normal = n.normalize();
+        }
+        return inter;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bounds3::Bounds3;
+    use crate::transform::Transform;
+
+    #[test]
+    fn test_transform_bounds_and_normal() {
+        let m = glm::translation(&glm::vec3(1., 2., 3.)) * glm::scaling(&glm::vec3(2., 1., 1.));
+        let t = Transform::new(&m);
+
+        let b = t.bounds(&Bounds3::new(&glm::vec3(-1., -1., -1.), &glm::vec3(1., 1., 1.)));
+        assert!(glm::distance(&b.p_min, &glm::vec3(-1., 1., 2.)) < 1e-5);
+        assert!(glm::distance(&b.p_max, &glm::vec3(3., 3., 4.)) < 1e-5);
+
+        let p = t.inverse().point(&t.point(&glm::vec3(0.3, 0.2, 0.1)));
+        assert!(glm::distance(&p, &glm::vec3(0.3, 0.2, 0.1)) < 1e-5);
+
+        // a 45 degree plane normal must stay perpendicular after the non-uniform scale
+        let n = t.normal(&glm::vec3(1., 1., 0.)).normalize();
+        let tangent = t.vector(&glm::vec3(1., -1., 0.));
+        assert!(glm::dot(&n, &tangent).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_transform_compose() {
+        let t = Transform::translate(&glm::vec3(0., 0., -5.))
+            .compose(&Transform::rotate(90., &glm::vec3(0., 1., 0.)))
+            .compose(&Transform::scale(2., 1., 1.));
+        // scale x first, rotate it onto -z, then move
+        let p = t.point(&glm::vec3(1., 0., 0.));
+        assert!(glm::distance(&p, &glm::vec3(0., 0., -7.)) < 1e-5, "{:?}", p);
+        let q = t.inverse().point(&p);
+        assert!(glm::distance(&q, &glm::vec3(1., 0., 0.)) < 1e-5);
+    }
+}
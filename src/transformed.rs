@@ -0,0 +1,102 @@
+use crate::bounds3::Bounds3;
+use crate::intersection::IntersectData;
+use crate::object::ObjectTrait;
+use crate::ray::Ray;
+use crate::transform::Transform;
+
+// [comment]
+// Places any object in the scene through an affine transform. Rays are moved into
+// object space, hits are moved back and normals use the inverse transpose, so
+// non-uniform scales keep correct shading.
+// [/comment]
+pub struct TransformedObject<'a> {
+    pub object: &'a dyn ObjectTrait,
+    pub transform: Transform,
+    pub bounding_box: Bounds3,
+}
+
+impl<'a> TransformedObject<'a> {
+    pub fn new(object: &'a dyn ObjectTrait, transform: Transform) -> Self {
+        let bounding_box = transform.bounds(&object.get_bounds());
+        TransformedObject {
+            object,
+            transform,
+            bounding_box,
+        }
+    }
+
+    pub fn translate(self, delta: &glm::Vec3) -> Self {
+        let t = Transform::translate(delta).compose(&self.transform);
+        return TransformedObject::new(self.object, t);
+    }
+
+    pub fn rotate(self, theta: f32, axis: &glm::Vec3) -> Self {
+        let t = Transform::rotate(theta, axis).compose(&self.transform);
+        return TransformedObject::new(self.object, t);
+    }
+
+    pub fn scale(self, x: f32, y: f32, z: f32) -> Self {
+        let t = Transform::scale(x, y, z).compose(&self.transform);
+        return TransformedObject::new(self.object, t);
+    }
+}
+
+impl<'a> ObjectTrait for TransformedObject<'a> {
+    fn get_intersection(&self, ray: &Ray) -> Option<IntersectData<'_>> {
+        let object_ray = self.transform.inverse().ray(ray);
+        let inter = self.object.get_intersection(&object_ray)?;
+        return Some(self.transform.intersect_data(inter));
+    }
+
+    // the object space ray is not renormalized, so t_max carries over unchanged
+    fn occluded(&self, ray: &Ray, t_max: f32) -> bool {
+        return self.object.occluded(&self.transform.inverse().ray(ray), t_max);
+    }
+
+    fn get_bounds(&self) -> Bounds3 {
+        return self.bounding_box.clone();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::material::Material;
+    use crate::object::ObjectTrait;
+    use crate::ray::Ray;
+    use crate::sphere::Sphere;
+    use crate::transform::Transform;
+    use crate::transformed::TransformedObject;
+
+    #[test]
+    fn test_scaled_sphere() {
+        let mat = Material::default();
+        let s = Sphere::new(&glm::vec3(0., 0., 0.), 1., &mat);
+        // ellipsoid with radii (2, 1, 1) centered at (0, 0, -10)
+        let e = TransformedObject::new(&s, Transform::default())
+            .scale(2., 1., 1.)
+            .translate(&glm::vec3(0., 0., -10.));
+
+        let b = e.get_bounds();
+        assert!(glm::distance(&b.p_min, &glm::vec3(-2., -1., -11.)) < 1e-5);
+        assert!(glm::distance(&b.p_max, &glm::vec3(2., 1., -9.)) < 1e-5);
+
+        let ray = Ray::new(&glm::vec3(0., 0., 0.), &glm::vec3(0., 0., -1.));
+        let inter = e.get_intersection(&ray).unwrap();
+        assert!((inter.distance - 9.).abs() < 1e-4, "{}", inter.distance);
+        assert!(glm::distance(&inter.coords, &glm::vec3(0., 0., -9.)) < 1e-4);
+
+        // on the ellipsoid x^2/4 + y^2 + z^2 = 1 the normal at (sqrt(2), sqrt(0.5), 0)
+        // is proportional to (x/4, y, 0)
+        let p = glm::vec3(2f32.sqrt(), 0.5f32.sqrt(), -10.);
+        let ray = Ray::new(&glm::vec3(5., p.y, p.z), &glm::vec3(-1., 0., 0.));
+        let inter = e.get_intersection(&ray).unwrap();
+        let expected = glm::vec3(p.x / 4., p.y, 0.).normalize();
+        assert!(glm::distance(&inter.normal, &expected) < 1e-3, "{:?}", inter.normal);
+
+        let rotated = TransformedObject::new(&s, Transform::default())
+            .scale(2., 1., 1.)
+            .rotate(90., &glm::vec3(0., 0., 1.));
+        let b = rotated.get_bounds();
+        assert!(glm::distance(&b.p_max, &glm::vec3(1., 2., 1.)) < 1e-5, "{:?}", b.p_max);
+    }
+}
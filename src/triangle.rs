@@ -1,34 +1,127 @@
 
 
-use crate::{bvh::{BVHAccel, SplitMethod}, bounds3::Bounds3, intersection::IntersectData, object::*};
+use crate::{bvh::SplitMethod, bounds3::Bounds3, intersection::IntersectData, object::*};
+use crate::accelerator::{build_accelerator, Accelerator, AcceleratorType};
 use crate::material;
+use crate::bvh_cache;
+use crate::error::{Error, Result};
+use crate::packet::{keep_nearest, RayPacket};
+use crate::ray::Ray;
+use crate::obj_loader::ObjMesh;
+use crate::global::LoadedMesh;
+use crate::global::{deg_2_rad, gamma};
+use rayon::prelude::*;
 
+const MESH_MAX_PRIMS_IN_NODE: u32 = 4;
+
+// NaN or infinite vertices give triangles no accelerator can sort
+fn check_vertices(vertices: &[glm::Vec3]) -> Result<()> {
+    if let Some(i) = vertices.iter().position(|v| !v.iter().all(|c| c.is_finite())) {
+        return Err(Error::InvalidScene(format!("vertex {} is not finite", i)));
+    }
+    return Ok(());
+}
+
+// see mesh_validation::repair_mesh to fix meshes these checks fail on
+fn check_indices(indices: &[u32], num_vertices: usize) -> Result<()> {
+    if !indices.len().is_multiple_of(3) {
+        return Err(Error::InvalidScene(format!("{} indices are not whole triangles", indices.len())));
+    }
+    if let Some(i) = indices.iter().position(|&i| i as usize >= num_vertices) {
+        return Err(Error::InvalidScene(format!(
+            "index {} is {} but there are {} vertices", i, indices[i], num_vertices
+        )));
+    }
+    return Ok(());
+}
+
+// [comment]
+// Watertight ray triangle test (Woop, Benthin and Wald 2013). The vertices are moved
+// into a space where the ray starts at the origin and runs along +z, so the test
+// reduces to 2D edge functions that give consistent results for triangles sharing
+// an edge: a ray through the edge hits at least one of them. Hits outside
+// [ray.t_min, ray.t_max] or too close to the origin to be told apart from zero
+// are rejected.
+// [/comment]
 fn ray_triangle_intersect(v0: &glm::Vec3, v1: &glm::Vec3, v2: &glm::Vec3,
-                        orig: &glm::Vec3, dir: &glm::Vec3, tnear: &mut f32, 
+                        ray: &Ray, tnear: &mut f32,
                         u: &mut f32, v: &mut f32) -> bool {
-    let e1 = v1 - v0;
-    let e2 = v2 - v0;
-    let s = orig - v0;
-    let s1 = glm::cross(dir, &e2);
-    let s2 = glm::cross(&s, &e1);
+    // the axis the ray advances fastest along becomes z
+    let d_abs = glm::abs(&ray.direction);
+    let kz = if d_abs.x > d_abs.y {
+        if d_abs.x > d_abs.z { 0 } else { 2 }
+    } else {
+        if d_abs.y > d_abs.z { 1 } else { 2 }
+    };
+    let kx = (kz + 1) % 3;
+    let ky = (kx + 1) % 3;
+    let permute = |p: &glm::Vec3| glm::vec3(p[kx], p[ky], p[kz]);
+    let d = permute(&ray.direction);
+    let mut p0 = permute(&(v0 - ray.origin));
+    let mut p1 = permute(&(v1 - ray.origin));
+    let mut p2 = permute(&(v2 - ray.origin));
+
+    // shear so the ray direction becomes (0, 0, 1), z is scaled later
+    let sx = -d.x / d.z;
+    let sy = -d.y / d.z;
+    let sz = 1.0 / d.z;
+    for p in [&mut p0, &mut p1, &mut p2].iter_mut() {
+        p.x += sx * p.z;
+        p.y += sy * p.z;
+    }
 
-    let r_m00 = glm::dot(&s2, &e2);
-    let r_m01 = glm::dot(&s1, &s);
-    let r_m02 = glm::dot(&s2, dir);
+    let mut e0 = p1.x * p2.y - p1.y * p2.x;
+    let mut e1 = p2.x * p0.y - p2.y * p0.x;
+    let mut e2 = p0.x * p1.y - p0.y * p1.x;
+    // exactly on an edge, decide in double precision
+    if e0 == 0. || e1 == 0. || e2 == 0. {
+        e0 = (p1.x as f64 * p2.y as f64 - p1.y as f64 * p2.x as f64) as f32;
+        e1 = (p2.x as f64 * p0.y as f64 - p2.y as f64 * p0.x as f64) as f32;
+        e2 = (p0.x as f64 * p1.y as f64 - p0.y as f64 * p1.x as f64) as f32;
+    }
+    if (e0 < 0. || e1 < 0. || e2 < 0.) && (e0 > 0. || e1 > 0. || e2 > 0.) {
+        return false;
+    }
+    let det = e0 + e1 + e2;
+    if det == 0. {
+        return false;
+    }
 
-    let l = 1.0 / (glm::dot(&s1, &e1));
+    // interval test on the scaled distance avoids the division for misses
+    p0.z *= sz;
+    p1.z *= sz;
+    p2.z *= sz;
+    let t_scaled = e0 * p0.z + e1 * p1.z + e2 * p2.z;
+    if det < 0. && (t_scaled > ray.t_min * det || t_scaled < ray.t_max * det) {
+        return false;
+    }
+    if det > 0. && (t_scaled < ray.t_min * det || t_scaled > ray.t_max * det) {
+        return false;
+    }
 
-    let t  = l * r_m00;
-    let b1 = l * r_m01;
-    let b2 = l * r_m02;
+    let inv_det = 1.0 / det;
+    let b1 = e1 * inv_det;
+    let b2 = e2 * inv_det;
+    let t = t_scaled * inv_det;
 
-    if  t >= 0. && b1 >= 0. && b2 >= 0. && (1.-b1-b2) >= 0.{
-        *tnear = t;
-        *u = b1;
-        *v = b2;
-        return true;
+    // t must be provably above zero given the rounding error of the computation
+    let max_zt = glm::comp_max(&glm::vec3(p0.z.abs(), p1.z.abs(), p2.z.abs()));
+    let delta_z = gamma(3) * max_zt;
+    let max_xt = glm::comp_max(&glm::vec3(p0.x.abs(), p1.x.abs(), p2.x.abs()));
+    let max_yt = glm::comp_max(&glm::vec3(p0.y.abs(), p1.y.abs(), p2.y.abs()));
+    let delta_x = gamma(5) * (max_xt + max_zt);
+    let delta_y = gamma(5) * (max_yt + max_zt);
+    let delta_e = 2. * (gamma(2) * max_xt * max_yt + delta_y * max_xt + delta_x * max_yt);
+    let max_e = glm::comp_max(&glm::vec3(e0.abs(), e1.abs(), e2.abs()));
+    let delta_t = 3. * (gamma(3) * max_e * max_zt + delta_e * max_zt + delta_z * max_e) * inv_det.abs();
+    if t <= delta_t || !ray.in_range(t) {
+        return false;
     }
-    return false;
+
+    *tnear = t;
+    *u = b1;
+    *v = b2;
+    return true;
 }
 
 pub struct Triangle<'a> {
@@ -45,9 +138,36 @@ impl<'a> Triangle<'a> {
     fn v0(&self) -> &glm::Vec3 { return &self._d.vertices[self.iv0()]; }
     fn v1(&self) -> &glm::Vec3 { return &self._d.vertices[self.iv1()]; }
     fn v2(&self) -> &glm::Vec3 { return &self._d.vertices[self.iv2()]; }
-    fn iv0(&self) -> usize { return self._d.indices[self.ind as usize + 0] as usize; }
-    fn iv1(&self) -> usize { return self._d.indices[self.ind as usize + 1] as usize; }
-    fn iv2(&self) -> usize { return self._d.indices[self.ind as usize + 2] as usize; }
+    fn iv0(&self) -> usize { return self._d.indices[(self.ind * 3 + 0) as usize] as usize; }
+    fn iv1(&self) -> usize { return self._d.indices[(self.ind * 3 + 1) as usize] as usize; }
+    fn iv2(&self) -> usize { return self._d.indices[(self.ind * 3 + 2) as usize] as usize; }
+
+    // unit normal of the plane, counter clockwise winding seen from its front
+    pub fn geometric_normal(&self) -> glm::Vec3 {
+        return glm::cross(&(self.v1() - self.v0()), &(self.v2() - self.v0())).normalize();
+    }
+
+    // interpolated corner normals, None for flat shaded meshes
+    fn shading_normal(&self, barycentric: &glm::Vec3) -> Option<glm::Vec3> {
+        if self._d.normals.is_empty() {
+            return None;
+        }
+        let corner = (self.ind * 3) as usize;
+        let n = self._d.normals[corner] * barycentric.x
+            + self._d.normals[corner + 1] * barycentric.y
+            + self._d.normals[corner + 2] * barycentric.z;
+        if glm::length2(&n) == 0. {
+            return None;
+        }
+        return Some(n.normalize());
+    }
+
+    fn material(&self) -> &'a material::Material {
+        if self._d.material_ids.is_empty() {
+            return self._d.materials[0];
+        }
+        return self._d.materials[self._d.material_ids[self.ind as usize] as usize];
+    }
 
     fn get_st(&self, uv: &glm::Vec2) -> glm::Vec2{
         let st0 = &self._d.st_coordinates[self._d.indices[(self.ind * 3 + 0) as usize] as usize];
@@ -58,26 +178,64 @@ impl<'a> Triangle<'a> {
         return st;
     }
 
-    fn eval_diffuse_color(&self, st: &glm::Vec2) -> glm::Vec3 {
+    fn eval_diffuse_color(&self, st: &glm::Vec2, barycentric: &glm::Vec3) -> glm::Vec3 {
+        let material = self.material();
+        if let Some(texture) = &material.diffuse_texture {
+            return texture.sample(st).component_mul(&material.get_color());
+        }
+        if !self._d.colors.is_empty() {
+            let c = &self._d.colors;
+            let color = c[self.iv0()] * barycentric.x + c[self.iv1()] * barycentric.y + c[self.iv2()] * barycentric.z;
+            return color.component_mul(&material.get_color());
+        }
+        if !self._d.checker_pattern {
+            return material.get_color();
+        }
         let scale = 5.0f32;
         let pattern = ((st.x * scale).rem_euclid(1.0) > 0.5) ^ ((st.y * scale).rem_euclid(1.0) > 0.5);
         let pattern = pattern as u32 as f32;
-        return glm::lerp(
+        let checker = glm::lerp(
             &glm::vec3(0.815, 0.235, 0.031), 
             &glm::vec3(0.937, 0.937, 0.231), 
             pattern
         );
+        // tinted by the material, white for the default one
+        return checker.component_mul(&material.get_color());
     }
 }
 
 impl<'a> ObjectTrait for Triangle<'a> {
-    fn get_intersection(&self, ray: &crate::ray::Ray) -> Option<IntersectData> {
+    fn get_intersection(&self, ray: &Ray) -> Option<IntersectData> {
+        return self.intersect(ray);
+    }
+
+    fn get_bounds(&self) -> crate::bounds3::Bounds3 {
+        return Bounds3::new(&self.v0(), &self.v1()).union_p(self.v2());
+    }
+}
+
+impl<'a> Triangle<'a> {
+    // distance along the ray only, without the shading data of intersect
+    pub fn intersect_distance(&self, ray: &Ray) -> Option<f32> {
+        let mut tnear = 0f32;
+        let mut u = 0f32;
+        let mut v = 0f32;
+        let ok = ray_triangle_intersect(
+            self.v0(), self.v1(), self.v2(),
+            ray,
+            &mut tnear, &mut u, &mut v
+        );
+        return if ok { Some(tnear) } else { None };
+    }
+
+    // the hit only borrows the material, so it may outlive this view
+    pub fn intersect(&self, ray: &Ray) -> Option<IntersectData<'a>> {
         let mut tnear = 0f32;
         let mut u = 0f32;
         let mut v = 0f32;
         let ok = ray_triangle_intersect(
             self.v0(), self.v1(), self.v2(), 
-            &ray.origin, &ray.direction,
+            ray,
             &mut tnear, &mut u, &mut v
         );
         if !ok {
@@ -86,24 +244,29 @@ impl<'a> ObjectTrait for Triangle<'a> {
 
         let uv = glm::vec2(u, v);
         let st = self.get_st(&uv);
-        let color = self.eval_diffuse_color(&st);
+        let barycentric = glm::vec3(1. - u - v, u, v);
+        let color = self.eval_diffuse_color(&st, &barycentric);
+        // interpolating the vertices is more accurate than origin + t * direction
+        let p0 = self.v0() * barycentric.x;
+        let p1 = self.v1() * barycentric.y;
+        let p2 = self.v2() * barycentric.z;
+        let p_abs_sum = glm::abs(&p0) + glm::abs(&p1) + glm::abs(&p2);
+        let normal = self.geometric_normal();
 
         return Some(IntersectData {
-            coords: glm::vec3(u, v, 1.0),
-            normal: glm::zero(), // todo:
+            coords: p0 + p1 + p2,
+            normal,
+            shading_normal: self.shading_normal(&barycentric).unwrap_or(normal),
+            p_error: p_abs_sum * gamma(7),
+            barycentric,
+            front_face: glm::dot(&ray.direction, &normal) < 0.,
             distance: tnear,
             index: self.ind,
             uv, st,
             eval_diffuse_color: color,
-            m: self._d.m,
+            m: self.material(),
         });
     }
-
-    fn get_bounds(&self) -> crate::bounds3::Bounds3 {
-        let b1 = Bounds3::new(&self.v0(), &self.v1());
-        let b2 = Bounds3::new(&self.v0(), &self.v1());
-        return Bounds3::intersect(&b1, &b2);
-    }
 }
 
 pub struct SMeshData<'a> {
@@ -111,14 +274,25 @@ pub struct SMeshData<'a> {
     pub vertices: Vec<glm::Vec3>,
     pub indices: Vec<u32>,
     pub st_coordinates: Vec<glm::Vec2>,
-    pub m: &'a material::Material,
+    // one shading normal per entry of indices, empty for flat shading
+    pub normals: Vec<glm::Vec3>,
+    // per vertex colors multiplying the material color, empty when not set
+    pub colors: Vec<glm::Vec3>,
+    // material of every triangle as an index into materials, empty when all use
+    // the first one
+    pub materials: Vec<&'a material::Material>,
+    pub material_ids: Vec<u32>,
+    // untextured triangles show a checker board tinted by the material color,
+    // gltf and pbrt meshes turn it off to show the plain color
+    pub checker_pattern: bool,
 }
 
 pub struct MeshTriangle<'a> {
     pub mesh_data: SMeshData<'a>,
     pub bounding_box: Bounds3,
-    pub bvh: Option<BVHAccel<'a>>,
-    pub triangles: Vec<Triangle<'a>>,
+    pub accelerator: Option<Box<dyn Accelerator>>,
+    pub accelerator_type: AcceleratorType,
+    pub split_method: SplitMethod,
 }
 
 impl<'a> MeshTriangle<'a> {
@@ -127,11 +301,85 @@ impl<'a> MeshTriangle<'a> {
         st_coordinates: Vec<glm::Vec2>,
         indices: Vec<u32>,
         mat: &'a material::Material,
+    ) -> Result<MeshTriangle<'a>>
+    {
+        let mut mesh = MeshTriangle::new_unbuilt(vertices, st_coordinates, indices, mat);
+        mesh.build()?;
+        return Ok(mesh);
+    }
+
+    // [comment]
+    // Unbuilt mesh for one mesh of an obj file, triangles get the materials of their
+    // material ids. Normals are not set, see set_vertex_normals and generate_normals.
+    // [/comment]
+    pub fn from_obj(mesh: &ObjMesh, materials: &'a [material::Material]) -> Result<MeshTriangle<'a>> {
+        if materials.is_empty() {
+            return Err(Error::InvalidScene("obj mesh without materials".to_string()));
+        }
+        let mut triangle_mesh = MeshTriangle::new_unbuilt(
+            mesh.vertices.clone(), mesh.st_coordinates.clone(), mesh.indices.clone(), &materials[0]
+        );
+        triangle_mesh.set_materials(materials.iter().collect(), mesh.material_ids.clone())?;
+        return Ok(triangle_mesh);
+    }
+
+    // [comment]
+    // Unbuilt mesh for a mesh file, with its vertex colors. Normals of the file are
+    // used when there are some, otherwise they are generated, both with crease_angle.
+    // [/comment]
+    pub fn from_loaded(mesh: LoadedMesh, mat: &'a material::Material, crease_angle: f32) -> Result<MeshTriangle<'a>> {
+        let mut triangle_mesh = MeshTriangle::new_unbuilt(mesh.vertices, mesh.st_coordinates, mesh.indices, mat);
+        if mesh.normals.is_empty() {
+            triangle_mesh.generate_normals(crease_angle)?;
+        } else {
+            triangle_mesh.set_vertex_normals(&mesh.normals, crease_angle)?;
+        }
+        if !mesh.colors.is_empty() {
+            triangle_mesh.set_vertex_colors(mesh.colors)?;
+        }
+        return Ok(triangle_mesh);
+    }
+
+    pub fn set_vertex_colors(&mut self, colors: Vec<glm::Vec3>) -> Result<()> {
+        if colors.len() != self.mesh_data.vertices.len() {
+            return Err(Error::InvalidScene(format!(
+                "{} colors for {} vertices", colors.len(), self.mesh_data.vertices.len()
+            )));
+        }
+        self.mesh_data.colors = colors;
+        return Ok(());
+    }
+
+    pub fn set_materials(&mut self, materials: Vec<&'a material::Material>, material_ids: Vec<u32>) -> Result<()> {
+        if materials.is_empty() {
+            return Err(Error::InvalidScene("mesh without materials".to_string()));
+        }
+        if !material_ids.is_empty() && material_ids.len() != self.mesh_data.num_triangles as usize {
+            return Err(Error::InvalidScene(format!(
+                "{} material ids for {} triangles", material_ids.len(), self.mesh_data.num_triangles
+            )));
+        }
+        if let Some(i) = material_ids.iter().position(|&id| id as usize >= materials.len()) {
+            return Err(Error::InvalidScene(format!(
+                "material id {} of triangle {} is out of range", material_ids[i], i
+            )));
+        }
+        self.mesh_data.materials = materials;
+        self.mesh_data.material_ids = material_ids;
+        return Ok(());
+    }
+
+    // mesh without an accelerator yet, call build or build_cached before tracing rays
+    pub fn new_unbuilt(
+        vertices: Vec<glm::Vec3>,
+        st_coordinates: Vec<glm::Vec2>,
+        indices: Vec<u32>,
+        mat: &'a material::Material,
     ) -> MeshTriangle<'a>
     {
-        let mut bounding_box = Bounds3::new(&vertices[0], &vertices[1]);   
+        let mut bounding_box = Bounds3::default();
         for vert in vertices.iter() {
-            bounding_box = bounding_box.intersect_p(vert);
+            bounding_box = bounding_box.union_p(vert);
         }
 
         let num_triangles = indices.len() as u32 / 3;
@@ -141,31 +389,207 @@ impl<'a> MeshTriangle<'a> {
             vertices,
             indices,
             st_coordinates,
-            m: mat,
+            normals: Vec::new(),
+            colors: Vec::new(),
+            materials: vec![mat],
+            material_ids: Vec::new(),
+            checker_pattern: true,
         };
 
         MeshTriangle {
             mesh_data,
             bounding_box,
-            triangles: Vec::new(), 
-            bvh: None,
+            accelerator: None,
+            accelerator_type: AcceleratorType::BVH,
+            split_method: SplitMethod::SAH,
+        }
+    }
+
+    // [comment]
+    // Triangles are light views into mesh_data, so the accelerator only keeps
+    // triangle indices and a Triangle is created on demand during traversal.
+    // Fails when the indices are not whole triangles of existing vertices or a
+    // vertex is NaN or infinite.
+    // [/comment]
+    pub fn build(&mut self) -> Result<()> {
+        check_indices(&self.mesh_data.indices, self.mesh_data.vertices.len())?;
+        check_vertices(&self.mesh_data.vertices)?;
+        // texture coordinates are looked up by the vertex indices
+        if self.mesh_data.st_coordinates.len() != self.mesh_data.vertices.len() {
+            return Err(Error::InvalidScene(format!(
+                "{} texture coordinates for {} vertices", self.mesh_data.st_coordinates.len(), self.mesh_data.vertices.len()
+            )));
+        }
+        let bounds = self.triangle_bounds();
+        self.accelerator = Some(build_accelerator(
+            self.accelerator_type, bounds, MESH_MAX_PRIMS_IN_NODE, self.split_method
+        ));
+        return Ok(());
+    }
+
+    // [comment]
+    // Returns true when the bvh came from the cache. Only bvhs are cached, other
+    // accelerator types are always built.
+    // [/comment]
+    pub fn build_cached(&mut self, cache_path: &std::path::Path) -> Result<bool> {
+        if self.accelerator_type != AcceleratorType::BVH {
+            self.build()?;
+            return Ok(false);
+        }
+        let hash = bvh_cache::mesh_hash(
+            &self.mesh_data.vertices, &self.mesh_data.indices, MESH_MAX_PRIMS_IN_NODE, &self.split_method
+        );
+        if let Ok(Some(bvh)) = bvh_cache::load_bvh(cache_path, hash, self.mesh_data.num_triangles as usize) {
+            self.accelerator = Some(Box::new(bvh));
+            return Ok(true);
+        }
+
+        self.build()?;
+        if let Some(bvh) = self.accelerator.as_ref().and_then(|a| a.as_bvh()) {
+            if let Err(e) = bvh_cache::save_bvh(cache_path, bvh, hash) {
+                println!("failed to write bvh cache {}: {}", cache_path.display(), e);
+            }
+        }
+        return Ok(false);
+    }
+
+    fn triangle_bounds(&self) -> Vec<Bounds3> {
+        return (0..self.mesh_data.num_triangles)
+            .into_par_iter()
+            .map(|i| Triangle::new(&self.mesh_data, i).get_bounds())
+            .collect();
+    }
+
+    // [comment]
+    // Move the vertices of an animated or deforming mesh. Indices stay the same,
+    // so a bvh is refitted bottom up instead of being rebuilt.
+    // The renderer draws a single frame, so only callers animating a mesh reach
+    // this and rebuild_if_degraded.
+    // [/comment]
+    #[allow(dead_code)]
+    pub fn update_vertices(&mut self, vertices: Vec<glm::Vec3>) -> Result<()> {
+        if vertices.len() != self.mesh_data.vertices.len() {
+            return Err(Error::InvalidScene(format!(
+                "vertex count changed from {} to {}, use build()", self.mesh_data.vertices.len(), vertices.len()
+            )));
+        }
+        check_vertices(&vertices)?;
+        self.mesh_data.vertices = vertices;
+
+        let bounds = self.triangle_bounds();
+        match self.accelerator.as_mut() {
+            Some(accelerator) => accelerator.refit(&bounds),
+            None => self.build()?,
+        }
+        // the refitted root covers every moved triangle
+        if let Some(accelerator) = self.accelerator.as_ref() {
+            self.bounding_box = accelerator.get_bounds();
+        }
+        return Ok(());
+    }
+
+    // [comment]
+    // Rebuild when refitting degraded the sah cost by more than max_cost_ratio
+    // since the last build, returns whether a rebuild happened.
+    // [/comment]
+    #[allow(dead_code)]
+    pub fn rebuild_if_degraded(&mut self, max_cost_ratio: f32) -> Result<bool> {
+        let degraded = match &self.accelerator {
+            Some(accelerator) => match accelerator.as_bvh() {
+                Some(bvh) => bvh.needs_rebuild(max_cost_ratio),
+                // only the binary bvh keeps track of its refit quality
+                None => false,
+            },
+            None => true,
+        };
+        if degraded {
+            self.build()?;
+        }
+        return Ok(degraded);
+    }
+
+    // [comment]
+    // Smooth shading from per vertex normals, e.g. the ones of an obj file. A corner
+    // whose normal is more than crease_angle degrees off its face normal gets the
+    // face normal instead, so hard edges stay hard.
+    // [/comment]
+    pub fn set_vertex_normals(&mut self, normals: &[glm::Vec3], crease_angle: f32) -> Result<()> {
+        check_indices(&self.mesh_data.indices, self.mesh_data.vertices.len())?;
+        if normals.len() != self.mesh_data.vertices.len() {
+            return Err(Error::InvalidScene(format!(
+                "{} normals for {} vertices", normals.len(), self.mesh_data.vertices.len()
+            )));
+        }
+        let cos_crease = deg_2_rad(crease_angle).cos();
+        let mut corner_normals = Vec::with_capacity(self.mesh_data.indices.len());
+        for t in 0..self.mesh_data.num_triangles {
+            let face = self.triangle(t).geometric_normal();
+            for k in 0..3usize {
+                let n = normals[self.mesh_data.indices[t as usize * 3 + k] as usize];
+                let n = if glm::length2(&n) > 0. { n.normalize() } else { face };
+                corner_normals.push(if glm::dot(&n, &face) >= cos_crease { n } else { face });
+            }
         }
+        self.mesh_data.normals = corner_normals;
+        return Ok(());
     }
 
-    pub fn build(&'a mut self) {
-        let num_triangles = self.mesh_data.num_triangles;
-        let mut triangles = Vec::with_capacity(num_triangles as usize);
-        for i in 0..num_triangles {
-            let tri = Triangle::new(&self.mesh_data, i);
-            triangles.push(tri);
+    // [comment]
+    // Generate smooth normals for a mesh without any. Every corner averages the
+    // normals of the faces around its position weighted by their angle at that
+    // position, vertices split only for texture coordinates still smooth together.
+    // Faces meeting at more than crease_angle degrees do not smooth each other.
+    // [/comment]
+    pub fn generate_normals(&mut self, crease_angle: f32) -> Result<()> {
+        check_indices(&self.mesh_data.indices, self.mesh_data.vertices.len())?;
+        let cos_crease = deg_2_rad(crease_angle).cos();
+        let d = &self.mesh_data;
+        let n_corners = d.num_triangles as usize * 3;
+        let position = |corner: usize| -> glm::Vec3 { d.vertices[d.indices[corner] as usize] };
+
+        let mut face_normals = Vec::with_capacity(d.num_triangles as usize);
+        let mut angles = Vec::with_capacity(n_corners);
+        for t in 0..d.num_triangles as usize {
+            let p = [position(t * 3), position(t * 3 + 1), position(t * 3 + 2)];
+            let n = glm::cross(&(p[1] - p[0]), &(p[2] - p[0]));
+            face_normals.push(if glm::length2(&n) > 0. { n.normalize() } else { glm::zero() });
+            for k in 0..3usize {
+                let e1 = p[(k + 1) % 3] - p[k];
+                let e2 = p[(k + 2) % 3] - p[k];
+                let angle = if glm::length2(&e1) > 0. && glm::length2(&e2) > 0. {
+                    glm::angle(&e1, &e2)
+                } else {
+                    0.
+                };
+                angles.push(angle);
+            }
         }
-        self.triangles = triangles;
 
-        let mut object_vec = Vec::with_capacity(num_triangles as usize);
-        for tri in self.triangles.iter() {
-            object_vec.push(tri as &dyn ObjectTrait)
+        // corners sharing a position, keyed by the exact coordinates
+        let mut shared: std::collections::HashMap<[u32; 3], Vec<usize>> = std::collections::HashMap::new();
+        for corner in 0..n_corners {
+            let p = position(corner);
+            shared.entry([p.x.to_bits(), p.y.to_bits(), p.z.to_bits()]).or_default().push(corner);
         }
-        self.bvh = Some(BVHAccel::new(object_vec, 0, SplitMethod::NAIVE));
+
+        let normals: Vec<glm::Vec3> = (0..n_corners).into_par_iter().map(|corner| {
+            let p = position(corner);
+            let face = face_normals[corner / 3];
+            let mut n = glm::Vec3::zeros();
+            for &other in &shared[&[p.x.to_bits(), p.y.to_bits(), p.z.to_bits()]] {
+                let other_face = face_normals[other / 3];
+                if glm::dot(&face, &other_face) >= cos_crease {
+                    n += other_face * angles[other];
+                }
+            }
+            return if glm::length2(&n) > 0. { n.normalize() } else { face };
+        }).collect();
+        self.mesh_data.normals = normals;
+        return Ok(());
+    }
+
+    pub fn triangle(&self, ind: u32) -> Triangle<'_> {
+        return Triangle::new(&self.mesh_data, ind);
     }
 
 }
@@ -175,14 +599,68 @@ impl<'a> ObjectTrait for MeshTriangle<'a> {
         return self.bounding_box.clone();
     }
 
-    fn get_intersection(&self, ray: &crate::ray::Ray) -> Option<IntersectData> {
-        return self.bvh.as_ref().unwrap().get_intersection(ray);
+    // [comment]
+    // A mesh that was not built yet has its triangles tested one by one.
+    // [/comment]
+    fn get_intersection(&self, ray: &Ray) -> Option<IntersectData> {
+        let intersect = |i: usize, r: &Ray| self.triangle(i as u32).intersect(r);
+        return match &self.accelerator {
+            Some(accelerator) => accelerator.get_intersection(ray, &intersect),
+            None => {
+                let mut nearest = None;
+                for i in 0..self.mesh_data.num_triangles as usize {
+                    if let Some(data) = intersect(i, ray) {
+                        keep_nearest(&mut nearest, data);
+                    }
+                }
+                nearest
+            }
+        };
+    }
+
+    fn occluded(&self, ray: &Ray, t_max: f32) -> bool {
+        let occluded = |i: usize, r: &Ray, t: f32| self.triangle(i as u32).intersect_distance(r).is_some_and(|d| d < t);
+        return match &self.accelerator {
+            Some(accelerator) => accelerator.occluded(ray, t_max, &occluded),
+            None => (0..self.mesh_data.num_triangles as usize).any(|i| occluded(i, ray, t_max)),
+        };
+    }
+
+    fn get_intersection_packet<'s>(
+        &'s self,
+        packet: &RayPacket,
+        active: &[bool],
+        hits: &mut [Option<IntersectData<'s>>]
+    ) {
+        let intersect = |i: usize, p: &RayPacket, mask: &[bool], h: &mut [Option<IntersectData<'s>>]| {
+            let triangle = self.triangle(i as u32);
+            for (k, ray) in p.rays.iter().enumerate() {
+                if mask[k] {
+                    if let Some(data) = triangle.intersect(ray) {
+                        keep_nearest(&mut h[k], data);
+                    }
+                }
+            }
+        };
+        match &self.accelerator {
+            Some(accelerator) => accelerator.get_intersection_packet(packet, active, &intersect, hits),
+            None => {
+                for i in 0..self.mesh_data.num_triangles as usize {
+                    intersect(i, packet, active, hits);
+                }
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::triangle::ray_triangle_intersect;
+    use crate::error::Error;
+    use crate::material::Material;
+    use crate::obj_loader::ObjMesh;
+    use crate::object::ObjectTrait;
+    use crate::ray::Ray;
+    use crate::triangle::{ray_triangle_intersect, MeshTriangle};
 
     #[test]
     fn test_ray_triangle_intersect() {
@@ -195,13 +673,264 @@ mod tests {
         let mut v = 0f32;
         let r = ray_triangle_intersect(
             &v0, &v1, &v2,
-            &glm::vec3(0., 0., 0.), &glm::vec3(1., 1., 0.).normalize(),
+            &Ray::new(&glm::vec3(0., 0., 0.), &glm::vec3(1., 1., 0.).normalize()),
             &mut t, &mut u, &mut v);
         assert!(r && (f32::abs(t - 0.5_f32.sqrt()) <= 0.001), format!("{}:{} {} {}", r, t, u, v));
         let r = ray_triangle_intersect(
             &v0, &v1, &v2,
-            &glm::vec3(0., 0., 0.), &glm::vec3(1., 1., 1.).normalize(),
+            &Ray::new(&glm::vec3(0., 0., 0.), &glm::vec3(1., 1., 1.).normalize()),
             &mut t, &mut u, &mut v);
         assert!(r && (f32::abs(t - 0.577) <= 0.001), format!("{}:{} {} {}", r, t*t, u, v));
+        let r = ray_triangle_intersect(
+            &v0, &v1, &v2,
+            &Ray::segment(&glm::vec3(0., 0., 0.), &glm::vec3(1., 1., 1.).normalize(), 0., 0.5),
+            &mut t, &mut u, &mut v);
+        assert!(!r);
+    }
+
+    fn assert_close(a: &glm::Vec3, b: &glm::Vec3) {
+        assert!(glm::distance(a, b) < 1e-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn test_triangle_hit_data() {
+        let mat = Material::default();
+        // right triangle at z = -2 facing +z, st follows x and y
+        let mesh = MeshTriangle::new(
+            vec![glm::vec3(0., 0., -2.), glm::vec3(2., 0., -2.), glm::vec3(0., 2., -2.)],
+            vec![glm::vec2(0., 0.), glm::vec2(1., 0.), glm::vec2(0., 1.)],
+            vec![0, 1, 2],
+            &mat
+        ).unwrap();
+        let hit = mesh.get_intersection(&Ray::new(&glm::vec3(0.5, 0.5, 0.), &glm::vec3(0., 0., -1.))).unwrap();
+        assert!((hit.distance - 2.).abs() < 1e-5);
+        assert_close(&hit.coords, &glm::vec3(0.5, 0.5, -2.));
+        assert_close(&hit.normal, &glm::vec3(0., 0., 1.));
+        assert_close(&hit.barycentric, &glm::vec3(0.5, 0.25, 0.25));
+        assert!(glm::distance(&hit.st, &glm::vec2(0.25, 0.25)) < 1e-5);
+        assert!(glm::distance(&hit.uv, &glm::vec2(0.25, 0.25)) < 1e-5);
+        assert!(hit.front_face);
+
+        // from behind the normal keeps its winding, only the flag changes
+        let hit = mesh.get_intersection(&Ray::new(&glm::vec3(1.5, 0.25, -5.), &glm::vec3(0., 0., 1.))).unwrap();
+        assert_close(&hit.coords, &glm::vec3(1.5, 0.25, -2.));
+        assert_close(&hit.normal, &glm::vec3(0., 0., 1.));
+        assert_close(&hit.barycentric, &glm::vec3(0.125, 0.75, 0.125));
+        assert!(!hit.front_face);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_tilted_triangle_hit_data() {
+        let mat = Material::default();
+        // the plane x + y + z = 1, hit in its centroid
+        let mesh = MeshTriangle::new(
+            vec![glm::vec3(1., 0., 0.), glm::vec3(0., 1., 0.), glm::vec3(0., 0., 1.)],
+            vec![glm::vec2(0., 0.); 3],
+            vec![0, 1, 2],
+            &mat
+        ).unwrap();
+        let dir = glm::vec3(1., 1., 1.).normalize();
+        let hit = mesh.get_intersection(&Ray::new(&glm::vec3(0., 0., 0.), &dir)).unwrap();
+        let third = 1. / 3.;
+        assert!((hit.distance - 3f32.sqrt() * third).abs() < 1e-5);
+        assert_close(&hit.coords, &glm::vec3(third, third, third));
+        assert_close(&hit.barycentric, &glm::vec3(third, third, third));
+        assert_close(&hit.normal, &dir);
+        assert!(!hit.front_face);
+        assert!(glm::comp_max(&hit.p_error) > 0. && glm::comp_max(&hit.p_error) < 1e-5);
+    }
+
+    #[test]
+    fn test_smooth_normals() {
+        let mat = Material::default();
+        // a floor facing +z folded up at y = 1 into a wall facing -y
+        let vertices = vec![
+            glm::vec3(0., 0., 0.), glm::vec3(1., 0., 0.), glm::vec3(1., 1., 0.),
+            glm::vec3(0., 1., 0.), glm::vec3(1., 1., 1.), glm::vec3(0., 1., 1.),
+        ];
+        let indices = vec![0, 1, 2, 0, 2, 3, 3, 2, 4, 3, 4, 5];
+        let mut mesh = MeshTriangle::new(vertices.clone(), vec![glm::vec2(0., 0.); 6], indices, &mat).unwrap();
+        let ray = Ray::new(&glm::vec3(0.25, 0.75, 1.), &glm::vec3(0., 0., -1.));
+        let up = glm::vec3(0., 0., 1.);
+        let edge = glm::vec3(0., -1., 1.).normalize();
+
+        // both faces have a right angle at the fold, so it averages to the diagonal
+        mesh.generate_normals(180.).unwrap();
+        let hit = mesh.get_intersection(&ray).unwrap();
+        assert_close(&hit.barycentric, &glm::vec3(0.25, 0.25, 0.5));
+        assert_close(&hit.normal, &up);
+        assert_close(&hit.shading_normal, &(up * 0.25 + edge * 0.75).normalize());
+
+        // a crease below the fold angle keeps the edge hard
+        mesh.generate_normals(30.).unwrap();
+        assert_close(&mesh.get_intersection(&ray).unwrap().shading_normal, &up);
+
+        // given normals 45 degrees off the faces are kept or replaced by the face normal
+        let mut normals = vec![edge; 6];
+        normals[0] = up;
+        normals[1] = up;
+        mesh.set_vertex_normals(&normals, 60.).unwrap();
+        assert_close(&mesh.get_intersection(&ray).unwrap().shading_normal, &(up * 0.25 + edge * 0.75).normalize());
+        mesh.set_vertex_normals(&normals, 30.).unwrap();
+        assert_close(&mesh.get_intersection(&ray).unwrap().shading_normal, &up);
+    }
+
+    #[test]
+    fn test_mesh_materials() {
+        let materials = vec![
+            Material::default(),
+            Material::new(None, Some(glm::vec3(1., 0., 0.)), None),
+        ];
+        // two triangles side by side at z = -1, the right one uses material 1
+        let obj = ObjMesh {
+            name: "quad".to_string(),
+            vertices: vec![glm::vec3(-1., -1., -1.), glm::vec3(0., -1., -1.), glm::vec3(0., 1., -1.), glm::vec3(1., -1., -1.)],
+            st_coordinates: vec![glm::vec2(0., 0.); 4],
+            normals: Vec::new(),
+            indices: vec![0, 1, 2, 1, 3, 2],
+            material_ids: vec![0, 1],
+        };
+        let mut mesh = MeshTriangle::from_obj(&obj, &materials).unwrap();
+        mesh.build().unwrap();
+        let hit = |x: f32| mesh.get_intersection(&Ray::new(&glm::vec3(x, -0.5, 0.), &glm::vec3(0., 0., -1.))).unwrap();
+        assert!(std::ptr::eq(hit(-0.2).m, &materials[0]));
+        assert!(std::ptr::eq(hit(0.2).m, &materials[1]));
+        // the checker pattern is tinted by the material color
+        assert_eq!(hit(0.2).eval_diffuse_color.y, 0.);
+        assert!(hit(-0.2).eval_diffuse_color.y > 0.);
+    }
+
+    #[test]
+    fn test_bad_mesh_attributes() {
+        let mat = Material::default();
+        let vertices = vec![glm::vec3(0., 0., 0.), glm::vec3(1., 0., 0.), glm::vec3(0., 1., 0.)];
+        // index 5 of three vertices fails before any normal is looked up
+        let mut mesh = MeshTriangle::new_unbuilt(vertices.clone(), Vec::new(), vec![0, 1, 5], &mat);
+        assert!(mesh.set_vertex_normals(&[glm::vec3(0., 0., 1.); 3], 180.).is_err());
+        assert!(mesh.generate_normals(180.).is_err());
+
+        let mut mesh = MeshTriangle::new_unbuilt(vertices, Vec::new(), vec![0, 1, 2], &mat);
+        assert!(mesh.set_vertex_normals(&[glm::vec3(0., 0., 1.); 2], 180.).is_err());
+        assert!(mesh.set_vertex_colors(vec![glm::vec3(1., 0., 0.); 4]).is_err());
+        assert!(mesh.set_materials(Vec::new(), Vec::new()).is_err());
+        assert!(mesh.set_materials(vec![&mat], vec![1]).is_err());
+        assert!(mesh.set_materials(vec![&mat], vec![0, 0]).is_err());
+        assert!(mesh.set_materials(vec![&mat], vec![0]).is_ok());
+        // texture coordinates are missing for the three vertices
+        assert!(matches!(mesh.build(), Err(Error::InvalidScene(_))));
+        mesh.mesh_data.st_coordinates = vec![glm::vec2(0., 0.); 3];
+        assert!(mesh.build().is_ok());
+    }
+
+    #[test]
+    fn test_watertight_shared_edge() {
+        // two triangles sharing the edge a-b, far from the origin so rounding matters
+        let a = glm::vec3(1000.1, 999.7, -3000.3);
+        let b = glm::vec3(1003.9, 1002.3, -3001.1);
+        let c = glm::vec3(1000.3, 1003.1, -3000.9);
+        let d = glm::vec3(1004.2, 998.9, -3000.2);
+        let (mut t, mut u, mut v) = (0f32, 0f32, 0f32);
+        let mut misses = 0;
+        for k in 0..1000 {
+            let f = k as f32 / 1000.;
+            let target = a + (b - a) * f;
+            let origin = glm::vec3((k % 7) as f32 * 0.37, (k % 11) as f32 * 0.21, 0.);
+            let ray = Ray::new(&origin, &(target - origin).normalize());
+            let hit_1 = ray_triangle_intersect(&a, &b, &c, &ray, &mut t, &mut u, &mut v);
+            let hit_2 = ray_triangle_intersect(&b, &a, &d, &ray, &mut t, &mut u, &mut v);
+            if !hit_1 && !hit_2 {
+                misses += 1;
+            }
+        }
+        assert_eq!(misses, 0);
+    }
+
+    #[test]
+    fn test_mesh_update_vertices() {
+        let mat = Material::default();
+        // a strip of quads along x at z = -5
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        for i in 0..=16u32 {
+            vertices.push(glm::vec3(i as f32, 0., -5.));
+            vertices.push(glm::vec3(i as f32, 1., -5.));
+            if i < 16 {
+                let b = i * 2;
+                indices.extend_from_slice(&[b, b + 2, b + 3, b, b + 3, b + 1]);
+            }
+        }
+        let sts = vec![glm::vec2(0., 0.); vertices.len()];
+        let mut mesh = MeshTriangle::new(vertices.clone(), sts, indices, &mat).unwrap();
+
+        let ray = Ray::new(&glm::vec3(10.5, 0.5, 0.), &glm::vec3(0., 0., -1.));
+        assert!((mesh.get_intersection(&ray).unwrap().distance - 5.).abs() < 1e-4);
+        assert!(mesh.occluded(&ray, 6.));
+        // the strip lies behind a light 4 units away
+        assert!(!mesh.occluded(&ray, 4.));
+        // segments that end before or start after the strip
+        let dir = glm::vec3(0., 0., -1.);
+        assert!(mesh.get_intersection(&Ray::segment(&ray.origin, &dir, 0., 4.9)).is_none());
+        assert!(mesh.get_intersection(&Ray::segment(&ray.origin, &dir, 5.1, 10.)).is_none());
+
+        // push the strip back, the refitted tree must still find it
+        let moved: Vec<glm::Vec3> = vertices.iter().map(|v| v - glm::vec3(0., 0., 3.)).collect();
+        mesh.update_vertices(moved).unwrap();
+        assert!((mesh.get_bounds().p_min.z + 8.).abs() < 1e-5);
+        assert!((mesh.get_intersection(&ray).unwrap().distance - 8.).abs() < 1e-4);
+        assert!(!mesh.rebuild_if_degraded(1.5).unwrap());
+    }
+
+    #[test]
+    fn test_mesh_bvh_cache() {
+        let mat = Material::default();
+        let path = std::env::temp_dir().join(format!("game101_5_mesh_{}.bvh", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let quad = |z: f32| -> Vec<glm::Vec3> {
+            vec![
+                glm::vec3(-1., -1., z), glm::vec3(1., -1., z),
+                glm::vec3(1., 1., z), glm::vec3(-1., 1., z),
+            ]
+        };
+        let sts = vec![glm::vec2(0., 0.); 4];
+        let indices = vec![0, 1, 2, 0, 2, 3];
+
+        let mut mesh = MeshTriangle::new_unbuilt(quad(-2.), sts.clone(), indices.clone(), &mat);
+        assert!(!mesh.build_cached(&path).unwrap());
+        assert!(path.exists());
+        assert!(mesh.build_cached(&path).unwrap());
+
+        // different vertices, the stale cache must be replaced
+        let mut other = MeshTriangle::new_unbuilt(quad(-4.), sts, indices, &mat);
+        assert!(!other.build_cached(&path).unwrap());
+        let ray = Ray::new(&glm::vec3(0.5, 0.5, 0.), &glm::vec3(0., 0., -1.));
+        assert!((other.get_intersection(&ray).unwrap().distance - 4.).abs() < 1e-4);
+        assert!(other.build_cached(&path).unwrap());
+        assert!(!mesh.build_cached(&path).unwrap());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_mesh_build_errors() {
+        let mat = Material::default();
+        let vertices = vec![glm::vec3(-1., -1., -2.), glm::vec3(1., -1., -2.), glm::vec3(0., 1., -2.)];
+        let sts = vec![glm::vec2(0., 0.); 3];
+
+        // an unbuilt mesh is traced triangle by triangle
+        let mut mesh = MeshTriangle::new_unbuilt(vertices.clone(), sts.clone(), vec![0, 1, 2], &mat);
+        let ray = Ray::new(&glm::vec3(0., 0., 0.), &glm::vec3(0., 0., -1.));
+        assert!((mesh.get_intersection(&ray).unwrap().distance - 2.).abs() < 1e-5);
+        assert!(mesh.occluded(&ray, 3.) && !mesh.occluded(&ray, 1.));
+
+        assert!(matches!(mesh.update_vertices(vertices[..2].to_vec()), Err(Error::InvalidScene(_))));
+        let mut broken = vertices.clone();
+        broken[1].y = f32::NAN;
+        assert!(matches!(mesh.update_vertices(broken.clone()), Err(Error::InvalidScene(_))));
+        assert!(MeshTriangle::new(broken, sts.clone(), vec![0, 1, 2], &mat).is_err());
+
+        // bad indices are reported instead of panicking, also for tiny meshes
+        assert!(MeshTriangle::new(vertices.clone(), sts.clone(), vec![0, 1, 3], &mat).is_err());
+        assert!(MeshTriangle::new(vertices, sts, vec![0, 1, 2, 0], &mat).is_err());
+        assert!(MeshTriangle::new(vec![glm::vec3(0., 0., 0.)], vec![glm::vec2(0., 0.)], Vec::new(), &mat).is_ok());
+    }
+}
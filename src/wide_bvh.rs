@@ -0,0 +1,440 @@
+use crate::accelerator::Accelerator;
+use crate::bounds3::Bounds3;
+use crate::bvh::{count_node_visit, count_primitive_test, BVHAccel, BVHBuildNode, SplitMethod};
+use crate::intersection::IntersectData;
+use crate::ray::Ray;
+
+#[derive(Copy, Clone)]
+pub enum WideChild {
+    Empty,
+    Node(u32),
+    // primitives[first..first + count] of WideBVH::primitives
+    Leaf { first: u32, count: u32 },
+}
+
+// [comment]
+// N child boxes stored as structure of arrays, so one node is tested against a
+// ray with a handful of N wide min/max operations.
+// [/comment]
+pub struct WideNode<const N: usize> {
+    pub min_x: [f32; N],
+    pub min_y: [f32; N],
+    pub min_z: [f32; N],
+    pub max_x: [f32; N],
+    pub max_y: [f32; N],
+    pub max_z: [f32; N],
+    pub children: [WideChild; N],
+}
+
+impl<const N: usize> Default for WideNode<N> {
+    fn default() -> Self {
+        WideNode {
+            min_x: [f32::INFINITY; N],
+            min_y: [f32::INFINITY; N],
+            min_z: [f32::INFINITY; N],
+            max_x: [f32::NEG_INFINITY; N],
+            max_y: [f32::NEG_INFINITY; N],
+            max_z: [f32::NEG_INFINITY; N],
+            children: [WideChild::Empty; N],
+        }
+    }
+}
+
+impl<const N: usize> WideNode<N> {
+    fn set_bounds(&mut self, k: usize, b: &Bounds3) {
+        self.min_x[k] = b.p_min.x;
+        self.min_y[k] = b.p_min.y;
+        self.min_z[k] = b.p_min.z;
+        self.max_x[k] = b.p_max.x;
+        self.max_y[k] = b.p_max.y;
+        self.max_z[k] = b.p_max.z;
+    }
+
+    // union of all child boxes
+    fn bounds(&self) -> Bounds3 {
+        let mut b = Bounds3::default();
+        for k in 0..N {
+            if !matches!(self.children[k], WideChild::Empty) {
+                b = Bounds3::union(&b, &Bounds3 {
+                    p_min: glm::vec3(self.min_x[k], self.min_y[k], self.min_z[k]),
+                    p_max: glm::vec3(self.max_x[k], self.max_y[k], self.max_z[k]),
+                });
+            }
+        }
+        return b;
+    }
+}
+
+// ray data shared by all box tests of one traversal
+pub struct RayData {
+    pub origin: [f32; 3],
+    pub direction_inv: [f32; 3],
+}
+
+pub trait WideNodeTest<const N: usize> {
+    // entry distance of the ray into each child box clipped to [t_min, t_max],
+    // f32::INFINITY on a miss
+    fn child_distances(&self, ray: &RayData, t_min: f32, t_max: f32) -> [f32; N];
+}
+
+#[cfg(feature = "simd")]
+macro_rules! impl_simd_node_test {
+    ($n:expr, $simd:ty) => {
+        impl WideNodeTest<$n> for WideNode<$n> {
+            fn child_distances(&self, ray: &RayData, t_min: f32, t_max: f32) -> [f32; $n] {
+                use wide::CmpLe;
+                let ox = <$simd>::splat(ray.origin[0]);
+                let oy = <$simd>::splat(ray.origin[1]);
+                let oz = <$simd>::splat(ray.origin[2]);
+                let ix = <$simd>::splat(ray.direction_inv[0]);
+                let iy = <$simd>::splat(ray.direction_inv[1]);
+                let iz = <$simd>::splat(ray.direction_inv[2]);
+
+                let t0x = (<$simd>::from(self.min_x) - ox) * ix;
+                let t1x = (<$simd>::from(self.max_x) - ox) * ix;
+                let t0y = (<$simd>::from(self.min_y) - oy) * iy;
+                let t1y = (<$simd>::from(self.max_y) - oy) * iy;
+                let t0z = (<$simd>::from(self.min_z) - oz) * iz;
+                let t1z = (<$simd>::from(self.max_z) - oz) * iz;
+
+                let t_near = t0x.min(t1x)
+                    .max(t0y.min(t1y))
+                    .max(t0z.min(t1z))
+                    .max(<$simd>::splat(t_min));
+                let t_far = t0x.max(t1x)
+                    .min(t0y.max(t1y))
+                    .min(t0z.max(t1z))
+                    .min(<$simd>::splat(t_max));
+                let hit = t_near.cmp_le(t_far);
+                return hit.blend(t_near, <$simd>::splat(f32::INFINITY)).to_array();
+            }
+        }
+    };
+}
+
+#[cfg(feature = "simd")]
+impl_simd_node_test!(4, wide::f32x4);
+#[cfg(feature = "simd")]
+impl_simd_node_test!(8, wide::f32x8);
+
+// scalar fallback when built without the simd feature
+#[cfg(not(feature = "simd"))]
+impl<const N: usize> WideNodeTest<N> for WideNode<N> {
+    fn child_distances(&self, ray: &RayData, t_min: f32, t_max: f32) -> [f32; N] {
+        let mut ret = [f32::INFINITY; N];
+        for k in 0..N {
+            let t0x = (self.min_x[k] - ray.origin[0]) * ray.direction_inv[0];
+            let t1x = (self.max_x[k] - ray.origin[0]) * ray.direction_inv[0];
+            let t0y = (self.min_y[k] - ray.origin[1]) * ray.direction_inv[1];
+            let t1y = (self.max_y[k] - ray.origin[1]) * ray.direction_inv[1];
+            let t0z = (self.min_z[k] - ray.origin[2]) * ray.direction_inv[2];
+            let t1z = (self.max_z[k] - ray.origin[2]) * ray.direction_inv[2];
+            let t_near = t0x.min(t1x).max(t0y.min(t1y)).max(t0z.min(t1z)).max(t_min);
+            let t_far = t0x.max(t1x).min(t0y.max(t1y)).min(t0z.max(t1z)).min(t_max);
+            if t_near <= t_far {
+                ret[k] = t_near;
+            }
+        }
+        return ret;
+    }
+}
+
+// [comment]
+// BVH with up to N children per node, made by collapsing a binary BVHAccel: the
+// largest interior child is repeatedly replaced by its own children until all N
+// slots are used. Primitive order and leaves are taken over unchanged.
+// [/comment]
+pub struct WideBVH<const N: usize> {
+    pub nodes: Vec<WideNode<N>>,
+    pub primitives: Vec<usize>,
+    pub bounds: Bounds3,
+}
+
+pub type BVH4 = WideBVH<4>;
+pub type BVH8 = WideBVH<8>;
+
+thread_local! {
+    // traversal stack kept between rays, see get_intersection
+    static STACK: std::cell::Cell<Vec<(WideChild, f32)>> = const { std::cell::Cell::new(Vec::new()) };
+}
+
+impl<const N: usize> WideBVH<N> {
+    pub fn new(bounds: Vec<Bounds3>, max_prims_in_node: u32, split_method: SplitMethod) -> Self {
+        let bvh = BVHAccel::from_bounds(bounds, max_prims_in_node, split_method);
+        return WideBVH::from_bvh(&bvh);
+    }
+
+    pub fn from_bvh(bvh: &BVHAccel) -> Self {
+        let mut wide = WideBVH {
+            nodes: Vec::new(),
+            primitives: bvh.primitives.clone(),
+            bounds: bvh.get_bounds(),
+        };
+        if let Some(root) = &bvh.root {
+            wide.collapse(root);
+        }
+        return wide;
+    }
+
+    fn collapse(&mut self, node: &BVHBuildNode) -> u32 {
+        let mut children: Vec<&BVHBuildNode> = vec![node];
+        while children.len() < N {
+            let mut largest: Option<usize> = None;
+            for (k, c) in children.iter().enumerate() {
+                if c.is_leaf() {
+                    continue;
+                }
+                if largest.is_none()
+                    || c.bounds.surface_area() > children[largest.unwrap()].bounds.surface_area() {
+                    largest = Some(k);
+                }
+            }
+            let k = match largest {
+                Some(k) => k,
+                None => break,
+            };
+            let c = children.swap_remove(k);
+            for g in [&c.left, &c.right].iter().copied().flatten() {
+                children.push(g);
+            }
+        }
+
+        let index = self.nodes.len();
+        self.nodes.push(WideNode::default());
+        for (k, c) in children.iter().enumerate() {
+            let child = if c.is_leaf() {
+                WideChild::Leaf { first: c.first_prim_offset as u32, count: c.n_primitives as u32 }
+            } else {
+                WideChild::Node(self.collapse(c))
+            };
+            let node = &mut self.nodes[index];
+            node.set_bounds(k, &c.bounds);
+            node.children[k] = child;
+        }
+        return index as u32;
+    }
+}
+
+impl<const N: usize> Accelerator for WideBVH<N> where WideNode<N>: WideNodeTest<N> {
+    fn get_bounds(&self) -> Bounds3 {
+        return self.bounds.clone();
+    }
+
+    // [comment]
+    // Stack based traversal, all children of a node are tested at once and the ones
+    // hit are pushed far to near so the nearest child is visited first.
+    // [/comment]
+    fn get_intersection<'b>(
+        &self,
+        ray: &Ray,
+        intersect: &dyn Fn(usize, &Ray) -> Option<IntersectData<'b>>
+    ) -> Option<IntersectData<'b>> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        self.bounds.ray_interval(ray)?;
+        let ray_data = RayData {
+            origin: [ray.origin.x, ray.origin.y, ray.origin.z],
+            direction_inv: [ray.direction_inv.x, ray.direction_inv.y, ray.direction_inv.z],
+        };
+
+        // t_max shrinks to the nearest hit so far. The stack is taken out of the
+        // thread local for the traversal, so an intersect callback tracing into
+        // another wide bvh starts with a fresh one instead of sharing it.
+        let mut ray = ray.clone();
+        let mut nearest: Option<IntersectData<'b>> = None;
+        let mut stack = STACK.with(|s| s.take());
+        stack.clear();
+        stack.push((WideChild::Node(0), 0.));
+        while let Some((child, t_near)) = stack.pop() {
+            if t_near > ray.t_max {
+                continue;
+            }
+            match child {
+                WideChild::Empty => {}
+                WideChild::Leaf { first, count } => {
+                    for &prim in &self.primitives[first as usize..(first + count) as usize] {
+                        count_primitive_test();
+                        if let Some(data) = intersect(prim, &ray) {
+                            if data.distance <= ray.t_max {
+                                ray.t_max = data.distance;
+                                nearest = Some(data);
+                            }
+                        }
+                    }
+                }
+                WideChild::Node(index) => {
+                    count_node_visit();
+                    let node = &self.nodes[index as usize];
+                    let dist = node.child_distances(&ray_data, ray.t_min, ray.t_max);
+                    // insertion sort of the children hit, farthest first
+                    let mut hits = [(0f32, 0u8); N];
+                    let mut n_hits = 0;
+                    for (k, &t) in dist.iter().enumerate() {
+                        if t == f32::INFINITY || matches!(node.children[k], WideChild::Empty) {
+                            continue;
+                        }
+                        let mut i = n_hits;
+                        while i > 0 && hits[i - 1].0 < t {
+                            hits[i] = hits[i - 1];
+                            i -= 1;
+                        }
+                        hits[i] = (t, k as u8);
+                        n_hits += 1;
+                    }
+                    for &(t, k) in &hits[..n_hits] {
+                        stack.push((node.children[k as usize], t));
+                    }
+                }
+            }
+        }
+        STACK.with(|s| s.set(stack));
+        return nearest;
+    }
+
+    fn occluded(&self, ray: &Ray, t_max: f32, occluded: &dyn Fn(usize, &Ray, f32) -> bool) -> bool {
+        if self.nodes.is_empty() {
+            return false;
+        }
+        let ray_data = RayData {
+            origin: [ray.origin.x, ray.origin.y, ray.origin.z],
+            direction_inv: [ray.direction_inv.x, ray.direction_inv.y, ray.direction_inv.z],
+        };
+        let mut stack = STACK.with(|s| s.take());
+        stack.clear();
+        stack.push((WideChild::Node(0), 0.));
+        let mut hit = false;
+        while let Some((child, _)) = stack.pop() {
+            match child {
+                WideChild::Empty => {}
+                WideChild::Leaf { first, count } => {
+                    let prims = &self.primitives[first as usize..(first + count) as usize];
+                    hit = prims.iter().any(|&prim| {
+                        count_primitive_test();
+                        return occluded(prim, ray, t_max);
+                    });
+                    if hit {
+                        break;
+                    }
+                }
+                WideChild::Node(index) => {
+                    count_node_visit();
+                    let node = &self.nodes[index as usize];
+                    let dist = node.child_distances(&ray_data, ray.t_min, t_max.min(ray.t_max));
+                    for (k, &t) in dist.iter().enumerate() {
+                        if t < f32::INFINITY {
+                            stack.push((node.children[k], t));
+                        }
+                    }
+                }
+            }
+        }
+        STACK.with(|s| s.set(stack));
+        return hit;
+    }
+
+    // [comment]
+    // Children are always stored after their parent, so walking the nodes
+    // backwards refits every child box before the parent box that holds it.
+    // [/comment]
+    fn refit(&mut self, bounds: &[Bounds3]) {
+        for index in (0..self.nodes.len()).rev() {
+            for k in 0..N {
+                let b = match self.nodes[index].children[k] {
+                    WideChild::Empty => continue,
+                    WideChild::Leaf { first, count } => self.primitives[first as usize..(first + count) as usize]
+                        .iter()
+                        .fold(Bounds3::default(), |b, &prim| Bounds3::union(&b, &bounds[prim])),
+                    WideChild::Node(child) => self.nodes[child as usize].bounds(),
+                };
+                self.nodes[index].set_bounds(k, &b);
+            }
+        }
+        self.bounds = match self.nodes.first() {
+            Some(root) => root.bounds(),
+            None => Bounds3::default(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accelerator::Accelerator;
+    use crate::bounds3::Bounds3;
+    use crate::bvh::SplitMethod;
+    use crate::wide_bvh::{RayData, WideChild, WideNode, WideNodeTest, BVH4, BVH8};
+
+    #[test]
+    fn test_child_distances() {
+        let mut node = WideNode::<4>::default();
+        for k in 0..3 {
+            let p = glm::vec3(0., 0., -(k as f32) * 3. - 2.);
+            node.set_bounds(k, &Bounds3::new(&p, &(p + glm::vec3(1., 1., 1.))));
+        }
+        // straight down -z through x = y = 0.5, the fourth slot stays empty
+        let ray = RayData {
+            origin: [0.5, 0.5, 0.],
+            direction_inv: [f32::INFINITY, f32::INFINITY, -1.],
+        };
+        let d = node.child_distances(&ray, 0., 6.);
+        assert_eq!(d[0], 1.);
+        assert_eq!(d[1], 4.);
+        // starts at 7, beyond t_max
+        assert_eq!(d[2], f32::INFINITY);
+        // entry clipped to t_min
+        let d = node.child_distances(&ray, 4.5, 6.);
+        assert_eq!(d[0], f32::INFINITY);
+        assert_eq!(d[1], 4.5);
+    }
+
+    #[test]
+    fn test_collapse() {
+        let bounds: Vec<Bounds3> = (0..100)
+            .map(|i| {
+                let p = glm::vec3(i as f32, (i % 10) as f32, 0.);
+                Bounds3::new(&p, &(p + glm::vec3(0.5, 0.5, 0.5)))
+            })
+            .collect();
+        let bvh4 = BVH4::new(bounds.clone(), 1, SplitMethod::SAH);
+        let bvh8 = BVH8::new(bounds, 1, SplitMethod::SAH);
+        let count_leaves = |children: Vec<WideChild>| -> u32 {
+            return children.iter().map(|c| match c {
+                WideChild::Leaf { count, .. } => *count,
+                _ => 0,
+            }).sum();
+        };
+        assert_eq!(count_leaves(bvh4.nodes.iter().flat_map(|n| n.children.to_vec()).collect()), 100);
+        assert_eq!(count_leaves(bvh8.nodes.iter().flat_map(|n| n.children.to_vec()).collect()), 100);
+        // a binary tree over 100 leaves has 99 interior nodes
+        assert!(bvh4.nodes.len() < 99 * 2 / 3, "{}", bvh4.nodes.len());
+        assert!(bvh8.nodes.len() < bvh4.nodes.len());
+    }
+
+    #[test]
+    fn test_refit() {
+        let bounds: Vec<Bounds3> = (0..50)
+            .map(|i| {
+                let p = glm::vec3(i as f32 * 2., 0., 0.);
+                Bounds3::new(&p, &(p + glm::vec3(1., 1., 1.)))
+            })
+            .collect();
+        let mut bvh = BVH4::new(bounds.clone(), 2, SplitMethod::SAH);
+        let moved: Vec<Bounds3> = bounds.iter()
+            .map(|b| Bounds3::new(&(b.p_min + glm::vec3(0., 5., 0.)), &(b.p_max + glm::vec3(0., 5., 0.))))
+            .collect();
+        bvh.refit(&moved);
+        assert_eq!(bvh.bounds.p_min, glm::vec3(0., 5., 0.));
+        assert_eq!(bvh.bounds.p_max, glm::vec3(99., 6., 1.));
+
+        // every child box holds the moved primitives below it
+        for node in bvh.nodes.iter() {
+            for k in 0..4 {
+                if let WideChild::Leaf { first, count } = node.children[k] {
+                    for &prim in &bvh.primitives[first as usize..(first + count) as usize] {
+                        assert!(node.min_y[k] <= moved[prim].p_min.y && moved[prim].p_max.y <= node.max_y[k]);
+                    }
+                }
+            }
+        }
+    }
+}